@@ -0,0 +1,18 @@
+// Stamps the build with the commit it was made from, as GIT_HASH, which runs record in ingestion_log. A build
+// outside a git checkout (e.g. from a source archive) is stamped "unknown".
+
+use std::process::Command;
+
+fn main() {
+    let hash = Command::new("git").args(["rev-parse", "HEAD"]).output().ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_owned())
+        .filter(|hash| !hash.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned());
+
+    println!("cargo:rustc-env=GIT_HASH={}", hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+}
@@ -0,0 +1,328 @@
+// The raw document archive. Each legacy text release we parse is kept so that documents can be parsed again after
+// a parser improves (--reparse).
+//
+// Documents are stored by content: <root>/blobs/<ab>/<sha256>.txt, where ab are the first two characters of the
+// hash, with <root>/index/<IDENTIFIER>.tsv mapping each report date to the hash of its document. A release that
+// arrives through more than one path (ESMIS, a Market News archive page, a partner's archive) is stored once.
+//
+// Archives from before this layout kept documents as <root>/<IDENTIFIER>/<YYYY-MM-DD>.txt. They are still read,
+// and --migrate-archive moves them into the new layout.
+//
+// With --archive-payloads, what datamart, MARS and ESMIS send is kept too, as it came and before it is parsed, so
+// that a report whose parse failed can be looked into, and history parsed again, without downloading it again:
+// <root>/<source>/<slug>/<YYYY-MM-DD>.json holds the rows of each section of a datamart or MARS report for a report
+// date, and <root>/esmis/<IDENTIFIER>/<YYYY-MM-DD>.txt an ESMIS release. Rows without a date are kept in undated.json.
+// --replay-archive parses and inserts what is kept here instead of fetching it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::NaiveDate;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+use crate::usda::USDADataPackage;
+
+lazy_static! {
+    /// Held while a document is stored, so that documents archived at once don't overwrite each other's index entries
+    /// or temporary files
+    static ref STORE_LOCK: Mutex<()> = Mutex::new(());
+
+    /// The archive responses are kept in, by --archive-payloads
+    static ref PAYLOAD_ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+pub fn content_hash(text: &str) -> String {
+    format!("{:x}", Sha256::digest(text.as_bytes()))
+}
+
+pub fn blob_path(root: &Path, hash: &str) -> PathBuf {
+    root.join("blobs").join(hash.get(..2).unwrap_or(hash)).join(format!("{}.txt", hash))
+}
+
+fn index_path(root: &Path, identifier: &str) -> PathBuf {
+    root.join("index").join(format!("{}.tsv", identifier.to_uppercase()))
+}
+
+/// Where documents were kept before the archive was content-addressed
+fn legacy_document_path(root: &Path, identifier: &str, report_date: NaiveDate) -> PathBuf {
+    root.join(identifier.to_uppercase()).join(format!("{}.txt", report_date.format("%Y-%m-%d")))
+}
+
+/// The date a parsed document is filed under: the latest report date among its rows
+pub fn package_report_date(package: &USDADataPackage) -> Option<NaiveDate> {
+    package.sections.values().flatten().map(|row| row.report_date).max()
+}
+
+/// The hashes of a report's documents by report date, empty for a report with none archived
+pub fn read_index(root: &Path, identifier: &str) -> Result<BTreeMap<NaiveDate, String>, String> {
+    let path = index_path(root, identifier);
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => { c },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => { return Ok(BTreeMap::new()) },
+        Err(e) => { return Err(format!("Failed to read archive index {}: {}", path.display(), e)) }
+    };
+
+    contents.lines().filter(|line| !line.is_empty()).map(|line| {
+        let (date, hash) = line.split_once('\t').ok_or_else(|| format!("Invalid line in {}: {}", path.display(), line))?;
+        let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| format!("Invalid date in {}: {}", path.display(), line))?;
+        Ok((date, hash.to_owned()))
+    }).collect()
+}
+
+/// Replaces an index, through a temporary file so that it is never left half-written
+fn write_index(root: &Path, identifier: &str, index: &BTreeMap<NaiveDate, String>) -> Result<(), String> {
+    let path = index_path(root, identifier);
+    let contents: String = index.iter().map(|(date, hash)| format!("{}\t{}\n", date.format("%Y-%m-%d"), hash)).collect();
+
+    write_atomically(&path, &contents).map_err(|e| format!("Failed to write archive index {}: {}", path.display(), e))
+}
+
+fn write_atomically(path: &Path, contents: &str) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let partial = path.with_extension("partial");
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, path)
+}
+
+/// The report identifiers with archived documents
+pub fn identifiers(root: &Path) -> Result<Vec<String>, String> {
+    let directory = root.join("index");
+    let entries = match fs::read_dir(&directory) {
+        Ok(e) => { e },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => { return Ok(Vec::new()) },
+        Err(e) => { return Err(format!("Failed to list {}: {}", directory.display(), e)) }
+    };
+
+    let mut identifiers: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.strip_suffix(".tsv")).map(str::to_owned))
+        .collect();
+    identifiers.sort();
+
+    Ok(identifiers)
+}
+
+/// Archives a document under a report date, giving the path of its blob. A document already archived, under any
+/// report or date, isn't stored again.
+pub fn store_document(root: &Path, identifier: &str, report_date: NaiveDate, text: &str) -> Result<PathBuf, String> {
+    let hash = content_hash(text);
+    let path = blob_path(root, &hash);
+    let _lock = STORE_LOCK.lock().unwrap();
+
+    if !path.exists() {
+        write_atomically(&path, text).map_err(|e| format!("Failed to archive document to {}: {}", path.display(), e))?;
+    }
+
+    let mut index = read_index(root, identifier)?;
+    if index.get(&report_date) != Some(&hash) {
+        index.insert(report_date, hash);
+        write_index(root, identifier, &index)?;
+    }
+
+    Ok(path)
+}
+
+pub fn read_document(root: &Path, identifier: &str, report_date: NaiveDate) -> Result<String, String> {
+    let path = match read_index(root, identifier)?.get(&report_date) {
+        Some(hash) => { blob_path(root, hash) },
+        None => { legacy_document_path(root, identifier, report_date) }
+    };
+
+    fs::read_to_string(&path).map_err(|e| format!("Failed to read archived document {}: {}", path.display(), e))
+}
+
+/// Keeps the responses of datamart, MARS and ESMIS in the archive at `root` as they come
+pub fn set_payload_archive(root: &Path) {
+    *PAYLOAD_ROOT.lock().unwrap() = Some(root.to_owned());
+}
+
+/// The archive responses are kept in, if they are
+pub fn payload_archive() -> Option<PathBuf> {
+    PAYLOAD_ROOT.lock().unwrap().clone()
+}
+
+pub fn payload_path(root: &Path, source: &str, slug: &str, report_date: Option<NaiveDate>, extension: &str) -> PathBuf {
+    let name = report_date.map(|d| d.format("%Y-%m-%d").to_string()).unwrap_or_else(|| "undated".to_owned());
+    root.join(source).join(slug).join(format!("{}.{}", name, extension))
+}
+
+/// Keeps the rows of one section of a datamart or MARS response, filed by the report date `date_of` finds in each.
+/// What was kept of the section for those dates before is replaced.
+pub fn store_rows<T: Serialize>(root: &Path, source: &str, slug: &str, section: &str, rows: &[T], date_of: impl Fn(&T) -> Option<NaiveDate>) -> Result<(), String> {
+    let mut by_date: BTreeMap<Option<NaiveDate>, Vec<&T>> = BTreeMap::new();
+    for row in rows {
+        by_date.entry(date_of(row)).or_default().push(row);
+    }
+
+    let _lock = STORE_LOCK.lock().unwrap();
+
+    for (report_date, rows) in by_date {
+        let path = payload_path(root, source, slug, report_date, "json");
+
+        let mut sections: BTreeMap<String, serde_json::Value> = match fs::read_to_string(&path) {
+            Ok(text) => { serde_json::from_str(&text).map_err(|e| format!("Invalid archived response {}: {}", path.display(), e))? },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => { BTreeMap::new() },
+            Err(e) => { return Err(format!("Failed to read archived response {}: {}", path.display(), e)) }
+        };
+        sections.insert(section.to_owned(), serde_json::to_value(rows).map_err(|e| e.to_string())?);
+
+        let contents = serde_json::to_string(&sections).map_err(|e| e.to_string())?;
+        write_atomically(&path, &contents).map_err(|e| format!("Failed to archive response to {}: {}", path.display(), e))?;
+    }
+
+    Ok(())
+}
+
+/// Keeps a release as it was fetched, before it is parsed
+pub fn store_text(root: &Path, source: &str, identifier: &str, report_date: Option<NaiveDate>, text: &str) -> Result<(), String> {
+    let path = payload_path(root, source, &identifier.to_uppercase(), report_date, "txt");
+    let _lock = STORE_LOCK.lock().unwrap();
+
+    write_atomically(&path, text).map_err(|e| format!("Failed to archive release to {}: {}", path.display(), e))
+}
+
+/// The responses of a report kept by --archive-payloads with the report date each is filed under, oldest first and
+/// the undated last, empty for a report with none kept
+pub fn payload_files(root: &Path, source: &str, slug: &str) -> Result<Vec<(Option<NaiveDate>, PathBuf)>, String> {
+    let directory = root.join(source).join(slug);
+    let entries = match fs::read_dir(&directory) {
+        Ok(e) => { e },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => { return Ok(Vec::new()) },
+        Err(e) => { return Err(format!("Failed to list {}: {}", directory.display(), e)) }
+    };
+
+    let mut files: Vec<(Option<NaiveDate>, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| matches!(path.extension().and_then(|e| e.to_str()), Some("json") | Some("txt")))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            match stem {
+                "undated" => { Some((None, path)) },
+                _ => { NaiveDate::parse_from_str(stem, "%Y-%m-%d").ok().map(|d| (Some(d), path)) }
+            }
+        })
+        .collect();
+    // undated last
+    files.sort_by_key(|(date, _)| (date.is_none(), *date));
+
+    Ok(files)
+}
+
+/// The rows of each section of a kept datamart or MARS response
+pub fn read_rows<T: DeserializeOwned>(path: &Path) -> Result<BTreeMap<String, Vec<T>>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Failed to read archived response {}: {}", path.display(), e))?;
+    serde_json::from_str(&text).map_err(|e| format!("Invalid archived response {}: {}", path.display(), e))
+}
+
+/// Moves documents kept in the layout from before the archive was content-addressed into it, removing the old
+/// files and the report directories left empty. Returns the number of documents moved.
+pub fn migrate(root: &Path) -> Result<usize, String> {
+    let mut moved = 0;
+
+    for entry in fs::read_dir(root).map_err(|e| format!("Failed to list {}: {}", root.display(), e))? {
+        let directory = entry.map_err(|e| e.to_string())?.path();
+        let identifier = match directory.file_name().and_then(|n| n.to_str()) {
+            Some(name) if directory.is_dir() && name != "blobs" && name != "index" => { name.to_owned() },
+            _ => { continue }
+        };
+
+        for file in fs::read_dir(&directory).map_err(|e| format!("Failed to list {}: {}", directory.display(), e))? {
+            let path = file.map_err(|e| e.to_string())?.path();
+            let report_date = match path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".txt")) {
+                Some(date) => { NaiveDate::parse_from_str(date, "%Y-%m-%d").ok() },
+                None => { None }
+            };
+
+            if let Some(report_date) = report_date {
+                let text = fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                store_document(root, &identifier, report_date, &text)?;
+                fs::remove_file(&path).map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+                moved += 1;
+            }
+        }
+
+        // left in place if anything other than documents was kept there
+        let _ = fs::remove_dir(&directory);
+    }
+
+    Ok(moved)
+}
+
+#[test]
+fn test_document_archive() {
+    let root = std::env::temp_dir().join(format!("data-acquisition-archive-test-{}", std::process::id()));
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+
+    let path = store_document(&root, "BroiHatc", report_date, "Broiler Hatchery").unwrap();
+    assert_eq!(path, blob_path(&root, &content_hash("Broiler Hatchery")));
+    assert_eq!(read_document(&root, "broihatc", report_date).unwrap(), "Broiler Hatchery");
+    assert!(read_document(&root, "broihatc", report_date.succ_opt().unwrap()).is_err());
+
+    // the same release filed again, here under another report, shares the blob
+    assert_eq!(store_document(&root, "LM_XB463", report_date, "Broiler Hatchery").unwrap(), path);
+    assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    assert_eq!(identifiers(&root).unwrap(), vec!["BROIHATC".to_owned(), "LM_XB463".to_owned()]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_payload_archive() {
+    use std::collections::HashMap;
+
+    let root = std::env::temp_dir().join(format!("data-acquisition-payload-archive-test-{}", std::process::id()));
+    let row = |date: Option<&str>, head_count: &str| -> HashMap<String, Option<String>> {
+        vec![("report_date".to_owned(), date.map(str::to_owned)), ("head_count".to_owned(), Some(head_count.to_owned()))].into_iter().collect()
+    };
+    let date_of = |row: &HashMap<String, Option<String>>| row["report_date"].as_deref().and_then(crate::usda::dates::find_date);
+    let march_2 = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+
+    let rows = vec![row(Some("03/02/2020"), "1,250"), row(Some("03/03/2020"), "830"), row(None, "12")];
+    store_rows(&root, "datamart", "2466", "Summary", &rows, date_of).unwrap();
+    store_rows(&root, "datamart", "2466", "Detail", &rows[..1], date_of).unwrap();
+    store_rows(&root, "datamart", "2466", "Summary", &[row(Some("03/02/2020"), "1,300")], date_of).unwrap();
+
+    let path = payload_path(&root, "datamart", "2466", Some(march_2), "json");
+    assert_eq!(path, root.join("datamart").join("2466").join("2020-03-02.json"));
+
+    let kept: BTreeMap<String, Vec<HashMap<String, Option<String>>>> = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(kept["Summary"], vec![row(Some("03/02/2020"), "1,300")]);
+    assert_eq!(kept["Detail"], vec![row(Some("03/02/2020"), "1,250")]);
+    assert!(payload_path(&root, "datamart", "2466", None, "json").exists());
+
+    let files = payload_files(&root, "datamart", "2466").unwrap();
+    assert_eq!(files.iter().map(|(d, _)| *d).collect::<Vec<_>>(), vec![Some(march_2), NaiveDate::from_ymd_opt(2020, 3, 3), None]);
+    let replayed: BTreeMap<String, Vec<HashMap<String, Option<String>>>> = read_rows(&files[0].1).unwrap();
+    assert_eq!(replayed, kept);
+    assert!(payload_files(&root, "datamart", "2467").unwrap().is_empty());
+
+    store_text(&root, "esmis", "BroiHatc", Some(march_2), "Broiler Hatchery").unwrap();
+    assert_eq!(fs::read_to_string(root.join("esmis").join("BROIHATC").join("2020-03-02.txt")).unwrap(), "Broiler Hatchery");
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_migrate() {
+    let root = std::env::temp_dir().join(format!("data-acquisition-archive-migrate-test-{}", std::process::id()));
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 6).unwrap();
+
+    fs::create_dir_all(root.join("LM_XB463")).unwrap();
+    fs::write(root.join("LM_XB463").join("2020-03-06.txt"), "Boxed beef").unwrap();
+    assert_eq!(read_document(&root, "LM_XB463", report_date).unwrap(), "Boxed beef");
+
+    assert_eq!(migrate(&root), Ok(1));
+    assert!(!root.join("LM_XB463").exists());
+    assert_eq!(read_index(&root, "LM_XB463").unwrap()[&report_date], content_hash("Boxed beef"));
+    assert_eq!(read_document(&root, "LM_XB463", report_date).unwrap(), "Boxed beef");
+
+    fs::remove_dir_all(&root).unwrap();
+}
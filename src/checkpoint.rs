@@ -0,0 +1,105 @@
+// Checkpoints of --backfill-datamart, so that a backfill stopped partway picks up where it left off (--resume)
+// rather than starting again from the first report.
+//
+// A backfill is made of parts: a report, or one section of it when sections are fetched apart. Each part is
+// recorded in the checkpoint file once it has been written, one line each, and a resumed backfill skips the parts
+// listed. A part names its date range too, so that a backfill of other dates doesn't skip what it hasn't done.
+// The file is removed once a backfill completes without failures.
+
+use std::collections::BTreeSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::usda::datamart::DateRange;
+
+pub struct Checkpoint {
+    path: PathBuf,
+    done: BTreeSet<String>,
+    file: Mutex<File>
+}
+
+/// The line of a part: report, section ("*" for all of them) and date range ("all" for the whole history)
+pub fn part(slug: &str, section: Option<&str>, range: Option<DateRange>) -> String {
+    let range = match range {
+        Some((from, to)) => { format!("{}..{}", from, to) },
+        None => { "all".to_owned() }
+    };
+
+    format!("{}\t{}\t{}", slug, section.unwrap_or("*"), range)
+}
+
+impl Checkpoint {
+    /// Opens the checkpoint at `path`, keeping the parts it lists if `resume`, or starting it afresh
+    pub fn open(path: &Path, resume: bool) -> Result<Checkpoint, String> {
+        let done: BTreeSet<String> = match fs::read_to_string(path) {
+            Ok(text) if resume => { text.lines().filter(|l| !l.trim().is_empty()).map(str::to_owned).collect() },
+            _ => { BTreeSet::new() }
+        };
+
+        let file = OpenOptions::new().create(true).append(true).truncate(false).open(path)
+            .map_err(|e| format!("Failed to open checkpoint {}: {}", path.display(), e))?;
+        if !resume {
+            file.set_len(0).map_err(|e| format!("Failed to clear checkpoint {}: {}", path.display(), e))?;
+        }
+
+        Ok(Checkpoint { path: path.to_owned(), done, file: Mutex::new(file) })
+    }
+
+    /// How many parts were done before this run
+    pub fn done_count(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Whether a part was done before this run
+    pub fn is_done(&self, part: &str) -> bool {
+        self.done.contains(part)
+    }
+
+    /// Records a part as done, on disk straight away
+    pub fn record(&self, part: &str) -> Result<(), String> {
+        let mut file = self.file.lock().unwrap();
+
+        writeln!(file, "{}", part)
+            .and_then(|_| file.sync_data())
+            .map_err(|e| format!("Failed to record {} in checkpoint {}: {}", part.replace('\t', " "), self.path.display(), e))
+    }
+
+    /// Removes the checkpoint, once the backfill is complete
+    pub fn remove(self) -> Result<(), String> {
+        let Checkpoint { path, file, .. } = self;
+        drop(file);
+        fs::remove_file(&path).map_err(|e| format!("Failed to remove checkpoint {}: {}", path.display(), e))
+    }
+}
+
+#[test]
+fn test_checkpoint() {
+    use chrono::NaiveDate;
+
+    let path = std::env::temp_dir().join(format!("checkpoint_test_{}", std::process::id()));
+    let year = (NaiveDate::from_ymd_opt(2020, 1, 1).unwrap(), NaiveDate::from_ymd_opt(2020, 12, 31).unwrap());
+
+    let checkpoint = Checkpoint::open(&path, true).unwrap();
+    assert_eq!(checkpoint.done_count(), 0);
+    checkpoint.record(&part("2466", Some("Summary"), None)).unwrap();
+    checkpoint.record(&part("2477", None, Some(year))).unwrap();
+    drop(checkpoint);
+
+    let resumed = Checkpoint::open(&path, true).unwrap();
+    assert_eq!(resumed.done_count(), 2);
+    assert!(resumed.is_done(&part("2466", Some("Summary"), None)));
+    assert!(resumed.is_done("2477\t*\t2020-01-01..2020-12-31"));
+    assert!(!resumed.is_done(&part("2466", Some("Detail"), None)));
+    assert!(!resumed.is_done(&part("2477", None, None)));
+    drop(resumed);
+
+    // a backfill that isn't resumed starts over
+    assert_eq!(Checkpoint::open(&path, false).unwrap().done_count(), 0);
+    let restarted = Checkpoint::open(&path, true).unwrap();
+    assert_eq!(restarted.done_count(), 0);
+
+    restarted.remove().unwrap();
+    assert!(!path.exists());
+}
@@ -0,0 +1,84 @@
+// The configuration files every run reads, named by the --{name}-config options: read together, with the settings
+// of --set applied, into the typed configuration of each, so that a file that is missing or doesn't parse is named
+// before anything is fetched.
+//
+// Files that a run can go without (network, derived and secret) are empty when missing; the secret file's settings
+// may then all come from --set.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+
+use crate::http::NetworkConfigFile;
+use crate::integration::derived::DerivedConfig;
+use crate::noaa::NoaaConfig;
+use crate::overrides::{self, Override};
+use crate::secret::SecretConfig;
+use crate::usda::datamart::{DatamartConfig, DatamartConfigFile};
+
+#[derive(Debug)]
+pub struct AppConfig {
+    pub datamart: DatamartConfigFile,
+    pub legacy: HashMap<String, DatamartConfig>,
+    pub noaa: NoaaConfig,
+    pub network: NetworkConfigFile,
+    pub derived: DerivedConfig,
+    pub secret: SecretConfig
+}
+
+impl AppConfig {
+    /// Reads the configuration files, `path` giving the file of each by name, e.g. "datamart"
+    pub fn load(path: impl Fn(&str) -> PathBuf, overrides: &[Override]) -> Result<AppConfig, String> {
+        Ok(AppConfig {
+            datamart: load_file("datamart", &path("datamart"), true, overrides)?,
+            legacy: load_file("legacy", &path("legacy"), true, overrides)?,
+            noaa: load_file("noaa", &path("noaa"), true, overrides)?,
+            network: load_file("network", &path("network"), false, overrides)?,
+            derived: load_file("derived", &path("derived"), false, overrides)?,
+            secret: load_file("secret", &path("secret"), false, overrides)?
+        })
+    }
+}
+
+/// Reads the configuration file `name` from `path` with its overrides applied. One that isn't `required` is empty
+/// when missing.
+fn load_file<T: DeserializeOwned>(name: &str, path: &Path, required: bool, overrides: &[Override]) -> Result<T, String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => { text },
+        Err(e) if required => { return Err(format!("Failed to read the {} configuration {}: {}", name, path.display(), e)) },
+        Err(_) => { String::new() }
+    };
+
+    overrides::load(&text, name, overrides).map_err(|e| format!("{} ({})", e, path.display()))
+}
+
+#[test]
+fn test_app_config() {
+    let root = std::env::temp_dir().join(format!("data-acquisition-app-config-test-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let path = |name: &str| root.join(format!("{}.toml", name));
+
+    for name in ["datamart", "legacy", "noaa", "network", "derived"] {
+        fs::copy(Path::new("config").join(format!("{}.toml", name)), path(name)).unwrap();
+    }
+
+    // there is no secret file, so its settings come from --set alone
+    let overrides = vec![overrides::parse_override("secret.postgres.password=p").unwrap()];
+    let config = AppConfig::load(path, &overrides).unwrap();
+    assert!(config.datamart.reports.contains_key("2466"));
+    assert_eq!(config.secret.postgres.password.as_deref(), Some("p"));
+
+    // a required file that is missing, or a table that is misspelt, is named
+    fs::remove_file(path("noaa")).unwrap();
+    let error = AppConfig::load(path, &[]).unwrap_err();
+    assert!(error.contains("noaa configuration"), "{}", error);
+
+    fs::copy("config/noaa.toml", path("noaa")).unwrap();
+    fs::write(path("secret"), "[postgress]\npassword = \"p\"\n").unwrap();
+    let error = AppConfig::load(path, &[]).unwrap_err();
+    assert!(error.contains("secret config") && error.contains("postgress"), "{}", error);
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,430 @@
+// Sources of data: the datamart reports of the configuration, and sources beyond the USDA and NOAA ones built in,
+// added by crates that build on this one, e.g. a brokerage's price feed.
+//
+// A source describes its tables in the shape of a datamart configuration, fetches a range of report dates as a
+// package, and says whether it can be reached. Sources registered with `register` take part in --create, --update,
+// --backfill-datamart, --completeness and --selftest with the built in reports; a binary of a downstream crate
+// registers its own at start-up and then runs `cli::main`.
+//
+// Each configured datamart report is fetched as a DatamartSource, in the same loops of --update and
+// --backfill-datamart as registered sources, and for webhook requests. A legacy report is a LegacySource, the ESMIS
+// releases of a range of dates: --backfill-datamart fetches it as it does the others, while --update, webhook
+// requests and scheduled polls look up the releases of many reports at once, leaving out those already processed,
+// and have the source download and parse each. NOAA observations are a NoaaSource, backfilled the same way by
+// --backfill-noaa; as NOAA publishes them as one archive of every station's history, --update leaves them alone.
+
+use std::collections::HashMap;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Duration, Local};
+
+use crate::{archive, http, integration, noaa};
+use crate::integration::sink::{Sink, NO_DATE_FOUND};
+use crate::memory::MemoryBudget;
+use crate::usda::{self, USDADataPackage};
+use crate::usda::datamart::{DateRange, DatamartConfig};
+use crate::usda::esmis::ESMISRelease;
+
+pub trait DataSource: Send + Sync {
+    /// The tables of the source, as a datamart report is configured. Its name names the source and its tables.
+    fn schema(&self) -> DatamartConfig;
+
+    /// The name and description of the source, with its tables: the schema unless a source says more of itself
+    fn metadata(&self) -> DatamartConfig {
+        self.schema()
+    }
+
+    /// The rows of the report dates of `range`, or all of those the source has if None
+    fn fetch(&self, range: Option<DateRange>) -> Result<USDADataPackage, String>;
+
+    /// As `fetch`, handing the rows to `send` in packages sized to fit `budget`. Sources that can't split their rows
+    /// send one package.
+    fn fetch_batches(&self, range: Option<DateRange>, _budget: &MemoryBudget, send: &mut dyn FnMut(USDADataPackage) -> Result<(), String>) -> Result<(), String> {
+        send(self.fetch(range)?)
+    }
+
+    /// Checks that the source answers, describing what it found
+    fn health_check(&self) -> Result<String, String>;
+}
+
+lazy_static! {
+    static ref SOURCES: Mutex<Vec<Arc<dyn DataSource>>> = Mutex::new(Vec::new());
+}
+
+/// Adds a source to those every run includes. Names are unique.
+pub fn register(source: Arc<dyn DataSource>) -> Result<(), String> {
+    let mut sources = SOURCES.lock().unwrap();
+    let name = source.metadata().name;
+
+    if sources.iter().any(|s| s.metadata().name == name) {
+        return Err(format!("A data source named {} is already registered", name));
+    }
+
+    sources.push(source);
+    Ok(())
+}
+
+/// The sources registered, in the order they were
+pub fn registered() -> Vec<Arc<dyn DataSource>> {
+    SOURCES.lock().unwrap().clone()
+}
+
+/// The report dates an update fetches of a source: None if `sink` is up to date, or else those since the latest
+/// report date in it, or all of them (a range of None) if it has none
+pub fn pending(source: &dyn DataSource, sink: &mut dyn Sink) -> Result<Option<Option<DateRange>>, String> {
+    let today = Local::now().naive_local().date();
+
+    match sink.max_date(&source.schema()) {
+        Ok(latest) if latest >= today => { Ok(None) },
+        Ok(latest) => { Ok(Some(Some((latest + Duration::days(1), today)))) },
+        Err(e) if e == NO_DATE_FOUND => { Ok(Some(None)) },
+        Err(e) => { Err(e) }
+    }
+}
+
+/// What a source has published since the latest report date in `sink`, everything if it has none, or None if
+/// the sink is up to date
+pub fn fetch_new(source: &dyn DataSource, sink: &mut dyn Sink) -> Result<Option<USDADataPackage>, String> {
+    match pending(source, sink)? {
+        Some(range) => { source.fetch(range).map(Some) },
+        None => { Ok(None) }
+    }
+}
+
+/// A report of the datamart configuration, fetched from datamart or, while datamart is down, from its MARS equivalent.
+/// Its rows come transformed as the report configures.
+pub struct DatamartSource<'a> {
+    pub slug: &'a str,
+    pub config: &'a HashMap<String, DatamartConfig>,    // holding the report, and only its sections to be fetched
+    pub datamart_available: bool,
+    pub http_connect_timeout: Arc<u64>,
+    pub http_receive_timeout: Arc<u64>,
+    pub mars_api_key: Option<&'a str>
+}
+
+impl DatamartSource<'_> {
+    fn fetch_untransformed(&self, range: Option<DateRange>) -> Result<USDADataPackage, String> {
+        if self.datamart_available {
+            return usda::datamart::process_datamart(self.slug.to_owned(), range, self.config, self.http_connect_timeout.clone(), self.http_receive_timeout.clone(), self.mars_api_key);
+        }
+
+        match (self.config[self.slug].mars_slug.as_ref(), self.mars_api_key) {
+            (Some(mars_slug), Some(key)) => {
+                info!("Datamart is unavailable, fetching {} from MARS report {} instead.", self.slug, mars_slug);
+                usda::mars::process_datamart_equivalent(self.slug, self.config, key, range, *self.http_connect_timeout, *self.http_receive_timeout)
+            },
+            (Some(_), None) => {
+                Err(format!("Datamart is unavailable and the MARS fallback for {} requires a key under [mars] in the secret configuration.", self.slug))
+            },
+            (None, _) => {
+                Err(format!("Datamart is unavailable and {} has no MARS equivalent configured.", self.slug))
+            }
+        }
+    }
+}
+
+impl DataSource for DatamartSource<'_> {
+    fn schema(&self) -> DatamartConfig {
+        self.config[self.slug].clone()
+    }
+
+    fn fetch(&self, range: Option<DateRange>) -> Result<USDADataPackage, String> {
+        self.fetch_untransformed(range)
+            .and_then(|package| usda::transform::transform_package(package, &self.config[self.slug].transforms))
+    }
+
+    fn health_check(&self) -> Result<String, String> {
+        usda::datamart::check_datamart().map(|_| "answered a report query".to_owned())
+    }
+}
+
+/// Parses a legacy text release and files the document in the raw archive under the date it reports
+pub fn parse_and_archive(identifier: &str, config: &DatamartConfig, text: String, archive_root: &Path) -> Result<USDADataPackage, String> {
+    let package = usda::legacy::parse_report(identifier, config, text.clone())?;
+
+    if let Some(report_date) = archive::package_report_date(&package) {
+        if let Err(e) = archive::store_document(archive_root, identifier, report_date, &text) {
+            error!("{}", e);
+        }
+    }
+
+    Ok(package)
+}
+
+/// A legacy report, fetched as the text releases ESMIS lists for it. Each is parsed and transformed as the report
+/// configures, and kept in the raw archive for --reparse.
+pub struct LegacySource<'a> {
+    pub identifier: &'a str,
+    pub config: &'a DatamartConfig,
+    pub esmis_api_key: &'a str,
+    pub http_connect_timeout: Arc<u64>,
+    pub http_receive_timeout: Arc<u64>,
+    pub raw_archive: &'a Path
+}
+
+impl LegacySource<'_> {
+    /// The releases ESMIS lists for the report between the dates of `range`, or all of them if None
+    pub fn releases(&self, range: Option<DateRange>) -> Result<Vec<ESMISRelease>, String> {
+        usda::esmis::fetch_release_records(self.esmis_api_key, self.identifier, range.map(|(from, _)| from), range.map(|(_, to)| to), self.http_connect_timeout.clone(), self.http_receive_timeout.clone())
+    }
+
+    /// Downloads a release file. PDFs and other files that aren't text fail.
+    pub fn download(&self, file: &str) -> Result<String, String> {
+        let mut request = ureq::get(file);
+        request.timeout_connect(*self.http_connect_timeout).timeout_read(*self.http_receive_timeout);
+        let read = http::call(&mut request, &http::RetryPolicy::ESMIS, |response| {
+            match response.synthetic_error() {
+                Some(error) => { Err(error.to_string()) },
+                None => { Ok(usda::content::read_release(file, response)) }
+            }
+        });
+
+        match read {
+            Ok(Ok(text)) => { Ok(text) },
+            Ok(Err(e)) => { Err(format!("Failed to read release {}", e)) },
+            Err(error) => { Err(format!("Failed to retrieve {}: {}", file, error)) }
+        }
+    }
+
+    /// Parses the text of a release file, keeping it in the archives, and transforms its rows
+    pub fn parse_release(&self, file: &str, text: String) -> Result<USDADataPackage, String> {
+        if let Some(root) = archive::payload_archive() {
+            // filed by the report date where it can be found without parsing, or else the day it was fetched
+            let file_name = file.rsplit('/').next().unwrap_or_default();
+            let report_date = usda::legacy::release_date(self.config, &text)
+                .or_else(|| usda::legacy::file_name_date(self.config, file_name).ok().flatten())
+                .unwrap_or_else(|| Local::now().naive_local().date());
+
+            if let Err(e) = archive::store_text(&root, "esmis", self.identifier, Some(report_date), &text) {
+                error!("{}", e);
+            }
+        }
+
+        parse_and_archive(self.identifier, self.config, text, self.raw_archive)
+            .and_then(|package| usda::transform::transform_package(package, &self.config.transforms))
+    }
+}
+
+impl DataSource for LegacySource<'_> {
+    fn schema(&self) -> DatamartConfig {
+        self.config.clone()
+    }
+
+    fn fetch(&self, range: Option<DateRange>) -> Result<USDADataPackage, String> {
+        let mut package = USDADataPackage::new(self.config.name.to_owned());
+
+        // a release that can't be read is noted by its file, as a section of a datamart report that can't be fetched is
+        for release in self.releases(range)? {
+            let file = match release.files.first() {
+                Some(f) => { f },
+                None => {
+                    warn!("Release {} of {} has no files, skipping it.", release.id, self.identifier);
+                    continue;
+                }
+            };
+
+            match self.download(file).and_then(|text| self.parse_release(file, text)) {
+                Ok(parsed) => { package.extend(parsed) },
+                Err(e) => { package.section_errors.insert(file.to_owned(), e); }
+            }
+        }
+
+        Ok(package)
+    }
+
+    fn health_check(&self) -> Result<String, String> {
+        match usda::esmis::fetch_latest_release(self.esmis_api_key, self.identifier, self.http_connect_timeout.clone(), self.http_receive_timeout.clone())? {
+            Some(release) => { Ok(format!("latest release of {} is dated {}", self.identifier, release.release_date()?)) },
+            None => { Err(format!("ESMIS lists no releases of {}", self.identifier)) }
+        }
+    }
+}
+
+/// The daily observations of the GHCND GSN stations, of the elements and countries the NOAA configuration names
+pub struct NoaaSource<'a> {
+    pub config: &'a noaa::NoaaConfig,
+    pub email: &'a str      // who the FTP login identifies us as
+}
+
+impl DataSource for NoaaSource<'_> {
+    fn schema(&self) -> DatamartConfig {
+        integration::noaa::configured_structure(self.config)
+    }
+
+    fn fetch(&self, range: Option<DateRange>) -> Result<USDADataPackage, String> {
+        let mut package = USDADataPackage::new(self.schema().name);
+        self.fetch_batches(range, &MemoryBudget::unlimited(), &mut |batch| {
+            package.extend(batch);
+            Ok(())
+        })?;
+
+        Ok(package)
+    }
+
+    /// The archive is downloaded to disk rather than into memory under a limited budget
+    fn fetch_batches(&self, range: Option<DateRange>, budget: &MemoryBudget, send: &mut dyn FnMut(USDADataPackage) -> Result<(), String>) -> Result<(), String> {
+        let reader: Box<dyn Read> = if budget.is_limited() {
+            let path = std::env::temp_dir().join("ghcnd_gsn.tar.gz");
+            info!("Downloading to {} to stay within the memory limit.", path.display());
+            Box::new(BufReader::new(noaa::retrieve_noaa_ftp_to_disk(self.email, &path)?))
+        } else {
+            Box::new(noaa::retrieve_noaa_ftp(self.email)?)
+        };
+
+        info!("Parsing NOAA data...");
+        let elements: Vec<&str> = self.config.elements.iter().map(String::as_str).collect();
+        let countries: Vec<&str> = self.config.countries.iter().map(String::as_str).collect();
+
+        noaa::process_noaa_batches(reader, Some(&elements), Some(&countries), budget, |observations| {
+            info!("Parsed {} station-months.", observations.len());
+            send(integration::noaa::noaa_package(observations, self.config.units, range))
+        })
+    }
+
+    fn health_check(&self) -> Result<String, String> {
+        noaa::check_noaa_ftp(self.email).map(|entries| format!("listed {} GHCND entries", entries))
+    }
+}
+
+#[cfg(test)]
+struct TestSource {
+    fetched: Mutex<Vec<Option<DateRange>>>
+}
+
+#[cfg(test)]
+impl DataSource for TestSource {
+    fn schema(&self) -> DatamartConfig {
+        crate::integration::usda::test_structure("test_source")
+    }
+
+    fn fetch(&self, range: Option<DateRange>) -> Result<USDADataPackage, String> {
+        self.fetched.lock().unwrap().push(range);
+        Ok(crate::integration::usda::test_package("test_source", chrono::NaiveDate::from_ymd_opt(2020, 3, 2).unwrap(), "Iowa", "101.5"))
+    }
+
+    fn health_check(&self) -> Result<String, String> {
+        Ok("answered".to_owned())
+    }
+}
+
+#[test]
+fn test_registered_source() {
+    use crate::integration::sink::MemorySink;
+    use crate::integration::usda::OnConflict;
+
+    let source = Arc::new(TestSource { fetched: Mutex::new(Vec::new()) });
+    register(source.clone()).unwrap();
+    assert!(register(source.clone()).is_err());
+    assert!(registered().iter().any(|s| s.metadata().name == "test_source"));
+
+    let mut sink = MemorySink::default();
+    sink.create_schema(&source.schema()).unwrap();
+
+    // an empty sink takes everything, and then what is new since
+    assert_eq!(pending(source.as_ref(), &mut sink).unwrap(), Some(None));
+    let package = fetch_new(source.as_ref(), &mut sink).unwrap().unwrap();
+    sink.insert_package(package, &source.schema(), OnConflict::Update).unwrap();
+    fetch_new(source.as_ref(), &mut sink).unwrap();
+
+    let fetched = source.fetched.lock().unwrap();
+    assert_eq!(fetched[0], None);
+    assert_eq!(fetched[1].unwrap().0, chrono::NaiveDate::from_ymd_opt(2020, 3, 3).unwrap());
+}
+
+#[test]
+fn test_datamart_source() {
+    let structure = crate::integration::usda::test_structure("test_datamart_source");
+    let config: HashMap<String, DatamartConfig> = vec![("9999".to_owned(), structure)].into_iter().collect();
+
+    let source = DatamartSource {
+        slug: "9999",
+        config: &config,
+        datamart_available: false,
+        http_connect_timeout: Arc::new(1000),
+        http_receive_timeout: Arc::new(1000),
+        mars_api_key: None
+    };
+
+    assert_eq!(source.schema().name, "test_datamart_source");
+    // with datamart down, a report without a MARS equivalent fails without a request being made
+    assert_eq!(source.fetch(None).unwrap_err(), "Datamart is unavailable and 9999 has no MARS equivalent configured.");
+}
+
+#[test]
+fn test_legacy_source() {
+    let config: DatamartConfig = toml::from_str(r#"
+        name = "broihatc"
+        description = "test"
+        independent = "report_date"
+        [sections]
+    "#).unwrap();
+    let root = std::env::temp_dir().join(format!("test_legacy_source_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+
+    let source = LegacySource {
+        identifier: "BroiHatc",
+        config: &config,
+        esmis_api_key: "",
+        http_connect_timeout: Arc::new(1000),
+        http_receive_timeout: Arc::new(1000),
+        raw_archive: &root
+    };
+
+    let text = "Broiler Hatchery
+Released January 3, 2024, by the National Agricultural Statistics Service (NASS).
+
+Broiler-Type Eggs Set - States and United States: 2023
+-------------------------------------------------------------------------------------
+                 |                          Week ending
+      State      |-------------------------------------------------------------------
+                 | Dec 9, 2023  | Dec 16, 2023 | Dec 23, 2023 | Dec 30, 2023
+-------------------------------------------------------------------------------------
+                 |                         (1,000 eggs)
+                 |
+Alabama .........|     29,418   |     29,656   |     29,533   |     29,101
+-------------------------------------------------------------------------------------
+
+Broiler-Type Chicks Placed - States and United States: 2023
+-------------------------------------------------------------------------------------
+                 |                          Week ending
+      State      |-------------------------------------------------------------------
+                 | Dec 9, 2023  | Dec 16, 2023 | Dec 23, 2023 | Dec 30, 2023
+-------------------------------------------------------------------------------------
+                 |                         (1,000 chicks)
+                 |
+Alabama .........|     20,001   |     20,110   |     20,050   |     19,870
+-------------------------------------------------------------------------------------
+";
+
+    // a release is parsed as the report and kept in the raw archive, by the date it reports
+    let package = source.parse_release("https://example.com/broihatc0124.txt", text.to_owned()).unwrap();
+    assert_eq!(package.sections["eggs_set"][0].entries["eggs_set"], "29,101");
+    let date = chrono::NaiveDate::from_ymd_opt(2023, 12, 30).unwrap();
+    assert_eq!(crate::archive::read_document(&root, "BroiHatc", date).unwrap(), text);
+
+    // and fetched releases are put together as one package
+    let mut fetched = USDADataPackage::new(source.schema().name);
+    fetched.extend(package);
+    fetched.extend(source.parse_release("https://example.com/broihatc0124.txt", text.to_owned()).unwrap());
+    assert_eq!(fetched.sections["eggs_set"].len(), 2);
+    assert_eq!(fetched.parser_version, usda::legacy::parser_version("BROIHATC"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_noaa_source() {
+    let config = noaa::NoaaConfig {
+        elements: vec!["TMAX".to_owned()],
+        countries: vec!["US".to_owned()],
+        units: noaa::NoaaUnits::Metric,
+        indexes: Some(vec![vec!["station_id".to_owned()]])
+    };
+    let source = NoaaSource { config: &config, email: "test@example.com" };
+
+    let schema = source.schema();
+    assert_eq!(schema.table_name("TMIN"), "noaa_tmin");
+    assert_eq!(schema.indexes, config.indexes);
+}
@@ -0,0 +1,190 @@
+// Daily email summary of what daemon mode ingested, for teams that want a report in their inbox.
+//
+// SMTP settings are read from the [smtp] table of the secret configuration:
+//
+//     [smtp]
+//     host = "smtp.example.com"
+//     port = 587                                    # optional, defaults to the submission port with STARTTLS
+//     username = "reports@example.com"
+//     password = "..."
+//     from = "Data Acquisition <reports@example.com>"
+//     to = ["analyst@example.com", "team@example.com"]     # or one string, separated by commas
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::NaiveDateTime;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::Deserialize;
+use lettre::transport::smtp::authentication::Credentials;
+
+use crate::usda::USDADataPackage;
+
+#[derive(Debug, Default)]
+struct ReportActivity {
+    releases: usize,
+    rows: usize,
+    failures: Vec<String>
+}
+
+/// What happened across the update passes since the last digest was sent
+#[derive(Debug)]
+pub struct Digest {
+    since: NaiveDateTime,
+    checked: BTreeSet<String>,
+    reports: BTreeMap<String, ReportActivity>,
+    insert_failures: usize
+}
+
+impl Digest {
+    pub fn new(since: NaiveDateTime) -> Digest {
+        Digest {
+            since,
+            checked: BTreeSet::new(),
+            reports: BTreeMap::new(),
+            insert_failures: 0
+        }
+    }
+
+    /// Notes that a report was looked at, whether or not anything new was found
+    pub fn record_checked(&mut self, report: &str) {
+        self.checked.insert(report.to_owned());
+    }
+
+    pub fn record_release(&mut self, report: &str, package: &USDADataPackage) {
+        let activity = self.reports.entry(report.to_owned()).or_default();
+        activity.releases += 1;
+        activity.rows += package.row_count();
+    }
+
+    pub fn record_failure(&mut self, report: &str, error: &str) {
+        self.reports.entry(report.to_owned()).or_default().failures.push(error.to_owned());
+    }
+
+    pub fn record_insert_failures(&mut self, count: usize) {
+        self.insert_failures += count;
+    }
+
+    /// The plain text body of the digest email
+    pub fn render(&self, now: NaiveDateTime) -> String {
+        let mut body = format!("Reports ingested from {} to {}.\n", self.since.format("%Y-%m-%d %H:%M"), now.format("%Y-%m-%d %H:%M"));
+
+        let updated: Vec<(&String, &ReportActivity)> = self.reports.iter().filter(|(_, a)| a.releases > 0).collect();
+        if updated.is_empty() {
+            body.push_str("\nNo new releases.\n");
+        } else {
+            body.push_str("\nNew releases:\n");
+            for (report, activity) in &updated {
+                body.push_str(&format!("  {}: {} release(s), {} rows\n", report, activity.releases, activity.rows));
+            }
+        }
+
+        let unchanged: Vec<&str> = self.checked.iter()
+            .filter(|r| self.reports.get(*r).is_none_or(|a| a.releases == 0))
+            .map(|r| r.as_str())
+            .collect();
+        if !unchanged.is_empty() {
+            body.push_str(&format!("\nNothing new: {}\n", unchanged.join(", ")));
+        }
+
+        let failures: Vec<(&String, &String)> = self.reports.iter().flat_map(|(r, a)| a.failures.iter().map(move |f| (r, f))).collect();
+        if !failures.is_empty() {
+            body.push_str("\nFailures:\n");
+            for (report, failure) in failures {
+                body.push_str(&format!("  {}: {}\n", report, failure));
+            }
+        }
+
+        if self.insert_failures > 0 {
+            body.push_str(&format!("\n{} release(s) failed to insert into the database.\n", self.insert_failures));
+        }
+
+        body
+    }
+}
+
+/// The [smtp] table of the secret configuration
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpSettings {
+    host: String,
+    #[serde(default, deserialize_with = "crate::secret::port")]
+    port: Option<u16>,
+    username: String,
+    password: String,
+    from: String,
+    #[serde(deserialize_with = "crate::secret::list")]
+    to: Vec<String>
+}
+
+/// Emails the digest to the configured recipients
+pub fn send_digest(settings: &SmtpSettings, digest: &Digest, now: NaiveDateTime) -> Result<(), String> {
+    let mut builder = Message::builder()
+        .from(settings.from.parse().map_err(|e| format!("Invalid sender address {}: {}", settings.from, e))?)
+        .subject(format!("Data acquisition digest for {}", now.format("%Y-%m-%d")));
+
+    for recipient in &settings.to {
+        builder = builder.to(recipient.parse().map_err(|e| format!("Invalid recipient address {}: {}", recipient, e))?);
+    }
+
+    let message = builder.body(digest.render(now)).map_err(|e| format!("Failed to build digest email: {}", e))?;
+
+    let mut transport = SmtpTransport::starttls_relay(&settings.host)
+        .map_err(|e| format!("Failed to set up SMTP connection to {}: {}", settings.host, e))?
+        .credentials(Credentials::new(settings.username.clone(), settings.password.clone()));
+
+    if let Some(port) = settings.port {
+        transport = transport.port(port);
+    }
+
+    transport.build().send(&message).map_err(|e| format!("Failed to send digest email: {}", e))?;
+
+    Ok(())
+}
+
+#[test]
+fn test_digest_render() {
+    use chrono::NaiveDate;
+    use crate::usda::USDADataPackageSection;
+
+    let start = NaiveDate::from_ymd_opt(2020, 3, 1).unwrap().and_hms_opt(7, 0, 0).unwrap();
+    let mut digest = Digest::new(start);
+
+    let mut package = USDADataPackage::new("lm_ct100".to_owned());
+    let mut section = USDADataPackageSection::new(start.date());
+    section.entries.insert("head_count".to_owned(), "100".to_owned());
+    section.entries.insert("weight".to_owned(), "1200".to_owned());
+    package.sections.insert("Summary".to_owned(), vec![section]);
+
+    for report in &["lm_ct100", "dc_gr110", "LM_XB463"] {
+        digest.record_checked(report);
+    }
+    digest.record_release("lm_ct100", &package);
+    digest.record_failure("LM_XB463", "Failed to locate date line");
+    digest.record_insert_failures(1);
+
+    let body = digest.render(start + chrono::Duration::days(1));
+
+    assert!(body.starts_with("Reports ingested from 2020-03-01 07:00 to 2020-03-02 07:00."));
+    assert!(body.contains("  lm_ct100: 1 release(s), 2 rows\n"));
+    assert!(body.contains("Nothing new: LM_XB463, dc_gr110\n"));
+    assert!(body.contains("  LM_XB463: Failed to locate date line\n"));
+    assert!(body.contains("1 release(s) failed to insert"));
+}
+
+#[test]
+fn test_smtp_settings() {
+    let section = |extra: &str| format!("host = \"smtp.example.com\"\nusername = \"u\"\npassword = \"p\"\nfrom = \"reports@example.com\"\n{}", extra);
+
+    let settings: SmtpSettings = toml::from_str(&section("to = \"a@example.com, b@example.com\"")).unwrap();
+    assert_eq!(settings.to, vec!["a@example.com", "b@example.com"]);
+    assert_eq!(settings.port, None);
+
+    let settings: SmtpSettings = toml::from_str(&section("to = [\"a@example.com\"]\nport = \"587\"")).unwrap();
+    assert_eq!(settings.to, vec!["a@example.com"]);
+    assert_eq!(settings.port, Some(587));
+    assert_eq!(toml::from_str::<SmtpSettings>(&section("to = \"a@example.com\"\nport = 465")).unwrap().port, Some(465));
+
+    assert!(toml::from_str::<SmtpSettings>(&section("to = \"a@example.com\"\nport = \"smtp\"")).is_err());
+    assert!(toml::from_str::<SmtpSettings>(&section("to = \" , \"")).is_err());
+    assert!(toml::from_str::<SmtpSettings>(&section("")).is_err());
+}
@@ -0,0 +1,354 @@
+// The files --extract, --completeness and --stats write their CSV to (--output), compressed with --compression and
+// split into parts of at most --split-mb megabytes, so that large extracts (NOAA observations above all) are
+// practical to move around.
+//
+// A split output is written as numbered parts beside the path given, e.g. extract-0001.csv.gz, extract-0002.csv.gz,
+// each starting with the CSV header. Parts are cut between records, never inside a quoted value, and measured before
+// compression.
+//
+// Every file exported, here or by --parquet, is described by a JSON sidecar beside it, _<file name>.json (see
+// Sidecar), for data lake tools to validate and catalog them with. The leading underscore keeps dataset readers
+// such as Spark from taking it for data.
+//
+// Exports are written in a fixed order (by date, then the independent columns, then the variable), so exporting the
+// same range again gives byte-identical files, and the sidecar's sha256 can be compared to tell whether anything
+// changed.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{NaiveDate, Utc};
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// What the sidecar of an exported file says of it
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Sidecar {
+    pub file: String,
+    pub report: String,                 // the report, or what else the file holds, e.g. an extract
+    pub section: Option<String>,
+    pub first_date: Option<String>,     // the span of the file's first column, if it holds dates
+    pub last_date: Option<String>,
+    pub rows: u64,
+    pub schema: Vec<SchemaColumn>,
+    pub sha256: Option<String>,         // of the file's bytes, which are the same whenever the same rows are exported
+    pub generated_at: String,
+    pub tool_version: String
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SchemaColumn {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String
+}
+
+impl SchemaColumn {
+    pub fn new(name: &str, kind: &str) -> SchemaColumn {
+        SchemaColumn { name: name.to_owned(), kind: kind.to_owned() }
+    }
+}
+
+impl Sidecar {
+    /// The sidecar of the file at `path`, generated now by this version
+    pub fn new(path: &Path, report: &str, section: Option<&str>, dates: Option<(NaiveDate, NaiveDate)>, rows: u64, schema: Vec<SchemaColumn>) -> Sidecar {
+        let date = |d: NaiveDate| d.format("%Y-%m-%d").to_string();
+
+        Sidecar {
+            file: path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+            report: report.to_owned(),
+            section: section.map(str::to_owned),
+            first_date: dates.map(|(first, _)| date(first)),
+            last_date: dates.map(|(_, last)| date(last)),
+            rows,
+            schema,
+            sha256: None,
+            generated_at: Utc::now().to_rfc3339(),
+            tool_version: env!("CARGO_PKG_VERSION").to_owned()
+        }
+    }
+
+    /// Records the checksum of the file's contents, read from `data`, which may be where it is written before being moved
+    pub fn with_checksum(mut self, data: &Path) -> Result<Sidecar, String> {
+        let mut file = File::open(data).map_err(|e| format!("Failed to read {}: {}", data.display(), e))?;
+        let mut hasher = Sha256::new();
+        io::copy(&mut file, &mut hasher).map_err(|e| format!("Failed to read {}: {}", data.display(), e))?;
+
+        self.sha256 = Some(format!("{:x}", hasher.finalize()));
+        Ok(self)
+    }
+
+    /// Writes the sidecar beside the file it describes
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let sidecar = sidecar_path(path);
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(&sidecar, json).map_err(|e| format!("Failed to write {}: {}", sidecar.display(), e))
+    }
+}
+
+/// Where the sidecar of the file at `path` is written
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("_{}.json", name))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd
+}
+
+impl Compression {
+    pub fn parse(name: &str) -> Result<Compression, String> {
+        match name.to_lowercase().as_str() {
+            "none" => { Ok(Compression::None) },
+            "gzip" | "gz" => { Ok(Compression::Gzip) },
+            "zstd" | "zst" if cfg!(feature = "zstd") => { Ok(Compression::Zstd) },
+            "zstd" | "zst" => { Err("This build has no zstd support. Rebuild with `cargo build --release --features zstd` to use it.".to_owned()) },
+            _ => { Err(format!("Unknown compression '{}', expected none, gzip or zstd", name)) }
+        }
+    }
+
+    /// What the name of a file compressed this way ends in
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => { "" },
+            Compression::Gzip => { ".gz" },
+            Compression::Zstd => { ".zst" }
+        }
+    }
+
+    fn encoder(self, file: File) -> io::Result<Encoder> {
+        match self {
+            Compression::None => { Ok(Encoder::Plain(BufWriter::new(file))) },
+            Compression::Gzip => { Ok(Encoder::Gzip(GzEncoder::new(file, flate2::Compression::default()))) },
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => { Ok(Encoder::Zstd(zstd::Encoder::new(file, 0)?)) },
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => { Err(io::Error::new(io::ErrorKind::Unsupported, "This build has no zstd support")) }
+        }
+    }
+}
+
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, File>)
+}
+
+impl Encoder {
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Plain(w) => { w },
+            Encoder::Gzip(w) => { w },
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => { w }
+        }
+    }
+
+    /// Writes what is buffered and the end of the compressed stream
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(mut w) => { w.flush() },
+            Encoder::Gzip(w) => { w.finish()?.flush() },
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(w) => { w.finish()?.flush() }
+        }
+    }
+}
+
+/// The rows of a part and the span of its first column, for its sidecar
+#[derive(Default)]
+struct PartStats {
+    rows: u64,
+    dates: Option<(NaiveDate, NaiveDate)>
+}
+
+/// A CSV file being written, see the top of this module. `finish` must be called once everything is written, for
+/// the end of a compressed stream, and the sidecars, to be written.
+pub struct ExportFile {
+    path: PathBuf,
+    report: String,
+    compression: Compression,
+    split_size: Option<u64>,
+    current: Option<Encoder>,
+    written: u64,           // bytes of the current part, before compression
+    header: Option<Vec<u8>>,
+    record: Vec<u8>,        // the record being written, until its end is
+    quoted: bool,           // whether the record being written is inside a quoted value
+    paths: Vec<PathBuf>,
+    stats: Vec<PartStats>   // of each of paths
+}
+
+impl ExportFile {
+    /// Creates the file at `path`, or its first part if it is split every `split_size` bytes
+    pub fn create(path: &Path, report: &str, compression: Compression, split_size: Option<u64>) -> Result<ExportFile, String> {
+        let mut file = ExportFile {
+            path: path.to_owned(), report: report.to_owned(), compression, split_size, current: None, written: 0,
+            header: None, record: Vec::new(), quoted: false, paths: Vec::new(), stats: Vec::new()
+        };
+
+        file.next_part().map_err(|e| format!("Failed to create {}: {}", file.path.display(), e))?;
+        Ok(file)
+    }
+
+    /// The name of the file, or of part `part` when split
+    pub fn part_path(path: &Path, compression: Compression, part: Option<usize>) -> PathBuf {
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+        let name = match part {
+            Some(part) => {
+                match name.split_once('.') {
+                    Some((stem, extension)) => { format!("{}-{:04}.{}", stem, part, extension) },
+                    None => { format!("{}-{:04}", name, part) }
+                }
+            },
+            None => { name }
+        };
+
+        let name = match name.ends_with(compression.extension()) {
+            true => { name },
+            false => { format!("{}{}", name, compression.extension()) }
+        };
+
+        path.with_file_name(name)
+    }
+
+    fn next_part(&mut self) -> io::Result<()> {
+        if let Some(current) = self.current.take() {
+            current.finish()?;
+        }
+
+        let part = self.split_size.map(|_| self.paths.len() + 1);
+        let path = ExportFile::part_path(&self.path, self.compression, part);
+        let mut encoder = self.compression.encoder(File::create(&path)?)?;
+        self.paths.push(path);
+        self.stats.push(PartStats::default());
+
+        self.written = 0;
+        if let Some(header) = &self.header {
+            encoder.writer().write_all(header)?;
+            self.written = header.len() as u64;
+        }
+
+        self.current = Some(encoder);
+        Ok(())
+    }
+
+    /// Writes a whole record, in a new part if it would make the current one too large
+    fn write_record(&mut self) -> io::Result<()> {
+        let record = std::mem::take(&mut self.record);
+
+        match &self.header {
+            None => { self.header = Some(record.clone()) },
+            Some(header) => {
+                let full = self.split_size.is_some_and(|size| self.written + record.len() as u64 > size);
+                if full && self.written > header.len() as u64 {
+                    self.next_part()?;
+                }
+
+                let stats = self.stats.last_mut().unwrap();
+                stats.rows += 1;
+
+                let first = record.split(|b| *b == b',').next().unwrap_or_default();
+                if let Some(date) = std::str::from_utf8(first).ok().and_then(|f| NaiveDate::parse_from_str(f.trim(), "%Y-%m-%d").ok()) {
+                    stats.dates = Some(stats.dates.map_or((date, date), |(from, to)| (from.min(date), to.max(date))));
+                }
+            }
+        }
+
+        self.written += record.len() as u64;
+        self.current.as_mut().unwrap().writer().write_all(&record)
+    }
+
+    /// The columns named by the header, all text in CSV
+    fn schema(&self) -> Vec<SchemaColumn> {
+        let header = match &self.header {
+            Some(h) => { h },
+            None => { return Vec::new() }
+        };
+
+        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(header.as_slice());
+        match reader.records().next() {
+            Some(Ok(names)) => { names.iter().map(|name| SchemaColumn::new(name, "string")).collect() },
+            _ => { Vec::new() }
+        }
+    }
+
+    /// Writes what is left and ends the file, returning the files written
+    pub fn finish(mut self) -> Result<Vec<PathBuf>, String> {
+        let path = self.path.clone();
+        let failed = |e: io::Error| format!("Failed to write {}: {}", path.display(), e);
+
+        if !self.record.is_empty() {
+            self.write_record().map_err(failed)?;
+        }
+        self.current.take().unwrap().finish().map_err(failed)?;
+
+        for (path, stats) in self.paths.iter().zip(&self.stats) {
+            Sidecar::new(path, &self.report, None, stats.dates, stats.rows, self.schema()).with_checksum(path)?.write(path)?;
+        }
+
+        Ok(self.paths)
+    }
+}
+
+impl Write for ExportFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.record.push(byte);
+
+            match byte {
+                // a quote inside a quoted value is doubled, which leaves it quoted
+                b'"' => { self.quoted = !self.quoted },
+                b'\n' if !self.quoted => { self.write_record()? },
+                _ => {}
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current.as_mut().unwrap().writer().flush()
+    }
+}
+
+#[test]
+fn test_split_export() {
+    use std::io::Read;
+
+    let directory = std::env::temp_dir().join(format!("export_test_{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    let mut file = ExportFile::create(&directory.join("extract.csv"), "weekly_corn", Compression::Gzip, Some(60)).unwrap();
+    file.write_all(b"report_date,value\n2020-03-02,\"a\nb\"\n2020-03-09,\"say \"\"hi\"\"\"\n2020-03-16,3\n").unwrap();
+    let paths = file.finish().unwrap();
+
+    assert_eq!(paths, vec![directory.join("extract-0001.csv.gz"), directory.join("extract-0002.csv.gz")]);
+
+    let read = |path: &Path| {
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(File::open(path).unwrap()).read_to_string(&mut text).unwrap();
+        text
+    };
+    assert_eq!(read(&paths[0]), "report_date,value\n2020-03-02,\"a\nb\"\n2020-03-09,\"say \"\"hi\"\"\"\n");
+    assert_eq!(read(&paths[1]), "report_date,value\n2020-03-16,3\n");
+
+    let sidecar: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(directory.join("_extract-0001.csv.gz.json")).unwrap()).unwrap();
+    assert_eq!(sidecar["report"], "weekly_corn");
+    assert_eq!((&sidecar["rows"], &sidecar["first_date"], &sidecar["last_date"]), (&2.into(), &"2020-03-02".into(), &"2020-03-09".into()));
+    assert_eq!(sidecar["schema"][1]["name"], "value");
+
+    let mut file = ExportFile::create(&directory.join("stats.csv"), "stats", Compression::None, None).unwrap();
+    file.write_all(b"table,rows\n").unwrap();
+    assert_eq!(file.finish().unwrap(), vec![directory.join("stats.csv")]);
+
+    assert_eq!(ExportFile::part_path(Path::new("out/extract.csv.gz"), Compression::Gzip, None), Path::new("out/extract.csv.gz"));
+    assert!(Compression::parse("lz4").is_err());
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
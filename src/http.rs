@@ -0,0 +1,442 @@
+// Requests to the USDA APIs. Datamart, ESMIS and MARS all drop connections and answer with 502s and 503s now and
+// then, so requests go through `call`, which retries those failures after a pause that doubles with each attempt,
+// with jitter so that parallel workers don't retry in step.
+//
+// Each source has its own policy; --http-retries overrides the number of retries of them all.
+//
+// With --rate-limit, requests (retries included) are also spaced out per host, so that a backfill of every report
+// doesn't send the USDA servers one request after another as fast as they answer.
+//
+// The [network] section of the network configuration (config/network.toml, see NetworkConfig) sets the same for
+// scheduled runs, along with a limit on requests waiting for an answer at once, whatever --jobs is; a request keeps
+// its place until its body has been read. --rate-limit and --http-retries take precedence over it.
+//
+// Datamart and ESMIS lookups ask for gzip, which datamart honours for some endpoints, shrinking its large JSON
+// responses several times over; a response that comes back uncompressed is read as it is.
+//
+// Datamart and ESMIS lookups can also be kept in an on-disk cache keyed by URL (see ResponseCache) given with
+// --http-cache, so that a failed backfill run again, or one rerun during development, doesn't download everything
+// again. The daemon, which polls for new data, and --no-cache go without.
+//
+// Each attempt is made through session::send, which records it for --record or answers it for --replay.
+
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{metrics, session};
+
+lazy_static! {
+    static ref RATE_LIMITER: Mutex<Option<RateLimiter>> = Mutex::new(None);
+    static ref RESPONSE_CACHE: Mutex<Option<ResponseCache>> = Mutex::new(None);
+    /// Requests waiting for an answer, and the condition they are waited on
+    static ref IN_FLIGHT: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+}
+
+/// Most requests waiting for an answer at once, 0 for no limit
+static MAX_CONCURRENT_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// The network configuration file
+#[derive(Deserialize, Debug, Default)]
+pub struct NetworkConfigFile {
+    #[serde(default)]
+    pub network: NetworkConfig
+}
+
+/// Politeness and retry settings, for tuning scheduled runs without changing their command lines
+#[derive(Deserialize, Debug, Default, PartialEq)]
+pub struct NetworkConfig {
+    pub max_concurrent_requests: Option<usize>, // across every thread and host
+    pub per_host_delay_ms: Option<u64>,         // least time between starting requests to a host, as --rate-limit
+    pub retries: Option<u32>                    // as --http-retries
+}
+
+/// Applies the settings of the network configuration
+pub fn configure(config: &NetworkConfig) -> Result<(), String> {
+    if let Some(requests) = config.max_concurrent_requests {
+        if requests == 0 {
+            return Err("Invalid max_concurrent_requests: 0, expected at least 1".to_owned());
+        }
+        MAX_CONCURRENT_REQUESTS.store(requests, Ordering::Relaxed);
+    }
+
+    if let Some(delay) = config.per_host_delay_ms {
+        *RATE_LIMITER.lock().unwrap() = Some(RateLimiter::with_interval(Duration::from_millis(delay)));
+    }
+
+    if let Some(retries) = config.retries {
+        set_retries(retries);
+    }
+
+    Ok(())
+}
+
+/// A request's place among those waiting for an answer, given up when dropped
+struct Permit;
+
+impl Permit {
+    /// Waits for fewer than the most requests allowed to be waiting for an answer, if there is a limit
+    fn acquire() -> Option<Permit> {
+        let limit = MAX_CONCURRENT_REQUESTS.load(Ordering::Relaxed);
+        if limit == 0 || session::is_replaying() {
+            return None;
+        }
+
+        let (count, available) = &*IN_FLIGHT;
+        let mut count = available.wait_while(count.lock().unwrap(), |count| *count >= limit).unwrap();
+        *count += 1;
+        Some(Permit)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let (count, available) = &*IN_FLIGHT;
+        *count.lock().unwrap() -= 1;
+        available.notify_one();
+    }
+}
+
+/// Numbers the temporary files of cache entries, so that threads storing the same URL don't write over each other
+static PARTIAL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub base_delay: Duration,   // the pause before the first retry
+    pub max_delay: Duration
+}
+
+impl RetryPolicy {
+    /// Datamart requests are long-running and are retried sparingly
+    pub const DATAMART: RetryPolicy = RetryPolicy { retries: 3, base_delay: Duration::from_secs(5), max_delay: Duration::from_secs(120) };
+    pub const ESMIS: RetryPolicy = RetryPolicy { retries: 4, base_delay: Duration::from_secs(2), max_delay: Duration::from_secs(60) };
+    pub const MARS: RetryPolicy = RetryPolicy { retries: 4, base_delay: Duration::from_secs(2), max_delay: Duration::from_secs(60) };
+
+    /// The pause before retry `attempt` (from 1), with `jitter` from 0 to 1: at least half the exponential delay,
+    /// and at most all of it
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let exponential = self.base_delay.checked_mul(2u32.saturating_pow(attempt.saturating_sub(1))).unwrap_or(self.max_delay);
+        let capped = exponential.min(self.max_delay);
+
+        capped.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+/// Retries set by --http-retries, u32::MAX while unset
+static RETRIES_OVERRIDE: AtomicU32 = AtomicU32::new(u32::MAX);
+
+/// Sets the number of retries of every source, in place of their defaults
+pub fn set_retries(retries: u32) {
+    RETRIES_OVERRIDE.store(retries, Ordering::Relaxed);
+}
+
+fn retries(policy: &RetryPolicy) -> u32 {
+    match RETRIES_OVERRIDE.load(Ordering::Relaxed) {
+        u32::MAX => { policy.retries },
+        retries => { retries }
+    }
+}
+
+/// Spaces out requests to each host so that no more than a set number are started per second
+pub struct RateLimiter {
+    interval: Duration,
+    next: HashMap<String, Instant>   // when each host may next be sent a request
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: f64) -> RateLimiter {
+        RateLimiter::with_interval(Duration::from_secs_f64(1.0 / requests_per_second))
+    }
+
+    /// Requests to a host started at least `interval` apart
+    pub fn with_interval(interval: Duration) -> RateLimiter {
+        RateLimiter { interval, next: HashMap::new() }
+    }
+
+    /// Takes the next slot for a request to `host` as of `now`, giving when it may be sent
+    pub fn reserve(&mut self, host: &str, now: Instant) -> Instant {
+        let slot = match self.next.get(host) {
+            Some(next) if *next > now => { *next },
+            _ => { now }
+        };
+        self.next.insert(host.to_owned(), slot + self.interval);
+
+        slot
+    }
+}
+
+/// Limits requests to each host to `requests_per_second`
+pub fn set_rate_limit(requests_per_second: f64) -> Result<(), String> {
+    if !(requests_per_second.is_finite() && requests_per_second > 0.0) {
+        return Err(format!("Invalid rate limit: {}, expected a number of requests per second above 0", requests_per_second));
+    }
+
+    *RATE_LIMITER.lock().unwrap() = Some(RateLimiter::new(requests_per_second));
+    Ok(())
+}
+
+/// Waits for a turn to send a request to `host`, if requests are rate limited
+fn wait_turn(host: &str) {
+    if session::is_replaying() {
+        return;
+    }
+
+    // the slot is taken under the lock, and waited for outside it so that requests to other hosts go ahead
+    let slot = match RATE_LIMITER.lock().unwrap().as_mut() {
+        Some(limiter) => { limiter.reserve(host, Instant::now()) },
+        None => { return }
+    };
+
+    thread::sleep(slot.saturating_duration_since(Instant::now()));
+}
+
+/// Whether a response is a failure worth retrying: a lookup or connection that failed or timed out, or a server
+/// that is overloaded or down for the moment. Bad URLs and other mistakes of ours aren't.
+pub fn is_transient(response: &ureq::Response) -> bool {
+    match response.synthetic_error() {
+        Some(ureq::Error::DnsFailed(_)) | Some(ureq::Error::ConnectionFailed(_)) | Some(ureq::Error::Io(_)) => { true },
+        Some(_) => { false },
+        None => { matches!(response.status(), 408 | 429 | 500 | 502 | 503 | 504) }
+    }
+}
+
+/// A number from 0 to 1 that differs from call to call, for jitter
+fn jitter() -> f64 {
+    let random = RandomState::new().build_hasher().finish();
+    (random % 1000) as f64 / 999.0
+}
+
+/// Makes a request, retrying transient failures under `policy`. The last response is given to `read` as it came,
+/// failure or not, for the caller to read or report; the request keeps its place among those waiting for an answer
+/// until `read` returns.
+pub fn call<T>(request: &mut ureq::Request, policy: &RetryPolicy, read: impl FnOnce(ureq::Response) -> T) -> T {
+    let retries = retries(policy);
+    let host = request.get_host().unwrap_or_default();
+    let mut attempt = 0;
+
+    loop {
+        wait_turn(&host);
+        let permit = Permit::acquire();
+        let start = Instant::now();
+        let response = session::send(request);
+        metrics::observe_duration(&metrics::HTTP_REQUEST_DURATION, &[("host", &host)], start.elapsed());
+
+        if attempt >= retries || !is_transient(&response) {
+            let result = read(response);
+            drop(permit);
+            return result;
+        }
+
+        drop(permit);
+
+        attempt += 1;
+
+        // a server that says when to come back is taken at its word, up to the longest pause
+        let delay = response.header("Retry-After")
+            .and_then(|seconds| seconds.trim().parse::<u64>().ok())
+            .map(|seconds| Duration::from_secs(seconds).min(policy.max_delay))
+            .unwrap_or_else(|| policy.delay(attempt, jitter()));
+
+        let reason = match response.synthetic_error() {
+            Some(error) => { error.to_string() },
+            None => { format!("status {}", response.status()) }
+        };
+        warn!("Request to {} failed ({}), retry {} of {} in {:.1}s", request.get_url(), reason, attempt, retries, delay.as_secs_f64());
+
+        if !session::is_replaying() {
+            thread::sleep(delay);
+        }
+    }
+}
+
+/// Response bodies kept on disk by URL, as <root>/<ab>/<sha256 of the URL>, and used until they are `ttl` old
+pub struct ResponseCache {
+    root: PathBuf,
+    ttl: Duration
+}
+
+impl ResponseCache {
+    pub fn new(root: &Path, ttl: Duration) -> ResponseCache {
+        ResponseCache { root: root.to_owned(), ttl }
+    }
+
+    fn path(&self, url: &str) -> PathBuf {
+        let hash = format!("{:x}", Sha256::digest(url.as_bytes()));
+        self.root.join(&hash[..2]).join(hash)
+    }
+
+    /// The body kept for `url`, unless there is none or it has expired
+    pub fn get(&self, url: &str) -> Option<String> {
+        let path = self.path(url);
+        let age = fs::metadata(&path).and_then(|m| m.modified()).ok()
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+
+        if age >= self.ttl {
+            return None;
+        }
+
+        fs::read_to_string(&path).ok()
+    }
+
+    pub fn put(&self, url: &str, body: &str) -> Result<(), String> {
+        let path = self.path(url);
+        let partial = path.with_extension(format!("{}.{}.partial", std::process::id(), PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed)));
+
+        let write = || -> Result<(), std::io::Error> {
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&partial, body)?;
+            fs::rename(&partial, &path)
+        };
+
+        write().map_err(|e| format!("Failed to cache the response from {} in {}: {}", url, path.display(), e))
+    }
+}
+
+/// Keeps fetched datamart and ESMIS responses in `cache`
+pub fn set_cache(cache: ResponseCache) {
+    *RESPONSE_CACHE.lock().unwrap() = Some(cache);
+}
+
+/// The text of a response body sent with `encoding` (its Content-Encoding), decompressed if gzipped. The USDA APIs
+/// answer in UTF-8.
+pub fn decode_body(bytes: Vec<u8>, encoding: Option<&str>) -> Result<String, String> {
+    let bytes = match encoding.map(str::trim) {
+        Some(e) if e.eq_ignore_ascii_case("gzip") || e.eq_ignore_ascii_case("x-gzip") => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(&bytes[..]).read_to_end(&mut decompressed).map_err(|e| format!("Failed to decompress the response: {}", e))?;
+            decompressed
+        },
+        None => { bytes },
+        Some(e) if e.is_empty() || e.eq_ignore_ascii_case("identity") => { bytes },
+        Some(e) => { return Err(format!("Unsupported response encoding: {}", e)) }
+    };
+
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Fetches the body of a response as `call` does, taking it from the response cache when `cached` and a cache is
+/// set. Only successful responses are cached; the bodies of others are given as they came, for the caller to find
+/// them invalid. Fails if the request does, or the body can't be read.
+pub fn fetch_text(request: &mut ureq::Request, policy: &RetryPolicy, cached: bool) -> Result<String, String> {
+    let url = request.get_url().to_owned();
+
+    if cached {
+        if let Some(body) = RESPONSE_CACHE.lock().unwrap().as_ref().and_then(|c| c.get(&url)) {
+            return Ok(body);
+        }
+    }
+
+    request.set("Accept-Encoding", "gzip");
+
+    let (body, ok) = call(request, policy, |response| {
+        if let Some(error) = response.synthetic_error() {
+            return Err(error.to_string());
+        }
+
+        let ok = response.ok();
+        let encoding = response.header("Content-Encoding").map(str::to_owned);
+
+        let bytes = session::read_body(response, u64::MAX).map_err(|e| format!("Failed to read the response: {}", e))?;
+        Ok((decode_body(bytes, encoding.as_deref())?, ok))
+    })?;
+
+    if cached && ok {
+        if let Some(cache) = RESPONSE_CACHE.lock().unwrap().as_ref() {
+            if let Err(e) = cache.put(&url, &body) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    Ok(body)
+}
+
+#[test]
+fn test_retry_delay() {
+    let policy = RetryPolicy { retries: 5, base_delay: Duration::from_secs(2), max_delay: Duration::from_secs(10) };
+
+    assert_eq!(policy.delay(1, 1.0), Duration::from_secs(2));
+    assert_eq!(policy.delay(2, 1.0), Duration::from_secs(4));
+    assert_eq!(policy.delay(3, 0.0), Duration::from_secs(4));
+    assert_eq!(policy.delay(4, 1.0), Duration::from_secs(10));
+    assert_eq!(policy.delay(40, 1.0), Duration::from_secs(10));
+
+    assert!(is_transient(&ureq::Response::new(503, "Service Unavailable", "")));
+    assert!(!is_transient(&ureq::Response::new(404, "Not Found", "")));
+    assert!(!is_transient(&ureq::Response::new(200, "OK", "")));
+}
+
+#[test]
+fn test_decode_body() {
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    let body = "{\"results\": [{\"report_date\": \"03/02/2020\"}]}";
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes()).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    assert_eq!(decode_body(compressed.clone(), Some("gzip")), Ok(body.to_owned()));
+    assert_eq!(decode_body(body.as_bytes().to_vec(), None), Ok(body.to_owned()));
+    assert_eq!(decode_body(body.as_bytes().to_vec(), Some("identity")), Ok(body.to_owned()));
+    assert!(decode_body(body.as_bytes().to_vec(), Some("gzip")).is_err());
+    assert!(decode_body(compressed, Some("br")).is_err());
+}
+
+#[test]
+fn test_rate_limiter() {
+    let mut limiter = RateLimiter::new(2.0);
+    let start = Instant::now();
+    let half = Duration::from_millis(500);
+
+    assert_eq!(limiter.reserve("marsapi.ams.usda.gov", start), start);
+    assert_eq!(limiter.reserve("marsapi.ams.usda.gov", start), start + half);
+    assert_eq!(limiter.reserve("marsapi.ams.usda.gov", start + Duration::from_millis(100)), start + half * 2);
+
+    // hosts are limited apart, and a host left alone may be sent a request straight away
+    assert_eq!(limiter.reserve("usda.library.cornell.edu", start), start);
+    assert_eq!(limiter.reserve("marsapi.ams.usda.gov", start + Duration::from_secs(5)), start + Duration::from_secs(5));
+}
+
+#[test]
+fn test_network_config() {
+    let file: NetworkConfigFile = toml::from_str("[network]\nmax_concurrent_requests = 4\nper_host_delay_ms = 500").unwrap();
+    assert_eq!(file.network, NetworkConfig { max_concurrent_requests: Some(4), per_host_delay_ms: Some(500), retries: None });
+    assert_eq!(toml::from_str::<NetworkConfigFile>("").unwrap().network, NetworkConfig::default());
+    assert!(configure(&NetworkConfig { max_concurrent_requests: Some(0), ..Default::default() }).is_err());
+
+    let mut limiter = RateLimiter::with_interval(Duration::from_millis(500));
+    let start = Instant::now();
+    limiter.reserve("mpr.datamart.ams.usda.gov", start);
+    assert_eq!(limiter.reserve("mpr.datamart.ams.usda.gov", start), start + Duration::from_millis(500));
+}
+
+#[test]
+fn test_response_cache() {
+    let root = std::env::temp_dir().join(format!("data-acquisition-http-cache-test-{}", std::process::id()));
+    let url = "https://mpr.datamart.ams.usda.gov/services/v1.1/reports/2466/Summary";
+    let cache = ResponseCache::new(&root, Duration::from_secs(3600));
+
+    assert_eq!(cache.get(url), None);
+    cache.put(url, "{\"results\": []}").unwrap();
+    assert_eq!(cache.get(url), Some("{\"results\": []}".to_owned()));
+    assert_eq!(cache.get(&format!("{}?q=report_date=03/02/2020", url)), None);
+
+    // expired entries are fetched again
+    assert_eq!(ResponseCache::new(&root, Duration::from_secs(0)).get(url), None);
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,201 @@
+// Completeness of the stored time series, for deciding which historical backfills are worth doing.
+//
+// A variable is expected on every date its table has data for, so a report's own release calendar sets the
+// expectation. Years inside a table's span with no data at all are expected to look like a typical year.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::usda::datamart::DatamartConfig;
+
+#[derive(Debug, PartialEq)]
+pub struct Completeness {
+    pub table: String,
+    pub variable: String,
+    pub first_year: i32,
+    pub last_year: i32,
+    pub present: usize,     // report dates with a value for the variable
+    pub expected: usize,    // report dates the variable could have had a value for
+    pub worst_year: i32,    // the year with the largest share missing
+}
+
+impl Completeness {
+    pub fn score(&self) -> f64 {
+        if self.expected == 0 {
+            return 1.0;
+        }
+
+        (self.present as f64 / self.expected as f64).min(1.0)
+    }
+
+    pub fn missing(&self) -> usize {
+        self.expected.saturating_sub(self.present)
+    }
+}
+
+/// Expected report dates per year from the dates a table has data for, filling years without any with the median
+pub fn expected_per_year(table_counts: &BTreeMap<i32, usize>) -> BTreeMap<i32, usize> {
+    let (first, last) = match (table_counts.keys().next(), table_counts.keys().next_back()) {
+        (Some(first), Some(last)) => { (*first, *last) },
+        _ => { return BTreeMap::new() }
+    };
+
+    let mut counts: Vec<usize> = table_counts.values().copied().collect();
+    counts.sort_unstable();
+    let median = counts[counts.len() / 2];
+
+    (first..=last).map(|year| (year, table_counts.get(&year).copied().unwrap_or(median))).collect()
+}
+
+/// Scores one variable against its table's expected dates per year
+pub fn score_variable(table: &str, variable: &str, expected: &BTreeMap<i32, usize>, present: &BTreeMap<i32, usize>) -> Completeness {
+    let mut score = Completeness {
+        table: table.to_owned(),
+        variable: variable.to_owned(),
+        first_year: expected.keys().next().copied().unwrap_or_default(),
+        last_year: expected.keys().next_back().copied().unwrap_or_default(),
+        present: 0,
+        expected: 0,
+        worst_year: expected.keys().next().copied().unwrap_or_default(),
+    };
+
+    let mut worst_share = f64::INFINITY;
+
+    for (year, expected_count) in expected {
+        let present_count = present.get(year).copied().unwrap_or(0).min(*expected_count);
+        score.present += present_count;
+        score.expected += expected_count;
+
+        let share = present_count as f64 / (*expected_count).max(1) as f64;
+        if share < worst_share {
+            worst_share = share;
+            score.worst_year = *year;
+        }
+    }
+
+    score
+}
+
+/// Scores every variable stored in a table
+pub fn score_table(table: &str, client: &mut postgres::Client) -> Result<Vec<Completeness>, String> {
+    let sql = format!("SELECT EXTRACT(YEAR FROM report_date)::integer, COUNT(DISTINCT report_date) FROM {} GROUP BY 1", table);
+    let rows = client.query(sql.as_str(), &[]).map_err(|e| format!("Failed to count report dates in {}: {}", table, e))?;
+
+    let table_counts: BTreeMap<i32, usize> = rows.iter().map(|row| (row.get::<_, i32>(0), row.get::<_, i64>(1) as usize)).collect();
+    let expected = expected_per_year(&table_counts);
+
+    let sql = format!(
+        "SELECT variable_name, EXTRACT(YEAR FROM report_date)::integer, COUNT(DISTINCT report_date) FROM {} \
+         WHERE value IS NOT NULL OR NULLIF(value_text, '') IS NOT NULL GROUP BY 1, 2",
+        table
+    );
+    let rows = client.query(sql.as_str(), &[]).map_err(|e| format!("Failed to count observations in {}: {}", table, e))?;
+
+    let mut present: BTreeMap<String, BTreeMap<i32, usize>> = BTreeMap::new();
+    for row in rows {
+        present.entry(row.get(0)).or_default().insert(row.get(1), row.get::<_, i64>(2) as usize);
+    }
+
+    Ok(present.iter().map(|(variable, counts)| score_variable(table, variable, &expected, counts)).collect())
+}
+
+/// Scores every section table of the given reports, least complete first. Tables that don't exist yet are skipped.
+pub fn score_reports<'a, I: IntoIterator<Item = &'a DatamartConfig>>(configs: I, client: &mut postgres::Client) -> Vec<Completeness> {
+    let mut scores = Vec::new();
+
+    for config in configs {
+        for section in config.sections.keys() {
+            let table_name = config.table_name(section);
+
+            match score_table(&table_name, client) {
+                Ok(s) => { scores.extend(s) },
+                Err(e) => { warn!("Skipping {}: {}", table_name, e) }
+            }
+        }
+    }
+
+    rank(&mut scores);
+    scores
+}
+
+/// Orders scores least complete first, then by the number of missing observations
+pub fn rank(scores: &mut [Completeness]) {
+    scores.sort_by(|a, b| {
+        a.score().partial_cmp(&b.score()).unwrap()
+            .then(b.missing().cmp(&a.missing()))
+            .then(a.table.cmp(&b.table))
+            .then(a.variable.cmp(&b.variable))
+    });
+}
+
+/// Writes ranked scores as CSV
+pub fn write_report<W: Write>(scores: &[Completeness], writer: W) -> Result<(), String> {
+    let mut writer = csv::Writer::from_writer(writer);
+
+    writer.write_record(["table", "variable", "first_year", "last_year", "present", "expected", "missing", "completeness", "worst_year"])
+        .map_err(|e| e.to_string())?;
+
+    for score in scores {
+        writer.write_record(&[
+            score.table.clone(), score.variable.clone(), score.first_year.to_string(), score.last_year.to_string(),
+            score.present.to_string(), score.expected.to_string(), score.missing().to_string(),
+            format!("{:.3}", score.score()), score.worst_year.to_string()
+        ]).map_err(|e| e.to_string())?;
+    }
+
+    writer.flush().map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_scoring() {
+    let table_counts: BTreeMap<i32, usize> = vec![(2018, 52), (2020, 50), (2021, 52)].into_iter().collect();
+    let expected = expected_per_year(&table_counts);
+
+    // 2019 has no data at all, but is expected to look like a typical year
+    assert_eq!(expected[&2019], 52);
+
+    let complete: BTreeMap<i32, usize> = table_counts.clone();
+    let gappy: BTreeMap<i32, usize> = vec![(2020, 25), (2021, 52)].into_iter().collect();
+
+    let mut scores = vec![
+        score_variable("t", "complete", &expected, &complete),
+        score_variable("t", "gappy", &expected, &gappy),
+    ];
+
+    assert_eq!(scores[0].missing(), 52);
+    assert_eq!(scores[1].present, 77);
+    assert_eq!(scores[1].expected, 206);
+    assert_eq!(scores[1].worst_year, 2018);
+
+    rank(&mut scores);
+    assert_eq!(scores[0].variable, "gappy");
+
+    let mut output = Vec::new();
+    write_report(&scores, &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.lines().nth(1).unwrap().starts_with("t,gappy,2018,2021,77,206,129,0.374,2018"));
+}
+
+#[test]
+fn test_score_table() {
+    use chrono::NaiveDate;
+    use super::usda::{create_table, insert_usda_package, test_package, test_structure, OnConflict};
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_completeness");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_completeness_bids").unwrap();
+    create_table("test_completeness_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    for (year, day) in &[(2019, 1), (2019, 8), (2020, 1)] {
+        insert_usda_package(test_package("test_completeness", NaiveDate::from_ymd_opt(*year, 3, *day).unwrap(), "Colby", "3.50"), &structure, OnConflict::Keep, client).unwrap();
+    }
+
+    let scores = score_reports(vec![&structure], client);
+
+    // the empty note column has no observations and so isn't scored
+    assert_eq!(scores.len(), 1);
+    assert_eq!(scores[0].variable, "bid");
+    assert_eq!((scores[0].present, scores[0].expected), (3, 3));
+}
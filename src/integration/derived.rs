@@ -0,0 +1,340 @@
+// Derived series: tables computed from other tables, defined in config/derived.toml and kept up to date by updates.
+//
+// A derived series is an extract spec (see extract) whose `name` is the table its series are stored in, laid out as
+// a report table: report_date is the start of the period and variable_name the series' column. As derived tables
+// look like report tables, one derived series can take its inputs from another.
+//
+// The derived series and their input tables form a graph. After an update, each derived table is recomputed in
+// dependency order for the periods its inputs were written for, and only when none of its inputs failed to be
+// fetched or written in that update; a derived table left alone leaves those derived from it alone too.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{Datelike, Duration, NaiveDate};
+use serde::Deserialize;
+
+use crate::usda::USDADataPackage;
+use crate::usda::datamart::{DatamartConfig, DateRange};
+use super::extract::{build_extract_sql_within, check_identifier, ExtractSpec};
+use super::usda::create_table;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct DerivedConfig {
+    #[serde(default)]
+    pub derived: Vec<ExtractSpec>
+}
+
+/// The tables an update wrote, with the report dates written to each, and those it failed to fetch or write
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Ingested {
+    pub written: BTreeMap<String, DateRange>,
+    pub failed: BTreeSet<String>
+}
+
+/// The table of each section of a report
+fn report_tables(structure: &DatamartConfig) -> impl Iterator<Item = (&String, String)> {
+    structure.sections.keys().map(move |section| (section, structure.table_name(section)))
+}
+
+impl Ingested {
+    /// Notes the report dates of a package that was written
+    pub fn record_written(&mut self, package: &USDADataPackage, structure: &DatamartConfig) {
+        for (section, table) in report_tables(structure) {
+            let dates = package.sections.get(section).into_iter().flatten().map(|s| s.report_date);
+            if let (Some(from), Some(to)) = (dates.clone().min(), dates.max()) {
+                self.record_range(table, (from, to));
+            }
+        }
+    }
+
+    /// Notes that a report, or a package of it, could not be fetched or written
+    pub fn record_failure(&mut self, structure: &DatamartConfig) {
+        self.failed.extend(report_tables(structure).map(|(_, table)| table));
+    }
+
+    /// Adds what another update ingested
+    pub fn merge(&mut self, other: Ingested) {
+        for (table, range) in other.written {
+            self.record_range(table, range);
+        }
+        self.failed.extend(other.failed);
+    }
+
+    fn record_range(&mut self, table: String, (from, to): DateRange) {
+        let range = self.written.entry(table).or_insert((from, to));
+        *range = (range.0.min(from), range.1.max(to));
+    }
+}
+
+/// The first day of the extract period `date` falls in
+pub fn period_start(period: &str, date: NaiveDate) -> NaiveDate {
+    match period {
+        "week" => { date - Duration::days(date.weekday().num_days_from_monday() as i64) },
+        "month" => { date.with_day(1).unwrap() },
+        "quarter" => { NaiveDate::from_ymd_opt(date.year(), (date.month0() / 3) * 3 + 1, 1).unwrap() },
+        "year" => { NaiveDate::from_ymd_opt(date.year(), 1, 1).unwrap() },
+        _ => { date }
+    }
+}
+
+/// The derived series of a configuration in dependency order, every one after those it takes inputs from
+pub struct DependencyGraph<'a> {
+    order: Vec<&'a ExtractSpec>
+}
+
+impl<'a> DependencyGraph<'a> {
+    pub fn new(derived: &'a [ExtractSpec]) -> Result<DependencyGraph<'a>, String> {
+        let mut by_name: BTreeMap<&str, &ExtractSpec> = BTreeMap::new();
+        for spec in derived {
+            if by_name.insert(&spec.name, spec).is_some() {
+                return Err(format!("Derived series {} is defined more than once", spec.name));
+            }
+        }
+
+        let mut order: Vec<&ExtractSpec> = Vec::new();
+        let mut placed: BTreeSet<&str> = BTreeSet::new();
+
+        // each pass places the series whose derived inputs are all placed, in name order so the order is stable
+        while order.len() < by_name.len() {
+            let ready: Vec<&ExtractSpec> = by_name.values()
+                .filter(|spec| !placed.contains(spec.name.as_str()))
+                .filter(|spec| inputs(spec).iter().all(|input| !by_name.contains_key(input) || placed.contains(input)))
+                .cloned()
+                .collect();
+
+            if ready.is_empty() {
+                let remaining: Vec<&str> = by_name.keys().filter(|name| !placed.contains(*name)).cloned().collect();
+                return Err(format!("Derived series depend on each other in a cycle: {}", remaining.join(", ")));
+            }
+
+            for spec in ready {
+                placed.insert(&spec.name);
+                order.push(spec);
+            }
+        }
+
+        Ok(DependencyGraph { order })
+    }
+
+    pub fn order(&self) -> &[&'a ExtractSpec] {
+        &self.order
+    }
+
+    /// The derived series to recompute after an update that ingested `ingested`, in dependency order, with the
+    /// report dates each is to be recomputed for
+    pub fn affected(&self, ingested: &Ingested) -> Vec<(&'a ExtractSpec, DateRange)> {
+        let mut ingested = ingested.clone();
+        let mut affected = Vec::new();
+
+        for spec in &self.order {
+            let inputs = inputs(spec);
+
+            if inputs.iter().any(|input| ingested.failed.contains(*input)) {
+                if inputs.iter().any(|input| ingested.written.contains_key(*input)) {
+                    warn!("Not recomputing {}, as not all of its inputs were updated.", spec.name);
+                }
+                ingested.failed.insert(spec.name.to_owned());
+                continue;
+            }
+
+            let written = inputs.iter().filter_map(|input| ingested.written.get(*input));
+            if let (Some(from), Some(to)) = (written.clone().map(|r| r.0).min(), written.map(|r| r.1).max()) {
+                let range = (period_start(&spec.period, from), period_start(&spec.period, to));
+                ingested.record_range(spec.name.to_owned(), range);
+                affected.push((*spec, (from, to)));
+            }
+        }
+
+        affected
+    }
+
+    /// The graph in Graphviz's dot language, with an edge from each input table to the series derived from it
+    pub fn to_dot(&self) -> String {
+        let mut dot = "digraph derived {\n".to_owned();
+
+        for spec in &self.order {
+            dot.push_str(&format!("    \"{}\" [shape=box];\n", spec.name));
+        }
+        for spec in &self.order {
+            for input in inputs(spec) {
+                dot.push_str(&format!("    \"{}\" -> \"{}\";\n", input, spec.name));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Each series with its inputs, in dependency order
+    pub fn describe(&self) -> String {
+        self.order.iter().map(|spec| format!("{} <- {}\n", spec.name, inputs(spec).into_iter().collect::<Vec<&str>>().join(", "))).collect()
+    }
+}
+
+/// The tables a derived series takes its inputs from
+pub fn inputs(spec: &ExtractSpec) -> BTreeSet<&str> {
+    spec.series.iter().map(|s| s.table.as_str()).collect()
+}
+
+pub fn create_derived_table(spec: &ExtractSpec, client: &mut postgres::Client) -> Result<usize, postgres::Error> {
+    create_table(spec.name.to_owned(), &["report_date".to_owned()], client)
+}
+
+/// Replaces the periods of a derived table that overlap `range` with those computed from its inputs, returning
+/// the number of rows written
+pub fn recompute(spec: &ExtractSpec, range: DateRange, client: &mut postgres::Client) -> Result<u64, String> {
+    check_identifier(&spec.name)?;
+    let (sql, mut parameters) = build_extract_sql_within(spec, Some(range))?;
+
+    let columns: Vec<String> = (0..spec.series.len()).map(|i| format!("s{}", i)).collect();
+    let values: Vec<String> = spec.series.iter().enumerate().map(|(i, s)| format!("('{}', e.s{})", s.column, i)).collect();
+
+    parameters.push(format!("{}@1", spec.name));
+    let insert = format!(
+        "INSERT INTO {table} (report_date, variable_name, value, value_text, provenance) \
+         SELECT e.period, v.variable_name, v.value::real, v.value::text, ${provenance} \
+         FROM ({sql}) AS e(period, {columns}) CROSS JOIN LATERAL (VALUES {values}) AS v(variable_name, value) \
+         WHERE v.value IS NOT NULL",
+        table=spec.name, provenance=parameters.len(), sql=sql, columns=columns.join(", "), values=values.join(", ")
+    );
+    let parameters: Vec<&(dyn postgres::types::ToSql + Sync)> = parameters.iter().map(|p| p as &(dyn postgres::types::ToSql + Sync)).collect();
+
+    let failed = |e: postgres::Error| format!("Failed to recompute {}: {}", spec.name, e);
+    let mut transaction = client.transaction().map_err(failed)?;
+
+    transaction.execute(
+        format!("DELETE FROM {} WHERE report_date >= $1 AND report_date <= $2", spec.name).as_str(),
+        &[&period_start(&spec.period, range.0), &range.1]
+    ).map_err(failed)?;
+    let written = transaction.execute(insert.as_str(), &parameters[..]).map_err(failed)?;
+
+    transaction.commit().map_err(failed)?;
+    Ok(written)
+}
+
+/// Recomputes the derived series an update affected, leaving alone those derived from one that could not be
+pub fn update(graph: &DependencyGraph, ingested: &Ingested, client: &mut postgres::Client) {
+    let mut failed: BTreeSet<&str> = BTreeSet::new();
+
+    for (spec, range) in graph.affected(ingested) {
+        if inputs(spec).iter().any(|input| failed.contains(input)) {
+            warn!("Not recomputing {}, as not all of its inputs were updated.", spec.name);
+            failed.insert(&spec.name);
+            continue;
+        }
+
+        match recompute(spec, range, client) {
+            Ok(rows) => { info!("Recomputed {} from {} to {}, {} rows.", spec.name, range.0, range.1, rows) },
+            Err(e) => {
+                error!("{}", e);
+                failed.insert(&spec.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_derived() -> Vec<ExtractSpec> {
+    let config: DerivedConfig = toml::from_str(r#"
+        [[derived]]
+        name = "weekly_corn"
+        period = "week"
+        [[derived.series]]
+        column = "bid"
+        table = "dc_gr110_corn"
+        variable = "bid"
+        aggregate = "avg"
+
+        [[derived]]
+        name = "monthly_cutout_corn"
+        period = "month"
+        [[derived.series]]
+        column = "loads"
+        table = "lm_xb463_summary"
+        variable = "total_loads"
+        aggregate = "sum"
+        [[derived.series]]
+        column = "corn"
+        table = "weekly_corn"
+        variable = "bid"
+        aggregate = "avg"
+    "#).unwrap();
+    config.derived
+}
+
+#[test]
+fn test_dependency_graph() {
+    let derived = test_derived();
+    let graph = DependencyGraph::new(&derived).unwrap();
+
+    let order: Vec<&str> = graph.order().iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(order, vec!["weekly_corn", "monthly_cutout_corn"]);
+    assert_eq!(graph.describe(), "weekly_corn <- dc_gr110_corn\nmonthly_cutout_corn <- lm_xb463_summary, weekly_corn\n");
+    assert!(graph.to_dot().contains("    \"weekly_corn\" -> \"monthly_cutout_corn\";\n"));
+
+    let mut cycle = test_derived();
+    cycle[0].series[0].table = "monthly_cutout_corn".to_owned();
+    assert!(DependencyGraph::new(&cycle).is_err());
+}
+
+#[test]
+fn test_affected_series() {
+    let date = |day| NaiveDate::from_ymd_opt(2020, 3, day).unwrap();
+    let derived = test_derived();
+    let graph = DependencyGraph::new(&derived).unwrap();
+    let names = |affected: Vec<(&ExtractSpec, DateRange)>| affected.into_iter().map(|(s, r)| (s.name.to_owned(), r)).collect::<Vec<_>>();
+
+    // the monthly series is recomputed from the start of the week of corn bids that changed
+    let mut ingested = Ingested::default();
+    ingested.written.insert("dc_gr110_corn".to_owned(), (date(4), date(5)));
+    assert_eq!(names(graph.affected(&ingested)), vec![("weekly_corn".to_owned(), (date(4), date(5))), ("monthly_cutout_corn".to_owned(), (date(2), date(2)))]);
+
+    // a failed input holds back everything downstream of it
+    ingested.failed.insert("dc_gr110_corn".to_owned());
+    ingested.written.insert("lm_xb463_summary".to_owned(), (date(10), date(10)));
+    assert!(graph.affected(&ingested).is_empty());
+
+    ingested.failed.clear();
+    ingested.failed.insert("lm_xb463_summary".to_owned());
+    assert_eq!(names(graph.affected(&ingested)), vec![("weekly_corn".to_owned(), (date(4), date(5)))]);
+}
+
+#[test]
+fn test_recompute() {
+    use super::usda::{insert_usda_package, test_package, test_structure, OnConflict};
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_derived");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_derived_bids; DROP TABLE IF EXISTS test_weekly_bids").unwrap();
+    create_table("test_derived_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    let mut ingested = Ingested::default();
+    for (day, bid) in &[(2, "5.00"), (4, "6.00"), (10, "8.00")] {
+        let package = test_package("test_derived", NaiveDate::from_ymd_opt(2020, 3, *day).unwrap(), "Dodge City", bid);
+        ingested.record_written(&package, &structure);
+        insert_usda_package(package, &structure, OnConflict::Keep, client).unwrap();
+    }
+    assert_eq!(ingested.written["test_derived_bids"], (NaiveDate::from_ymd_opt(2020, 3, 2).unwrap(), NaiveDate::from_ymd_opt(2020, 3, 10).unwrap()));
+
+    let spec: ExtractSpec = toml::from_str(r#"
+        name = "test_weekly_bids"
+        period = "week"
+        [[series]]
+        column = "bid"
+        table = "test_derived_bids"
+        variable = "bid"
+        aggregate = "avg"
+    "#).unwrap();
+    create_derived_table(&spec, client).unwrap();
+
+    // only the week of the 4th is recomputed
+    client.batch_execute("INSERT INTO test_weekly_bids (report_date, variable_name, value) VALUES ('2020-03-09', 'bid', 1)").unwrap();
+    let week = NaiveDate::from_ymd_opt(2020, 3, 4).unwrap();
+    assert_eq!(recompute(&spec, (week, week), client).unwrap(), 1);
+
+    let rows = client.query("SELECT report_date::text, value FROM test_weekly_bids ORDER BY report_date", &[]).unwrap();
+    let values: Vec<(String, Option<f32>)> = rows.iter().map(|r| (r.get(0), r.get(1))).collect();
+    assert_eq!(values, vec![("2020-03-02".to_owned(), Some(5.5)), ("2020-03-09".to_owned(), Some(1.0))]);
+}
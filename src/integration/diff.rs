@@ -0,0 +1,178 @@
+// Comparing fetched data with what is stored, for spotting the history USDA revises without notice (--diff).
+//
+// Values are compared as the text USDA published, so a reformatted number counts as a change.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+use chrono::NaiveDate;
+use postgres::GenericClient;
+
+use crate::usda::USDADataPackage;
+use crate::usda::datamart::DatamartConfig;
+
+/// A stored observation: report date, the other independent columns, and the variable
+pub type Key = (NaiveDate, Vec<String>, String);
+
+#[derive(Debug, PartialEq)]
+pub enum Difference {
+    New { fetched: String },
+    Changed { stored: String, fetched: String },
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct TableDiff {
+    pub differences: BTreeMap<Key, Difference>,
+    pub identical: usize
+}
+
+impl TableDiff {
+    pub fn new_count(&self) -> usize {
+        self.differences.values().filter(|d| matches!(d, Difference::New { .. })).count()
+    }
+
+    pub fn changed_count(&self) -> usize {
+        self.differences.values().filter(|d| matches!(d, Difference::Changed { .. })).count()
+    }
+}
+
+/// Classifies fetched values against stored ones. Empty values are never stored, so they are left out.
+pub fn compare(stored: &HashMap<Key, String>, fetched: Vec<(Key, String)>) -> TableDiff {
+    let mut diff = TableDiff::default();
+
+    for (key, value) in fetched {
+        if value.is_empty() {
+            continue;
+        }
+
+        match stored.get(&key) {
+            Some(existing) if *existing == value => { diff.identical += 1 },
+            Some(existing) => { diff.differences.insert(key, Difference::Changed { stored: existing.to_owned(), fetched: value }); },
+            None => { diff.differences.insert(key, Difference::New { fetched: value }); }
+        }
+    }
+
+    diff
+}
+
+/// Stored values of a table for the given report dates. A table that doesn't exist yet has none.
+pub fn stored_values<C: GenericClient>(table_name: &str, independent: &[String], dates: &[NaiveDate], client: &mut C) -> Result<HashMap<Key, String>, String> {
+    let exists: bool = client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&table_name])
+        .map_err(|e| e.to_string())?.get(0);
+    if !exists {
+        return Ok(HashMap::new());
+    }
+
+    let columns: Vec<String> = independent[1..].iter().map(|c| super::usda::quoted_column(c)).collect();
+    let sql = format!(
+        "SELECT report_date, variable_name, value_text{}{} FROM {} WHERE report_date = ANY($1)",
+        if columns.is_empty() { "" } else { ", " }, columns.join(", "), table_name
+    );
+
+    let rows = client.query(sql.as_str(), &[&dates]).map_err(|e| format!("Failed to read stored values from {}: {}", table_name, e))?;
+
+    Ok(rows.iter().map(|row| {
+        let independent: Vec<String> = (3..row.len()).map(|i| row.get(i)).collect();
+        ((row.get(0), independent, row.get(1)), row.get::<_, Option<String>>(2).unwrap_or_default())
+    }).collect())
+}
+
+/// Compares every section of a package with the stored rows for the same report dates, by table
+pub fn diff_usda_package(package: USDADataPackage, structure: &DatamartConfig, client: &mut postgres::Client) -> Result<BTreeMap<String, TableDiff>, String> {
+    let mut diffs = BTreeMap::new();
+
+    for (section, results) in package.sections {
+        let table_name = structure.table_name(&section);
+
+        let mut dates: Vec<NaiveDate> = results.iter().map(|r| r.report_date).collect();
+        dates.sort_unstable();
+        dates.dedup();
+
+        let stored = stored_values(&table_name, &structure.sections[&section].independent, &dates, client)?;
+
+        let fetched: Vec<(Key, String)> = results.into_iter().flat_map(|row| {
+            let (report_date, independent) = (row.report_date, row.independent[1..].to_vec());
+            row.entries.into_iter().map(move |(variable, value)| ((report_date, independent.clone(), variable), value))
+        }).collect();
+
+        diffs.insert(table_name, compare(&stored, fetched));
+    }
+
+    Ok(diffs)
+}
+
+/// Writes each new and changed value, then a count per table
+pub fn write_diff<W: Write>(diffs: &BTreeMap<String, TableDiff>, mut writer: W) -> Result<(), String> {
+    for (table, diff) in diffs {
+        for ((report_date, independent, variable), difference) in &diff.differences {
+            let location = if independent.is_empty() { String::new() } else { format!(" [{}]", independent.join(", ")) };
+
+            match difference {
+                Difference::New { fetched } => {
+                    writeln!(writer, "+ {} {}{} {}: {}", table, report_date, location, variable, fetched)
+                },
+                Difference::Changed { stored, fetched } => {
+                    writeln!(writer, "~ {} {}{} {}: {} -> {}", table, report_date, location, variable, stored, fetched)
+                }
+            }.map_err(|e| e.to_string())?;
+        }
+
+        writeln!(writer, "{}: {} new, {} changed, {} identical", table, diff.new_count(), diff.changed_count(), diff.identical)
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_compare_and_write() {
+    let date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    let key = |region: &str, variable: &str| (date, vec![region.to_owned()], variable.to_owned());
+
+    let stored: HashMap<Key, String> = vec![
+        (key("Colby", "bid"), "3.50".to_owned()),
+        (key("Dodge City", "bid"), "5.41".to_owned()),
+    ].into_iter().collect();
+
+    let fetched = vec![
+        (key("Colby", "bid"), "3.50".to_owned()),
+        (key("Dodge City", "bid"), "5.45".to_owned()),
+        (key("Garden City", "bid"), "4.10".to_owned()),
+        (key("Garden City", "note"), "".to_owned()),
+    ];
+
+    let diff = compare(&stored, fetched);
+    assert_eq!((diff.new_count(), diff.changed_count(), diff.identical), (1, 1, 1));
+
+    let mut diffs = BTreeMap::new();
+    diffs.insert("test_bids".to_owned(), diff);
+
+    let mut output = Vec::new();
+    write_diff(&diffs, &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap(), "\
+~ test_bids 2020-03-02 [Dodge City] bid: 5.41 -> 5.45
++ test_bids 2020-03-02 [Garden City] bid: 4.10
+test_bids: 1 new, 1 changed, 1 identical
+");
+}
+
+#[test]
+fn test_diff_usda_package() {
+    use super::usda::{create_table, insert_usda_package, test_package, test_structure, OnConflict};
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_diff");
+    let date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+
+    // nothing stored yet, not even a table
+    client.batch_execute("DROP TABLE IF EXISTS test_diff_bids").unwrap();
+    let diffs = diff_usda_package(test_package("test_diff", date, "Colby", "3.50"), &structure, client).unwrap();
+    assert_eq!(diffs["test_diff_bids"].new_count(), 1);
+
+    create_table("test_diff_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+    insert_usda_package(test_package("test_diff", date, "Colby", "3.50"), &structure, OnConflict::Keep, client).unwrap();
+
+    let diffs = diff_usda_package(test_package("test_diff", date, "Colby", "3.55"), &structure, client).unwrap();
+    assert_eq!(diffs["test_diff_bids"].differences.values().next(), Some(&Difference::Changed { stored: "3.50".to_owned(), fetched: "3.55".to_owned() }));
+}
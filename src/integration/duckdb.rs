@@ -0,0 +1,206 @@
+// Storing reports in a DuckDB database file rather than on a PostgreSQL server (--duckdb), which suits analysis:
+// there is no server to run, and the file can be queried directly from Python or R.
+//
+// Tables have the same layout as in PostgreSQL, less the run and revision bookkeeping, which needs PostgreSQL; revised
+// values can still be written over with --on-conflict update.
+
+use std::path::Path;
+
+use chrono::NaiveDate;
+use duckdb::ToSql;
+
+use crate::usda::USDADataPackage;
+use crate::usda::datamart::DatamartConfig;
+use super::sink::{Sink, NO_DATE_FOUND};
+use super::usda::{column_name, quoted_column, OnConflict};
+
+pub struct DuckDb {
+    connection: duckdb::Connection
+}
+
+impl DuckDb {
+    /// Opens a database file, creating it if it doesn't exist
+    pub fn open(path: &Path) -> Result<DuckDb, String> {
+        let connection = duckdb::Connection::open(path).map_err(|e| format!("Failed to open DuckDB database {}: {}", path.display(), e))?;
+        Ok(DuckDb { connection })
+    }
+
+    #[cfg(test)]
+    fn open_in_memory() -> DuckDb {
+        DuckDb { connection: duckdb::Connection::open_in_memory().unwrap() }
+    }
+
+    /// Renames the columns of a table made before columns were named with `column_name`, e.g. "ClassDescription"
+    /// to class_description
+    fn rename_columns(&mut self, name: &str, independent: &[String]) -> Result<(), String> {
+        for column in &independent[1..] {
+            let renamed = column_name(column);
+            if *column == renamed {
+                continue;
+            }
+
+            let exists: bool = self.connection.query_row(
+                "SELECT COUNT(*) > 0 FROM information_schema.columns WHERE table_name = ? AND column_name = ?",
+                duckdb::params![name, column],
+                |row| row.get(0)
+            ).map_err(|e| format!("Failed to look up the columns of {}: {}", name, e))?;
+
+            if !exists {
+                continue;
+            }
+
+            let old = format!("\"{}\"", column.replace('"', "\"\""));
+            // DuckDB matches names regardless of case, so a column whose name only changes case is renamed by way
+            // of another name
+            let sql = if column.to_lowercase() == renamed {
+                format!("ALTER TABLE {0} RENAME COLUMN {1} TO \"{2}_renamed\";\nALTER TABLE {0} RENAME COLUMN \"{2}_renamed\" TO \"{2}\";", name, old, renamed)
+            } else {
+                format!("ALTER TABLE {} RENAME COLUMN {} TO \"{}\";", name, old, renamed)
+            };
+
+            self.connection.execute_batch(&sql).map_err(|e| format!("Failed to rename column {} of {}: {}", column, name, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Another connection to the same database, for a writer thread
+    pub fn try_clone(&self) -> Result<DuckDb, String> {
+        let connection = self.connection.try_clone().map_err(|e| format!("Failed to open another DuckDB connection: {}", e))?;
+        Ok(DuckDb { connection })
+    }
+}
+
+impl Sink for DuckDb {
+    fn create_table(&mut self, name: &str, independent: &[String]) -> Result<(), String> {
+        let columns: Vec<String> = independent[1..].iter().map(|c| quoted_column(c)).collect();
+
+        let mut sql = format!("CREATE TABLE IF NOT EXISTS {} (\n\treport_date date not null,\n", name);
+        for column in &columns {
+            sql.push_str(&format!("\t{} text not null,\n", column));
+        }
+        sql.push_str("\tvariable_name text not null,\n\tvalue real,\n\tvalue_text text,\n\tsource text,\n\tprovenance text,\n\tparser_version integer,\n");
+        sql.push_str(&format!("\tprimary key (report_date, variable_name{}{})\n)", if columns.is_empty() { "" } else { ", " }, columns.join(", ")));
+
+        self.connection.execute_batch(&sql).map_err(|e| format!("Failed to create table {}: {}", name, e))?;
+        self.rename_columns(name, independent)
+    }
+
+    fn insert_package(&mut self, package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict) -> Result<u64, String> {
+        let on_conflict = on_conflict.for_report(structure);
+        if on_conflict == OnConflict::TrackRevisions {
+            return Err("Tracking revisions requires PostgreSQL".to_owned());
+        }
+
+        let report_name = package.name;
+        let source = package.source;
+        let parser_version = package.parser_version.map(|v| v as i32);
+
+        let transaction = self.connection.transaction().map_err(|e| format!("Failed to begin inserting {}: {}", report_name, e))?;
+        let mut inserted = 0;
+
+        for (section, results) in package.sections {
+            let table_name = structure.table_name(&section);
+
+            let independent = &structure.sections[&section].independent;
+            let mut columns = vec!["report_date".to_owned()];
+            columns.extend(independent[1..].iter().map(|c| quoted_column(c)));
+            columns.extend(["variable_name", "value", "value_text", "source", "provenance", "parser_version"].iter().map(|c| (*c).to_owned()));
+
+            let resolution = match on_conflict {
+                OnConflict::Update => {
+                    "DO UPDATE SET value = excluded.value, value_text = excluded.value_text, source = excluded.source, \
+                     provenance = excluded.provenance, parser_version = excluded.parser_version \
+                     WHERE value_text IS DISTINCT FROM excluded.value_text"
+                },
+                _ => { "DO NOTHING" }
+            };
+
+            let sql = format!(
+                "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT {}",
+                table_name, columns.join(", "), vec!["?"; columns.len()].join(", "), resolution
+            );
+
+            let failed = |e: duckdb::Error| format!("Failed to insert {} into {}, nothing from the package was written: {}", report_name, table_name, e);
+            let mut statement = transaction.prepare(&sql).map_err(failed)?;
+
+            for row in results {
+                for (key, value) in &row.entries {
+                    if value.is_empty() {
+                        continue;
+                    }
+
+                    let value_numeric = value.replace(",", "").parse::<f32>().ok();
+                    let provenance = row.provenance.get(key);
+
+                    let mut params: Vec<&dyn ToSql> = vec![&row.report_date];
+                    for column in &row.independent[1..] {
+                        params.push(column);
+                    }
+                    params.extend_from_slice(&[key, &value_numeric, value, &source, &provenance, &parser_version]);
+
+                    inserted += statement.execute(&params[..]).map_err(failed)? as u64;
+                }
+            }
+        }
+
+        transaction.commit().map_err(|e| format!("Failed to commit {}: {}", report_name, e))?;
+        Ok(inserted)
+    }
+
+    fn max_date(&mut self, config: &DatamartConfig) -> Result<NaiveDate, String> {
+        let mut maximum: Option<NaiveDate> = None;
+
+        for section in config.sections.keys() {
+            let table_name = config.table_name(section);
+
+            let latest: Option<NaiveDate> = self.connection.query_row(&format!("SELECT MAX(report_date) FROM {}", table_name), [], |row| row.get(0))
+                .map_err(|e| format!("Failed to obtain latest data for {}: {}", table_name, e))?;
+
+            maximum = maximum.max(latest);
+        }
+
+        maximum.ok_or_else(|| NO_DATE_FOUND.to_owned())
+    }
+}
+
+#[test]
+fn test_duckdb_sink() {
+    use super::usda::{test_package, test_structure};
+
+    let mut database = DuckDb::open_in_memory();
+    let structure = test_structure("test_duckdb");
+    let date = |day| NaiveDate::from_ymd_opt(2020, 3, day).unwrap();
+
+    database.create_table("test_duckdb_bids", &structure.sections["bids"].independent).unwrap();
+    assert!(database.max_date(&structure).is_err()); // no rows
+
+    assert_eq!(database.insert_package(test_package("test_duckdb", date(2), "Dodge City", "5.41"), &structure, OnConflict::Keep), Ok(1));
+    assert_eq!(database.insert_package(test_package("test_duckdb", date(9), "Dodge City", "5.45"), &structure, OnConflict::Keep), Ok(1));
+
+    // the stored value is kept, unless it is to be updated
+    assert_eq!(database.insert_package(test_package("test_duckdb", date(2), "Dodge City", "5.50"), &structure, OnConflict::Keep), Ok(0));
+    assert_eq!(database.insert_package(test_package("test_duckdb", date(9), "Dodge City", "5.45"), &structure, OnConflict::Update), Ok(0));
+    assert_eq!(database.insert_package(test_package("test_duckdb", date(9), "Dodge City", "5.47"), &structure, OnConflict::Update), Ok(1));
+    assert!(database.insert_package(test_package("test_duckdb", date(2), "Dodge City", "5.50"), &structure, OnConflict::TrackRevisions).is_err());
+
+    assert_eq!(database.max_date(&structure), Ok(date(9)));
+
+    let stored: String = database.connection.query_row("SELECT value_text FROM test_duckdb_bids WHERE report_date = ?", [date(2)], |row| row.get(0)).unwrap();
+    assert_eq!(stored, "5.41");
+}
+
+#[test]
+fn test_duckdb_rename_columns() {
+    let mut database = DuckDb::open_in_memory();
+    let independent = vec!["report_date".to_owned(), "ClassDescription".to_owned(), "Region".to_owned()];
+
+    // a table made when columns kept their configured names
+    database.connection.execute_batch("CREATE TABLE test_duckdb_renamed (report_date date, \"ClassDescription\" text, \"Region\" text)").unwrap();
+    database.create_table("test_duckdb_renamed", &independent).unwrap();
+    database.create_table("test_duckdb_renamed", &independent).unwrap();
+
+    let mut statement = database.connection.prepare("SELECT column_name FROM information_schema.columns WHERE table_name = 'test_duckdb_renamed' ORDER BY ordinal_position").unwrap();
+    let columns: Vec<String> = statement.query_map([], |row| row.get(0)).unwrap().map(|c| c.unwrap()).collect();
+    assert_eq!(columns, vec!["report_date", "class_description", "region"]);
+}
@@ -0,0 +1,90 @@
+// The ESMIS publication record of legacy reports, kept in esmis_releases as releases are found.
+//
+// Report rows can be joined to the release they came from on identifier and release_date, and a release is marked
+// processed once it has been parsed and queued for insertion, so that later updates don't fetch it again.
+
+use std::collections::HashSet;
+
+use chrono::NaiveDate;
+
+use crate::usda::esmis::ESMISRelease;
+
+pub fn create_releases_table(client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    client.batch_execute(r#"
+        CREATE TABLE IF NOT EXISTS esmis_releases (
+            id text primary key,
+            identifier text not null,
+            release_date date not null,
+            release_datetime text not null,
+            files text[] not null,
+            title text,
+            date_modified text,
+            first_seen_at timestamptz not null default now(),
+            processed_at timestamptz
+        );
+    "#)
+}
+
+/// Records the metadata of releases found, updating what ESMIS may have changed since. Returns the IDs of those
+/// already processed.
+pub fn record_releases(releases: &[ESMISRelease], client: &mut postgres::Client) -> Result<HashSet<String>, String> {
+    create_releases_table(client).map_err(|e| format!("Failed to create esmis_releases: {}", e))?;
+
+    let mut transaction = client.transaction().map_err(|e| e.to_string())?;
+    let statement = transaction.prepare(r#"
+        INSERT INTO esmis_releases (id, identifier, release_date, release_datetime, files, title, date_modified)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (id) DO UPDATE SET files = EXCLUDED.files, title = EXCLUDED.title, date_modified = EXCLUDED.date_modified
+    "#).map_err(|e| e.to_string())?;
+
+    for release in releases {
+        let release_date: NaiveDate = release.release_date()?;
+        let identifier = release.identifier.first().map(String::as_str).unwrap_or_default();
+        let title = release.title.first();
+
+        transaction.execute(&statement, &[
+            &release.id, &identifier, &release_date, &release.release_datetime, &release.files, &title, &release.date_modified
+        ]).map_err(|e| format!("Failed to record ESMIS release {}: {}", release.id, e))?;
+    }
+
+    let ids: Vec<&String> = releases.iter().map(|r| &r.id).collect();
+    let processed = transaction.query("SELECT id FROM esmis_releases WHERE id = ANY($1) AND processed_at IS NOT NULL", &[&ids])
+        .map_err(|e| format!("Failed to look up processed ESMIS releases: {}", e))?;
+
+    transaction.commit().map_err(|e| e.to_string())?;
+    Ok(processed.iter().map(|row| row.get(0)).collect())
+}
+
+pub fn mark_processed(ids: &[String], client: &mut postgres::Client) -> Result<(), String> {
+    client.execute("UPDATE esmis_releases SET processed_at = now() WHERE id = ANY($1)", &[&ids])
+        .map_err(|e| format!("Failed to mark ESMIS releases processed: {}", e))?;
+    Ok(())
+}
+
+#[test]
+fn test_record_releases() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    client.batch_execute("DROP TABLE IF EXISTS esmis_releases").unwrap();
+
+    let release = |id: &str, date: &str| -> ESMISRelease {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "files": [format!("https://downloads.usda.library.cornell.edu/{}.txt", id)],
+            "title": ["Comprehensive beef cutout"],
+            "release_datetime": date,
+            "identifier": ["LM_XB463"],
+            "agency_acronym": ["AMS"]
+        })).unwrap()
+    };
+
+    let releases = vec![release("a1", "2020-03-05T15:00:00Z"), release("b2", "2020-03-06T15:00:00Z")];
+    assert!(record_releases(&releases, client).unwrap().is_empty());
+
+    mark_processed(&["a1".to_owned()], client).unwrap();
+    let processed = record_releases(&releases, client).unwrap();
+    assert_eq!(processed.into_iter().collect::<Vec<String>>(), vec!["a1"]);
+
+    let date: NaiveDate = client.query_one("SELECT release_date FROM esmis_releases WHERE id = 'b2'", &[]).unwrap().get(0);
+    assert_eq!(date, NaiveDate::from_ymd_opt(2020, 3, 6).unwrap());
+}
@@ -0,0 +1,210 @@
+// Joined datasets across reports, defined by an extract spec (see config/extracts/) and written as CSV, or as
+// Parquet with --extract-format parquet (see parquet::write_extract).
+//
+// Every series is aggregated to the spec's period and the series are joined on that period, so reports
+// published on different days of the week still line up.
+//
+// Values are aggregated as numeric, which is exact, so the order PostgreSQL happens to read rows in can't change the
+// last digits of a sum or average, and extracting the same range twice writes the same file.
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use chrono::NaiveDate;
+use serde::Deserialize;
+
+use crate::usda::datamart::DateRange;
+
+const PERIODS: [&str; 5] = ["day", "week", "month", "quarter", "year"];
+const AGGREGATES: [&str; 5] = ["avg", "sum", "min", "max", "count"];
+
+#[derive(Deserialize, Debug)]
+pub struct ExtractSpec {
+    pub name: String,
+    pub period: String,
+    pub series: Vec<SeriesSpec>
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SeriesSpec {
+    pub column: String,
+    pub table: String,
+    pub variable: String,
+    pub aggregate: String,
+    #[serde(default)]
+    pub filter: BTreeMap<String, String>
+}
+
+/// Table and column names are spliced into SQL, so only plain identifiers are accepted
+pub fn check_identifier(identifier: &str) -> Result<(), String> {
+    if !identifier.is_empty() && identifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(())
+    } else {
+        Err(format!("Not a valid identifier in extract spec: '{}'", identifier))
+    }
+}
+
+/// Builds the query for a spec, returning it with its parameters in order
+pub fn build_extract_sql(spec: &ExtractSpec) -> Result<(String, Vec<String>), String> {
+    build_extract_sql_within(spec, None)
+}
+
+/// As `build_extract_sql`, only aggregating the periods that overlap `range` if one is given
+pub fn build_extract_sql_within(spec: &ExtractSpec, range: Option<DateRange>) -> Result<(String, Vec<String>), String> {
+    if !PERIODS.contains(&spec.period.as_str()) {
+        return Err(format!("Unknown extract period '{}', expected one of {:?}", spec.period, PERIODS));
+    }
+
+    if spec.series.is_empty() {
+        return Err("An extract needs at least one series".to_owned());
+    }
+
+    let mut parameters: Vec<String> = Vec::new();
+    let mut ctes: Vec<String> = Vec::new();
+
+    for (index, series) in spec.series.iter().enumerate() {
+        check_identifier(&series.table)?;
+        check_identifier(&series.column)?;
+
+        if !AGGREGATES.contains(&series.aggregate.as_str()) {
+            return Err(format!("Unknown aggregate '{}' for {}, expected one of {:?}", series.aggregate, series.column, AGGREGATES));
+        }
+
+        parameters.push(series.variable.to_owned());
+        let mut conditions = vec![format!("variable_name = ${}", parameters.len())];
+
+        for (column, value) in &series.filter {
+            check_identifier(column)?;
+            parameters.push(value.to_owned());
+            conditions.push(format!("{} = ${}", super::usda::quoted_column(column), parameters.len()));
+        }
+
+        if let Some((from, to)) = range {
+            conditions.push(format!(
+                "report_date >= date_trunc('{period}', '{from}'::date) AND report_date < date_trunc('{period}', '{to}'::date) + interval '1 {period}'",
+                period=spec.period, from=from.format("%Y-%m-%d"), to=to.format("%Y-%m-%d")
+            ));
+        }
+
+        ctes.push(format!(
+            "s{index} AS (SELECT date_trunc('{period}', report_date)::date AS period, {aggregate}(value::numeric)::double precision AS value FROM {table} WHERE {conditions} GROUP BY 1)",
+            index=index, period=spec.period, aggregate=series.aggregate, table=series.table, conditions=conditions.join(" AND ")
+        ));
+    }
+
+    let periods = (0..spec.series.len()).map(|i| format!("SELECT period FROM s{}", i)).collect::<Vec<String>>().join(" UNION ");
+    let columns = (0..spec.series.len()).map(|i| format!("s{}.value", i)).collect::<Vec<String>>().join(", ");
+    let joins = (0..spec.series.len()).map(|i| format!("LEFT JOIN s{0} ON s{0}.period = periods.period", i)).collect::<Vec<String>>().join(" ");
+
+    let sql = format!(
+        "WITH {ctes}, periods AS ({periods}) SELECT periods.period, {columns} FROM periods {joins} ORDER BY periods.period",
+        ctes=ctes.join(", "), periods=periods, columns=columns, joins=joins
+    );
+
+    Ok((sql, parameters))
+}
+
+/// The joined dataset of an extract: each period, in order, with the value of every series, if any
+pub struct Extract {
+    pub columns: Vec<String>,
+    pub rows: Vec<(NaiveDate, Vec<Option<f64>>)>
+}
+
+/// Runs an extract's query
+pub fn query_extract(spec: &ExtractSpec, client: &mut postgres::Client) -> Result<Extract, String> {
+    let (sql, parameters) = build_extract_sql(spec)?;
+    let parameters: Vec<&(dyn postgres::types::ToSql + Sync)> = parameters.iter().map(|p| p as &(dyn postgres::types::ToSql + Sync)).collect();
+
+    let rows = client.query(sql.as_str(), &parameters[..]).map_err(|e| format!("Extract {} failed: {}", spec.name, e))?;
+
+    Ok(Extract {
+        columns: spec.series.iter().map(|s| s.column.to_owned()).collect(),
+        rows: rows.iter()
+            .map(|row| (row.get(0), (0..spec.series.len()).map(|index| row.get(index + 1)).collect()))
+            .collect()
+    })
+}
+
+/// Writes an extract as CSV with a header row, and returns the number of rows written
+pub fn write_csv<W: Write>(extract: &Extract, output: W) -> Result<usize, String> {
+    let mut writer = csv::Writer::from_writer(output);
+    let mut header = vec!["period".to_owned()];
+    header.extend(extract.columns.iter().cloned());
+    writer.write_record(&header).map_err(|e| e.to_string())?;
+
+    for (period, values) in &extract.rows {
+        let mut record = vec![period.format("%Y-%m-%d").to_string()];
+        record.extend(values.iter().map(|v| v.map(|v| v.to_string()).unwrap_or_default()));
+        writer.write_record(&record).map_err(|e| e.to_string())?;
+    }
+
+    writer.flush().map_err(|e| e.to_string())?;
+    Ok(extract.rows.len())
+}
+
+/// Runs an extract, writing it as CSV with a header row, and returns the number of rows written
+pub fn run_extract<W: Write>(spec: &ExtractSpec, client: &mut postgres::Client, output: W) -> Result<usize, String> {
+    write_csv(&query_extract(spec, client)?, output)
+}
+
+#[test]
+fn test_build_extract_sql() {
+    let spec: ExtractSpec = toml::from_str(&std::fs::read_to_string("config/extracts/cutout_slaughter_corn.toml").unwrap()).unwrap();
+    let (sql, parameters) = build_extract_sql(&spec).unwrap();
+
+    assert_eq!(parameters, vec!["total_loads", "B_dom_formula_head_count", "bid", "Dodge City"]);
+    assert!(sql.contains(r#"FROM dc_gr110_corn WHERE variable_name = $3 AND "region" = $4"#));
+    assert!(sql.contains("date_trunc('week', report_date)"));
+
+    let bad: ExtractSpec = toml::from_str(r#"
+        name = "bad"
+        period = "week"
+        [[series]]
+        column = "x"
+        table = "lm_xb463_summary; DROP TABLE y"
+        variable = "total_loads"
+        aggregate = "sum"
+    "#).unwrap();
+    assert!(build_extract_sql(&bad).is_err());
+
+    let range = (NaiveDate::from_ymd_opt(2020, 3, 4).unwrap(), NaiveDate::from_ymd_opt(2020, 3, 10).unwrap());
+    let (sql, _) = build_extract_sql_within(&spec, Some(range)).unwrap();
+    assert!(sql.contains("report_date >= date_trunc('week', '2020-03-04'::date) AND report_date < date_trunc('week', '2020-03-10'::date) + interval '1 week'"));
+}
+
+#[test]
+fn test_run_extract() {
+    use super::usda::{create_table, insert_usda_package, test_package, test_structure, OnConflict};
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_extract");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_extract_bids").unwrap();
+    create_table("test_extract_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    // 2020-03-02 is a Monday; the 4th falls in the same week, the 10th in the next
+    for (day, region, bid) in &[(2, "Dodge City", "5.00"), (4, "Dodge City", "6.00"), (4, "Colby", "7.00"), (10, "Colby", "8.00")] {
+        insert_usda_package(test_package("test_extract", NaiveDate::from_ymd_opt(2020, 3, *day).unwrap(), region, bid), &structure, OnConflict::Keep, client).unwrap();
+    }
+
+    let spec: ExtractSpec = toml::from_str(r#"
+        name = "test"
+        period = "week"
+        [[series]]
+        column = "dodge_city"
+        table = "test_extract_bids"
+        variable = "bid"
+        aggregate = "avg"
+        filter = { region = "Dodge City" }
+        [[series]]
+        column = "bids"
+        table = "test_extract_bids"
+        variable = "bid"
+        aggregate = "count"
+    "#).unwrap();
+
+    let mut output: Vec<u8> = Vec::new();
+    assert_eq!(run_extract(&spec, client, &mut output).unwrap(), 2);
+    assert_eq!(String::from_utf8(output).unwrap(), "period,dodge_city,bids\n2020-03-02,5.5,3\n2020-03-09,,1\n");
+}
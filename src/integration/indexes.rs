@@ -0,0 +1,172 @@
+// Secondary indexes on report tables. The primary key leads with report_date, while queries are almost always
+// filtered by variable_name and then a date range, so each table also gets (variable_name, report_date) unless its
+// report configures other indexes with `indexes`. NOAA tables add (station_id).
+//
+// Indexes are made with the tables by --create, and --reindex makes any that are missing on existing tables and
+// rebuilds them all.
+
+use crate::usda::datamart::DatamartConfig;
+use super::usda::{column_name, quoted_column, safe_identifier, truncated_identifier, MAX_IDENTIFIER_LENGTH};
+
+/// The indexes of a report without an `indexes` setting
+pub const DEFAULT_INDEXES: &[&[&str]] = &[&["variable_name", "report_date"]];
+
+/// The secondary indexes of a report's tables, as lists of columns
+pub fn report_indexes(config: &DatamartConfig) -> Vec<Vec<String>> {
+    match &config.indexes {
+        Some(indexes) => { indexes.clone() },
+        None => { DEFAULT_INDEXES.iter().map(|columns| columns.iter().map(|c| c.to_string()).collect()).collect() }
+    }
+}
+
+/// The name of the index on `columns` of `table`, e.g. lm_ct100_summary_variable_name_report_date_idx, shortened as
+/// `safe_identifier` does
+fn index_name(table: &str, columns: &[String]) -> String {
+    let columns: Vec<String> = columns.iter()
+        .map(|c| column_name(c))
+        .collect();
+
+    safe_identifier(&format!("{}_{}", table, columns.join("_")), "_idx")
+}
+
+/// The names the index on `columns` of `table` may have been made under before, as PostgreSQL kept them, along with
+/// whether it cut them short: with its columns named as configured, e.g. lm_ct109_detail_classdescription_idx, and
+/// with a long name cut short rather than shortened
+fn stale_index_names(table: &str, columns: &[String]) -> Vec<(String, bool)> {
+    let name = index_name(table, columns);
+    let configured: Vec<String> = columns.iter()
+        .map(|c| c.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect())
+        .collect();
+    let snake_case: Vec<String> = columns.iter().map(|c| column_name(c)).collect();
+
+    let mut stale: Vec<(String, bool)> = Vec::new();
+    for columns in [configured, snake_case] {
+        let full = format!("{}_{}_idx", table, columns.join("_")).to_lowercase();
+        let kept = truncated_identifier(&full, MAX_IDENTIFIER_LENGTH).to_owned();
+        if kept != name && !stale.iter().any(|(s, _)| *s == kept) {
+            stale.push((kept, full.len() > MAX_IDENTIFIER_LENGTH));
+        }
+    }
+    stale
+}
+
+/// The statements creating the indexes of a table that don't exist yet. An index still under a stale name is
+/// renamed, or dropped if the index under the new name exists too. One whose name was cut short is dropped, as it
+/// may be the index of other columns whose name was cut short the same.
+pub fn create_indexes_sql(table: &str, indexes: &[Vec<String>]) -> String {
+    indexes.iter()
+        .filter(|columns| !columns.is_empty())
+        .map(|columns| {
+            let name = index_name(table, columns);
+            let quoted: Vec<String> = columns.iter().map(|c| quoted_column(c)).collect();
+
+            let mut statements: Vec<String> = stale_index_names(table, columns).into_iter()
+                .map(|(stale, cut_short)| if cut_short {
+                    format!("DROP INDEX IF EXISTS {};", stale)
+                } else {
+                    format!(
+                        "DO $$ BEGIN IF to_regclass('{1}') IS NULL THEN ALTER INDEX IF EXISTS {0} RENAME TO {1}; ELSE DROP INDEX IF EXISTS {0}; END IF; END $$;",
+                        stale, name
+                    )
+                })
+                .collect();
+            statements.push(format!("CREATE INDEX IF NOT EXISTS {} ON {} ({});", name, table, quoted.join(", ")));
+
+            statements.join("\n")
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+pub fn create_indexes(table: &str, indexes: &[Vec<String>], client: &mut postgres::Client) -> Result<(), String> {
+    client.batch_execute(&create_indexes_sql(table, indexes))
+        .map_err(|e| format!("Failed to create the indexes of {}: {}", table, e))
+}
+
+/// Makes the configured indexes of a table missing any and rebuilds all of its indexes. Returns false, doing
+/// nothing, for a table that doesn't exist.
+pub fn reindex(table: &str, indexes: &[Vec<String>], client: &mut postgres::Client) -> Result<bool, String> {
+    let exists: bool = client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&table])
+        .map_err(|e| format!("Failed to look up {}: {}", table, e))?
+        .get(0);
+
+    if !exists {
+        return Ok(false);
+    }
+
+    create_indexes(table, indexes, client)?;
+    client.batch_execute(&format!("REINDEX TABLE {}", table)).map_err(|e| format!("Failed to reindex {}: {}", table, e))?;
+
+    Ok(true)
+}
+
+#[test]
+fn test_create_indexes_sql() {
+    let indexes = vec![
+        vec!["variable_name".to_owned(), "report_date".to_owned()],
+        vec!["class description".to_owned()],
+        vec!["ClassDescription".to_owned()],
+        vec![]
+    ];
+
+    assert_eq!(
+        create_indexes_sql("lm_ct109_detail", &indexes),
+        "CREATE INDEX IF NOT EXISTS lm_ct109_detail_variable_name_report_date_idx ON lm_ct109_detail (\"variable_name\", \"report_date\");\n\
+         CREATE INDEX IF NOT EXISTS lm_ct109_detail_class_description_idx ON lm_ct109_detail (\"class_description\");\n\
+         DO $$ BEGIN IF to_regclass('lm_ct109_detail_class_description_idx') IS NULL THEN \
+         ALTER INDEX IF EXISTS lm_ct109_detail_classdescription_idx RENAME TO lm_ct109_detail_class_description_idx; \
+         ELSE DROP INDEX IF EXISTS lm_ct109_detail_classdescription_idx; END IF; END $$;\n\
+         CREATE INDEX IF NOT EXISTS lm_ct109_detail_class_description_idx ON lm_ct109_detail (\"class_description\");"
+    );
+}
+
+#[test]
+fn test_long_index_names() {
+    let table = "ams_3192_national_weekly_grain_market_review_feed_exports_all_regions";
+    assert!(table.len() > MAX_IDENTIFIER_LENGTH);
+
+    let by_class = index_name(table, &["variable_name".to_owned(), "class".to_owned()]);
+    let by_grade = index_name(table, &["variable_name".to_owned(), "grade".to_owned()]);
+    assert_eq!(by_class.len(), MAX_IDENTIFIER_LENGTH);
+    assert!(by_class.starts_with("ams_3192_national_weekly_grain_market_review_") && by_class.ends_with("_idx"));
+    assert_ne!(by_class, by_grade);
+
+    // the index PostgreSQL made under the name it cut short is dropped rather than kept beside the new one
+    let sql = create_indexes_sql(table, &[vec!["variable_name".to_owned(), "class".to_owned()]]);
+    assert_eq!(sql, format!(
+        "DROP INDEX IF EXISTS {};\nCREATE INDEX IF NOT EXISTS {} ON {} (\"variable_name\", \"class\");",
+        &format!("{}_variable_name_class_idx", table)[..MAX_IDENTIFIER_LENGTH], by_class, table
+    ));
+}
+
+#[test]
+fn test_reindex() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = super::usda::test_structure("test_indexes");
+    let table = "test_indexes_bids";
+
+    client.batch_execute(&format!("DROP TABLE IF EXISTS {}", table)).unwrap();
+    assert_eq!(reindex(table, &report_indexes(&structure), client), Ok(false));
+
+    super::usda::create_table(table.to_owned(), &structure.sections["bids"].independent, client).unwrap();
+    assert_eq!(reindex(table, &report_indexes(&structure), client), Ok(true));
+    assert_eq!(reindex(table, &report_indexes(&structure), client), Ok(true));
+
+    let count: i64 = client.query_one("SELECT COUNT(*) FROM pg_indexes WHERE tablename = $1 AND indexname = $2", &[&table, &"test_indexes_bids_variable_name_report_date_idx"])
+        .unwrap().get(0);
+    assert_eq!(count, 1);
+
+    // an index under its legacy name is renamed, or dropped once the index under the new name exists
+    let indexes = vec![vec!["variableName".to_owned()]];
+    let count_index = |client: &mut postgres::Client, index: &str| -> i64 {
+        client.query_one("SELECT COUNT(*) FROM pg_indexes WHERE tablename = $1 AND indexname = $2", &[&table, &index]).unwrap().get(0)
+    };
+
+    for _ in 0..2 {
+        client.batch_execute(&format!("CREATE INDEX test_indexes_bids_variablename_idx ON {} (variable_name)", table)).unwrap();
+        assert_eq!(reindex(table, &indexes, client), Ok(true));
+        assert_eq!(count_index(client, "test_indexes_bids_variablename_idx"), 0);
+        assert_eq!(count_index(client, "test_indexes_bids_variable_name_idx"), 1);
+    }
+}
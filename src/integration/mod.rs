@@ -1,2 +1,21 @@
+pub mod completeness;
+pub mod derived;
+pub mod diff;
+#[cfg(feature = "duckdb")]
+pub mod duckdb;
+pub mod esmis;
+pub mod extract;
+pub mod indexes;
 pub mod noaa;
-pub mod usda;
\ No newline at end of file
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod pool;
+pub mod runs;
+pub mod sink;
+pub mod skipped;
+pub mod stats;
+pub mod usda;
+pub mod writer;
+
+#[cfg(test)]
+pub mod test_postgres;
@@ -1,11 +1,16 @@
 use crate::noaa;
 use crate::usda;
 use crate::usda::{USDADataPackage, USDADataPackageSection};
+use crate::usda::datamart::DateRange;
 
 use std::collections::{HashMap, HashSet};
 use chrono::NaiveDate;
 use std::convert::TryInto;
 
+/// Provenance of values computed here rather than reported by NOAA
+const NOAA_IMPERIAL_PROVENANCE: &str = "to_imperial@1";
+const NOAA_SEASON_PROVENANCE: &str = "noaa_season@1";
+
 lazy_static! {
     pub static ref SUPPORTED_NOAA_ELEMENTS: HashSet<&'static str> = [
         /*
@@ -34,69 +39,84 @@ lazy_static! {
     ].iter().cloned().collect();
 }
 
-impl From<Vec<noaa::Observation>> for USDADataPackage {
-    fn from(package: Vec<noaa::Observation>) -> Self {
-        let mut output_package = USDADataPackage::new("NOAA".to_owned());
+/// The observations of the days of `range` (every day if None) as a package of the NOAA structure, a section per element.
+/// Numeric values are kept as the `value` variable in NOAA's native units and/or as `value_imperial` depending on
+/// `units`; elements without an imperial equivalent always keep their native value.
+pub fn noaa_package(observations: Vec<noaa::Observation>, units: noaa::NoaaUnits, range: Option<DateRange>) -> USDADataPackage {
+    let mut output_package = USDADataPackage::new("NOAA".to_owned());
 
-        for observation in package {
-            if !SUPPORTED_NOAA_ELEMENTS.contains(&(observation.element.as_str())) {
-                println!("Skipping unsupported element: {}", observation.element);
+    for observation in observations {
+        if !SUPPORTED_NOAA_ELEMENTS.contains(&(observation.element.as_str())) {
+            warn!("Skipping unsupported element: {}", observation.element);
+            continue;
+        }
+        for (day, data) in observation.observations.iter().enumerate() {
+            // if the value is empty, don't bother with this record
+            let value_string = match data.value.as_ref() {
+                Some(v) => { v.to_string() },
+                None => { continue }
+            };
+
+            let this_date = match NaiveDate::from_ymd_opt(
+                observation.year.try_into().unwrap(),
+                observation.month.try_into().unwrap(),
+                (day + 1).try_into().unwrap()
+            ) {
+                Some(d) => { d },
+                None => { continue } // e.g. day 31 of a 30 day month
+            };
+            if range.is_some_and(|(from, to)| this_date < from || this_date > to) {
                 continue;
             }
-            for (day, data) in observation.observations.iter().enumerate() {
-                // if the value is empty, don't bother with this record
-                let value_string = match data.value.as_ref() {
-                    Some(v) => { v.to_string() },
-                    None => { continue }
-                };
-
-                let this_date = NaiveDate::from_ymd(
-                    observation.year.try_into().unwrap(),
-                    observation.month.try_into().unwrap(),
-                    (day + 1).try_into().unwrap()
-                );
-
-                let mut destination_section = USDADataPackageSection::new(this_date);
-                destination_section.independent.push(this_date.format("%Y-%m-%d").to_string());
-                destination_section.independent.push(observation.station_id.to_owned());
-                
-                let measure_string = match data.measure_flag.as_ref() {
-                    Some(v) => {v.to_string()},
-                    None => {"".to_owned()}
-                };
-                
-                destination_section.entries.insert(
-                    "measure_flag".to_owned(),
-                    measure_string
-                );
-
-                let quality_string = match data.quality_flag.as_ref() {
-                    Some(v) => { v.to_string() },
-                    None => {"".to_owned()}
-                };
-
-                destination_section.entries.insert(
-                    "quality_flag".to_owned(),
-                    quality_string
-                );
-
-                destination_section.entries.insert(
-                    "source_flag".to_owned(),
-                    data.source_flag.to_owned()
-                );
-
-                destination_section.entries.insert(
-                    "value".to_owned(),
-                    value_string
-                );
-
-                let element = output_package.sections.entry(observation.element.to_owned()).or_insert_with(Vec::new);
-                element.push(destination_section);
+
+            let mut destination_section = USDADataPackageSection::new(this_date);
+            destination_section.independent.push(this_date.format("%Y-%m-%d").to_string());
+            destination_section.independent.push(observation.station_id.to_owned());
+            
+            let measure_string = match data.measure_flag.as_ref() {
+                Some(v) => {v.to_string()},
+                None => {"".to_owned()}
+            };
+            
+            destination_section.entries.insert(
+                "measure_flag".to_owned(),
+                measure_string
+            );
+
+            let quality_string = match data.quality_flag.as_ref() {
+                Some(v) => { v.to_string() },
+                None => {"".to_owned()}
+            };
+
+            destination_section.entries.insert(
+                "quality_flag".to_owned(),
+                quality_string
+            );
+
+            destination_section.entries.insert(
+                "source_flag".to_owned(),
+                data.source_flag.to_owned()
+            );
+
+            let value_imperial: Option<f64> = data.value.and_then(|v| noaa::to_imperial(&observation.element, v));
+
+            if units != noaa::NoaaUnits::Imperial || value_imperial.is_none() {
+                destination_section.entries.insert("value".to_owned(), value_string);
+            }
+
+            if units != noaa::NoaaUnits::Metric {
+                if let Some(imperial) = value_imperial {
+                    destination_section.entries.insert("value_imperial".to_owned(), format!("{:.2}", imperial));
+                    destination_section.provenance.insert("value_imperial".to_owned(), NOAA_IMPERIAL_PROVENANCE.to_owned());
+                }
             }
-        }
 
-        output_package
+            let element = output_package.sections.entry(observation.element.to_owned()).or_default();
+            element.push(destination_section);
+        }
     }
+
+    output_package
 }
 
 #[test]
@@ -131,7 +151,7 @@ AE000041196194404TMIN  180  I  180  I  163  I  146  I  135  I-9999   -9999     1
     let cursor = Cursor::new(result);
 
     let results = noaa::process_noaa(cursor, None, None).unwrap();
-    let converted_result = USDADataPackage::from(results);
+    let converted_result = noaa_package(results, noaa::NoaaUnits::Both, None);
 
     println!("{:#?}", converted_result)
 }
@@ -145,8 +165,11 @@ pub fn noaa_structure() -> usda::datamart::DatamartConfig {
             independent: vec!["report_date".to_owned(), "station_id".to_owned()],
             fields: vec![
                 "measure_flag".to_owned(), "source_flag".to_owned(), 
-                "quality_flag".to_owned(), "value".to_owned()
-            ]
+                "quality_flag".to_owned(), "value".to_owned(), "value_imperial".to_owned()
+            ],
+            types: Default::default(),
+            ignored: None,
+            required: true
         };
         sections.entry(String::from(*element)).or_insert(section);
     }
@@ -155,6 +178,24 @@ pub fn noaa_structure() -> usda::datamart::DatamartConfig {
         name: "NOAA".to_owned(),
         description: "National Oceanic and Atmospheric Administration Weather Data".to_owned(),
         independent: "report_date".to_owned(),
+        api_version: usda::datamart::DatamartApiVersion::V1,
+        mars_slug: None,
+        mars_filters: Default::default(),
+        independent_type: Default::default(),
+        mars_family: None,
+        on_conflict: None,
+        period: None,
+        file_date: None,
+        indexes: Some(vec![
+            vec!["variable_name".to_owned(), "report_date".to_owned()],
+            vec!["station_id".to_owned()]
+        ]),
+        archive_url: None,
+        release_time: None,
+        connect_timeout: None,
+        read_timeout: None,
+        transforms: Vec::new(),
+        parser: None,
         sections
     }
 }
@@ -164,67 +205,205 @@ fn test_noaa_structure() {
     println!("{:?}", noaa_structure())
 }
 
-pub fn insert_noaa_package(observations: Vec<noaa::Observation>, client: &mut postgres::Client) -> Result<(), postgres::Error> {
-    for observation in observations {
-        if !SUPPORTED_NOAA_ELEMENTS.contains(&(observation.element.as_str())) {
-            println!("Skipping unsupported element: {}", observation.element);
-            continue;
+/// The NOAA structure with the secondary indexes of the configuration, if it gives any
+pub fn configured_structure(config: &noaa::NoaaConfig) -> usda::datamart::DatamartConfig {
+    let mut structure = noaa_structure();
+    if config.indexes.is_some() {
+        structure.indexes = config.indexes.clone();
+    }
+
+    structure
+}
+
+pub fn create_station_table(client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    client.batch_execute(r#"
+        CREATE TABLE IF NOT EXISTS noaa_stations (
+            station_id text not null primary key,
+            latitude double precision not null,
+            longitude double precision not null,
+            elevation double precision,
+            state text,
+            name text
+        );
+    "#)
+}
+
+pub fn insert_noaa_stations(stations: &[noaa::Station], client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let statement = client.prepare(r#"
+        INSERT INTO noaa_stations (station_id, latitude, longitude, elevation, state, name) VALUES($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (station_id) DO UPDATE SET latitude = EXCLUDED.latitude, longitude = EXCLUDED.longitude,
+            elevation = EXCLUDED.elevation, state = EXCLUDED.state, name = EXCLUDED.name
+    "#)?;
+
+    for station in stations {
+        client.execute(&statement, &[
+            &station.station_id, &station.latitude, &station.longitude, &station.elevation, &station.state, &station.name
+        ])?;
+    }
+
+    Ok(())
+}
+
+/// Observation coverage of one element at one station.
+#[derive(Debug)]
+pub struct ElementCoverage {
+    pub element: String,
+    pub observations: i64,
+    pub first_date: Option<NaiveDate>,
+    pub last_date: Option<NaiveDate>
+}
+
+#[derive(Debug)]
+pub struct NearbyStation {
+    pub station_id: String,
+    pub name: String,
+    pub state: String,
+    pub distance_km: f64,
+    pub coverage: Vec<ElementCoverage>
+}
+
+/// Finds the `count` stations in `noaa_stations` closest to the given point, along with how much data
+/// each of them has in the NOAA element tables.
+pub fn find_nearest_stations(latitude: f64, longitude: f64, count: usize, client: &mut postgres::Client) -> Result<Vec<NearbyStation>, String> {
+    let rows = match client.query("SELECT station_id, latitude, longitude, coalesce(state, ''), coalesce(name, '') FROM noaa_stations", &[]) {
+        Ok(r) => { r },
+        Err(e) => { return Err(format!("Failed to read station list, has it been loaded with --backfill-noaa? Error: {}", e)) }
+    };
+
+    let mut stations: Vec<NearbyStation> = rows.iter().map(|row| {
+        NearbyStation {
+            station_id: row.get(0),
+            state: row.get(3),
+            name: row.get(4),
+            distance_km: noaa::haversine_km(latitude, longitude, row.get(1), row.get(2)),
+            coverage: Vec::new()
         }
+    }).collect();
 
-        let table_name = format!("noaa_{}", observation.element).to_owned();
-        let sql = format!(r#"
-            INSERT INTO {table_name} (report_date, station_id, variable_name, value, value_text) VALUES($1, $2, $3, $4, $5)
-            ON CONFLICT ON CONSTRAINT {table_name}_pkeys DO NOTHING
-        "#, table_name=&table_name).to_owned();
+    stations.sort_by(|a, b| a.distance_km.partial_cmp(&b.distance_km).unwrap_or(std::cmp::Ordering::Equal));
+    stations.truncate(count);
 
-        //println!("{}", sql);
-        
-        let statement = client.prepare(&sql).unwrap();
+    let station_ids: Vec<String> = stations.iter().map(|s| s.station_id.to_owned()).collect();
 
-        for (day, data) in observation.observations.iter().enumerate() {
-            // if the value is empty, don't bother with this record
-            let value_string = match data.value.as_ref() {
-                Some(v) => { v.to_string() },
-                None => { continue }
-            };
+    let mut elements: Vec<&str> = SUPPORTED_NOAA_ELEMENTS.iter().cloned().collect();
+    elements.sort_unstable();
 
-            let this_date = NaiveDate::from_ymd(
-                observation.year.try_into().unwrap(),
-                observation.month.try_into().unwrap(),
-                (day + 1).try_into().unwrap()
-            );
-            
-            let measure_string = match data.measure_flag.as_ref() {
-                Some(v) => {v.to_string()},
-                None => {"".to_owned()}
-            };
-            
-            let quality_string = match data.quality_flag.as_ref() {
-                Some(v) => { v.to_string() },
-                None => {"".to_owned()}
-            };
+    for element in elements {
+        let sql = format!(r#"
+            SELECT station_id, COUNT(DISTINCT report_date), MIN(report_date), MAX(report_date) FROM noaa_{}
+            WHERE variable_name IN ('value', 'value_imperial') AND station_id = ANY($1)
+            GROUP BY station_id
+        "#, element);
 
-            let empty_value: Option<f32> = None;
-
-            client.execute(&statement, &[
-                &this_date, &observation.station_id, &"quality_flag".to_owned(), &empty_value, &quality_string
-            ])?;
-            client.execute(&statement, &[
-                &this_date, &observation.station_id, &"source_flag".to_owned(), &empty_value, &data.source_flag
-            ])?;
-            client.execute(&statement, &[
-                &this_date, &observation.station_id, &"measure_flag".to_owned(), &empty_value, &measure_string
-            ])?;
-
-            let value_numeric: Option<f32> = match data.value.as_ref() {
-                Some(v) => {Some(*v as f32)},
-                None => {None}
-            };
+        let rows = match client.query(sql.as_str(), &[&station_ids]) {
+            Ok(r) => { r },
+            Err(e) => { return Err(format!("Failed to read coverage for element {}: {}", element, e)) }
+        };
 
-            client.execute(&statement, &[
-                &this_date, &observation.station_id, &"value".to_owned(), &value_numeric, &value_string
-            ])?;
+        for row in rows {
+            let station_id: String = row.get(0);
+            if let Some(station) = stations.iter_mut().find(|s| s.station_id == station_id) {
+                station.coverage.push(ElementCoverage {
+                    element: element.to_owned(),
+                    observations: row.get(1),
+                    first_date: row.get(2),
+                    last_date: row.get(3)
+                });
+            }
         }
     }
-    Ok(())
-}
\ No newline at end of file
+
+    Ok(stations)
+}
+
+/// Recomputes the derived growing season series in `noaa_season` for the given years from `noaa_tmin`.
+/// Frost is a minimum temperature at or below freezing; the last spring frost is the latest one before July,
+/// the first fall frost the earliest one from July on. Each station-year is keyed on January 1st of the year.
+/// Re-running for a year replaces its values, so this can be called after every insert of new dailies.
+pub fn update_noaa_season(years: &[i32], client: &mut postgres::Client) -> Result<u64, postgres::Error> {
+    client.execute(r#"
+        WITH frost AS (
+            SELECT station_id, report_date FROM noaa_tmin
+            WHERE extract(year FROM report_date)::int = ANY($1)
+            AND ((variable_name = 'value' AND value <= 0) OR (variable_name = 'value_imperial' AND value <= 32))
+        ), seasons AS (
+            SELECT station_id, extract(year FROM report_date)::int AS year,
+                MAX(report_date) FILTER (WHERE extract(month FROM report_date) < 7) AS last_spring_frost,
+                MIN(report_date) FILTER (WHERE extract(month FROM report_date) >= 7) AS first_fall_frost
+            FROM frost
+            GROUP BY 1, 2
+        )
+        INSERT INTO noaa_season (report_date, station_id, variable_name, value, value_text, provenance)
+        SELECT make_date(year, 1, 1), station_id, v.variable_name, v.value, v.value_text, $2::text
+        FROM seasons CROSS JOIN LATERAL (VALUES
+            ('last_spring_frost', extract(doy FROM last_spring_frost)::real, last_spring_frost::text),
+            ('first_fall_frost', extract(doy FROM first_fall_frost)::real, first_fall_frost::text),
+            ('growing_season_days', (first_fall_frost - last_spring_frost - 1)::real, (first_fall_frost - last_spring_frost - 1)::text)
+        ) AS v(variable_name, value, value_text)
+        WHERE v.value IS NOT NULL
+        ON CONFLICT ON CONSTRAINT noaa_season_pkeys DO UPDATE SET value = EXCLUDED.value, value_text = EXCLUDED.value_text,
+            provenance = EXCLUDED.provenance
+    "#, &[&years, &NOAA_SEASON_PROVENANCE])
+}
+
+#[test]
+fn test_noaa_package() {
+    let day = |value: Option<isize>| noaa::DailyObservation { value, measure_flag: None, quality_flag: None, source_flag: "S".to_owned() };
+    let observations = || vec![noaa::Observation {
+        station_id: "USW00014922".to_owned(),
+        year: 2020,
+        month: 2,
+        element: "TMAX".to_owned(),
+        observations: vec![day(Some(-56)), day(None), day(Some(10)), day(Some(12))]
+    }];
+
+    let date = |day| NaiveDate::from_ymd_opt(2020, 2, day).unwrap();
+    let package = noaa_package(observations(), noaa::NoaaUnits::Metric, Some((date(2), date(3))));
+    let days = &package.sections["TMAX"];
+    assert_eq!(days.len(), 1);
+    assert_eq!(days[0].independent, vec!["2020-02-03", "USW00014922"]);
+    assert_eq!(days[0].entries["value"], "10");
+    assert!(!days[0].entries.contains_key("value_imperial"));
+
+    // elements with an imperial equivalent keep only it in imperial units
+    let package = noaa_package(observations(), noaa::NoaaUnits::Imperial, None);
+    assert_eq!(package.sections["TMAX"].len(), 3);
+    assert_eq!(package.sections["TMAX"][0].entries["value_imperial"], "21.92");
+    assert!(!package.sections["TMAX"][0].entries.contains_key("value"));
+}
+
+#[test]
+fn test_insert_noaa_package() {
+    use super::usda::{copy_usda_package, create_table, OnConflict};
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+
+    client.batch_execute("DROP TABLE IF EXISTS noaa_tmax").unwrap();
+    create_table("NOAA_TMAX".to_owned(), &["report_date".to_owned(), "station_id".to_owned()], client).unwrap();
+
+    let day = |value: Option<isize>| noaa::DailyObservation {
+        value,
+        measure_flag: Some(noaa::MeasurementFlag::HourlyPoint),
+        quality_flag: Some(noaa::QualityFlag::Gap),
+        source_flag: "S".to_owned()
+    };
+    let observations = vec![noaa::Observation {
+        station_id: "USW00014922".to_owned(),
+        year: 2020,
+        month: 2,
+        element: "TMAX".to_owned(),
+        observations: vec![day(Some(-56)), day(None), day(Some(10))]
+    }];
+
+    let package = noaa_package(observations, noaa::NoaaUnits::Both, None);
+    copy_usda_package(package, &noaa_structure(), OnConflict::Keep, client).unwrap();
+
+    let rows = client.query("SELECT variable_name, value, provenance FROM noaa_tmax WHERE report_date = '2020-02-01' ORDER BY variable_name", &[]).unwrap();
+    let variables: Vec<String> = rows.iter().map(|r| r.get(0)).collect();
+    assert_eq!(variables, vec!["measure_flag", "quality_flag", "source_flag", "value", "value_imperial"]);
+    assert_eq!(rows[3].get::<_, Option<f32>>(1), Some(-56.0));
+    assert_eq!(rows[4].get::<_, Option<String>>(2), Some(NOAA_IMPERIAL_PROVENANCE.to_owned()));
+
+    assert_eq!(client.query_one("SELECT COUNT(DISTINCT report_date) FROM noaa_tmax", &[]).unwrap().get::<_, i64>(0), 2);
+}
@@ -0,0 +1,457 @@
+// Writing reports as Parquet files (--parquet), for a file-based data lake built straight from the update and
+// backfill commands, either alongside the database or instead of one.
+//
+// Each table is a dataset of its own, partitioned by report year:
+//
+//     <root>/<table>/year=<YYYY>/part-<first date>_<last date>.parquet
+//
+// with the columns of the database table less the run and revision bookkeeping. Files are never appended to: a
+// package covering the same days of a table as an earlier one replaces its file, and the latest date stored is read
+// from the file names. Files are compressed with Snappy unless --compression says otherwise, and each is described
+// by a sidecar, _part-<first date>_<last date>.parquet.json (see export::Sidecar).
+//
+// Extracts (--extract with --extract-format parquet) are written the same way, as a single file.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{Datelike, NaiveDate};
+use parquet::basic::{Compression, ConvertedType, GzipLevel, LogicalType, Repetition, Type as PhysicalType, ZstdLevel};
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, FloatType, Int32Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+
+use crate::export;
+use crate::usda::{USDADataPackage, USDADataPackageSection};
+use crate::usda::datamart::DatamartConfig;
+use crate::usda::esmis::ESMISRelease;
+use super::extract::Extract;
+use super::sink::{Sink, NO_DATE_FOUND};
+use super::usda::{column_name, OnConflict};
+
+lazy_static! {
+    /// How files are compressed, set by --compression
+    static ref COMPRESSION: Mutex<Compression> = Mutex::new(Compression::SNAPPY);
+}
+
+/// Compresses the files written from now on as `compression` says, rather than with Snappy
+pub fn set_compression(compression: export::Compression) {
+    *COMPRESSION.lock().unwrap() = match compression {
+        export::Compression::None => { Compression::UNCOMPRESSED },
+        export::Compression::Gzip => { Compression::GZIP(GzipLevel::default()) },
+        export::Compression::Zstd => { Compression::ZSTD(ZstdLevel::default()) }
+    };
+}
+
+#[derive(Clone)]
+pub struct ParquetSink {
+    root: PathBuf
+}
+
+/// The values of one table's rows, column by column, with `None` for a missing optional value
+#[derive(Default)]
+struct Columns {
+    report_date: Vec<i32>,
+    independent: Vec<Vec<ByteArray>>,
+    variable_name: Vec<ByteArray>,
+    value: Vec<Option<f32>>,
+    value_text: Vec<ByteArray>,
+    source: Vec<Option<ByteArray>>,
+    provenance: Vec<Option<ByteArray>>,
+    parser_version: Vec<Option<i32>>
+}
+
+impl ParquetSink {
+    /// Writes datasets under `root`, which is created if need be
+    pub fn new(root: &Path) -> Result<ParquetSink, String> {
+        fs::create_dir_all(root).map_err(|e| format!("Failed to create Parquet directory {}: {}", root.display(), e))?;
+        Ok(ParquetSink { root: root.to_owned() })
+    }
+
+    /// Writes every section of a package to its dataset, returning the number of rows written
+    pub fn write_package(&self, package: &USDADataPackage, structure: &DatamartConfig) -> Result<u64, String> {
+        let mut written = 0;
+
+        for (section, results) in &package.sections {
+            let table_name = structure.table_name(section);
+
+            let independent = &structure.sections[section].independent[1..];
+
+            let mut by_year: BTreeMap<i32, Vec<&USDADataPackageSection>> = BTreeMap::new();
+            for row in results {
+                by_year.entry(row.report_date.year()).or_default().push(row);
+            }
+
+            for (year, rows) in by_year {
+                written += self.write_partition(&table_name, section, year, independent, &rows, package)
+                    .map_err(|e| format!("Failed to write {} to Parquet: {}", table_name, e))?;
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn write_partition(&self, table_name: &str, section: &str, year: i32, independent: &[String], rows: &[&USDADataPackageSection], package: &USDADataPackage) -> Result<u64, String> {
+        let first = rows.iter().map(|r| r.report_date).min().unwrap();
+        let last = rows.iter().map(|r| r.report_date).max().unwrap();
+        let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+
+        let mut columns = Columns { independent: vec![Vec::new(); independent.len()], ..Default::default() };
+
+        // in a fixed order, so that writing the same rows again gives the same file
+        let mut entries: Vec<(&USDADataPackageSection, &String, &String)> = rows.iter()
+            .flat_map(|row| row.entries.iter().filter(|(_, v)| !v.is_empty()).map(move |(key, value)| (*row, key, value)))
+            .collect();
+        entries.sort_by(|(a, a_key, _), (b, b_key, _)| (a.report_date, &a.independent, a_key).cmp(&(b.report_date, &b.independent, b_key)));
+
+        for (row, key, value) in entries {
+            columns.report_date.push((row.report_date - epoch).num_days() as i32);
+            for (index, column) in columns.independent.iter_mut().enumerate() {
+                column.push(row.independent.get(index + 1).map(String::as_str).unwrap_or_default().into());
+            }
+            columns.variable_name.push(key.as_str().into());
+            columns.value.push(value.replace(",", "").parse::<f32>().ok());
+            columns.value_text.push(value.as_str().into());
+            columns.source.push(package.source.as_deref().map(ByteArray::from));
+            columns.provenance.push(row.provenance.get(key).map(|p| p.as_str().into()));
+            columns.parser_version.push(package.parser_version.map(|v| v as i32));
+        }
+
+        if columns.report_date.is_empty() {
+            return Ok(0);
+        }
+
+        let directory = self.root.join(table_name).join(format!("year={}", year));
+        fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+
+        // written aside and moved into place, so that readers never see a partial file
+        let path = directory.join(format!("part-{}_{}.parquet", first.format("%Y-%m-%d"), last.format("%Y-%m-%d")));
+        let partial = path.with_extension("parquet.partial");
+
+        write_file(&partial, independent, &columns).map_err(|e| e.to_string())?;
+        let schema = fields(independent).iter().map(|field| export::SchemaColumn::new(field.name(), type_name(field))).collect();
+        export::Sidecar::new(&path, &package.name, Some(section), Some((first, last)), columns.report_date.len() as u64, schema)
+            .with_checksum(&partial)?
+            .write(&path)?;
+        fs::rename(&partial, &path).map_err(|e| e.to_string())?;
+
+        Ok(columns.report_date.len() as u64)
+    }
+}
+
+fn column(name: &str, physical: PhysicalType, repetition: Repetition, logical: Option<LogicalType>) -> Arc<Type> {
+    let converted = match logical {
+        Some(LogicalType::String) => { ConvertedType::UTF8 },
+        Some(LogicalType::Date) => { ConvertedType::DATE },
+        _ => { ConvertedType::NONE }
+    };
+
+    Arc::new(Type::primitive_type_builder(name, physical)
+        .with_repetition(repetition)
+        .with_logical_type(logical)
+        .with_converted_type(converted)
+        .build()
+        .unwrap())
+}
+
+/// Definition levels of an optional column, with the values present
+fn present<T: Clone>(values: &[Option<T>]) -> (Vec<T>, Vec<i16>) {
+    let levels = values.iter().map(|v| if v.is_some() { 1 } else { 0 }).collect();
+    (values.iter().flatten().cloned().collect(), levels)
+}
+
+/// The columns of a file of a table with these independent columns after report_date
+fn fields(independent: &[String]) -> Vec<Arc<Type>> {
+    let mut fields = vec![column("report_date", PhysicalType::INT32, Repetition::REQUIRED, Some(LogicalType::Date))];
+    for name in independent {
+        fields.push(column(&column_name(name), PhysicalType::BYTE_ARRAY, Repetition::REQUIRED, Some(LogicalType::String)));
+    }
+    fields.push(column("variable_name", PhysicalType::BYTE_ARRAY, Repetition::REQUIRED, Some(LogicalType::String)));
+    fields.push(column("value", PhysicalType::FLOAT, Repetition::OPTIONAL, None));
+    fields.push(column("value_text", PhysicalType::BYTE_ARRAY, Repetition::REQUIRED, Some(LogicalType::String)));
+    fields.push(column("source", PhysicalType::BYTE_ARRAY, Repetition::OPTIONAL, Some(LogicalType::String)));
+    fields.push(column("provenance", PhysicalType::BYTE_ARRAY, Repetition::OPTIONAL, Some(LogicalType::String)));
+    fields.push(column("parser_version", PhysicalType::INT32, Repetition::OPTIONAL, None));
+    fields
+}
+
+/// The type of a column as a sidecar names it
+fn type_name(field: &Type) -> &'static str {
+    match (field.get_basic_info().logical_type(), field.get_physical_type()) {
+        (Some(LogicalType::Date), _) => { "date" },
+        (Some(LogicalType::String), _) => { "string" },
+        (_, PhysicalType::FLOAT) => { "float" },
+        (_, PhysicalType::DOUBLE) => { "double" },
+        (_, PhysicalType::INT32) => { "int32" },
+        _ => { "binary" }
+    }
+}
+
+fn write_file(path: &Path, independent: &[String], columns: &Columns) -> Result<(), parquet::errors::ParquetError> {
+    let schema = Arc::new(Type::group_type_builder("schema").with_fields(fields(independent)).build()?);
+    let properties = Arc::new(WriterProperties::builder().set_compression(*COMPRESSION.lock().unwrap()).build());
+
+    let mut writer = SerializedFileWriter::new(File::create(path)?, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+
+    while let Some(mut column_writer) = row_group.next_column()? {
+        let independent_count = columns.independent.len();
+
+        match index {
+            0 => { column_writer.typed::<Int32Type>().write_batch(&columns.report_date, None, None)?; },
+            i if i <= independent_count => { column_writer.typed::<ByteArrayType>().write_batch(&columns.independent[i - 1], None, None)?; },
+            i => {
+                match i - independent_count {
+                    1 => { column_writer.typed::<ByteArrayType>().write_batch(&columns.variable_name, None, None)?; },
+                    2 => {
+                        let (values, levels) = present(&columns.value);
+                        column_writer.typed::<FloatType>().write_batch(&values, Some(&levels), None)?;
+                    },
+                    3 => { column_writer.typed::<ByteArrayType>().write_batch(&columns.value_text, None, None)?; },
+                    4 => {
+                        let (values, levels) = present(&columns.source);
+                        column_writer.typed::<ByteArrayType>().write_batch(&values, Some(&levels), None)?;
+                    },
+                    5 => {
+                        let (values, levels) = present(&columns.provenance);
+                        column_writer.typed::<ByteArrayType>().write_batch(&values, Some(&levels), None)?;
+                    },
+                    _ => {
+                        let (values, levels) = present(&columns.parser_version);
+                        column_writer.typed::<Int32Type>().write_batch(&values, Some(&levels), None)?;
+                    }
+                }
+            }
+        }
+
+        column_writer.close()?;
+        index += 1;
+    }
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Writes an extract to `path`, the period followed by a column of each series, with a sidecar describing it as
+/// holding the extract `name`. Returns the number of rows written.
+pub fn write_extract(path: &Path, name: &str, extract: &Extract) -> Result<usize, String> {
+    let mut fields = vec![column("period", PhysicalType::INT32, Repetition::REQUIRED, Some(LogicalType::Date))];
+    fields.extend(extract.columns.iter().map(|c| column(c, PhysicalType::DOUBLE, Repetition::OPTIONAL, None)));
+    let schema = fields.iter().map(|field| export::SchemaColumn::new(field.name(), type_name(field))).collect();
+
+    // written aside and moved into place, as the files of datasets are
+    let partial = path.with_extension("parquet.partial");
+    write_extract_file(&partial, fields, extract).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    let dates = extract.rows.first().zip(extract.rows.last()).map(|((first, _), (last, _))| (*first, *last));
+    export::Sidecar::new(path, name, None, dates, extract.rows.len() as u64, schema)
+        .with_checksum(&partial)?
+        .write(path)?;
+    fs::rename(&partial, path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+
+    Ok(extract.rows.len())
+}
+
+fn write_extract_file(path: &Path, fields: Vec<Arc<Type>>, extract: &Extract) -> Result<(), parquet::errors::ParquetError> {
+    let schema = Arc::new(Type::group_type_builder("schema").with_fields(fields).build()?);
+    let properties = Arc::new(WriterProperties::builder().set_compression(*COMPRESSION.lock().unwrap()).build());
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+
+    let mut writer = SerializedFileWriter::new(File::create(path)?, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+    let mut index = 0;
+
+    while let Some(mut column_writer) = row_group.next_column()? {
+        if index == 0 {
+            let periods: Vec<i32> = extract.rows.iter().map(|(period, _)| (*period - epoch).num_days() as i32).collect();
+            column_writer.typed::<Int32Type>().write_batch(&periods, None, None)?;
+        } else {
+            let values: Vec<Option<f64>> = extract.rows.iter().map(|(_, values)| values[index - 1]).collect();
+            let (values, levels) = present(&values);
+            column_writer.typed::<DoubleType>().write_batch(&values, Some(&levels), None)?;
+        }
+
+        column_writer.close()?;
+        index += 1;
+    }
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+/// The last date in a file name of the form part-<first date>_<last date>.parquet
+fn last_date(file_name: &str) -> Option<NaiveDate> {
+    let dates = file_name.strip_prefix("part-")?.strip_suffix(".parquet")?;
+    let (_, last) = dates.split_once('_')?;
+    NaiveDate::parse_from_str(last, "%Y-%m-%d").ok()
+}
+
+impl Sink for ParquetSink {
+    fn create_table(&mut self, name: &str, _independent: &[String]) -> Result<(), String> {
+        let directory = self.root.join(name);
+        fs::create_dir_all(&directory).map_err(|e| format!("Failed to create dataset {}: {}", directory.display(), e))
+    }
+
+    fn insert_package(&mut self, package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict) -> Result<u64, String> {
+        // a package replaces the files of the days it covers, so values are always updated
+        if on_conflict.for_report(structure) == OnConflict::TrackRevisions {
+            return Err("Tracking revisions requires PostgreSQL".to_owned());
+        }
+
+        self.write_package(&package, structure)
+    }
+
+    fn max_date(&mut self, config: &DatamartConfig) -> Result<NaiveDate, String> {
+        let mut maximum: Option<NaiveDate> = None;
+
+        for section in config.sections.keys() {
+            let table_name = config.table_name(section);
+
+            let partitions = match fs::read_dir(self.root.join(&table_name)) {
+                Ok(p) => { p },
+                Err(_) => { continue }
+            };
+
+            for partition in partitions.flatten() {
+                for file in fs::read_dir(partition.path()).into_iter().flatten().flatten() {
+                    maximum = maximum.max(file.file_name().to_str().and_then(last_date));
+                }
+            }
+        }
+
+        maximum.ok_or_else(|| NO_DATE_FOUND.to_owned())
+    }
+}
+
+/// Another sink whose packages are also written to Parquet files, for `--parquet` alongside a database. Its tables
+/// and dates are those of the database.
+pub struct WithParquet<S: Sink> {
+    pub sink: S,
+    pub parquet: ParquetSink
+}
+
+impl<S: Sink> Sink for WithParquet<S> {
+    fn create_table(&mut self, name: &str, independent: &[String]) -> Result<(), String> {
+        self.parquet.create_table(name, independent)?;
+        self.sink.create_table(name, independent)
+    }
+
+    fn insert_package(&mut self, package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict) -> Result<u64, String> {
+        self.parquet.write_package(&package, structure)?;
+        self.sink.insert_package(package, structure, on_conflict)
+    }
+
+    fn max_date(&mut self, config: &DatamartConfig) -> Result<NaiveDate, String> {
+        self.sink.max_date(config)
+    }
+
+    fn record_releases(&mut self, releases: &[ESMISRelease]) -> Result<std::collections::HashSet<String>, String> {
+        self.sink.record_releases(releases)
+    }
+
+    fn mark_processed(&mut self, ids: &[String]) -> Result<(), String> {
+        self.sink.mark_processed(ids)
+    }
+}
+
+#[test]
+fn test_parquet_sink() {
+    use super::usda::{test_package, test_structure};
+
+    let root = std::env::temp_dir().join(format!("test_parquet_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let mut sink = ParquetSink::new(&root).unwrap();
+    let structure = test_structure("test_parquet");
+    let date = |year, day| NaiveDate::from_ymd_opt(year, 3, day).unwrap();
+
+    sink.create_schema(&structure).unwrap();
+    assert!(sink.max_date(&structure).is_err());
+
+    assert_eq!(sink.insert_package(test_package("test_parquet", date(2019, 2), "Dodge City", "5.41"), &structure, OnConflict::Keep), Ok(1));
+    assert_eq!(sink.insert_package(test_package("test_parquet", date(2020, 9), "Dodge City", "5.45"), &structure, OnConflict::Keep), Ok(1));
+    assert!(sink.insert_package(test_package("test_parquet", date(2020, 9), "Dodge City", "5.45"), &structure, OnConflict::TrackRevisions).is_err());
+
+    assert!(root.join("test_parquet_bids/year=2019/part-2019-03-02_2019-03-02.parquet").is_file());
+    let sidecar = fs::read_to_string(root.join("test_parquet_bids/year=2019/_part-2019-03-02_2019-03-02.parquet.json")).unwrap();
+    assert!(sidecar.contains("\"section\": \"bids\"") && sidecar.contains("\"name\": \"region\",\n      \"type\": \"string\""));
+    assert_eq!(sink.max_date(&structure), Ok(date(2020, 9)));
+
+    // the file can be read back
+    let file = File::open(root.join("test_parquet_bids/year=2020/part-2020-03-09_2020-03-09.parquet")).unwrap();
+    let reader = parquet::file::serialized_reader::SerializedFileReader::new(file).unwrap();
+    assert_eq!(parquet::file::reader::FileReader::metadata(&reader).file_metadata().num_rows(), 1);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_write_extract() {
+    use parquet::file::reader::FileReader;
+    use parquet::file::serialized_reader::SerializedFileReader;
+
+    let root = std::env::temp_dir().join(format!("test_parquet_extract_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    let date = |day| NaiveDate::from_ymd_opt(2020, 3, day).unwrap();
+    let extract = Extract {
+        columns: vec!["dodge_city".to_owned(), "bids".to_owned()],
+        rows: vec![(date(2), vec![Some(5.5), Some(3.0)]), (date(9), vec![None, Some(1.0)])]
+    };
+
+    let path = root.join("cutout.parquet");
+    assert_eq!(write_extract(&path, "cutout", &extract), Ok(2));
+
+    let sidecar = fs::read_to_string(root.join("_cutout.parquet.json")).unwrap();
+    assert!(sidecar.contains("\"report\": \"cutout\"") && sidecar.contains("\"last_date\": \"2020-03-09\""));
+    assert!(sidecar.contains("\"name\": \"dodge_city\",\n      \"type\": \"double\""));
+
+    let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+    let rows: Vec<String> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap().to_string()).collect();
+    assert_eq!(rows, vec!["{period: 2020-03-02, dodge_city: 5.5, bids: 3.0}", "{period: 2020-03-09, dodge_city: null, bids: 1.0}"]);
+
+    fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_deterministic_export() {
+    use super::usda::{test_package, test_structure};
+
+    let root = std::env::temp_dir().join(format!("test_parquet_order_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+
+    let sink = ParquetSink::new(&root).unwrap();
+    let structure = test_structure("test_order");
+    let date = |day| NaiveDate::from_ymd_opt(2020, 3, day).unwrap();
+
+    // the same rows, arriving in different orders
+    let mut package = test_package("test_order", date(2), "Dodge City", "5.41");
+    let mut rows = vec![];
+    for (day, region) in &[(9, "Colby"), (2, "Dodge City"), (2, "Colby"), (9, "Dodge City")] {
+        rows.extend(test_package("test_order", date(*day), region, "5.45").sections.remove("bids").unwrap());
+    }
+    package.sections.insert("bids".to_owned(), rows);
+
+    let path = root.join("test_order_bids/year=2020/part-2020-03-02_2020-03-09.parquet");
+    let sidecar = root.join("test_order_bids/year=2020/_part-2020-03-02_2020-03-09.parquet.json");
+    let checksum = |path: &Path| serde_json::from_str::<serde_json::Value>(&fs::read_to_string(path).unwrap()).unwrap()["sha256"].clone();
+
+    sink.write_package(&package, &structure).unwrap();
+    let (first, first_checksum) = (fs::read(&path).unwrap(), checksum(&sidecar));
+
+    package.sections.get_mut("bids").unwrap().reverse();
+    sink.write_package(&package, &structure).unwrap();
+
+    assert_eq!(fs::read(&path).unwrap(), first);
+    assert_eq!(checksum(&sidecar), first_checksum);
+    assert!(first_checksum.is_string());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,198 @@
+// Ingestion runs, so that the rows from a bad run (a mis-parsed batch, a wrong configuration) can be removed with
+// one command.
+//
+// Each invocation gets a run ID, recorded in ingestion_log along with its arguments. Pooled connections carry the
+// ID in a session setting, and tables made by `usda::create_table` default their run_id column to it, so every
+// insert path tags its rows without having to know about runs. Rows from before run tracking have no run ID.
+//
+// The log also records what produced a run's rows: the tool's version, the commit it was built from, and a hash of
+// the configuration it ran with, so that odd data can be traced to the revision of either.
+
+use std::fs;
+use std::path::Path;
+
+use postgres::GenericClient;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::overrides::Override;
+
+/// The session setting holding the current run ID
+pub const RUN_SETTING: &str = "data_acquisition.run_id";
+
+/// The default for a table's run_id column
+pub fn run_id_default() -> String {
+    format!("NULLIF(current_setting('{}', true), '')::uuid", RUN_SETTING)
+}
+
+pub fn new_run_id() -> Uuid {
+    Uuid::new_v4()
+}
+
+/// What a run was made by: the tool's version, the commit it was built from, and a hash of its configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunStamp {
+    pub tool_version: String,
+    pub git_hash: String,
+    pub config_hash: String
+}
+
+impl RunStamp {
+    pub fn new(config_hash: String) -> RunStamp {
+        RunStamp {
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            git_hash: env!("GIT_HASH").to_owned(),
+            config_hash
+        }
+    }
+}
+
+/// A hash of the configuration files a run reads, by name, and the --set overrides of them. A missing file counts as
+/// an empty one. Overrides of the secret configuration are left out, as changing a password changes nothing stored.
+pub fn config_hash(files: &[(&str, &Path)], overrides: &[Override]) -> String {
+    let mut hasher = Sha256::new();
+
+    for (name, path) in files {
+        hasher.update(format!("{}\n", name).as_bytes());
+        hasher.update(fs::read(path).unwrap_or_default());
+        hasher.update(b"\0");
+    }
+
+    for o in overrides.iter().filter(|o| o.file != "secret") {
+        hasher.update(format!("{}.{}={}\n", o.file, o.path.join("."), o.value).as_bytes());
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+/// Tags the rows a connection inserts with `run_id`
+pub fn tag_connection<C: GenericClient>(run_id: Uuid, client: &mut C) -> Result<(), postgres::Error> {
+    client.batch_execute(&format!("SET {} = '{}'", RUN_SETTING, run_id))
+}
+
+/// Applied to every connection the pool opens
+#[derive(Debug)]
+pub struct RunTagger(pub Uuid);
+
+impl r2d2::CustomizeConnection<postgres::Client, postgres::Error> for RunTagger {
+    fn on_acquire(&self, client: &mut postgres::Client) -> Result<(), postgres::Error> {
+        tag_connection(self.0, client)
+    }
+}
+
+pub fn create_log_table(client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    client.batch_execute(r#"
+        CREATE TABLE IF NOT EXISTS ingestion_log (
+            run_id uuid primary key,
+            started_at timestamptz not null default now(),
+            finished_at timestamptz,
+            arguments text not null,
+            rolled_back_at timestamptz
+        );
+        ALTER TABLE ingestion_log ADD COLUMN IF NOT EXISTS tool_version text;
+        ALTER TABLE ingestion_log ADD COLUMN IF NOT EXISTS git_hash text;
+        ALTER TABLE ingestion_log ADD COLUMN IF NOT EXISTS config_hash text;
+    "#)
+}
+
+pub fn start_run(run_id: Uuid, arguments: &str, stamp: &RunStamp, client: &mut postgres::Client) -> Result<(), String> {
+    create_log_table(client).map_err(|e| format!("Failed to create ingestion_log: {}", e))?;
+    client.execute(
+        "INSERT INTO ingestion_log (run_id, arguments, tool_version, git_hash, config_hash) VALUES ($1, $2, $3, $4, $5)",
+        &[&run_id, &arguments, &stamp.tool_version, &stamp.git_hash, &stamp.config_hash]
+    ).map_err(|e| format!("Failed to record run {}: {}", run_id, e))?;
+    Ok(())
+}
+
+pub fn finish_run(run_id: Uuid, client: &mut postgres::Client) -> Result<(), String> {
+    client.execute("UPDATE ingestion_log SET finished_at = now() WHERE run_id = $1", &[&run_id])
+        .map_err(|e| format!("Failed to record the end of run {}: {}", run_id, e))?;
+    Ok(())
+}
+
+/// Tables with a run_id column, i.e. those made or migrated by `usda::create_table` since runs were tracked
+fn tagged_tables(client: &mut postgres::Client) -> Result<Vec<String>, String> {
+    let rows = client.query(
+        "SELECT table_name FROM information_schema.columns WHERE table_schema = current_schema() AND column_name = 'run_id' \
+         AND table_name <> 'ingestion_log' ORDER BY table_name",
+        &[]
+    ).map_err(|e| format!("Failed to list tables: {}", e))?;
+
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Deletes every row inserted by a run, in one transaction. Gives the rows deleted per table.
+pub fn rollback_run(run_id: Uuid, client: &mut postgres::Client) -> Result<Vec<(String, u64)>, String> {
+    let tables = tagged_tables(client)?;
+    let mut transaction = client.transaction().map_err(|e| e.to_string())?;
+    let mut deleted = Vec::new();
+
+    for table in tables {
+        let count = transaction.execute(format!("DELETE FROM {} WHERE run_id = $1", table).as_str(), &[&run_id])
+            .map_err(|e| format!("Failed to delete run {} from {}: {}", run_id, table, e))?;
+
+        if count > 0 {
+            deleted.push((table, count));
+        }
+    }
+
+    // runs from before the log existed can still be rolled back
+    if transaction.query_one("SELECT to_regclass('ingestion_log') IS NOT NULL", &[]).map_err(|e| e.to_string())?.get(0) {
+        transaction.execute("UPDATE ingestion_log SET rolled_back_at = now() WHERE run_id = $1", &[&run_id]).map_err(|e| e.to_string())?;
+    }
+
+    transaction.commit().map_err(|e| format!("Failed to roll back run {}: {}", run_id, e))?;
+    Ok(deleted)
+}
+
+#[test]
+fn test_rollback_run() {
+    use chrono::NaiveDate;
+    use super::usda::{create_table, insert_usda_package, test_package, test_structure, OnConflict};
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_rollback");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_rollback_bids").unwrap();
+    create_table("test_rollback_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    // a row from before run tracking
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    insert_usda_package(test_package("test_rollback", report_date, "Colby", "3.50"), &structure, OnConflict::Keep, client).unwrap();
+
+    let (good, bad) = (new_run_id(), new_run_id());
+    let stamp = RunStamp::new("0123abcd".to_owned());
+    start_run(good, "--update", &stamp, client).unwrap();
+    tag_connection(good, client).unwrap();
+    insert_usda_package(test_package("test_rollback", report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+
+    start_run(bad, "--update", &stamp, client).unwrap();
+    tag_connection(bad, client).unwrap();
+    insert_usda_package(test_package("test_rollback", report_date, "Garden City", "541"), &structure, OnConflict::Keep, client).unwrap();
+    finish_run(bad, client).unwrap();
+
+    assert_eq!(rollback_run(bad, client).unwrap(), vec![("test_rollback_bids".to_owned(), 1)]);
+
+    let rows = client.query("SELECT region FROM test_rollback_bids ORDER BY region", &[]).unwrap();
+    assert_eq!(rows.iter().map(|r| r.get::<_, String>(0)).collect::<Vec<String>>(), vec!["Colby", "Dodge City"]);
+
+    let rolled_back: Option<chrono::DateTime<chrono::Utc>> = client.query_one("SELECT rolled_back_at FROM ingestion_log WHERE run_id = $1", &[&bad]).unwrap().get(0);
+    assert!(rolled_back.is_some());
+
+    let row = client.query_one("SELECT tool_version, config_hash FROM ingestion_log WHERE run_id = $1", &[&good]).unwrap();
+    assert_eq!((row.get::<_, String>(0), row.get::<_, String>(1)), (env!("CARGO_PKG_VERSION").to_owned(), "0123abcd".to_owned()));
+}
+
+#[test]
+fn test_config_hash() {
+    let files = [("datamart", Path::new("config/datamart.toml")), ("network", Path::new("config/missing.toml"))];
+    let overrides = |texts: &[&str]| -> Vec<Override> { texts.iter().map(|t| crate::overrides::parse_override(t).unwrap()).collect() };
+
+    let hash = config_hash(&files, &[]);
+    assert_eq!(hash.len(), 64);
+    assert_eq!(config_hash(&files, &[]), hash);
+    assert_eq!(config_hash(&files, &overrides(&["secret.postgres.password=\"hunter2\""])), hash);
+    assert_ne!(config_hash(&files, &overrides(&["datamart.2466.on_conflict=\"update\""])), hash);
+    assert_ne!(config_hash(&files[..1], &[]), hash);
+}
@@ -0,0 +1,189 @@
+// Where parsed packages are written: the operations that fetching and parsing need of a database, so that other
+// backends (see duckdb) and test doubles can stand in for PostgreSQL. Everything beyond them (runs, --diff,
+// extracts, NOAA stations) works with PostgreSQL only.
+
+use std::collections::HashSet;
+
+use chrono::NaiveDate;
+
+use crate::usda::USDADataPackage;
+use crate::usda::datamart::{DatamartConfig, Period};
+use crate::usda::esmis::ESMISRelease;
+use super::indexes::report_indexes;
+use super::pool::Connection;
+use super::usda::{add_period_columns, copy_usda_package, create_table, find_maximum_existing_datamart_date, OnConflict};
+
+/// The error of `Sink::max_date` for a report none of whose tables have rows yet
+pub const NO_DATE_FOUND: &str = "No date found";
+
+pub trait Sink: Send {
+    /// Creates a report table with the usual columns if it doesn't exist yet
+    fn create_table(&mut self, name: &str, independent: &[String]) -> Result<(), String>;
+
+    /// Writes a package in one transaction, returning the number of new and revised rows
+    fn insert_package(&mut self, package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict) -> Result<u64, String>;
+
+    /// The latest report date stored for any section of a report, failing with NO_DATE_FOUND if there is none
+    fn max_date(&mut self, config: &DatamartConfig) -> Result<NaiveDate, String>;
+
+    /// Creates secondary indexes on a report table if they don't exist yet. Sinks without indexes ignore them.
+    fn create_indexes(&mut self, _name: &str, _indexes: &[Vec<String>]) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Adds the period columns to the table of a report whose dates stand for a period. Sinks without them leave
+    /// report_date alone.
+    fn create_period_columns(&mut self, _name: &str, _period: Period) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Creates the table of every section of a report with its indexes, stopping at the first that fails
+    fn create_schema(&mut self, config: &DatamartConfig) -> Result<(), String> {
+        let indexes = report_indexes(config);
+
+        for (section, section_config) in &config.sections {
+            let table_name = config.table_name(section);
+
+            self.create_table(&table_name, &section_config.independent)?;
+            if let Some(period) = config.period {
+                self.create_period_columns(&table_name, period)?;
+            }
+            self.create_indexes(&table_name, &indexes)?;
+        }
+
+        Ok(())
+    }
+
+    /// Keeps the metadata of ESMIS releases found, giving the IDs of those already processed. A sink without a
+    /// release table leaves every release to be processed.
+    fn record_releases(&mut self, _releases: &[ESMISRelease]) -> Result<HashSet<String>, String> {
+        Ok(HashSet::new())
+    }
+
+    fn mark_processed(&mut self, _ids: &[String]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl Sink for postgres::Client {
+    fn create_table(&mut self, name: &str, independent: &[String]) -> Result<(), String> {
+        create_table(name.to_owned(), independent, self).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn create_indexes(&mut self, name: &str, indexes: &[Vec<String>]) -> Result<(), String> {
+        super::indexes::create_indexes(name, indexes, self)
+    }
+
+    fn create_period_columns(&mut self, name: &str, period: Period) -> Result<(), String> {
+        add_period_columns(name, period, self).map_err(|e| e.to_string())
+    }
+
+    fn insert_package(&mut self, package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict) -> Result<u64, String> {
+        copy_usda_package(package, structure, on_conflict, self)
+    }
+
+    fn max_date(&mut self, config: &DatamartConfig) -> Result<NaiveDate, String> {
+        find_maximum_existing_datamart_date(config, self)
+    }
+
+    fn record_releases(&mut self, releases: &[ESMISRelease]) -> Result<HashSet<String>, String> {
+        super::esmis::record_releases(releases, self)
+    }
+
+    fn mark_processed(&mut self, ids: &[String]) -> Result<(), String> {
+        super::esmis::mark_processed(ids, self)
+    }
+}
+
+impl Sink for Connection {
+    fn create_table(&mut self, name: &str, independent: &[String]) -> Result<(), String> {
+        (**self).create_table(name, independent)
+    }
+
+    fn create_indexes(&mut self, name: &str, indexes: &[Vec<String>]) -> Result<(), String> {
+        (**self).create_indexes(name, indexes)
+    }
+
+    fn create_period_columns(&mut self, name: &str, period: Period) -> Result<(), String> {
+        (**self).create_period_columns(name, period)
+    }
+
+    fn insert_package(&mut self, package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict) -> Result<u64, String> {
+        (**self).insert_package(package, structure, on_conflict)
+    }
+
+    fn max_date(&mut self, config: &DatamartConfig) -> Result<NaiveDate, String> {
+        (**self).max_date(config)
+    }
+
+    fn record_releases(&mut self, releases: &[ESMISRelease]) -> Result<HashSet<String>, String> {
+        (**self).record_releases(releases)
+    }
+
+    fn mark_processed(&mut self, ids: &[String]) -> Result<(), String> {
+        (**self).mark_processed(ids)
+    }
+}
+
+/// Rows kept by a `MemorySink`: report date, variable and value, by table
+#[cfg(test)]
+type MemoryTables = std::collections::BTreeMap<String, Vec<(NaiveDate, String, String)>>;
+
+/// A sink keeping packages in memory, for tests of the paths that write them. Clones share their tables.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    pub tables: std::sync::Arc<std::sync::Mutex<MemoryTables>>
+}
+
+#[cfg(test)]
+impl Sink for MemorySink {
+    fn create_table(&mut self, name: &str, _independent: &[String]) -> Result<(), String> {
+        self.tables.lock().unwrap().entry(name.to_owned()).or_default();
+        Ok(())
+    }
+
+    fn insert_package(&mut self, package: USDADataPackage, structure: &DatamartConfig, _on_conflict: OnConflict) -> Result<u64, String> {
+        let mut tables = self.tables.lock().unwrap();
+        let mut inserted = 0;
+
+        for (section, results) in package.sections {
+            let table_name = structure.table_name(&section);
+
+            let rows = tables.get_mut(&table_name).ok_or_else(|| format!("No table {}", table_name))?;
+            for row in results {
+                for (variable, value) in row.entries.into_iter().filter(|(_, v)| !v.is_empty()) {
+                    rows.push((row.report_date, variable, value));
+                    inserted += 1;
+                }
+            }
+        }
+
+        Ok(inserted)
+    }
+
+    fn max_date(&mut self, config: &DatamartConfig) -> Result<NaiveDate, String> {
+        let tables = self.tables.lock().unwrap();
+
+        config.sections.keys()
+            .filter_map(|section| tables.get(&config.table_name(section)))
+            .flat_map(|rows| rows.iter().map(|(date, _, _)| *date))
+            .max()
+            .ok_or_else(|| NO_DATE_FOUND.to_owned())
+    }
+}
+
+#[test]
+fn test_memory_sink() {
+    use super::usda::{test_package, test_structure};
+
+    let structure = test_structure("test_sink");
+    let mut sink = MemorySink::default();
+    let date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+
+    assert!(sink.insert_package(test_package("test_sink", date, "Colby", "3.50"), &structure, OnConflict::Keep).is_err());
+
+    sink.create_schema(&structure).unwrap();
+    assert_eq!(sink.insert_package(test_package("test_sink", date, "Colby", "3.50"), &structure, OnConflict::Keep), Ok(1));
+    assert_eq!(sink.max_date(&structure), Ok(date));
+}
@@ -0,0 +1,104 @@
+// Files that --backfill-text passed over: those of reports without a configuration, and those that couldn't be read
+// or parsed. The walk carries on past them and summarizes them at the end, and with --skipped-table they are also
+// kept in a table, so that a long backfill can be followed up on later.
+
+use std::collections::BTreeMap;
+
+/// The reason given for files of reports without a configuration
+pub const UNKNOWN_REPORT: &str = "unknown report";
+
+pub struct SkippedFile {
+    pub path: String,
+    pub identifier: String,   // the report directory the file was found in
+    pub reason: String
+}
+
+pub fn create_skipped_table(table_name: &str, client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    client.batch_execute(&format!(r#"
+        CREATE TABLE IF NOT EXISTS {} (
+            path text not null,
+            identifier text not null,
+            reason text not null,
+            run_id uuid default {},
+            recorded_at timestamptz not null default now()
+        );
+    "#, table_name, super::runs::run_id_default()))
+}
+
+/// Records skipped files in `table_name`, created if need be
+pub fn record_skipped(table_name: &str, files: &[SkippedFile], client: &mut postgres::Client) -> Result<(), String> {
+    create_skipped_table(table_name, client).map_err(|e| format!("Failed to create {}: {}", table_name, e))?;
+
+    let mut transaction = client.transaction().map_err(|e| e.to_string())?;
+    let statement = transaction.prepare(&format!("INSERT INTO {} (path, identifier, reason) VALUES ($1, $2, $3)", table_name))
+        .map_err(|e| e.to_string())?;
+
+    for file in files {
+        transaction.execute(&statement, &[&file.path, &file.identifier, &file.reason])
+            .map_err(|e| format!("Failed to record skipped file {}: {}", file.path, e))?;
+    }
+
+    transaction.commit().map_err(|e| format!("Failed to record skipped files in {}: {}", table_name, e))
+}
+
+/// A summary of skipped files for the end of a backfill: unknown reports with their file counts, then every file
+/// that failed to be read or parsed
+pub fn summarize(files: &[SkippedFile]) -> String {
+    let mut unknown: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut failed: Vec<&SkippedFile> = Vec::new();
+
+    for file in files {
+        if file.reason == UNKNOWN_REPORT {
+            *unknown.entry(file.identifier.as_str()).or_default() += 1;
+        } else {
+            failed.push(file);
+        }
+    }
+
+    let mut summary = format!("Skipped {} files.", files.len());
+
+    if !unknown.is_empty() {
+        summary.push_str("\nReports without a configuration:");
+        for (identifier, count) in unknown {
+            summary.push_str(&format!("\n  {} ({} files)", identifier, count));
+        }
+    }
+
+    if !failed.is_empty() {
+        summary.push_str("\nFiles that failed:");
+        for file in failed {
+            summary.push_str(&format!("\n  {}: {}", file.path, file.reason));
+        }
+    }
+
+    summary
+}
+
+#[test]
+fn test_summarize() {
+    let skipped = |path: &str, identifier: &str, reason: &str| SkippedFile { path: path.to_owned(), identifier: identifier.to_owned(), reason: reason.to_owned() };
+    let files = vec![
+        skipped("text/XX_YY1/a.txt", "XX_YY1", UNKNOWN_REPORT),
+        skipped("text/XX_YY1/b.txt", "XX_YY1", UNKNOWN_REPORT),
+        skipped("text/LM_XB463/c.txt", "LM_XB463", "No report date found")
+    ];
+
+    assert_eq!(
+        summarize(&files),
+        "Skipped 3 files.\nReports without a configuration:\n  XX_YY1 (2 files)\nFiles that failed:\n  text/LM_XB463/c.txt: No report date found"
+    );
+}
+
+#[test]
+fn test_record_skipped() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    client.batch_execute("DROP TABLE IF EXISTS test_skipped_files").unwrap();
+
+    let files = vec![SkippedFile { path: "text/XX_YY1/a.txt".to_owned(), identifier: "XX_YY1".to_owned(), reason: UNKNOWN_REPORT.to_owned() }];
+    record_skipped("test_skipped_files", &files, client).unwrap();
+    record_skipped("test_skipped_files", &files, client).unwrap();
+
+    let count: i64 = client.query_one("SELECT COUNT(*) FROM test_skipped_files WHERE identifier = 'XX_YY1'", &[]).unwrap().get(0);
+    assert_eq!(count, 2);
+}
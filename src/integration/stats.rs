@@ -0,0 +1,138 @@
+// Row counts, date spans and sizes on disk of the tables this tool writes, largest first, for deciding what to
+// partition or stop keeping.
+
+use std::io::Write;
+
+use chrono::NaiveDate;
+
+/// Columns a table's date span is taken from, the first it has
+const DATE_COLUMNS: [&str; 3] = ["report_date", "release_date", "started_at"];
+
+#[derive(Debug, PartialEq)]
+pub struct TableStats {
+    pub table: String,
+    pub rows: i64,
+    pub first_date: Option<NaiveDate>,
+    pub last_date: Option<NaiveDate>,
+    pub size: i64   // bytes, with indexes and TOAST
+}
+
+/// Counts the rows of a table and measures it, giving None if it doesn't exist yet
+pub fn table_stats(table: &str, client: &mut postgres::Client) -> Result<Option<TableStats>, String> {
+    let failed = |e: postgres::Error| format!("Failed to measure {}: {}", table, e);
+
+    let size: Option<i64> = client.query_one("SELECT pg_total_relation_size(to_regclass($1))", &[&table]).map_err(failed)?.get(0);
+    let size = match size {
+        Some(s) => { s },
+        None => { return Ok(None) }
+    };
+
+    let columns: Vec<String> = client.query(
+        "SELECT column_name::text FROM information_schema.columns WHERE table_schema = current_schema() AND table_name = $1",
+        &[&table]
+    ).map_err(failed)?.iter().map(|row| row.get(0)).collect();
+
+    let sql = match DATE_COLUMNS.iter().find(|c| columns.iter().any(|column| column == *c)) {
+        Some(column) => { format!("SELECT COUNT(*), MIN({0})::date, MAX({0})::date FROM {1}", column, table) },
+        None => { format!("SELECT COUNT(*), NULL::date, NULL::date FROM {}", table) }
+    };
+    let row = client.query_one(sql.as_str(), &[]).map_err(failed)?;
+
+    Ok(Some(TableStats { table: table.to_owned(), rows: row.get(0), first_date: row.get(1), last_date: row.get(2), size }))
+}
+
+/// Measures the given tables and the revision history tables kept beside them, largest first. Tables that don't
+/// exist yet are left out.
+pub fn collect_stats(tables: &[String], client: &mut postgres::Client) -> Vec<TableStats> {
+    let mut stats = Vec::new();
+
+    for table in tables {
+        for table in [table.to_owned(), super::usda::history_table(table)] {
+            match table_stats(&table, client) {
+                Ok(Some(s)) => { stats.push(s) },
+                Ok(None) => {},
+                Err(e) => { warn!("Skipping {}", e) }
+            }
+        }
+    }
+
+    rank(&mut stats);
+    stats
+}
+
+/// Orders tables largest first, then by name
+pub fn rank(stats: &mut [TableStats]) {
+    stats.sort_by(|a, b| b.size.cmp(&a.size).then(a.table.cmp(&b.table)));
+}
+
+/// A size in bytes in the largest unit it makes at least one of
+pub fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "kB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => { format!("{} {}", bytes, UNITS[0]) },
+        _ => { format!("{:.1} {}", size, UNITS[unit]) }
+    }
+}
+
+/// Writes measured tables as CSV
+pub fn write_report<W: Write>(stats: &[TableStats], writer: W) -> Result<(), String> {
+    let mut writer = csv::Writer::from_writer(writer);
+
+    writer.write_record(["table", "rows", "first_date", "last_date", "size_bytes", "size"]).map_err(|e| e.to_string())?;
+
+    let date = |d: Option<NaiveDate>| d.map(|d| d.format("%Y-%m-%d").to_string()).unwrap_or_default();
+    for table in stats {
+        writer.write_record(&[
+            table.table.clone(), table.rows.to_string(), date(table.first_date), date(table.last_date),
+            table.size.to_string(), format_size(table.size)
+        ]).map_err(|e| e.to_string())?;
+    }
+
+    writer.flush().map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_stats_report() {
+    assert_eq!(format_size(512), "512 bytes");
+    assert_eq!(format_size(1536), "1.5 kB");
+    assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+
+    let table = |table: &str, size: i64| TableStats { table: table.to_owned(), rows: 10, first_date: NaiveDate::from_ymd_opt(2020, 3, 2), last_date: None, size };
+    let mut stats = vec![table("b", 8192), table("c", 16384), table("a", 8192)];
+    rank(&mut stats);
+    assert_eq!(stats.iter().map(|s| s.table.as_str()).collect::<Vec<&str>>(), vec!["c", "a", "b"]);
+
+    let mut output = Vec::new();
+    write_report(&stats, &mut output).unwrap();
+    assert_eq!(String::from_utf8(output).unwrap().lines().nth(1).unwrap(), "c,10,2020-03-02,,16384,16.0 kB");
+}
+
+#[test]
+fn test_collect_stats() {
+    use super::usda::{create_table, insert_usda_package, test_package, test_structure, OnConflict};
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_stats");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_stats_bids; DROP TABLE IF EXISTS test_stats_bids_history").unwrap();
+    create_table("test_stats_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    for day in &[2, 9] {
+        insert_usda_package(test_package("test_stats", NaiveDate::from_ymd_opt(2020, 3, *day).unwrap(), "Colby", "3.50"), &structure, OnConflict::Keep, client).unwrap();
+    }
+
+    let stats = collect_stats(&["test_stats_bids".to_owned(), "test_stats_missing".to_owned()], client);
+
+    assert_eq!(stats.len(), 1);
+    assert_eq!((stats[0].rows, stats[0].first_date, stats[0].last_date), (2, NaiveDate::from_ymd_opt(2020, 3, 2), NaiveDate::from_ymd_opt(2020, 3, 9)));
+    assert!(stats[0].size > 0);
+}
@@ -0,0 +1,108 @@
+// Throwaway PostgreSQL servers for tests of the SQL we generate.
+//
+// A test gets its own cluster, created with initdb in a temporary directory and reachable only through a unix
+// socket in that directory. initdb refuses to run as root, so where that (or a missing PostgreSQL installation)
+// rules it out, point DATA_ACQUISITION_TEST_DSN at a disposable database instead, e.g.
+// `host=/tmp port=5432 user=postgres dbname=scratch` (TEST_DATABASE_URL is read as well). With neither available the
+// tests are skipped, except under CI (the CI variable is set), where they fail rather than pass without having run.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use postgres::NoTls;
+
+static CLUSTER_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestDatabase {
+    pub client: postgres::Client,
+    dsn: String,
+    cluster: Option<PathBuf>
+}
+
+impl TestDatabase {
+    /// Another connection to the same database
+    pub fn connect(&self) -> postgres::Client {
+        postgres::Client::connect(&self.dsn, NoTls).unwrap()
+    }
+
+    /// The connection settings of the database
+    pub fn config(&self) -> postgres::Config {
+        self.dsn.parse().unwrap()
+    }
+
+    /// A connection pool on the same database
+    pub fn pool(&self, size: u32) -> super::pool::Pool {
+        super::pool::connect(self.dsn.parse().unwrap(), size, None).unwrap()
+    }
+}
+
+fn remove_cluster(directory: &Path) {
+    let _ = Command::new("pg_ctl").arg("-D").arg(directory.join("data")).args(["-m", "immediate", "stop"])
+        .stdout(Stdio::null()).stderr(Stdio::null()).status();
+    let _ = fs::remove_dir_all(directory);
+}
+
+impl Drop for TestDatabase {
+    fn drop(&mut self) {
+        if let Some(directory) = self.cluster.as_ref() {
+            remove_cluster(directory);
+        }
+    }
+}
+
+fn start_cluster() -> Result<TestDatabase, String> {
+    let directory = env::temp_dir().join(format!("data-acquisition-test-{}-{}", std::process::id(), CLUSTER_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    let data = directory.join("data");
+    fs::create_dir_all(&directory).map_err(|e| e.to_string())?;
+
+    let run = |command: &mut Command| -> Result<(), String> {
+        match command.stdout(Stdio::null()).stderr(Stdio::null()).status() {
+            Ok(s) if s.success() => { Ok(()) },
+            Ok(s) => { Err(format!("{:?} exited with {}", command, s)) },
+            Err(e) => { Err(format!("{:?} could not be run: {}", command, e)) }
+        }
+    };
+
+    let started = run(Command::new("initdb").arg("-D").arg(&data).args(["-A", "trust", "-U", "postgres"]))
+        .and_then(|_| run(Command::new("pg_ctl").arg("-D").arg(&data).arg("-o")
+            .arg(format!("-p 5432 -k {} -c listen_addresses=''", directory.display())).args(["-w", "start"])));
+
+    if let Err(e) = started {
+        remove_cluster(&directory);
+        return Err(e);
+    }
+
+    let dsn = format!("host={} port=5432 user=postgres dbname=postgres", directory.display());
+
+    match postgres::Client::connect(&dsn, NoTls) {
+        Ok(client) => { Ok(TestDatabase { client, dsn, cluster: Some(directory) }) },
+        Err(e) => {
+            remove_cluster(&directory);
+            Err(e.to_string())
+        }
+    }
+}
+
+/// A database for one test, or None (with a note on stderr) when no PostgreSQL server can be had
+pub fn test_database() -> Option<TestDatabase> {
+    for variable in ["DATA_ACQUISITION_TEST_DSN", "TEST_DATABASE_URL"] {
+        if let Ok(dsn) = env::var(variable) {
+            let client = postgres::Client::connect(&dsn, NoTls).unwrap_or_else(|e| panic!("Failed to connect to {}: {}", variable, e));
+            return Some(TestDatabase { client, dsn, cluster: None });
+        }
+    }
+
+    match start_cluster() {
+        Ok(database) => { Some(database) },
+        Err(e) if env::var_os("CI").is_some() => {
+            panic!("No test database available under CI, set DATA_ACQUISITION_TEST_DSN: {}", e)
+        },
+        Err(e) => {
+            eprintln!("Skipping PostgreSQL test, no test database available: {}", e);
+            None
+        }
+    }
+}
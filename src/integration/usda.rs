@@ -1,79 +1,508 @@
-use crate::usda::USDADataPackage;
-use crate::usda::datamart::DatamartConfig;
-use postgres::types::ToSql;
+use crate::metrics;
+use crate::usda::{USDADataPackage, USDADataPackageSection};
+use crate::usda::datamart::{DatamartConfig, Period};
+use super::sink::NO_DATE_FOUND;
+use postgres::{GenericClient, Statement, Transaction};
+use postgres::binary_copy::BinaryCopyInWriter;
+use postgres::types::{ToSql, Type};
+
+use std::collections::HashMap;
 
 use chrono::NaiveDate;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// PostgreSQL's limit on the length of identifiers, in bytes; longer ones are silently cut short
+pub const MAX_IDENTIFIER_LENGTH: usize = 63;
+
+/// The name a configured independent column is stored under: snake_case and lowercase, as PostgreSQL folds
+/// unquoted names, so that `ClassDescription`, `class description` and `class_description` are one column in every
+/// sink and in extracts
+pub fn column_name(name: &str) -> String {
+    let mut column = String::with_capacity(name.len());
+    let mut previous: Option<char> = None;
+
+    for c in name.chars() {
+        if c.is_uppercase() && previous.is_some_and(|p| p.is_lowercase() || p.is_ascii_digit()) {
+            column.push('_');
+        }
+        if c.is_alphanumeric() {
+            column.extend(c.to_lowercase());
+        } else if !column.ends_with('_') {
+            column.push('_');
+        }
+        previous = Some(c);
+    }
+
+    column.trim_matches('_').to_owned()
+}
+
+/// A configured independent column as it is written in SQL
+pub fn quoted_column(name: &str) -> String {
+    format!("\"{}\"", column_name(name))
+}
+
+/// A generated name, `{base}{suffix}`, e.g. lm_xb463_summary_pkeys. A name PostgreSQL would cut short is shortened
+/// here instead, ending in a hash of the whole base so that names alike in their first 50 or so characters still
+/// differ.
+pub fn safe_identifier(base: &str, suffix: &str) -> String {
+    let base = base.to_lowercase();     // as PostgreSQL folds the unquoted names we give it
+    let name = format!("{}{}", base, suffix);
+    if name.len() <= MAX_IDENTIFIER_LENGTH {
+        return name;
+    }
+
+    let suffix = format!("_{}{}", &format!("{:x}", Sha256::digest(base.as_bytes()))[..8], suffix);
+    format!("{}{}", truncated_identifier(&base, MAX_IDENTIFIER_LENGTH - suffix.len()), suffix)
+}
+
+/// The first `length` bytes of a name, or fewer so as not to split a character: what PostgreSQL keeps of a name
+/// longer than `MAX_IDENTIFIER_LENGTH`
+pub fn truncated_identifier(name: &str, length: usize) -> &str {
+    if name.len() <= length {
+        return name;
+    }
+
+    let mut end = length;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    &name[..end]
+}
+
+/// The name of the primary key constraint of a report table, `{table}_pkeys`, shortened as `safe_identifier` does
+pub fn primary_key_name(table: &str) -> String {
+    safe_identifier(table, "_pkeys")
+}
+
+pub fn create_table(name:String, independent: &[String], client: &mut postgres::Client) -> Result<usize, postgres::Error> {
+    // warning: this SQL construction is sensitive magic and prone to breaking
+    let mut sql = format!(r#"
+        CREATE TABLE IF NOT EXISTS {0} (
+            report_date date not null,
+    "#, &name);
+
+    for column in &independent[1..] {
+        sql.push_str(&format!("\t{} text not null,", quoted_column(column)));
+    }
+
+    let primary_key = primary_key_name(&name);
+    sql.push_str(&format!(r#"
+        variable_name text not null,
+        value real,
+        value_text text,
+        constraint {0} primary key (report_date, variable_name,"#, &primary_key));
+    
+    for column in &independent[1..] {
+        sql.push_str(&format!("{},", quoted_column(column)));
+    }
+    sql.pop(); // remove trailing comma
+
+    sql.push_str("));");
+
+    // tables made before long names were shortened have the name PostgreSQL cut short
+    let truncated = format!("{}_pkeys", name.to_lowercase());
+    if truncated.len() > MAX_IDENTIFIER_LENGTH {
+        sql.push_str(&format!(
+            "\nDO $$ BEGIN IF EXISTS (SELECT 1 FROM pg_constraint WHERE conrelid = '{0}'::regclass AND conname = '{1}') THEN \
+             ALTER TABLE {0} RENAME CONSTRAINT \"{1}\" TO {2}; END IF; END $$;",
+            &name, truncated_identifier(&truncated, MAX_IDENTIFIER_LENGTH), &primary_key
+        ));
+    }
+
+    // columns used to be created with their configured names as given, so mixed-case ones are renamed in place
+    for column in &independent[1..] {
+        if *column != column_name(column) {
+            sql.push_str(&format!(
+                "\nDO $$ BEGIN IF EXISTS (SELECT 1 FROM information_schema.columns WHERE table_schema = current_schema() \
+                 AND table_name = '{0}' AND column_name = '{1}') THEN ALTER TABLE {0} RENAME COLUMN \"{2}\" TO {3}; END IF; END $$;",
+                name.to_lowercase(), column.replace('\'', "''"), column.replace('"', "\"\""), quoted_column(column)
+            ));
+        }
+    }
+
+    // added after the initial table layout, so existing tables are migrated in place
+    sql.push_str(&format!("\nALTER TABLE {0} ADD COLUMN IF NOT EXISTS source text;", &name));
+    sql.push_str(&format!("\nALTER TABLE {0} ADD COLUMN IF NOT EXISTS provenance text;", &name)); // null for reported values
+    sql.push_str(&format!("\nALTER TABLE {0} ADD COLUMN IF NOT EXISTS parser_version integer;", &name)); // legacy text parsers only
+    // the default is set separately so that rows already in the table aren't tagged with the current run
+    sql.push_str(&format!("\nALTER TABLE {0} ADD COLUMN IF NOT EXISTS run_id uuid;", &name));
+    sql.push_str(&format!("\nALTER TABLE {0} ALTER COLUMN run_id SET DEFAULT {1};", &name, super::runs::run_id_default()));
+    // when a value was written, which becomes valid_from if it is later revised (see OnConflict::TrackRevisions)
+    sql.push_str(&format!("\nALTER TABLE {0} ADD COLUMN IF NOT EXISTS recorded_at timestamptz;", &name));
+    sql.push_str(&format!("\nALTER TABLE {0} ALTER COLUMN recorded_at SET DEFAULT now();", &name));
+
+    client.batch_execute(&sql)?;
+    Ok(0)
+}
+
+/// Adds period_start and period_end to the table of a report whose dates stand for a period, as columns generated
+/// from report_date so that every way of loading a table fills them. report_date stays the key.
+pub fn add_period_columns(name: &str, period: Period, client: &mut postgres::Client) -> Result<(), postgres::Error> {
+    let (start, end) = period.bounds_sql("report_date");
+
+    client.batch_execute(&format!(
+        "ALTER TABLE {0} ADD COLUMN IF NOT EXISTS period_start date GENERATED ALWAYS AS ({1}) STORED;\n\
+         ALTER TABLE {0} ADD COLUMN IF NOT EXISTS period_end date GENERATED ALWAYS AS ({2}) STORED;",
+        name, start, end
+    ))
+}
+
+/// Prepared insert statements keyed by their SQL, which is determined by table and independent columns. Statements
+/// belong to the connection that prepared them, so a cache must only ever be used with one client.
+#[derive(Default)]
+pub struct StatementCache {
+    statements: HashMap<String, Statement>
+}
+
+impl StatementCache {
+    pub fn new() -> StatementCache {
+        StatementCache::default()
+    }
+
+    pub fn prepare<C: GenericClient>(&mut self, client: &mut C, sql: &str) -> Result<Statement, postgres::Error> {
+        if let Some(statement) = self.statements.get(sql) {
+            return Ok(statement.clone());
+        }
+
+        let statement = client.prepare(sql)?;
+        self.statements.insert(sql.to_owned(), statement.clone());
+        Ok(statement)
+    }
+}
+
+/// Inserts a package in one transaction, so that a failure leaves none of it behind. Returns the number of new and
+/// updated rows.
+pub fn insert_usda_package<C: GenericClient>(package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict, client: &mut C) -> Result<usize, String> {
+    insert_usda_package_with_cache(package, structure, on_conflict, client, &mut StatementCache::new())
+}
+
+/// As `insert_usda_package`, reusing statements prepared for earlier packages, which matters when many small
+/// packages are inserted in one run
+pub fn insert_usda_package_with_cache<C: GenericClient>(package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict, client: &mut C, cache: &mut StatementCache) -> Result<usize, String> {
+    let on_conflict = on_conflict.for_report(structure);
+
+    // revisions are moved to history in bulk, which only the COPY path does
+    if on_conflict == OnConflict::TrackRevisions {
+        return copy_usda_package(package, structure, on_conflict, client).map(|n| n as usize);
+    }
 
-pub fn insert_usda_package(package: USDADataPackage, structure: &DatamartConfig, client: &mut postgres::Client) -> Result<usize, postgres::Error> {
     let report_name = package.name;
+    let source = package.source;
+    let parser_version = package.parser_version.map(|v| v as i32);
+
+    // statements prepared in the transaction outlive it, so the cache stays valid after a rollback
+    let mut transaction = client.transaction().map_err(|e| format!("Failed to begin inserting {}: {}", report_name, e))?;
+    let mut inserted = 0;
 
     for (section, results) in package.sections {
         // Dynamic statement preparation
         // warning: this SQL construction is sensitive magic and prone to breaking
-        let table_name = match &structure.sections[&section].alias {
-            Some(alias) => {format!("{}_{}", report_name, alias).to_owned()},
-            None => {format!("{}_{}", report_name, section).to_owned()}
-        }.to_lowercase();
+        let table_name = structure.table_name(&section);
 
         let independent = &structure.sections[&section].independent;
         let mut sql = format!(r#"INSERT INTO {table_name} (report_date, "#, table_name=&table_name).to_owned();
         
         for column in &independent[1..] {
-            sql.push_str(&format!("\"{}\", ", column));
+            sql.push_str(&format!("{}, ", quoted_column(column)));
         }
-        sql.push_str("variable_name, value, value_text) VALUES(");
-        for i in 1..=independent.len()+3 {
+        sql.push_str("variable_name, value, value_text, source, provenance, parser_version) VALUES(");
+        for i in 1..=independent.len()+6 {
             sql.push_str(&format!("${},", i));
         }
         sql.pop();
-        sql.push_str(&format!(") ON CONFLICT ON CONSTRAINT {table_name}_pkeys DO NOTHING", table_name=table_name));
+        match on_conflict {
+            OnConflict::Update => {
+                sql.push_str(&format!(
+                    ") ON CONFLICT ON CONSTRAINT {primary_key} DO UPDATE SET value = EXCLUDED.value, value_text = EXCLUDED.value_text, \
+                     source = EXCLUDED.source, provenance = EXCLUDED.provenance, parser_version = EXCLUDED.parser_version, \
+                     run_id = DEFAULT, recorded_at = DEFAULT WHERE {table_name}.value_text IS DISTINCT FROM EXCLUDED.value_text",
+                    table_name=table_name, primary_key=primary_key_name(&table_name)
+                ));
+            },
+            _ => { sql.push_str(&format!(") ON CONFLICT ON CONSTRAINT {} DO NOTHING", primary_key_name(&table_name))); }
+        }
 
         //println!("{}", sql);
         
-        let statement = client.prepare(&sql).unwrap();
-        
+        let failed = |e: postgres::Error| format!("Failed to insert {} into {}, nothing from the package was written: {}", report_name, table_name, e);
+        let statement = cache.prepare(&mut transaction, &sql).map_err(failed)?;
+
+        let rows = staged_rows(results, &source, parser_version);
+        let rows = unstored_rows(&report_name, &table_name, independent, rows, on_conflict, &mut transaction)?;
+
         // Data processing and insertion
-        for usda_package in results {
-            let report_date = usda_package.report_date;
-            let independent = &usda_package.independent;
-
-            for (key, value) in usda_package.entries {
-                let value_numeric = {
-                    let temp = value.replace(",", "");
-                    match temp.parse::<f32>() {
-                        Ok(v) => { Some(v) },
-                        Err(_) => { None }
-                    }
-                };
-                if !value.is_empty() {
-                    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new(); // this is some kind of magic that i do not yet understand
-                    
-                    params.push(&report_date);
-                    for column in &independent[1..] {
-                        params.push(column);
-                    }
-                    params.push(&key);
-                    params.push(&value_numeric);
-                    params.push(&value);
-
-                    //println!("{:?}", params);
-
-                    client.execute(&statement, &params[..]).unwrap();
-                }
+        for row in rows {
+            let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new(); // this is some kind of magic that i do not yet understand
+            
+            params.push(&row.report_date);
+            for column in &row.independent {
+                params.push(column);
             }
+            params.push(&row.variable_name);
+            params.push(&row.value);
+            params.push(&row.value_text);
+            params.push(&row.source);
+            params.push(&row.provenance);
+            params.push(&row.parser_version);
+
+            //println!("{:?}", params);
+
+            inserted += transaction.execute(&statement, &params[..]).map_err(failed)?;
         }
     }
-    Ok(0)
+
+    transaction.commit().map_err(|e| format!("Failed to commit {}: {}", report_name, e))?;
+    Ok(inserted as usize)
+}
+
+/// One row of a report table, as staged for `copy_rows`
+pub struct StagedRow {
+    pub report_date: NaiveDate,
+    pub independent: Vec<String>,   // the independent columns after report_date
+    pub variable_name: String,
+    pub value: Option<f32>,
+    pub value_text: String,
+    pub source: Option<String>,
+    pub provenance: Option<String>,
+    pub parser_version: Option<i32>
+}
+
+/// The rows of a section's results, leaving out empty values, which are never stored
+fn staged_rows(results: Vec<USDADataPackageSection>, source: &Option<String>, parser_version: Option<i32>) -> Vec<StagedRow> {
+    let mut rows = Vec::new();
+
+    for usda_package in results {
+        for (key, value) in usda_package.entries {
+            if value.is_empty() {
+                continue;
+            }
+
+            rows.push(StagedRow {
+                report_date: usda_package.report_date,
+                independent: usda_package.independent[1..].to_vec(),
+                value: value.replace(",", "").parse::<f32>().ok(),
+                provenance: usda_package.provenance.get(&key).cloned(),
+                variable_name: key,
+                value_text: value,
+                source: source.clone(),
+                parser_version
+            });
+        }
+    }
+
+    rows
+}
+
+/// Leaves out the rows stored as they are, and with OnConflict::Keep every row stored at all, found with one query
+/// for the report dates of the rows. Daily runs fetch the dates of weekly reports again and again; writing those
+/// rows only for them to conflict would churn the table and the WAL for nothing.
+fn unstored_rows<C: GenericClient>(report_name: &str, table_name: &str, independent: &[String], rows: Vec<StagedRow>, on_conflict: OnConflict, client: &mut C) -> Result<Vec<StagedRow>, String> {
+    if rows.is_empty() {
+        return Ok(rows);
+    }
+
+    let mut dates: Vec<NaiveDate> = rows.iter().map(|r| r.report_date).collect();
+    dates.sort_unstable();
+    dates.dedup();
+
+    let stored = super::diff::stored_values(table_name, independent, &dates, client)?;
+    if stored.is_empty() {
+        return Ok(rows);
+    }
+
+    let count = rows.len();
+    let rows: Vec<StagedRow> = rows.into_iter().filter(|row| {
+        match stored.get(&(row.report_date, row.independent.clone(), row.variable_name.clone())) {
+            Some(value) => { on_conflict != OnConflict::Keep && *value != row.value_text },
+            None => { true }
+        }
+    }).collect();
+
+    let skipped = count - rows.len();
+    if skipped > 0 {
+        debug!("{}: {} of {} rows are already stored, skipping them.", table_name, skipped, count);
+        metrics::add(&metrics::ROWS_SKIPPED, &[("report", report_name)], skipped as f64);
+    }
+
+    Ok(rows)
+}
+
+/// What a load does with a value that is already stored. Set for a run with --on-conflict or --track-revisions,
+/// or for a report with `on_conflict` in its configuration, which takes precedence.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    Keep,           // the stored value stays
+    Update,         // a different value replaces the stored one
+    TrackRevisions  // as Update, the stored value moving to the table's _history table
+}
+
+impl OnConflict {
+    /// The behaviour for a report, which its configuration may set
+    pub fn for_report(self, structure: &DatamartConfig) -> OnConflict {
+        structure.on_conflict.unwrap_or(self)
+    }
+}
+
+/// The table keeping the revised values of a report table, `{table}_history`, shortened as `safe_identifier` does
+pub fn history_table(table: &str) -> String {
+    safe_identifier(table, "_history")
+}
+
+/// Moves the stored values that the staged rows revise into the table's history table, stamped with the time they
+/// were valid, and writes the revised values in their place
+fn apply_revisions(table_name: &str, staging: &str, columns: &[String], independent: &[String], transaction: &mut Transaction) -> Result<u64, postgres::Error> {
+    let history = history_table(table_name);
+
+    // no primary key, as a value can be revised any number of times. valid_from is null for values stored
+    // before recorded_at was.
+    transaction.batch_execute(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (LIKE {}, valid_from timestamptz, valid_to timestamptz not null)",
+        history, table_name
+    ))?;
+
+    let revised = revised_condition(independent);
+
+    let stored: Vec<String> = columns.iter().map(|c| format!("t.{}", c)).collect();
+    transaction.execute(format!(
+        "INSERT INTO {history} ({columns}, run_id, recorded_at, valid_from, valid_to) \
+         SELECT {stored}, t.run_id, t.recorded_at, t.recorded_at, now() FROM {table} t JOIN {staging} s ON {revised}",
+        history=history, columns=columns.join(", "), stored=stored.join(", "), table=table_name, staging=staging, revised=revised
+    ).as_str(), &[])?;
+
+    update_revised(table_name, staging, independent, transaction)
+}
+
+/// Writes the staged values that differ from those stored in their place, returning the number revised
+fn update_revised(table_name: &str, staging: &str, independent: &[String], transaction: &mut Transaction) -> Result<u64, postgres::Error> {
+    transaction.execute(format!(
+        "UPDATE {table} t SET value = s.value, value_text = s.value_text, source = s.source, provenance = s.provenance, \
+         parser_version = s.parser_version, run_id = DEFAULT, recorded_at = DEFAULT FROM {staging} s WHERE {revised}",
+        table=table_name, staging=staging, revised=revised_condition(independent)
+    ).as_str(), &[])
+}
+
+/// Matches a stored row `t` with a staged row `s` for the same value, when the staged one differs
+fn revised_condition(independent: &[String]) -> String {
+    let mut keys = vec!["t.report_date = s.report_date".to_owned(), "t.variable_name = s.variable_name".to_owned()];
+    keys.extend(independent[1..].iter().map(|c| format!("t.{0} = s.{0}", quoted_column(c))));
+    format!("{} AND t.value_text IS DISTINCT FROM s.value_text", keys.join(" AND "))
+}
+
+/// Loads rows into a table in one round trip: they are copied into a temporary staging table in binary form, then
+/// merged, handling rows already stored as `on_conflict` says. Returns the number of new and revised rows.
+pub fn copy_rows(table_name: &str, independent: &[String], rows: &[StagedRow], on_conflict: OnConflict, transaction: &mut Transaction) -> Result<u64, postgres::Error> {
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    // named apart from the staging tables of other tables alike in their first 50 or so characters, as each is
+    // made like its own table
+    let staging = safe_identifier(table_name, "_staging");
+
+    let mut column_names = vec!["report_date".to_owned()];
+    column_names.extend(independent[1..].iter().map(|c| quoted_column(c)));
+    column_names.extend(["variable_name", "value", "value_text", "source", "provenance", "parser_version"].iter().map(|c| (*c).to_owned()));
+    let columns = column_names.join(", ");
+
+    let mut types = vec![Type::DATE];
+    types.extend(independent[1..].iter().map(|_| Type::TEXT));
+    types.extend(vec![Type::TEXT, Type::FLOAT4, Type::TEXT, Type::TEXT, Type::TEXT, Type::INT4]);
+
+    // emptied at the end of each transaction, and otherwise reused for the rest of the session
+    transaction.batch_execute(&format!("CREATE TEMP TABLE IF NOT EXISTS {} (LIKE {}) ON COMMIT DELETE ROWS", staging, table_name))?;
+
+    let mut writer = BinaryCopyInWriter::new(transaction.copy_in(format!("COPY {} ({}) FROM STDIN BINARY", staging, columns).as_str())?, &types);
+    for row in rows {
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&row.report_date];
+        for column in &row.independent {
+            params.push(column);
+        }
+        params.extend_from_slice(&[&row.variable_name, &row.value, &row.value_text, &row.source, &row.provenance, &row.parser_version]);
+
+        writer.write(&params)?;
+    }
+    writer.finish()?;
+
+    let revised = match on_conflict {
+        OnConflict::Keep => { 0 },
+        OnConflict::Update => { update_revised(table_name, &staging, independent, transaction)? },
+        OnConflict::TrackRevisions => { apply_revisions(table_name, &staging, &column_names, independent, transaction)? }
+    };
+
+    // run_id is left to the table's default, see runs
+    let inserted = transaction.execute(format!(
+        "INSERT INTO {table} ({columns}) SELECT {columns} FROM {staging} ON CONFLICT ON CONSTRAINT {primary_key} DO NOTHING",
+        table=table_name, columns=columns, staging=staging, primary_key=primary_key_name(table_name)
+    ).as_str(), &[])?;
+
+    Ok(inserted + revised)
+}
+
+/// As `insert_usda_package`, but loading each section with COPY, which is far faster for large packages. The
+/// package is loaded in one transaction. Returns the number of new and revised rows.
+pub fn copy_usda_package<C: GenericClient>(package: USDADataPackage, structure: &DatamartConfig, on_conflict: OnConflict, client: &mut C) -> Result<u64, String> {
+    let on_conflict = on_conflict.for_report(structure);
+    let report_name = package.name;
+    let source = package.source;
+    let parser_version = package.parser_version.map(|v| v as i32);
+
+    let mut transaction = client.transaction().map_err(|e| format!("Failed to begin loading {}: {}", report_name, e))?;
+    let mut inserted = 0;
+
+    for (section, results) in package.sections {
+        let table_name = structure.table_name(&section);
+
+        let independent = &structure.sections[&section].independent;
+        let rows = staged_rows(results, &source, parser_version);
+        let rows = unstored_rows(&report_name, &table_name, independent, rows, on_conflict, &mut transaction)?;
+
+        inserted += copy_rows(&table_name, independent, &rows, on_conflict, &mut transaction)
+            .map_err(|e| format!("Failed to load {} into {}, nothing from the package was written: {}", report_name, table_name, e))?;
+    }
+
+    transaction.commit().map_err(|e| format!("Failed to commit {}: {}", report_name, e))?;
+    Ok(inserted)
+}
+
+/// Report dates with rows written by a parser older than `version` (or before versions were recorded)
+pub fn find_outdated_report_dates(current_config: &DatamartConfig, version: u32, client: &mut postgres::Client) -> Result<Vec<NaiveDate>, String> {
+    let mut dates: Vec<NaiveDate> = Vec::new();
+
+    for section in current_config.sections.keys() {
+        let table_name = current_config.table_name(section);
+
+        let sql = format!("SELECT DISTINCT report_date FROM {} WHERE parser_version IS NULL OR parser_version < $1", table_name);
+        match client.query(sql.as_str(), &[&(version as i32)]) {
+            Ok(rows) => { dates.extend(rows.iter().map(|r| r.get::<_, NaiveDate>(0))) },
+            Err(e) => { return Err(format!("Failed to find outdated rows in {}: {}", table_name, e)) }
+        }
+    }
+
+    dates.sort_unstable();
+    dates.dedup();
+    Ok(dates)
+}
+
+/// Removes every row of a report for one report date, ahead of writing it again
+pub fn delete_report_date<C: GenericClient>(current_config: &DatamartConfig, report_date: NaiveDate, client: &mut C) -> Result<u64, postgres::Error> {
+    let mut deleted = 0;
+
+    for section in current_config.sections.keys() {
+        let table_name = current_config.table_name(section);
+
+        deleted += client.execute(format!("DELETE FROM {} WHERE report_date = $1", table_name).as_str(), &[&report_date])?;
+    }
+
+    Ok(deleted)
 }
 
 pub fn find_maximum_existing_datamart_date(current_config: &DatamartConfig, client: &mut postgres::Client) -> Result<NaiveDate, String> {
     let mut max_date_found: Option<NaiveDate> = None;
 
     for section in current_config.sections.keys() {
-        let table_name = match &current_config.sections[section].alias {
-            Some(alias) => {format!("{}_{}", current_config.name, alias).to_owned()},
-            None => {format!("{}_{}", current_config.name, section).to_owned()}
-        }.to_lowercase();
+        let table_name = current_config.table_name(section);
 
         let sql = format!("SELECT MAX(report_date) FROM {}", table_name);
         let statement = match client.prepare(&sql) {
@@ -108,6 +537,455 @@ pub fn find_maximum_existing_datamart_date(current_config: &DatamartConfig, clie
 
     match max_date_found {
         Some(d) => { Ok(d) },
-        None => { Err(String::from("No date found"))}
+        None => { Err(NO_DATE_FOUND.to_owned()) }
+    }
+}
+
+#[cfg(test)]
+pub fn test_structure(name: &str) -> DatamartConfig {
+    use crate::usda::datamart::{DatamartSection, DatamartApiVersion};
+
+    let mut sections = HashMap::new();
+    sections.insert("bids".to_owned(), DatamartSection {
+        alias: None,
+        independent: vec!["report_date".to_owned(), "region".to_owned()],
+        fields: vec!["bid".to_owned()],
+        types: Default::default(),
+        ignored: None,
+        required: true
+    });
+
+    DatamartConfig {
+        name: name.to_owned(),
+        description: "test report".to_owned(),
+        independent: "report_date".to_owned(),
+        api_version: DatamartApiVersion::V1,
+        mars_slug: None,
+        mars_filters: Default::default(),
+        independent_type: Default::default(),
+        mars_family: None,
+        on_conflict: None,
+        period: None,
+        file_date: None,
+        indexes: None,
+        archive_url: None,
+        release_time: None,
+        connect_timeout: None,
+        read_timeout: None,
+        transforms: Vec::new(),
+        parser: None,
+        sections
+    }
+}
+
+#[cfg(test)]
+pub fn test_package(name: &str, report_date: NaiveDate, region: &str, bid: &str) -> USDADataPackage {
+    use crate::usda::USDADataPackageSection;
+
+    let mut section = USDADataPackageSection::new(report_date);
+    section.independent.push(report_date.format("%Y-%m-%d").to_string());
+    section.independent.push(region.to_owned());
+    section.entries.insert("bid".to_owned(), bid.to_owned());
+    section.entries.insert("note".to_owned(), "".to_owned());
+
+    let mut package = USDADataPackage::new(name.to_owned());
+    package.source = Some("test".to_owned());
+    package.sections.insert("bids".to_owned(), vec![section]);
+    package
+}
+
+#[test]
+fn test_create_and_insert() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_insert");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_insert_bids").unwrap();
+    create_table("test_insert_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+    create_table("test_insert_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap(); // idempotent
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    insert_usda_package(test_package("test_insert", report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+    insert_usda_package(test_package("test_insert", report_date, "Colby", "(NA)"), &structure, OnConflict::Keep, client).unwrap();
+
+    let rows = client.query("SELECT region, variable_name, value, value_text, source FROM test_insert_bids ORDER BY region", &[]).unwrap();
+    assert_eq!(rows.len(), 2); // empty values are not stored
+
+    assert_eq!(rows[0].get::<_, String>(0), "Colby");
+    assert_eq!(rows[0].get::<_, Option<f32>>(2), None);
+    assert_eq!(rows[0].get::<_, String>(3), "(NA)");
+
+    assert_eq!(rows[1].get::<_, String>(1), "bid");
+    assert_eq!(rows[1].get::<_, Option<f32>>(2), Some(5.41));
+    assert_eq!(rows[1].get::<_, Option<String>>(4), Some("test".to_owned()));
+}
+
+#[test]
+fn test_insert_provenance() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_provenance");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_provenance_bids").unwrap();
+    create_table("test_provenance_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    let mut package = test_package("test_provenance", NaiveDate::from_ymd_opt(2020, 3, 2).unwrap(), "Dodge City", "5.41");
+    let row = &mut package.sections.get_mut("bids").unwrap()[0];
+    row.entries.insert("bid_cents".to_owned(), "541".to_owned());
+    row.provenance.insert("bid_cents".to_owned(), "scale@1".to_owned());
+    insert_usda_package(package, &structure, OnConflict::Keep, client).unwrap();
+
+    let rows = client.query("SELECT variable_name, provenance FROM test_provenance_bids ORDER BY variable_name", &[]).unwrap();
+    assert_eq!(rows[0].get::<_, Option<String>>(1), None);
+    assert_eq!(rows[1].get::<_, Option<String>>(1), Some("scale@1".to_owned()));
+}
+
+#[test]
+fn test_statement_cache() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_cache");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_cache_bids").unwrap();
+    create_table("test_cache_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    let mut cache = StatementCache::new();
+    for day in 1..=3 {
+        let report_date = NaiveDate::from_ymd_opt(2020, 3, day).unwrap();
+        insert_usda_package_with_cache(test_package("test_cache", report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client, &mut cache).unwrap();
+    }
+
+    assert_eq!(cache.statements.len(), 1);
+    assert_eq!(client.query_one("SELECT COUNT(*) FROM test_cache_bids", &[]).unwrap().get::<_, i64>(0), 3);
+}
+
+#[test]
+fn test_insert_conflict_keeps_existing() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_conflict");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_conflict_bids").unwrap();
+    create_table("test_conflict_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    insert_usda_package(test_package("test_conflict", report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+    insert_usda_package(test_package("test_conflict", report_date, "Dodge City", "5.99"), &structure, OnConflict::Keep, client).unwrap();
+
+    let rows = client.query("SELECT value_text FROM test_conflict_bids", &[]).unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].get::<_, String>(0), "5.41");
+}
+
+#[test]
+fn test_safe_identifier() {
+    assert_eq!(safe_identifier("LM_XB463_summary", "_history"), "lm_xb463_summary_history");
+
+    let long = "ams_3192_national_weekly_grain_market_review_feed_exports_all_regions";
+    let name = safe_identifier(long, "_history");
+    assert_eq!(name.len(), MAX_IDENTIFIER_LENGTH);
+    assert!(name.starts_with("ams_3192_national_weekly_grain_market_review_") && name.ends_with("_history"));
+    assert_ne!(name, safe_identifier(&long.replace("regions", "states"), "_history"));
+
+    // tables alike in their first 55 characters keep their revisions apart
+    assert_eq!(history_table("lm_xb463_summary"), "lm_xb463_summary_history");
+    assert_ne!(history_table("test_copy_into_tables_alike_but_for_their_last_letter_bids_a"), history_table("test_copy_into_tables_alike_but_for_their_last_letter_bids_b"));
+
+    assert_eq!(truncated_identifier("lm_xb463", 5), "lm_xb");
+    assert_eq!(truncated_identifier("lm_xb463", 63), "lm_xb463");
+}
+
+#[test]
+fn test_primary_key_name() {
+    assert_eq!(primary_key_name("LM_XB463_summary"), "lm_xb463_summary_pkeys");
+
+    let long = "ams_3192_national_weekly_grain_market_review_feed_exports_all";
+    let name = primary_key_name(long);
+    assert_eq!(name.len(), MAX_IDENTIFIER_LENGTH);
+    assert!(name.starts_with("ams_3192_national_weekly_grain_market_review_fee_") && name.ends_with("_pkeys"));
+    assert_ne!(name, primary_key_name(&long.replace("exports", "imports")));
+}
+
+#[test]
+fn test_column_name() {
+    assert_eq!(column_name("region"), "region");
+    assert_eq!(column_name("ClassDescription"), "class_description");
+    assert_eq!(column_name("Class Description"), "class_description");
+    assert_eq!(column_name(" delivery  point (FOB) "), "delivery_point_fob");
+    assert_eq!(column_name("grade2Quality"), "grade2_quality");
+    assert_eq!(quoted_column("Region"), "\"region\"");
+}
+
+#[test]
+fn test_mixed_case_columns() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let mut structure = test_structure("test_mixed_case");
+    structure.sections.get_mut("bids").unwrap().independent[1] = "Region".to_owned();
+
+    // a table made with the configured name as given, which is migrated to the normalized name
+    client.batch_execute(
+        "DROP TABLE IF EXISTS test_mixed_case_bids; \
+         CREATE TABLE test_mixed_case_bids (report_date date not null, \"Region\" text not null, variable_name text not null, \
+         value real, value_text text, constraint test_mixed_case_bids_pkeys primary key (report_date, variable_name, \"Region\"))"
+    ).unwrap();
+    create_table("test_mixed_case_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+    create_table("test_mixed_case_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    insert_usda_package(test_package("test_mixed_case", report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+    copy_usda_package(test_package("test_mixed_case", report_date, "Colby", "5.10"), &structure, OnConflict::Keep, client).unwrap();
+
+    let rows = client.query("SELECT region FROM test_mixed_case_bids WHERE variable_name = 'bid' ORDER BY region", &[]).unwrap();
+    assert_eq!(rows.iter().map(|r| r.get(0)).collect::<Vec<String>>(), vec!["Colby", "Dodge City"]);
+}
+
+#[test]
+fn test_long_table_name() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let name = "test_long_report_name_close_to_the_identifier_limit_ab";
+    let mut structure = test_structure(name);
+    structure.sections.get_mut("bids").unwrap().alias = Some("bids".to_owned());
+
+    let table = format!("{}_bids", name);
+    client.batch_execute(&format!("DROP TABLE IF EXISTS {}", table)).unwrap();
+
+    // a table made before names were shortened, with the name PostgreSQL cut short, is renamed in place
+    client.batch_execute(&format!(
+        "CREATE TABLE {0} (report_date date not null, region text not null, variable_name text not null, value real, value_text text, \
+         constraint {0}_pkeys primary key (report_date, variable_name, region))", table
+    )).unwrap();
+    create_table(table.clone(), &structure.sections["bids"].independent, client).unwrap();
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    insert_usda_package(test_package(name, report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+    insert_usda_package(test_package(name, report_date, "Dodge City", "5.99"), &structure, OnConflict::Update, client).unwrap();
+    copy_usda_package(test_package(name, report_date, "Colby", "5.10"), &structure, OnConflict::Keep, client).unwrap();
+
+    let rows = client.query(format!("SELECT value_text FROM {} ORDER BY region", table).as_str(), &[]).unwrap();
+    assert_eq!(rows.iter().map(|r| r.get(0)).collect::<Vec<String>>(), vec!["5.10", "5.99"]);
+}
+
+#[test]
+fn test_copy_into_tables_alike() {
+    use crate::usda::USDADataPackageSection;
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+
+    // two tables alike but for their last letter, and laid out differently, load through staging tables of their own
+    let name = "test_copy_into_tables_alike_but_for_their_last_letter";
+    let mut structure = test_structure(name);
+    structure.sections.get_mut("bids").unwrap().alias = Some("bids_a".to_owned());
+    let mut offers = structure.sections["bids"].clone();
+    offers.alias = Some("bids_b".to_owned());
+    offers.independent.push("class".to_owned());
+    structure.sections.insert("offers".to_owned(), offers);
+
+    for section in ["bids", "offers"] {
+        let table = structure.table_name(section);
+        client.batch_execute(&format!("DROP TABLE IF EXISTS {}", table)).unwrap();
+        create_table(table, &structure.sections[section].independent, client).unwrap();
+    }
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    let mut package = test_package(name, report_date, "Dodge City", "5.41");
+    let mut offer = USDADataPackageSection::new(report_date);
+    offer.independent.extend(["2020-03-02".to_owned(), "Colby".to_owned(), "Choice".to_owned()]);
+    offer.entries.insert("bid".to_owned(), "5.10".to_owned());
+    package.sections.insert("offers".to_owned(), vec![offer]);
+
+    assert_eq!(copy_usda_package(package, &structure, OnConflict::Keep, client), Ok(2));
+
+    let rows = client.query(format!("SELECT region, class FROM {}", structure.table_name("offers")).as_str(), &[]).unwrap();
+    assert_eq!(rows.iter().map(|r| (r.get(0), r.get(1))).collect::<Vec<(String, String)>>(), vec![("Colby".to_owned(), "Choice".to_owned())]);
+}
+
+#[test]
+fn test_skip_stored_rows() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_skip");
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+
+    client.batch_execute("DROP TABLE IF EXISTS test_skip_bids").unwrap();
+    create_table("test_skip_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    assert_eq!(insert_usda_package(test_package("test_skip", report_date, "Colby", "3.50"), &structure, OnConflict::Keep, client), Ok(1));
+
+    let rows: Vec<StagedRow> = staged_rows(test_package("test_skip", report_date, "Colby", "3.50").sections.remove("bids").unwrap(), &None, None);
+    let mut transaction = client.transaction().unwrap();
+    assert!(unstored_rows("test_skip", "test_skip_bids", &structure.sections["bids"].independent, rows, OnConflict::Update, &mut transaction).unwrap().is_empty());
+    transaction.rollback().unwrap();
+
+    assert_eq!(copy_usda_package(test_package("test_skip", report_date, "Colby", "3.50"), &structure, OnConflict::Update, client), Ok(0));
+    assert_eq!(insert_usda_package(test_package("test_skip", report_date, "Colby", "3.75"), &structure, OnConflict::Keep, client), Ok(0));
+    assert_eq!(insert_usda_package(test_package("test_skip", report_date, "Colby", "3.75"), &structure, OnConflict::Update, client), Ok(1));
+}
+
+#[test]
+fn test_conflict_update() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let mut structure = test_structure("test_update");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_update_bids").unwrap();
+    create_table("test_update_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    insert_usda_package(test_package("test_update", report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+
+    // unchanged values aren't counted, revised ones are written over on either path
+    assert_eq!(insert_usda_package(test_package("test_update", report_date, "Dodge City", "5.41"), &structure, OnConflict::Update, client), Ok(0));
+    assert_eq!(insert_usda_package(test_package("test_update", report_date, "Dodge City", "5.99"), &structure, OnConflict::Update, client), Ok(1));
+    assert_eq!(copy_usda_package(test_package("test_update", report_date, "Dodge City", "6.05"), &structure, OnConflict::Update, client), Ok(1));
+
+    let value: String = client.query_one("SELECT value_text FROM test_update_bids", &[]).unwrap().get(0);
+    assert_eq!(value, "6.05");
+
+    // a report's own setting takes precedence
+    structure.on_conflict = Some(OnConflict::Keep);
+    assert_eq!(copy_usda_package(test_package("test_update", report_date, "Dodge City", "6.10"), &structure, OnConflict::Update, client), Ok(0));
+}
+
+#[test]
+fn test_insert_rolls_back_on_failure() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_rollback_insert");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_rollback_insert_bids").unwrap();
+    create_table("test_rollback_insert_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+    client.batch_execute("ALTER TABLE test_rollback_insert_bids ADD CONSTRAINT no_garden_city CHECK (region <> 'Garden City')").unwrap();
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    let mut package = test_package("test_rollback_insert", report_date, "Colby", "3.50");
+    let garden_city = test_package("test_rollback_insert", report_date, "Garden City", "4.10").sections.remove("bids").unwrap();
+    package.sections.get_mut("bids").unwrap().extend(garden_city);
+
+    let error = insert_usda_package(package, &structure, OnConflict::Keep, client).unwrap_err();
+    assert!(error.contains("test_rollback_insert_bids"));
+    assert_eq!(client.query_one("SELECT COUNT(*) FROM test_rollback_insert_bids", &[]).unwrap().get::<_, i64>(0), 0);
+
+    assert_eq!(insert_usda_package(test_package("test_rollback_insert", report_date, "Colby", "3.50"), &structure, OnConflict::Keep, client), Ok(1));
+}
+
+#[test]
+fn test_copy_package() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_copy");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_copy_bids").unwrap();
+    create_table("test_copy_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    insert_usda_package(test_package("test_copy", report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+
+    let mut package = test_package("test_copy", report_date, "Dodge City", "5.99");
+    let colby = test_package("test_copy", report_date, "Colby", "1,234.5").sections.remove("bids").unwrap();
+    package.sections.get_mut("bids").unwrap().extend(colby);
+
+    // the conflicting row is kept as it was, and the staging table is reused by a second load
+    assert_eq!(copy_usda_package(package, &structure, OnConflict::Keep, client).unwrap(), 1);
+    assert_eq!(copy_usda_package(test_package("test_copy", report_date, "Colby", "1"), &structure, OnConflict::Keep, client).unwrap(), 0);
+
+    let rows = client.query("SELECT region, value, source FROM test_copy_bids ORDER BY region", &[]).unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].get::<_, Option<f32>>(1), Some(1234.5));
+    assert_eq!(rows[0].get::<_, Option<String>>(2), Some("test".to_owned()));
+    assert_eq!(rows[1].get::<_, Option<f32>>(1), Some(5.41));
+}
+
+#[test]
+fn test_track_revisions() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_revisions");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_revisions_bids; DROP TABLE IF EXISTS test_revisions_bids_history").unwrap();
+    create_table("test_revisions_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    copy_usda_package(test_package("test_revisions", report_date, "Colby", "3.50"), &structure, OnConflict::TrackRevisions, client).unwrap();
+
+    // an unchanged value is left alone, a changed one is revised twice
+    assert_eq!(copy_usda_package(test_package("test_revisions", report_date, "Colby", "3.50"), &structure, OnConflict::TrackRevisions, client), Ok(0));
+    assert_eq!(copy_usda_package(test_package("test_revisions", report_date, "Colby", "3.55"), &structure, OnConflict::TrackRevisions, client), Ok(1));
+    copy_usda_package(test_package("test_revisions", report_date, "Colby", "3.60"), &structure, OnConflict::TrackRevisions, client).unwrap();
+
+    let current: String = client.query_one("SELECT value_text FROM test_revisions_bids", &[]).unwrap().get(0);
+    assert_eq!(current, "3.60");
+
+    let history = client.query("SELECT value_text, valid_from IS NOT NULL, valid_to >= valid_from FROM test_revisions_bids_history ORDER BY valid_to, value_text", &[]).unwrap();
+    assert_eq!(history.iter().map(|r| r.get::<_, String>(0)).collect::<Vec<String>>(), vec!["3.50", "3.55"]);
+    assert!(history.iter().all(|r| r.get::<_, bool>(1) && r.get::<_, bool>(2)));
+}
+
+#[test]
+fn test_outdated_report_dates() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_outdated");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_outdated_bids").unwrap();
+    create_table("test_outdated_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+
+    for (day, version) in &[(2, None), (3, Some(1)), (4, Some(2))] {
+        let mut package = test_package("test_outdated", NaiveDate::from_ymd_opt(2020, 3, *day).unwrap(), "Dodge City", "5.41");
+        package.parser_version = *version;
+        insert_usda_package(package, &structure, OnConflict::Keep, client).unwrap();
+    }
+
+    let outdated = find_outdated_report_dates(&structure, 2, client).unwrap();
+    assert_eq!(outdated, vec![NaiveDate::from_ymd_opt(2020, 3, 2).unwrap(), NaiveDate::from_ymd_opt(2020, 3, 3).unwrap()]);
+
+    let mut transaction = client.transaction().unwrap();
+    assert_eq!(delete_report_date(&structure, outdated[0], &mut transaction).unwrap(), 1);
+    transaction.commit().unwrap();
+
+    assert_eq!(find_outdated_report_dates(&structure, 2, client).unwrap().len(), 1);
+}
+
+#[test]
+fn test_find_maximum_existing_date() {
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let structure = test_structure("test_maximum");
+
+    client.batch_execute("DROP TABLE IF EXISTS test_maximum_bids").unwrap();
+    assert!(find_maximum_existing_datamart_date(&structure, client).is_err()); // no table
+
+    create_table("test_maximum_bids".to_owned(), &structure.sections["bids"].independent, client).unwrap();
+    assert!(find_maximum_existing_datamart_date(&structure, client).is_err()); // no rows
+
+    for day in &[3, 9, 5] {
+        let report_date = NaiveDate::from_ymd_opt(2020, 3, *day).unwrap();
+        insert_usda_package(test_package("test_maximum", report_date, "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+    }
+
+    assert_eq!(find_maximum_existing_datamart_date(&structure, client), Ok(NaiveDate::from_ymd_opt(2020, 3, 9).unwrap()));
+}
+
+#[test]
+fn test_period_columns() {
+    use super::sink::Sink;
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let client = &mut database.client;
+    let date = |month, day| NaiveDate::from_ymd_opt(2020, month, day).unwrap();
+
+    for (name, period, start, end) in [("test_week", Period::Week, date(2, 25), date(3, 2)), ("test_month", Period::Month, date(3, 1), date(3, 31))] {
+        let mut structure = test_structure(name);
+        structure.period = Some(period);
+
+        client.batch_execute(&format!("DROP TABLE IF EXISTS {}_bids", name)).unwrap();
+        client.create_schema(&structure).unwrap();
+        client.create_schema(&structure).unwrap();
+        copy_usda_package(test_package(name, date(3, 2), "Dodge City", "5.41"), &structure, OnConflict::Keep, client).unwrap();
+
+        let row = client.query_one(format!("SELECT period_start, period_end FROM {}_bids", name).as_str(), &[]).unwrap();
+        assert_eq!((row.get::<_, NaiveDate>(0), row.get::<_, NaiveDate>(1)), (start, end));
     }
 }
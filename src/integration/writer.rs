@@ -0,0 +1,186 @@
+// Inserting packages on a thread of its own, so that a slow database no longer holds up the next slow fetch.
+//
+// Producers hand packages to a bounded queue and only wait when it is full; the writer thread owns its own
+// connection and drains the queue in order, loading each package into its sink (with COPY for PostgreSQL). A
+// writer started with `diffing` prints how each package differs from the stored data instead (--diff).
+//
+// A producer that needs to know when a package is stored, such as a backfill keeping a checkpoint, queues it with
+// `send_then`; the callback runs on the writer thread once the package is written, and never when diffing. What
+// was written, and which reports failed, is noted in `ingested` for the derived series to be recomputed after (see
+// derived).
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crate::metrics;
+use crate::usda::USDADataPackage;
+use crate::usda::datamart::DatamartConfig;
+use super::derived::Ingested;
+use super::pool::Connection;
+use super::diff::{diff_usda_package, write_diff};
+use super::sink::Sink;
+use super::usda::OnConflict;
+
+/// Packages waiting to be written at most; kept small as a package can be an entire report history
+const WRITE_QUEUE_CAPACITY: usize = 2;
+
+/// Called by the writer thread once a package has been written
+pub type OnWritten = Box<dyn FnOnce() + Send>;
+
+pub struct PackageWriter {
+    sender: SyncSender<(USDADataPackage, DatamartConfig, Option<OnWritten>)>,
+    handle: JoinHandle<usize>,
+    ingested: Arc<Mutex<Ingested>>
+}
+
+/// What the writer thread does with each package
+enum Destination {
+    Load(Box<dyn Sink>, OnConflict),
+    Diff(Box<Connection>)
+}
+
+impl PackageWriter {
+    /// Starts the writer thread, which inserts into `sink` until `finish` is called
+    pub fn new<S: Sink + 'static>(sink: S, on_conflict: OnConflict) -> PackageWriter {
+        PackageWriter::start(Destination::Load(Box::new(sink), on_conflict))
+    }
+
+    /// Starts a writer that compares packages with the stored data and prints the differences, inserting nothing
+    pub fn diffing(client: Connection) -> PackageWriter {
+        PackageWriter::start(Destination::Diff(Box::new(client)))
+    }
+
+    fn start(mut destination: Destination) -> PackageWriter {
+        let (sender, receiver) = sync_channel::<(USDADataPackage, DatamartConfig, Option<OnWritten>)>(WRITE_QUEUE_CAPACITY);
+        let ingested = Arc::new(Mutex::new(Ingested::default()));
+        let noted = ingested.clone();
+
+        let handle = thread::spawn(move || {
+            let mut failures = 0;
+
+            for (package, structure, on_written) in receiver {
+                let name = package.name.to_owned();
+
+                let report = [("report", structure.name.as_str())];
+
+                let (action, result, on_written) = match &mut destination {
+                    Destination::Load(sink, on_conflict) => {
+                        let mut written = Ingested::default();
+                        written.record_written(&package, &structure);
+
+                        let inserted = sink.insert_package(package, &structure, *on_conflict);
+                        let mut noted = noted.lock().unwrap();
+                        match &inserted {
+                            Ok(rows) => {
+                                metrics::add(&metrics::ROWS_WRITTEN, &report, *rows as f64);
+                                noted.merge(written);
+                            },
+                            Err(_) => {
+                                metrics::add(&metrics::INSERT_FAILURES, &report, 1.0);
+                                noted.failed.extend(written.written.into_keys());
+                            }
+                        }
+                        ("insert", inserted.map(|_| ()), on_written)
+                    },
+                    // nothing is stored when comparing, so nothing waiting on the package is told it was
+                    Destination::Diff(client) => { ("compare", diff_usda_package(package, &structure, client).and_then(|d| write_diff(&d, std::io::stdout())), None) }
+                };
+
+                match result {
+                    Ok(_) => { on_written.into_iter().for_each(|f| f()) },
+                    Err(e) => {
+                        error!("Failed to {} {}: {}", action, name, e);
+                        failures += 1;
+                    }
+                }
+            }
+
+            failures
+        });
+
+        PackageWriter { sender, handle, ingested }
+    }
+
+    /// Queues a package for insertion, waiting while the queue is full
+    pub fn send(&self, package: USDADataPackage, structure: &DatamartConfig) -> Result<(), String> {
+        self.sender.send((package, structure.clone(), None)).map_err(|_| "The database writer has stopped".to_owned())
+    }
+
+    /// Queues a package for insertion as `send` does, calling `on_written` once it has been written
+    pub fn send_then(&self, package: USDADataPackage, structure: &DatamartConfig, on_written: OnWritten) -> Result<(), String> {
+        self.sender.send((package, structure.clone(), Some(on_written))).map_err(|_| "The database writer has stopped".to_owned())
+    }
+
+    /// Notes that a report could not be fetched, so that nothing derived from it is recomputed
+    pub fn record_failure(&self, structure: &DatamartConfig) {
+        self.ingested.lock().unwrap().record_failure(structure);
+    }
+
+    /// What has been written so far, and which reports failed. Complete once `finish` returns.
+    pub fn ingested(&self) -> Arc<Mutex<Ingested>> {
+        self.ingested.clone()
+    }
+
+    /// Waits for every queued package to be written, returning how many could not be
+    pub fn finish(self) -> Result<usize, String> {
+        drop(self.sender);
+        self.handle.join().map_err(|_| "The database writer panicked".to_owned())
+    }
+}
+
+#[test]
+fn test_package_writer() {
+    use chrono::NaiveDate;
+    use super::usda::{create_table, test_package, test_structure};
+
+    let mut database = match super::test_postgres::test_database() { Some(d) => { d }, None => { return } };
+    let structure = test_structure("test_writer");
+
+    database.client.batch_execute("DROP TABLE IF EXISTS test_writer_bids").unwrap();
+    create_table("test_writer_bids".to_owned(), &structure.sections["bids"].independent, &mut database.client).unwrap();
+
+    let pool = database.pool(1);
+    let writer = PackageWriter::new(pool.get().unwrap(), OnConflict::Keep);
+    for day in 1..=5 {
+        let report_date = NaiveDate::from_ymd_opt(2020, 3, day).unwrap();
+        writer.send(test_package("test_writer", report_date, "Dodge City", "5.41"), &structure).unwrap();
+    }
+
+    assert_eq!(writer.finish(), Ok(0));
+    assert_eq!(database.client.query_one("SELECT COUNT(*) FROM test_writer_bids", &[]).unwrap().get::<_, i64>(0), 5);
+}
+
+#[test]
+fn test_package_writer_with_sink() {
+    use chrono::NaiveDate;
+    use super::sink::MemorySink;
+    use super::usda::{test_package, test_structure};
+
+    let structure = test_structure("test_writer");
+    let missing = test_structure("test_missing"); // its tables aren't created
+    let mut sink = MemorySink::default();
+    sink.create_schema(&structure).unwrap();
+
+    let writer = PackageWriter::new(sink.clone(), OnConflict::Keep);
+    let ingested = writer.ingested();
+    for day in 1..=3 {
+        writer.send(test_package("test_writer", NaiveDate::from_ymd_opt(2020, 3, day).unwrap(), "Dodge City", "5.41"), &structure).unwrap();
+    }
+    writer.send(test_package("test_missing", NaiveDate::from_ymd_opt(2020, 3, 4).unwrap(), "Dodge City", "5.41"), &missing).unwrap();
+
+    // only packages written are reported as such
+    let (written, receiver) = std::sync::mpsc::channel();
+    for (name, day, structure) in [("test_writer", 5, &structure), ("test_missing", 6, &missing)] {
+        let written = written.clone();
+        writer.send_then(test_package(name, NaiveDate::from_ymd_opt(2020, 3, day).unwrap(), "Dodge City", "5.41"), structure, Box::new(move || written.send(name).unwrap())).unwrap();
+    }
+
+    assert_eq!(writer.finish(), Ok(2));
+    assert_eq!(sink.tables.lock().unwrap()["test_writer_bids"].len(), 4);
+    assert_eq!(receiver.try_iter().collect::<Vec<&str>>(), vec!["test_writer"]);
+
+    let ingested = ingested.lock().unwrap();
+    assert_eq!(ingested.written["test_writer_bids"], (NaiveDate::from_ymd_opt(2020, 3, 1).unwrap(), NaiveDate::from_ymd_opt(2020, 3, 5).unwrap()));
+    assert!(ingested.failed.contains("test_missing_bids"));
+}
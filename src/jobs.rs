@@ -0,0 +1,67 @@
+// Running slow, independent downloads side by side on a fixed number of worker threads.
+//
+// Fetches spend nearly all of their time waiting on USDA servers, so plain threads each blocking in ureq are
+// enough; there is no need for an async runtime. Work is handed out one item at a time, so a slow report
+// doesn't hold up the items queued behind it.
+
+use std::sync::Mutex;
+use std::thread;
+
+/// Applies `f` to every item on up to `jobs` threads, returning the results in the order of `items`.
+/// With one job the items are processed in order on the calling thread.
+pub fn parallel_map<T, R, F>(items: Vec<T>, jobs: usize, f: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.into_iter().map(f).collect();
+    }
+
+    let workers = jobs.min(items.len());
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    let queue = Mutex::new(items.into_iter().enumerate());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    // the lock is released before the work starts
+                    let next = queue.lock().unwrap().next();
+                    let (index, item) = match next {
+                        Some(n) => { n },
+                        None => { break }
+                    };
+
+                    let result = f(item);
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results.into_inner().unwrap().into_iter().map(|r| r.expect("Every queued item is processed")).collect()
+}
+
+#[test]
+fn test_parallel_map() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    let running = AtomicUsize::new(0);
+    let most_running = AtomicUsize::new(0);
+
+    let results = parallel_map((0..12).collect(), 3, |i: u64| {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        most_running.fetch_max(now, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(10 * (i % 3)));
+        running.fetch_sub(1, Ordering::SeqCst);
+        i * 2
+    });
+
+    assert_eq!(results, (0..12).map(|i| i * 2).collect::<Vec<u64>>());
+    assert!(most_running.load(Ordering::SeqCst) <= 3);
+
+    assert_eq!(parallel_map(vec!["a", "b"], 1, |s| s.to_uppercase()), vec!["A", "B"]);
+}
@@ -0,0 +1,41 @@
+//! Downloads, parses, and inserts USDA (and NOAA) data into PostgreSQL.
+//!
+//! The `data-acquisition` binary is a thin wrapper over `cli::main`. Projects that only need the data can use the
+//! parsers directly: `usda::legacy`, `usda::datamart`, `usda::esmis` and `usda::mars` produce `USDADataPackage`s
+//! without touching a database, and `noaa` reads GHCN-Daily observations. `integration` holds the PostgreSQL side.
+//! Crates adding sources of their own implement `datasource::DataSource`, register them, and call `cli::main` from
+//! a binary of their own.
+
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+
+pub mod archive;
+pub mod checkpoint;
+pub mod cli;
+pub mod config;
+pub mod datasource;
+pub mod digest;
+pub mod export;
+pub mod http;
+pub mod integration;
+pub mod jobs;
+pub mod memory;
+pub mod metrics;
+pub mod mirror;
+pub mod noaa;
+pub mod overrides;
+pub mod profiles;
+pub mod releases;
+pub mod remote;
+pub mod scrape;
+pub mod secret;
+pub mod selftest;
+pub mod session;
+pub mod service;
+pub mod usda;
+pub mod watch;
+pub mod webhook;
+
+pub use usda::{USDADataPackage, USDADataPackageSection};
@@ -0,0 +1,66 @@
+// Keeping full backfills inside a memory budget (--max-memory-mb), so they survive on small machines.
+//
+// Pipelines that would otherwise hold a whole download or a whole report in memory ask the budget how much to
+// hold at once, and hand over what they have early if the process is getting close to the limit anyway.
+
+use std::fs;
+
+/// Share of the limit a pipeline plans to fill with buffered records, leaving the rest for everything else
+const BATCH_SHARE: u64 = 4;
+/// Percentage of the limit at which buffered records are flushed regardless of batch size
+const FLUSH_PERCENT: u64 = 80;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryBudget {
+    limit_mb: Option<u64>
+}
+
+impl MemoryBudget {
+    pub fn new(limit_mb: Option<u64>) -> MemoryBudget {
+        MemoryBudget { limit_mb }
+    }
+
+    pub fn unlimited() -> MemoryBudget {
+        MemoryBudget { limit_mb: None }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.limit_mb.is_some()
+    }
+
+    /// How many items of roughly `item_bytes` each to buffer at once, or None for no limit
+    pub fn batch_size(&self, item_bytes: usize) -> Option<usize> {
+        self.limit_mb.map(|limit| ((limit * 1024 * 1024 / BATCH_SHARE) as usize / item_bytes.max(1)).max(1))
+    }
+
+    /// Whether the process is close enough to the limit that buffers should be flushed now. Always false where
+    /// the resident set size can't be read (anywhere but Linux).
+    pub fn is_approaching_limit(&self) -> bool {
+        match (self.limit_mb, resident_mb()) {
+            (Some(limit), Some(resident)) => { resident * 100 >= limit * FLUSH_PERCENT },
+            (_, _) => { false }
+        }
+    }
+}
+
+/// The resident set size of this process in megabytes, from /proc/self/status
+pub fn resident_mb() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+    Some(kilobytes / 1024)
+}
+
+#[test]
+fn test_memory_budget() {
+    assert_eq!(MemoryBudget::unlimited().batch_size(100), None);
+    assert_eq!(MemoryBudget::new(Some(4)).batch_size(1024), Some(1024));
+    assert_eq!(MemoryBudget::new(Some(1)).batch_size(usize::MAX), Some(1));
+    assert!(!MemoryBudget::unlimited().is_approaching_limit());
+
+    if resident_mb().is_some() {
+        assert!(MemoryBudget::new(Some(1)).is_approaching_limit());
+        assert!(!MemoryBudget::new(Some(1024 * 1024)).is_approaching_limit());
+    }
+}
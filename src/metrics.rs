@@ -0,0 +1,205 @@
+// Prometheus metrics of ingestion, for monitoring scheduled runs and alerting on them. The daemon serves them with
+// --metrics-listen in the Prometheus text format at GET /metrics.
+//
+// Metrics are kept for the life of the process in one registry, labelled by report or host:
+//
+//     data_acquisition_rows_written_total{report}            new and revised rows written
+//     data_acquisition_insert_failures_total{report}         packages that failed to be written
+//     data_acquisition_parse_failures_total{report}          releases and responses that failed to parse
+//     data_acquisition_malformed_values_total{report}        values of typed datamart fields not of their type
+//     data_acquisition_http_request_duration_seconds{host}   USDA API requests, retries included, as a histogram
+//     data_acquisition_last_update_timestamp_seconds         when the daemon last finished an update pass
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tiny_http::{Header, Method, Response, Server};
+
+/// Upper bounds of the request duration buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    kind: Kind
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram(&'static [f64])
+}
+
+pub const ROWS_WRITTEN: Metric = Metric { name: "data_acquisition_rows_written_total", help: "New and revised rows written", kind: Kind::Counter };
+pub const ROWS_SKIPPED: Metric = Metric { name: "data_acquisition_rows_skipped_total", help: "Fetched rows already stored, not written again", kind: Kind::Counter };
+pub const INSERT_FAILURES: Metric = Metric { name: "data_acquisition_insert_failures_total", help: "Packages that failed to be written", kind: Kind::Counter };
+pub const PARSE_FAILURES: Metric = Metric { name: "data_acquisition_parse_failures_total", help: "Releases and responses that failed to parse", kind: Kind::Counter };
+pub const MALFORMED_VALUES: Metric = Metric { name: "data_acquisition_malformed_values_total", help: "Values of typed datamart fields not of their type", kind: Kind::Counter };
+pub const HTTP_REQUEST_DURATION: Metric = Metric {
+    name: "data_acquisition_http_request_duration_seconds",
+    help: "Duration of requests to the USDA APIs",
+    kind: Kind::Histogram(DURATION_BUCKETS)
+};
+pub const LAST_UPDATE: Metric = Metric { name: "data_acquisition_last_update_timestamp_seconds", help: "When the last update pass finished", kind: Kind::Gauge };
+
+/// The counts of a histogram: observations in each bucket (not cumulative), then their sum and number
+#[derive(Clone, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64
+}
+
+/// Series by metric name, then by their labels rendered as `key="value",...`
+#[derive(Default)]
+struct Registry {
+    values: BTreeMap<&'static str, (&'static Metric, BTreeMap<String, f64>)>,
+    histograms: BTreeMap<&'static str, (&'static Metric, BTreeMap<String, Histogram>)>
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels.iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+impl Registry {
+    fn add(&mut self, metric: &'static Metric, labels: &[(&str, &str)], by: f64) {
+        let (_, series) = self.values.entry(metric.name).or_insert_with(|| (metric, BTreeMap::new()));
+        *series.entry(render_labels(labels)).or_default() += by;
+    }
+
+    fn set(&mut self, metric: &'static Metric, labels: &[(&str, &str)], value: f64) {
+        let (_, series) = self.values.entry(metric.name).or_insert_with(|| (metric, BTreeMap::new()));
+        series.insert(render_labels(labels), value);
+    }
+
+    fn observe(&mut self, metric: &'static Metric, labels: &[(&str, &str)], value: f64) {
+        let bounds = match metric.kind {
+            Kind::Histogram(bounds) => { bounds },
+            _ => { return }
+        };
+
+        let (_, series) = self.histograms.entry(metric.name).or_insert_with(|| (metric, BTreeMap::new()));
+        let histogram = series.entry(render_labels(labels)).or_insert_with(|| Histogram { buckets: vec![0; bounds.len()], ..Default::default() });
+
+        if let Some(bucket) = bounds.iter().position(|bound| value <= *bound) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+
+    /// The Prometheus text exposition of every series
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let with = |labels: &str, extra: &str| -> String {
+            match (labels.is_empty(), extra.is_empty()) {
+                (true, true) => { String::new() },
+                (false, true) => { format!("{{{}}}", labels) },
+                (true, false) => { format!("{{{}}}", extra) },
+                (false, false) => { format!("{{{},{}}}", labels, extra) }
+            }
+        };
+
+        for (name, (metric, series)) in &self.values {
+            let kind = if metric.kind == Kind::Gauge { "gauge" } else { "counter" };
+            let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, metric.help, name, kind);
+            for (labels, value) in series {
+                let _ = writeln!(text, "{}{} {}", name, with(labels, ""), value);
+            }
+        }
+
+        for (name, (metric, series)) in &self.histograms {
+            let bounds = match metric.kind { Kind::Histogram(bounds) => { bounds }, _ => { continue } };
+            let _ = writeln!(text, "# HELP {} {}\n# TYPE {} histogram", name, metric.help, name);
+
+            for (labels, histogram) in series {
+                let mut cumulative = 0;
+                for (bound, count) in bounds.iter().zip(&histogram.buckets) {
+                    cumulative += count;
+                    let _ = writeln!(text, "{}_bucket{} {}", name, with(labels, &format!("le=\"{}\"", bound)), cumulative);
+                }
+                let _ = writeln!(text, "{}_bucket{} {}", name, with(labels, "le=\"+Inf\""), histogram.count);
+                let _ = writeln!(text, "{}_sum{} {}", name, with(labels, ""), histogram.sum);
+                let _ = writeln!(text, "{}_count{} {}", name, with(labels, ""), histogram.count);
+            }
+        }
+
+        text
+    }
+}
+
+/// Adds to a counter
+pub fn add(metric: &'static Metric, labels: &[(&str, &str)], by: f64) {
+    REGISTRY.lock().unwrap().add(metric, labels, by);
+}
+
+/// Sets a gauge
+pub fn set(metric: &'static Metric, labels: &[(&str, &str)], value: f64) {
+    REGISTRY.lock().unwrap().set(metric, labels, value);
+}
+
+/// Records a duration in a histogram
+pub fn observe_duration(metric: &'static Metric, labels: &[(&str, &str)], duration: Duration) {
+    REGISTRY.lock().unwrap().observe(metric, labels, duration.as_secs_f64());
+}
+
+pub fn count_parse_failure(report: &str) {
+    add(&PARSE_FAILURES, &[("report", report)], 1.0);
+}
+
+pub fn render() -> String {
+    REGISTRY.lock().unwrap().render()
+}
+
+/// Serves the metrics at GET /metrics on `address`, on a thread of its own
+pub fn serve(address: &str) -> Result<JoinHandle<()>, String> {
+    let server = Server::http(address).map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+    let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+
+    Ok(std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if *request.method() == Method::Get && request.url() == "/metrics" {
+                Response::from_string(render()).with_header(content_type.clone())
+            } else {
+                Response::from_string("Not found").with_status_code(404)
+            };
+
+            if let Err(e) = request.respond(response) {
+                error!("Failed to answer metrics request: {}", e);
+            }
+        }
+    }))
+}
+
+#[test]
+fn test_render() {
+    let mut registry = Registry::default();
+
+    registry.add(&ROWS_WRITTEN, &[("report", "lm_ct100")], 12.0);
+    registry.add(&ROWS_WRITTEN, &[("report", "lm_ct100")], 3.0);
+    registry.add(&PARSE_FAILURES, &[("report", "LM_\"XB463\"")], 1.0);
+    registry.set(&LAST_UPDATE, &[], 1583150400.0);
+    registry.observe(&HTTP_REQUEST_DURATION, &[("host", "marsapi.ams.usda.gov")], 0.3);
+    registry.observe(&HTTP_REQUEST_DURATION, &[("host", "marsapi.ams.usda.gov")], 400.0);
+
+    let text = registry.render();
+
+    assert!(text.contains("# TYPE data_acquisition_rows_written_total counter\ndata_acquisition_rows_written_total{report=\"lm_ct100\"} 15\n"));
+    assert!(text.contains("data_acquisition_parse_failures_total{report=\"LM_\\\"XB463\\\"\"} 1\n"));
+    assert!(text.contains("# TYPE data_acquisition_last_update_timestamp_seconds gauge\ndata_acquisition_last_update_timestamp_seconds 1583150400\n"));
+    assert!(text.contains("data_acquisition_http_request_duration_seconds_bucket{host=\"marsapi.ams.usda.gov\",le=\"0.25\"} 0\n"));
+    assert!(text.contains("data_acquisition_http_request_duration_seconds_bucket{host=\"marsapi.ams.usda.gov\",le=\"0.5\"} 1\n"));
+    assert!(text.contains("data_acquisition_http_request_duration_seconds_bucket{host=\"marsapi.ams.usda.gov\",le=\"+Inf\"} 2\n"));
+    assert!(text.contains("data_acquisition_http_request_duration_seconds_count{host=\"marsapi.ams.usda.gov\"} 2\n"));
+}
@@ -0,0 +1,237 @@
+// Serving the raw document archive over HTTP, so a team can share one USDA mirror instead of each analyst
+// downloading from the slow USDA sources themselves.
+//
+// The archive is presented as a directory per report holding a <YYYY-MM-DD>.txt document per report date, whatever
+// its layout on disk (see archive). Directories are listed, and each client address is held to a number of requests
+// per minute so that one script can't monopolise the mirror.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDate;
+use percent_encoding::{AsciiSet, CONTROLS};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Worker threads answering requests; documents are small, so a few are plenty
+const MIRROR_WORKERS: usize = 4;
+
+/// Characters escaped in listing links
+const HREF_ESCAPED: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`');
+
+/// Counts requests per client address over fixed one minute windows
+pub struct RateLimiter {
+    per_minute: u32,
+    clients: HashMap<IpAddr, (Instant, u32)>
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> RateLimiter {
+        RateLimiter { per_minute, clients: HashMap::new() }
+    }
+
+    /// Counts a request, giving whether it is within the client's allowance
+    pub fn allow(&mut self, client: IpAddr, now: Instant) -> bool {
+        let window = Duration::from_secs(60);
+
+        // forget clients whose window has passed, so the table doesn't grow without bound
+        if self.clients.len() > 10_000 {
+            self.clients.retain(|_, (start, _)| now.duration_since(*start) < window);
+        }
+
+        let (start, count) = self.clients.entry(client).or_insert((now, 0));
+        if now.duration_since(*start) >= window {
+            *start = now;
+            *count = 0;
+        }
+
+        *count += 1;
+        *count <= self.per_minute
+    }
+}
+
+/// What a request path names in the archive
+#[derive(Debug, PartialEq)]
+pub enum Resource {
+    Reports,
+    Report(String),
+    Document(String, NaiveDate)
+}
+
+/// Maps a request path onto the archive, refusing anything but report identifiers and dated documents
+pub fn resolve(url: &str) -> Result<Resource, (u16, String)> {
+    let path = url.split('?').next().unwrap_or_default();
+    let path = percent_encoding::percent_decode_str(path).decode_utf8().map_err(|_| (400, "Invalid path".to_owned()))?;
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+
+    let identifier = match components.first() {
+        Some(identifier) => { identifier.to_uppercase() },
+        None => { return Ok(Resource::Reports) }
+    };
+    if !identifier.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err((400, "Invalid path".to_owned()));
+    }
+
+    match components.get(1..) {
+        Some([]) => { Ok(Resource::Report(identifier)) },
+        Some([document]) => {
+            document.strip_suffix(".txt")
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+                .map(|date| Resource::Document(identifier, date))
+                .ok_or_else(|| (404, "Not found".to_owned()))
+        },
+        _ => { Err((404, "Not found".to_owned())) }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// An HTML index of a directory's entries, given as (is a directory, name), subdirectories first
+pub fn listing(url_path: &str, mut entries: Vec<(bool, String)>) -> String {
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    let title = escape_html(url_path);
+    let mut body = format!("<!DOCTYPE html>\n<html><head><title>Index of {0}</title></head><body>\n<h1>Index of {0}</h1>\n<ul>\n", title);
+
+    if url_path != "/" {
+        body.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+
+    for (is_dir, name) in entries {
+        let name = if is_dir { format!("{}/", name) } else { name };
+        let href = percent_encoding::utf8_percent_encode(&name, HREF_ESCAPED).to_string();
+        body.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", href, escape_html(&name)));
+    }
+
+    body.push_str("</ul>\n</body></html>\n");
+    body
+}
+
+/// The entries of a listing of the archive's reports, or of one report's documents
+fn list(root: &Path, resource: &Resource) -> Result<Vec<(bool, String)>, String> {
+    match resource {
+        Resource::Reports => {
+            Ok(crate::archive::identifiers(root)?.into_iter().map(|identifier| (true, identifier)).collect())
+        },
+        Resource::Report(identifier) => {
+            let index = crate::archive::read_index(root, identifier)?;
+            Ok(index.keys().map(|date| (false, format!("{}.txt", date.format("%Y-%m-%d")))).collect())
+        },
+        Resource::Document(_, _) => { Ok(Vec::new()) }
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header::from_bytes(field.as_bytes(), value.as_bytes()).unwrap()
+}
+
+fn answer(request: Request, root: &Path, limiter: &Mutex<RateLimiter>) -> Result<(), std::io::Error> {
+    if *request.method() != Method::Get && *request.method() != Method::Head {
+        return request.respond(Response::from_string("Only GET is supported").with_status_code(405));
+    }
+
+    if let Some(address) = request.remote_addr() {
+        if !limiter.lock().unwrap().allow(address.ip(), Instant::now()) {
+            return request.respond(Response::from_string("Too many requests").with_status_code(429).with_header(header("Retry-After", "60")));
+        }
+    }
+
+    let url = request.url().to_owned();
+    let resource = match resolve(&url) {
+        Ok(r) => { r },
+        Err((status, message)) => { return request.respond(Response::from_string(message).with_status_code(status)) }
+    };
+
+    if let Resource::Document(identifier, report_date) = &resource {
+        return match crate::archive::read_document(root, identifier, *report_date) {
+            Ok(text) => { request.respond(Response::from_string(text).with_header(header("Content-Type", "text/plain; charset=utf-8"))) },
+            Err(_) => { request.respond(Response::from_string("Not found").with_status_code(404)) }
+        };
+    }
+
+    let url_path = url.split('?').next().unwrap_or_default().to_owned();
+    if !url_path.ends_with('/') {
+        return request.respond(Response::empty(301).with_header(header("Location", &format!("{}/", url_path))));
+    }
+
+    match list(root, &resource) {
+        Ok(entries) if entries.is_empty() && resource != Resource::Reports => {
+            request.respond(Response::from_string("Not found").with_status_code(404))
+        },
+        Ok(entries) => {
+            request.respond(Response::from_string(listing(&url_path, entries)).with_header(header("Content-Type", "text/html; charset=utf-8")))
+        },
+        Err(e) => {
+            error!("{}", e);
+            request.respond(Response::from_string("Failed to list directory").with_status_code(500))
+        }
+    }
+}
+
+/// Serves the archive under `root` on `address` until the process is stopped
+pub fn serve_archive(address: &str, root: &Path, requests_per_minute: u32) -> Result<(), String> {
+    if !root.is_dir() {
+        return Err(format!("Archive directory does not exist: {}", root.display()));
+    }
+
+    let server = Arc::new(Server::http(address).map_err(|e| format!("Failed to listen on {}: {}", address, e))?);
+    let limiter = Arc::new(Mutex::new(RateLimiter::new(requests_per_minute)));
+
+    let workers: Vec<std::thread::JoinHandle<()>> = (0..MIRROR_WORKERS).map(|_| {
+        let (server, limiter, root) = (server.clone(), limiter.clone(), root.to_path_buf());
+
+        std::thread::spawn(move || {
+            for request in server.incoming_requests() {
+                if let Err(e) = answer(request, &root, &limiter) {
+                    error!("Failed to answer mirror request: {}", e);
+                }
+            }
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().map_err(|_| "A mirror worker panicked".to_owned())?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_rate_limiter() {
+    let mut limiter = RateLimiter::new(2);
+    let (client, other) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+    let now = Instant::now();
+
+    assert!(limiter.allow(client, now));
+    assert!(limiter.allow(client, now));
+    assert!(!limiter.allow(client, now + Duration::from_secs(30)));
+    assert!(limiter.allow(other, now));
+    assert!(limiter.allow(client, now + Duration::from_secs(61)));
+}
+
+#[test]
+fn test_resolve_and_list() {
+    let root = std::env::temp_dir().join(format!("data-acquisition-mirror-test-{}", std::process::id()));
+    let report_date = NaiveDate::from_ymd_opt(2020, 3, 6).unwrap();
+    crate::archive::store_document(&root, "LM_XB463", report_date, "Boxed beef").unwrap();
+
+    assert_eq!(resolve("/"), Ok(Resource::Reports));
+    assert_eq!(resolve("/lm_xb463/"), Ok(Resource::Report("LM_XB463".to_owned())));
+    assert_eq!(resolve("/LM_XB463/2020-03-06.txt?x=1"), Ok(Resource::Document("LM_XB463".to_owned(), report_date)));
+    assert_eq!(resolve("/LM_XB463/../../etc/passwd").unwrap_err().0, 404);
+    assert_eq!(resolve("/%2E%2E/secret.toml").unwrap_err().0, 400);
+    assert_eq!(resolve("/blobs/ab/").unwrap_err().0, 404);
+
+    let index = listing("/", list(&root, &Resource::Reports).unwrap());
+    assert!(index.contains("<a href=\"LM_XB463/\">LM_XB463/</a>"));
+    assert!(!index.contains("../"));
+
+    let index = listing("/LM_XB463/", list(&root, &Resource::Report("LM_XB463".to_owned())).unwrap());
+    assert!(index.contains("<a href=\"2020-03-06.txt\">2020-03-06.txt</a>"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
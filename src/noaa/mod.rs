@@ -2,19 +2,25 @@ extern crate ftp;
 
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
 use std::io::{Read, Cursor};
+use std::path::Path;
 use std::convert::TryInto;
 use std::result;
 
 use fixed_width::{Reader, FixedWidth, Field, LineBreak};
 use flate2::read::GzDecoder;
-use ftp::FtpStream;
+use ftp::{FtpError, FtpStream};
 use ftp::types::FileType::Binary;
 use tar::Archive;
 
 use serde::{Deserialize, Deserializer};
 use serde::de::Error;
 
+use crate::memory::MemoryBudget;
+use crate::session;
+
 /*pub enum Element {
     Precipitation,  // PRCP, tenths of mm
     Snowfall,       // SNOW (mm)
@@ -177,6 +183,13 @@ pub struct Observation {
     pub observations: Vec<DailyObservation>
 }
 
+impl Observation {
+    /// Rough heap and inline size of one parsed observation (a station-month of one element), for memory budgeting
+    pub fn estimated_size() -> usize {
+        std::mem::size_of::<Observation>() + 32 + 31 * (std::mem::size_of::<DailyObservation>() + 8)
+    }
+}
+
 impl FixedWidth for Observation {
     fn fields() -> Vec<Field> {
         let mut field_vec = vec![
@@ -213,6 +226,50 @@ impl fmt::Display for Observation {
 
 /// Retrieve NOAA GHCND GSN archive, identifying ourselves with "email"
 pub fn retrieve_noaa_ftp(email: &str) -> Result<Cursor<Vec<u8>>, String> {
+    retrieve_ftp_file(email, "/pub/data/ghcn/daily/ghcnd_gsn.tar.gz")
+}
+
+/// Retrieve the GHCND station list (locations and names), identifying ourselves with "email"
+pub fn retrieve_noaa_stations_ftp(email: &str) -> Result<Cursor<Vec<u8>>, String> {
+    retrieve_ftp_file(email, "/pub/data/ghcn/daily/ghcnd-stations.txt")
+}
+
+/// Retrieve the GHCND GSN archive into a file at `destination` rather than into memory, for use under a memory budget
+pub fn retrieve_noaa_ftp_to_disk(email: &str, destination: &Path) -> Result<fs::File, String> {
+    let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(destination)
+        .map_err(|e| format!("Failed to create {}: {}", destination.display(), e))?;
+
+    let path = "/pub/data/ghcn/daily/ghcnd_gsn.tar.gz";
+    session::ftp_to_file(path, &mut file, |file| {
+        let mut ftp_stream = connect_ftp(email)?;
+
+        let written = ftp_stream.retr(path, |reader| {
+            let mut writer = &*file;
+            io::copy(reader, &mut writer).map_err(FtpError::ConnectionError)
+        });
+
+        written.map(|_| ()).map_err(|e| format!("Failed to read stream: {}", e))
+    })?;
+
+    Ok(file)
+}
+
+/// Lists the GHCND directory, to check that the FTP server can be reached and logged in to. Returns the number of
+/// entries listed.
+pub fn check_noaa_ftp(email: &str) -> Result<usize, String> {
+    let listing = session::ftp("nlst /pub/data/ghcn/daily", || {
+        let mut ftp_stream = connect_ftp(email)?;
+
+        let listing = ftp_stream.nlst(Some("/pub/data/ghcn/daily")).map_err(|e| format!("Failed to list the GHCND directory: {}", e))?;
+        let _ = ftp_stream.quit();
+
+        Ok(listing.join("\n").into_bytes())
+    })?;
+
+    Ok(String::from_utf8_lossy(&listing).lines().count())
+}
+
+fn connect_ftp(email: &str) -> Result<FtpStream, String> {
     let mut ftp_stream = {
         match FtpStream::connect("ftp.ncdc.noaa.gov:21") {
             Ok(stream) => { stream },
@@ -222,7 +279,7 @@ pub fn retrieve_noaa_ftp(email: &str) -> Result<Cursor<Vec<u8>>, String> {
         }
     };
 
-    match ftp_stream.login("anonymous", &email) {
+    match ftp_stream.login("anonymous", email) {
         Ok(_) => {},
         Err(e) => {
             return Err(e.to_string())
@@ -236,21 +293,171 @@ pub fn retrieve_noaa_ftp(email: &str) -> Result<Cursor<Vec<u8>>, String> {
         }
     }
 
-    let cursor = { 
-        match ftp_stream.simple_retr("/pub/data/ghcn/daily/ghcnd_gsn.tar.gz") {
-            Ok(stream) => { stream },
-            Err(e) => {
-                return Err(format!("Failed to read stream: {}", e))
+    Ok(ftp_stream)
+}
+
+fn retrieve_ftp_file(email: &str, path: &str) -> Result<Cursor<Vec<u8>>, String> {
+    let bytes = session::ftp(path, || {
+        let mut ftp_stream = connect_ftp(email)?;
+
+        match ftp_stream.simple_retr(path) {
+            Ok(cursor) => { Ok(cursor.into_inner()) },
+            Err(e) => { Err(format!("Failed to read stream: {}", e)) }
+        }
+    })?;
+
+    Ok(Cursor::new(bytes))
+}
+
+/// Unit system for stored NOAA values, see config/noaa.toml
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NoaaUnits {
+    Metric,
+    Imperial,
+    Both
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NoaaConfig {
+    pub elements: Vec<String>,
+    pub countries: Vec<String>,
+    pub units: NoaaUnits,
+    pub indexes: Option<Vec<Vec<String>>>     // secondary indexes of the element tables, see integration::indexes
+}
+
+/// Converts a native GHCN daily value to degrees Fahrenheit or inches, for the elements where that makes sense.
+pub fn to_imperial(element: &str, value: isize) -> Option<f64> {
+    let value = value as f64;
+
+    match element {
+        // tenths of degrees C
+        "TMAX" | "TMIN" | "TAVG" | "TOBS" | "MDTN" | "MDTX" => { Some(value / 10.0 * 9.0 / 5.0 + 32.0) },
+        // tenths of mm
+        "PRCP" | "EVAP" | "MDPR" | "MDEV" | "WESD" | "WESF" => { Some(value / 254.0) },
+        // mm
+        "SNOW" | "SNWD" | "MDSF" => { Some(value / 25.4) },
+        _ => { None }
+    }
+}
+
+#[derive(Debug)]
+pub struct Station {
+    pub station_id: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub elevation: Option<f64>, // metres, -999.9 when unknown
+    pub state: String,
+    pub name: String
+}
+
+/// Parses the fixed-width ghcnd-stations.txt listing. Lines are trimmed of trailing whitespace by some mirrors,
+/// so columns past the name are read leniently.
+pub fn process_noaa_stations<R: Read>(mut reader: R) -> Result<Vec<Station>, String> {
+    let mut text = String::new();
+    if let Err(e) = reader.read_to_string(&mut text) {
+        return Err(format!("Failed to read station list: {}", e));
+    }
+
+    let mut stations = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let column = |start: usize, end: usize| -> &str {
+            line.get(start..end.min(line.len())).unwrap_or("").trim()
+        };
+
+        let latitude = column(12, 20).parse::<f64>();
+        let longitude = column(21, 30).parse::<f64>();
+
+        match (latitude, longitude) {
+            (Ok(latitude), Ok(longitude)) => {
+                let elevation = column(31, 37).parse::<f64>().ok().filter(|e| *e > -999.0);
+
+                stations.push(Station {
+                    station_id: column(0, 11).to_owned(),
+                    latitude,
+                    longitude,
+                    elevation,
+                    state: column(38, 40).to_owned(),
+                    name: column(41, 71).to_owned()
+                });
+            },
+            (_, _) => {
+                return Err(format!("Failed to parse station coordinates on line {}: '{}'", number + 1, line));
             }
         }
+    }
+
+    Ok(stations)
+}
+
+/// Great-circle distance in kilometres between two points given in decimal degrees.
+pub fn haversine_km(latitude_a: f64, longitude_a: f64, latitude_b: f64, longitude_b: f64) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+
+    let delta_latitude = (latitude_b - latitude_a).to_radians();
+    let delta_longitude = (longitude_b - longitude_a).to_radians();
+
+    let a = (delta_latitude / 2.0).sin().powi(2)
+        + latitude_a.to_radians().cos() * latitude_b.to_radians().cos() * (delta_longitude / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// Finds the internal point (centroid) of a county in a Census Bureau county gazetteer file
+/// (tab separated, with GEOID, INTPTLAT and INTPTLONG columns). `fips` is the five digit state + county code.
+pub fn county_centroid(gazetteer: &str, fips: &str) -> Result<(f64, f64), String> {
+    let mut lines = gazetteer.lines();
+
+    let header: Vec<&str> = match lines.next() {
+        Some(h) => { h.split('\t').map(str::trim).collect() },
+        None => { return Err("County gazetteer file is empty".to_owned()) }
     };
 
-    Ok(cursor)
+    let position = |name: &str| header.iter().position(|h| *h == name).ok_or(format!("County gazetteer file has no {} column", name));
+    let geoid_column = position("GEOID")?;
+    let latitude_column = position("INTPTLAT")?;
+    let longitude_column = position("INTPTLONG")?;
+
+    for line in lines {
+        let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+
+        if fields.get(geoid_column) == Some(&fips) {
+            let latitude = fields.get(latitude_column).and_then(|v| v.parse::<f64>().ok());
+            let longitude = fields.get(longitude_column).and_then(|v| v.parse::<f64>().ok());
+
+            return match (latitude, longitude) {
+                (Some(latitude), Some(longitude)) => { Ok((latitude, longitude)) },
+                (_, _) => { Err(format!("County {} has invalid coordinates in the gazetteer file", fips)) }
+            }
+        }
+    }
+
+    Err(format!("County {} not found in the gazetteer file", fips))
 }
 
 /// Parses a NOAA tar.gz file and returns an appropriate datastructure. The optional filters are logically processed with 
 /// case-insensitive "OR" logic with respect to other elements in the same vector, but "AND" logic with respect to the different filters.
-pub fn process_noaa<R: Read>(cursor: R, element_filter: Option<&[&str]>, station_country_filter: Option<&[&str]>) -> Result<Vec<Observation>, String> {   
+#[allow(dead_code)]
+pub fn process_noaa<R: Read>(cursor: R, element_filter: Option<&[&str]>, station_country_filter: Option<&[&str]>) -> Result<Vec<Observation>, String> {
+    let mut results = Vec::new();
+
+    process_noaa_batches(cursor, element_filter, station_country_filter, &MemoryBudget::unlimited(), |batch| {
+        results.extend(batch);
+        Ok(())
+    })?;
+
+    Ok(results)
+}
+
+/// As `process_noaa`, but hands observations to `sink` in batches sized to fit `budget` rather than returning them
+/// all at once. With an unlimited budget there is a single batch.
+pub fn process_noaa_batches<R, F>(cursor: R, element_filter: Option<&[&str]>, station_country_filter: Option<&[&str]>, budget: &MemoryBudget, mut sink: F) -> Result<(), String>
+where R: Read, F: FnMut(Vec<Observation>) -> Result<(), String> {
     let tar = GzDecoder::new(cursor);
     match tar.header() {
         Some(_) => {},
@@ -266,7 +473,9 @@ pub fn process_noaa<R: Read>(cursor: R, element_filter: Option<&[&str]>, station
         Err(_) => { return Err(String::from("Failed to read archive from NOAA")) }
     };
 
+    let batch_size = budget.batch_size(Observation::estimated_size());
     let mut results = Vec::new();
+
     for file in entries {
         let mut file = match file {
             Ok(f) => {f},
@@ -302,39 +511,37 @@ pub fn process_noaa<R: Read>(cursor: R, element_filter: Option<&[&str]>, station
 
             match record_result {
                 Ok(record) => {
-                    match (element_filter.as_ref(), station_country_filter.as_ref()) {
-                        (Some(elements), Some(countries)) => {
-                            match (
-                                elements.iter().any(|&x| x.to_lowercase() == record.element.to_lowercase()),
-                                countries.iter().any(|&x| record.station_id.to_lowercase().starts_with(&x.to_lowercase()))
-                            ) {
-                                (true, true) =>  {results.push(record);}
-                                (_, _) => {}
-                            }
-                        },
-                        (None, Some(countries)) => {
-                            if countries.iter().any(|x| record.station_id.to_lowercase().starts_with(x)) {
-                                results.push(record);
-                            }
-                        }
-                        (Some(elements), None) => {
-                            if elements.iter().any(|x| *x.to_lowercase() == record.element.to_lowercase()) { 
-                                results.push(record);
-                            }
-                        }
-                        (None, None) => {
-                            results.push(record);
-                        }
+                    let element_matches = match element_filter.as_ref() {
+                        Some(elements) => { elements.iter().any(|x| x.to_lowercase() == record.element.to_lowercase()) },
+                        None => { true }
+                    };
+
+                    let country_matches = match station_country_filter.as_ref() {
+                        Some(countries) => { countries.iter().any(|x| record.station_id.to_lowercase().starts_with(&x.to_lowercase())) },
+                        None => { true }
+                    };
+
+                    if element_matches && country_matches {
+                        results.push(record);
                     }
                 },
                 Err(e) => {
-                    println!("error for {}: {}", path_name, e)
+                    error!("Failed to read {}: {}", path_name, e)
                 }
             }
         }
+
+        // station files are the unit of work, so the budget is checked between them
+        if batch_size.is_some_and(|b| results.len() >= b) || (!results.is_empty() && budget.is_approaching_limit()) {
+            sink(std::mem::take(&mut results))?;
+        }
     }
 
-    Ok(results)
+    if !results.is_empty() {
+        sink(results)?;
+    }
+
+    Ok(())
 }
 
 #[test]
@@ -370,7 +577,89 @@ US000041196194404TMIN  180  I  180  I  163  I  146  I  135  I-9999   -9999     1
     let results = process_noaa(cursor, Some(&["TAVG"]), Some(&["AE"])).unwrap();
     assert_eq!(results.len(), 1);
     for observation in results {
-        assert_eq!(observation.station_id.starts_with("AE"), true);
+        assert!(observation.station_id.starts_with("AE"));
         assert_eq!(observation.element, "TAVG");
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_process_noaa_batches() {
+    use tar::{Builder, Header};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::prelude::*;
+
+    let station_file = "US000041196194404TMIN  180  I  180  I  163  I  146  I  135  I-9999   -9999     196  I  235  I  213  I  163  I-9999     180  I  174  I-9999     196  I  241  I  235  I  208  I  196  I  208  I  213  I  180  I  174  I  180  I  180  I  169  I  152  I  169  I  169  I-9999   \n";
+
+    let mut archive = Builder::new(Vec::new());
+    for name in &["a.dly", "b.dly", "c.dly"] {
+        let mut header = Header::new_gnu();
+        header.set_path(name).unwrap();
+        header.set_size(station_file.len().try_into().unwrap());
+        header.set_cksum();
+        archive.append(&header, Cursor::new(station_file)).unwrap();
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&archive.into_inner().unwrap()[..]).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut batches: Vec<usize> = Vec::new();
+    process_noaa_batches(Cursor::new(&compressed), None, None, &MemoryBudget::unlimited(), |b| { batches.push(b.len()); Ok(()) }).unwrap();
+    assert_eq!(batches, vec![3]);
+
+    // a one megabyte budget is always nearly exhausted, so every station file is flushed on its own
+    let mut batches: Vec<usize> = Vec::new();
+    process_noaa_batches(Cursor::new(&compressed), None, None, &MemoryBudget::new(Some(1)), |b| { batches.push(b.len()); Ok(()) }).unwrap();
+    assert_eq!(batches.iter().sum::<usize>(), 3);
+    if crate::memory::resident_mb().is_some() {
+        assert_eq!(batches, vec![1, 1, 1]);
+    }
+}
+
+#[test]
+fn test_process_noaa_stations() {
+    let test_string = "USC00141559  37.7628  -99.9650  789.4 KS DODGE CITY                               \nAE000041196  25.3330   55.5170   34.0    SHARJAH INTER. AIRP            GSN     41196\n";
+    let stations = process_noaa_stations(Cursor::new(test_string)).unwrap();
+
+    assert_eq!(stations.len(), 2);
+    assert_eq!(stations[0].station_id, "USC00141559");
+    assert_eq!(stations[0].state, "KS");
+    assert_eq!(stations[0].name, "DODGE CITY");
+    assert_eq!(stations[1].state, "");
+    assert!((stations[1].longitude - 55.517).abs() < 1e-9);
+}
+
+#[test]
+fn test_haversine_km() {
+    // Dodge City, KS to Garden City, KS is roughly 70 km
+    let distance = haversine_km(37.7628, -99.9650, 37.9275, -100.7244);
+    assert!((distance - 69.0).abs() < 5.0, "unexpected distance {}", distance);
+    assert!(haversine_km(10.0, 10.0, 10.0, 10.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_county_centroid() {
+    let gazetteer = "USPS\tGEOID\tANSICODE\tNAME\tALAND\tAWATER\tALAND_SQMI\tAWATER_SQMI\tINTPTLAT\tINTPTLONG                                                                                                               \nKS\t20057\t00485000\tFord County\t2842401283\t1866658\t1097.457\t0.721\t37.691694\t-99.884249                           \n";
+
+    assert_eq!(county_centroid(gazetteer, "20057").unwrap(), (37.691694, -99.884249));
+    assert!(county_centroid(gazetteer, "20055").is_err());
+}
+
+#[test]
+fn test_to_imperial() {
+    assert!((to_imperial("TMAX", 0).unwrap() - 32.0).abs() < 1e-9);
+    assert!((to_imperial("TMIN", -400).unwrap() - -40.0).abs() < 1e-9);
+    assert!((to_imperial("PRCP", 254).unwrap() - 1.0).abs() < 1e-9);
+    assert!((to_imperial("SNOW", 254).unwrap() - 10.0).abs() < 1e-9);
+    assert_eq!(to_imperial("ACMC", 50), None);
+}
+
+#[test]
+fn test_noaa_config_file() {
+    use std::fs;
+
+    let config: NoaaConfig = toml::from_str(&fs::read_to_string("config/noaa.toml").unwrap()).unwrap();
+    assert_eq!(config.units, NoaaUnits::Metric);
+    assert!(!config.elements.is_empty());
+}
@@ -0,0 +1,123 @@
+// Overriding configuration from the command line with --set FILE.KEY.PATH=VALUE, for trying out a setting without
+// editing files that configuration management keeps in place, e.g. --set noaa.elements=[TMAX] or
+// --set datamart.2466.description="5 Area Weekly".
+//
+// The first part of the path names the configuration file: datamart, legacy, noaa, remote or secret. The rest
+// is a path of keys through its tables, made as needed, to the value replaced. Values are read as TOML, and what
+// doesn't read as TOML as a string, so that words in arrays need no quotes for the shell to strip.
+
+use serde::de::DeserializeOwned;
+use toml::Value;
+
+/// Configuration files that can be overridden, by the name given first in the path
+pub const FILES: &[&str] = &["datamart", "legacy", "network", "noaa", "remote", "secret"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Override {
+    pub file: String,
+    pub path: Vec<String>,
+    pub value: Value
+}
+
+/// Parses an override given as FILE.KEY.PATH=VALUE
+pub fn parse_override(text: &str) -> Result<Override, String> {
+    let (key, value) = text.split_once('=')
+        .ok_or_else(|| format!("Invalid --set {}, expected FILE.KEY.PATH=VALUE", text))?;
+
+    let mut path: Vec<String> = key.trim().split('.').map(|k| k.trim().to_owned()).collect();
+    if path.iter().any(|k| k.is_empty()) {
+        return Err(format!("Invalid key path in --set {}, expected keys separated by dots", text));
+    }
+
+    let file = path.remove(0);
+    if !FILES.contains(&file.as_str()) {
+        return Err(format!("Invalid --set {}, the path must start with a configuration file: one of {}", text, FILES.join(", ")));
+    }
+    if path.is_empty() {
+        return Err(format!("Invalid --set {}, a whole configuration file can't be replaced", text));
+    }
+
+    Ok(Override { file, path, value: parse_value(value.trim()) })
+}
+
+/// Reads a value as TOML, taking the items of an array and anything else that isn't TOML as strings
+fn parse_value(text: &str) -> Value {
+    if let Ok(mut table) = format!("value = {}", text).parse::<Value>() {
+        if let Some(value) = table.as_table_mut().and_then(|t| t.remove("value")) {
+            return value;
+        }
+    }
+
+    match text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        Some(items) if items.trim().is_empty() => { Value::Array(vec![]) },
+        Some(items) => { Value::Array(items.split(',').map(|item| parse_value(item.trim())).collect()) },
+        None => { Value::String(text.to_owned()) }
+    }
+}
+
+/// Sets the value at `path` in `config`, making tables along the way where there are none
+pub fn apply(config: &mut Value, path: &[String], value: Value) -> Result<(), String> {
+    let mut current = config;
+
+    for (depth, key) in path.iter().enumerate() {
+        let table = current.as_table_mut()
+            .ok_or_else(|| format!("Can't set {}, {} is not a table", path.join("."), path[..depth].join(".")))?;
+
+        if depth == path.len() - 1 {
+            table.insert(key.to_owned(), value);
+            return Ok(());
+        }
+
+        current = table.entry(key.to_owned()).or_insert_with(|| Value::Table(Default::default()));
+    }
+
+    Ok(())
+}
+
+/// Parses configuration `text` of `file` with the overrides of that file applied
+pub fn load<T: DeserializeOwned>(text: &str, file: &str, overrides: &[Override]) -> Result<T, String> {
+    let mut config: Value = text.parse().map_err(|e| format!("Failed to parse {} config TOML: {}", file, e))?;
+
+    for o in overrides.iter().filter(|o| o.file == file) {
+        apply(&mut config, &o.path, o.value.clone())?;
+    }
+
+    config.try_into().map_err(|e| format!("Invalid {} config: {}", file, e))
+}
+
+#[test]
+fn test_parse_override() {
+    let elements = parse_override("noaa.elements=[TMAX, \"TMIN\"]").unwrap();
+    assert_eq!(elements.file, "noaa");
+    assert_eq!(elements.path, vec!["elements"]);
+    assert_eq!(elements.value, Value::Array(vec![Value::String("TMAX".to_owned()), Value::String("TMIN".to_owned())]));
+
+    assert_eq!(parse_override("datamart.2466.release_time=14:00").unwrap().value, Value::String("14:00".to_owned()));
+    assert_eq!(parse_override("datamart.2466.indexes=[]").unwrap().value, Value::Array(vec![]));
+    assert_eq!(parse_override("noaa.countries = [\"US\", \"CA\"]").unwrap().path, vec!["countries"]);
+    assert_eq!(parse_override("legacy.LM_XB463.api_version=2").unwrap().value, Value::Integer(2));
+
+    assert!(parse_override("noaa.elements").is_err());
+    assert!(parse_override("noaa=TMAX").is_err());
+    assert!(parse_override("elements=[TMAX]").is_err());
+    assert!(parse_override("noaa..elements=[TMAX]").is_err());
+}
+
+#[test]
+fn test_load_with_overrides() {
+    use std::collections::HashMap;
+
+    let text = "elements = [\"TMAX\", \"PRCP\"]\n[stations]\nkeep = \"all\"\n";
+    let overrides = vec![
+        parse_override("noaa.elements=[TMAX]").unwrap(),
+        parse_override("noaa.limits.stations=10").unwrap(),
+        parse_override("legacy.elements=[PRCP]").unwrap()
+    ];
+
+    let config: HashMap<String, Value> = load(text, "noaa", &overrides).unwrap();
+    assert_eq!(config["elements"], Value::Array(vec![Value::String("TMAX".to_owned())]));
+    assert_eq!(config["limits"]["stations"], Value::Integer(10));
+    assert_eq!(config["stations"]["keep"], Value::String("all".to_owned()));
+
+    assert!(load::<HashMap<String, Value>>(text, "noaa", &[parse_override("noaa.elements.TMAX=true").unwrap()]).is_err());
+}
@@ -0,0 +1,92 @@
+// Named profiles of command line options (see config/profiles.toml), so that one set of configuration files can
+// drive several environments: `--profile staging` picks the database, sinks and reports of [profile.staging].
+//
+// A profile is a table of options by their long names, given as if on the command line after those that were, so
+// options given there win over the profile's.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use serde::Deserialize;
+use toml::Value;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ProfileFile {
+    #[serde(default)]
+    pub profile: BTreeMap<String, toml::value::Table>
+}
+
+/// The profile `name` of a profile configuration
+pub fn find_profile<'a>(file: &'a ProfileFile, name: &str) -> Result<&'a toml::value::Table, String> {
+    file.profile.get(name).ok_or_else(|| {
+        let known: Vec<&str> = file.profile.keys().map(String::as_str).collect();
+        format!("No profile {} in the profile configuration, expected one of: {}", name, known.join(", "))
+    })
+}
+
+/// A value of an option as given on the command line
+fn argument_value(option: &str, value: &Value) -> Result<OsString, String> {
+    match value {
+        Value::String(s) => { Ok(s.into()) },
+        Value::Integer(i) => { Ok(i.to_string().into()) },
+        Value::Float(f) => { Ok(f.to_string().into()) },
+        _ => { Err(format!("Invalid value of {} in profile, expected a string or number: {}", option, value)) }
+    }
+}
+
+/// The command line arguments of `profile`, leaving out options for which `given` is true. Switches are set with
+/// `true`, and options given more than once (e.g. --set) with an array.
+pub fn profile_arguments(profile: &toml::value::Table, given: impl Fn(&str) -> bool) -> Result<Vec<OsString>, String> {
+    let mut arguments = Vec::new();
+
+    for (option, value) in profile {
+        if given(option) || option == "profile" {
+            continue;
+        }
+
+        let flag = OsString::from(format!("--{}", option));
+        match value {
+            Value::Boolean(true) => { arguments.push(flag) },
+            Value::Boolean(false) => {},
+            Value::Array(values) => {
+                for value in values {
+                    arguments.push(flag.clone());
+                    arguments.push(argument_value(option, value)?);
+                }
+            },
+            value => {
+                arguments.push(flag);
+                arguments.push(argument_value(option, value)?);
+            }
+        }
+    }
+
+    Ok(arguments)
+}
+
+#[test]
+fn test_profile_arguments() {
+    let file: ProfileFile = toml::from_str(r#"
+        [profile.staging]
+        host = "staging-db.internal"
+        port = 5433
+        group = "cattle"
+        update = true
+        daemon = false
+        set = ["noaa.elements=[TMAX]", "secret.postgres.dbname=usda_staging"]
+    "#).unwrap();
+
+    let staging = find_profile(&file, "staging").unwrap();
+    let arguments: Vec<OsString> = profile_arguments(staging, |option| option == "group").unwrap();
+
+    assert_eq!(
+        arguments,
+        ["--host", "staging-db.internal", "--port", "5433", "--set", "noaa.elements=[TMAX]", "--set", "secret.postgres.dbname=usda_staging", "--update"]
+            .iter().map(OsString::from).collect::<Vec<OsString>>()
+    );
+
+    assert!(find_profile(&file, "prod").unwrap_err().contains("staging"));
+
+    let nested: ProfileFile = toml::from_str("[profile.prod.host]\nname = \"db\"").unwrap();
+    assert!(profile_arguments(find_profile(&nested, "prod").unwrap(), |_| false).is_err());
+}
@@ -0,0 +1,185 @@
+// Watching for scheduled legacy releases in daemon mode, so that a report is ingested as soon as USDA publishes it
+// rather than at the next update pass.
+//
+// Reports with a `release_time` in the legacy configuration have their latest ESMIS release polled every few
+// minutes from that time of day until a release dated that day turns up, or until the window closes, as it will on
+// days the report isn't published.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+
+use crate::usda::datamart::DatamartConfig;
+
+/// Minutes between polls of a report while its release is awaited
+const POLL_INTERVAL_MINUTES: i64 = 5;
+
+/// Hours after its scheduled time that a release is waited for
+const POLL_WINDOW_HOURS: i64 = 3;
+
+struct Watched {
+    release_time: NaiveTime,
+    last_polled: Option<NaiveDateTime>,
+    received: Option<NaiveDate>      // the day of the latest release ingested
+}
+
+impl Watched {
+    fn window(&self, day: NaiveDate) -> (NaiveDateTime, NaiveDateTime) {
+        let start = day.and_time(self.release_time);
+        (start, start + Duration::hours(POLL_WINDOW_HOURS))
+    }
+
+    /// When the report should next be polled, at or after `now`
+    fn next_poll(&self, now: NaiveDateTime) -> NaiveDateTime {
+        let (start, end) = self.window(now.date());
+        let tomorrow = self.window(now.date() + Duration::days(1)).0;
+
+        if self.received == Some(now.date()) || now >= end {
+            return tomorrow;
+        }
+
+        if now < start {
+            return start;
+        }
+
+        match self.last_polled {
+            Some(polled) if polled + Duration::minutes(POLL_INTERVAL_MINUTES) > now => {
+                let next = polled + Duration::minutes(POLL_INTERVAL_MINUTES);
+                if next < end { next } else { tomorrow }
+            },
+            _ => { now }
+        }
+    }
+}
+
+pub struct ReleaseWatch {
+    reports: BTreeMap<String, Watched>
+}
+
+impl ReleaseWatch {
+    /// Watches every legacy report with a release time configured
+    pub fn from_config(legacy_config: &HashMap<String, DatamartConfig>) -> Result<ReleaseWatch, String> {
+        let mut reports = BTreeMap::new();
+
+        for (identifier, config) in legacy_config {
+            if let Some(time) = config.release_time.as_ref() {
+                let release_time = NaiveTime::parse_from_str(time, "%H:%M")
+                    .map_err(|_| format!("Invalid release time for {}, expected HH:MM: {}", identifier, time))?;
+
+                reports.insert(identifier.to_owned(), Watched { release_time, last_polled: None, received: None });
+            }
+        }
+
+        Ok(ReleaseWatch { reports })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reports.is_empty()
+    }
+
+    /// Reports whose release is awaited and that haven't been polled within the interval
+    pub fn due(&self, now: NaiveDateTime) -> Vec<String> {
+        self.reports.iter()
+            .filter(|(_, watched)| watched.next_poll(now) <= now)
+            .map(|(identifier, _)| identifier.to_owned())
+            .collect()
+    }
+
+    /// The earliest time any report should next be polled
+    pub fn next_poll(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        self.reports.values().map(|watched| watched.next_poll(now)).min()
+    }
+
+    pub fn record_poll(&mut self, identifier: &str, now: NaiveDateTime) {
+        if let Some(watched) = self.reports.get_mut(identifier) {
+            watched.last_polled = Some(now);
+        }
+    }
+
+    /// Whether a release is later than the last one noted for the report
+    pub fn is_new(&self, identifier: &str, release_date: NaiveDate) -> bool {
+        self.reports.get(identifier).is_some_and(|w| w.received.is_none_or(|r| r < release_date))
+    }
+
+    /// Notes the day of a report's latest release, which ends the wait for it when it is the current day
+    pub fn record_release(&mut self, identifier: &str, release_date: NaiveDate) {
+        if let Some(watched) = self.reports.get_mut(identifier) {
+            watched.received = Some(release_date);
+        }
+    }
+
+    /// The file to ingest of a report's latest release if the release is new, noting the release either way. A new
+    /// release without files is warned of and skipped, so that it isn't polled for again.
+    pub fn take_new(&mut self, identifier: &str, release_date: NaiveDate, files: &[String]) -> Option<String> {
+        let new = self.is_new(identifier, release_date);
+        self.record_release(identifier, release_date);
+
+        if !new {
+            return None;
+        }
+
+        let file = files.first().cloned();
+        if file.is_none() {
+            warn!("The {} release of {} has no files, skipping it.", release_date, identifier);
+        }
+        file
+    }
+}
+
+#[test]
+fn test_release_watch() {
+    let mut config = crate::integration::usda::test_structure("lm_xb463");
+    config.release_time = Some("15:00".to_owned());
+    let mut legacy_config = HashMap::new();
+    legacy_config.insert("LM_XB463".to_owned(), config.clone());
+
+    let day = NaiveDate::from_ymd_opt(2020, 3, 6).unwrap();
+    let at = |hour, minute| day.and_hms_opt(hour, minute, 0).unwrap();
+
+    let mut watch = ReleaseWatch::from_config(&legacy_config).unwrap();
+    assert!(watch.due(at(14, 59)).is_empty());
+    assert_eq!(watch.next_poll(at(9, 0)), Some(at(15, 0)));
+
+    assert_eq!(watch.due(at(15, 0)), vec!["LM_XB463"]);
+    watch.record_poll("LM_XB463", at(15, 0));
+    assert!(watch.due(at(15, 3)).is_empty());
+    assert_eq!(watch.next_poll(at(15, 3)), Some(at(15, 5)));
+
+    // the release turns up, so the next poll is tomorrow's
+    watch.record_poll("LM_XB463", at(15, 5));
+    assert!(watch.is_new("LM_XB463", day));
+    watch.record_release("LM_XB463", day);
+    assert!(!watch.is_new("LM_XB463", day));
+    assert!(watch.due(at(15, 10)).is_empty());
+    assert_eq!(watch.next_poll(at(15, 10)), Some(at(15, 0) + Duration::days(1)));
+
+    // nothing is published after the window
+    let mut watch = ReleaseWatch::from_config(&legacy_config).unwrap();
+    assert!(watch.due(at(18, 0)).is_empty());
+    watch.record_poll("LM_XB463", at(17, 58));
+    assert_eq!(watch.next_poll(at(17, 59)), Some(at(15, 0) + Duration::days(1)));
+
+    config.release_time = Some("3pm".to_owned());
+    legacy_config.insert("LM_XB463".to_owned(), config);
+    assert!(ReleaseWatch::from_config(&legacy_config).is_err());
+}
+
+#[test]
+fn test_take_new_release() {
+    let mut config = crate::integration::usda::test_structure("lm_xb463");
+    config.release_time = Some("15:00".to_owned());
+    let mut legacy_config = HashMap::new();
+    legacy_config.insert("LM_XB463".to_owned(), config);
+
+    let day = NaiveDate::from_ymd_opt(2020, 3, 6).unwrap();
+    let file = "https://downloads.usda.library.cornell.edu/lm_xb463.txt".to_owned();
+    let mut watch = ReleaseWatch::from_config(&legacy_config).unwrap();
+
+    // a release without files is noted all the same, ending the wait for it
+    assert_eq!(watch.take_new("LM_XB463", day, &[]), None);
+    assert!(!watch.is_new("LM_XB463", day));
+    assert!(watch.due(day.and_hms_opt(15, 10, 0).unwrap()).is_empty());
+
+    assert_eq!(watch.take_new("LM_XB463", day, std::slice::from_ref(&file)), None);
+    assert_eq!(watch.take_new("LM_XB463", day + Duration::days(1), std::slice::from_ref(&file)), Some(file));
+}
@@ -0,0 +1,415 @@
+// Mirroring historical archives that partners host on SFTP or FTP servers, so that the text files they add can be
+// ingested as with --backfill-text. Remotes are defined in config/remotes.toml, each naming a table of the secret
+// configuration holding its credentials, and are laid out as --backfill-text expects: a folder per report.
+//
+// Files already mirrored at the same size are left alone, and a file is downloaded under a .partial name and
+// renamed once complete, so that an interrupted sync neither leaves nor ingests half a file. Listings and downloads
+// go through the session, so that --record keeps them and --replay mirrors without connecting.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use ftp::FtpStream;
+use ftp::types::FileType::Binary;
+use serde::{Deserialize, Serialize};
+
+use crate::session;
+
+/// Folders below a remote's directory that are followed, guarding against link loops
+const MAX_DEPTH: usize = 8;
+
+/// Read timeout of remote connections
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Sftp,
+    Ftp,
+    Ftps
+}
+
+/// A remote archive, see config/remotes.toml
+#[derive(Deserialize, Debug)]
+pub struct RemoteConfig {
+    pub protocol: Protocol,
+    pub host: String,
+    pub port: Option<u16>,
+    pub remote_dir: String,
+    pub local_dir: PathBuf,
+    pub credentials: Option<String>     // the table of [remotes] in the secret configuration, the remote's name by default
+}
+
+/// A remote's table of the secret configuration
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    username: String,
+    password: Option<String>,
+    #[cfg_attr(not(feature = "sftp"), allow(dead_code))]
+    key_file: Option<PathBuf>,          // SFTP only, used instead of the password when given
+    #[cfg_attr(not(feature = "sftp"), allow(dead_code))]
+    known_hosts: Option<PathBuf>        // SFTP only, ~/.ssh/known_hosts by default
+}
+
+/// An entry of a remote directory listing
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: Option<u64>
+}
+
+/// The operations mirroring needs of a server
+trait Remote {
+    fn list(&mut self, dir: &str) -> Result<Vec<Entry>, String>;
+    fn fetch(&mut self, path: &str, into: &mut File) -> Result<(), String>;
+}
+
+/// What a sync of a remote archive did: the files downloaded, and why those that couldn't be weren't
+#[derive(Debug, Default, PartialEq)]
+pub struct Synced {
+    pub downloaded: Vec<PathBuf>,
+    pub errors: Vec<String>
+}
+
+/// Mirrors the remote archive `name`. Only failing to connect fails the sync; a folder or file that can't be
+/// fetched is noted and the rest go ahead.
+pub fn sync(name: &str, config: &RemoteConfig, credentials: &Credentials) -> Result<Synced, String> {
+    let connected: Option<Box<dyn Remote>> = match config.protocol {
+        Protocol::Ftps => {
+            return Err(format!("Remote {} uses FTPS, which isn't supported: the FTP client has no TLS support that builds against current OpenSSL. Use sftp or ftp.", name))
+        },
+        _ if session::is_replaying() => { None },
+        Protocol::Ftp => { Some(Box::new(connect_ftp(config, credentials)?)) },
+        Protocol::Sftp => { Some(connect_sftp(config, credentials)?) }
+    };
+
+    let base = format!("{:?}://{}", config.protocol, config.host).to_lowercase();
+    let mut remote = SessionRemote { connected, base };
+
+    let mut synced = Synced::default();
+    mirror(&mut remote, config.remote_dir.trim_end_matches('/'), &config.local_dir, 0, &mut synced);
+    Ok(synced)
+}
+
+/// A remote whose listings and downloads are recorded in the session, or answered from the one replayed, in which
+/// case there is no connection
+struct SessionRemote {
+    connected: Option<Box<dyn Remote>>,
+    base: String    // the protocol and host, e.g. sftp://archive.example.com, naming the exchanges of the remote
+}
+
+impl SessionRemote {
+    fn connected(&mut self) -> Result<&mut Box<dyn Remote>, String> {
+        let base = &self.base;
+        self.connected.as_mut().ok_or_else(|| format!("{} isn't connected to while a session is replayed", base))
+    }
+}
+
+impl Remote for SessionRemote {
+    fn list(&mut self, dir: &str) -> Result<Vec<Entry>, String> {
+        let key = format!("list {}{}", self.base, dir);
+        let listing = session::ftp(&key, || {
+            let entries = self.connected()?.list(dir)?;
+            serde_json::to_vec(&entries).map_err(|e| e.to_string())
+        })?;
+
+        serde_json::from_slice(&listing).map_err(|e| format!("Invalid listing of {} in the session: {}", key, e))
+    }
+
+    fn fetch(&mut self, path: &str, into: &mut File) -> Result<(), String> {
+        let key = format!("{}{}", self.base, path);
+        session::ftp_to_file(&key, into, |file| self.connected()?.fetch(path, file))
+    }
+}
+
+/// Copies the files of `remote_dir` and the folders below it that are missing from `local_dir`, or differ in size
+fn mirror(remote: &mut dyn Remote, remote_dir: &str, local_dir: &Path, depth: usize, synced: &mut Synced) {
+    let entries = match remote.list(remote_dir) {
+        Ok(entries) => { entries },
+        Err(e) => {
+            error!("{}", e);
+            synced.errors.push(e);
+            return;
+        }
+    };
+
+    for entry in entries {
+        // names come from the server, so none may lead out of the local directory
+        if entry.name.is_empty() || entry.name.starts_with('.') || entry.name.contains(['/', '\\']) {
+            continue;
+        }
+
+        let remote_path = format!("{}/{}", remote_dir, entry.name);
+        let local_path = local_dir.join(&entry.name);
+
+        if entry.is_dir {
+            if depth < MAX_DEPTH {
+                mirror(remote, &remote_path, &local_path, depth + 1, synced);
+            }
+            continue;
+        }
+
+        if let Ok(metadata) = fs::metadata(&local_path) {
+            if entry.size.map(|size| size == metadata.len()).unwrap_or(true) {
+                continue;
+            }
+        }
+
+        match download(remote, &remote_path, local_dir, &entry.name) {
+            Ok(_) => {
+                info!("Downloaded {}", remote_path);
+                synced.downloaded.push(local_path);
+            },
+            Err(e) => {
+                error!("{}", e);
+                synced.errors.push(e);
+            }
+        }
+    }
+}
+
+/// Downloads a file into `local_dir` under a .partial name, moving it into place once complete
+fn download(remote: &mut dyn Remote, remote_path: &str, local_dir: &Path, name: &str) -> Result<(), String> {
+    fs::create_dir_all(local_dir).map_err(|e| format!("Failed to create {}: {}", local_dir.display(), e))?;
+    let partial = local_dir.join(format!("{}.partial", name));
+    let mut file = File::create(&partial).map_err(|e| format!("Failed to create {}: {}", partial.display(), e))?;
+
+    let fetched = remote.fetch(remote_path, &mut file)
+        .and_then(|_| file.sync_all().map_err(|e| format!("Failed to write {}: {}", partial.display(), e)));
+    if let Err(e) = fetched {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+
+    fs::rename(&partial, local_dir.join(name)).map_err(|e| format!("Failed to move {} into place: {}", partial.display(), e))
+}
+
+/// Parses a line of an FTP LIST response, in the Unix `ls -l` format or the Windows (IIS) one. Links and lines in
+/// neither format are passed over.
+fn parse_list_line(line: &str) -> Option<Entry> {
+    // the first `count` whitespace separated fields and the rest of the line, which keeps spaces in names
+    fn fields(line: &str, count: usize) -> Option<(Vec<&str>, &str)> {
+        let mut rest = line.trim_start();
+        let mut taken = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            let end = rest.find(char::is_whitespace)?;
+            taken.push(&rest[..end]);
+            rest = rest[end..].trim_start();
+        }
+
+        Some((taken, rest))
+    }
+
+    let first = line.chars().next()?;
+
+    if first.is_ascii_digit() {
+        // 03-02-20  10:00AM       <DIR>          LM_XB463
+        let (taken, name) = fields(line, 3)?;
+        return match taken[2] {
+            "<DIR>" => { Some(Entry { name: name.to_owned(), is_dir: true, size: None }) },
+            size => { Some(Entry { name: name.to_owned(), is_dir: false, size: Some(size.parse().ok()?) }) }
+        };
+    }
+
+    // -rw-r--r--   1 owner group     1234 Mar  2  2020 lm_xb463 2020-03-02.txt
+    let (taken, name) = fields(line, 8)?;
+    match first {
+        'd' => { Some(Entry { name: name.to_owned(), is_dir: true, size: None }) },
+        '-' => { Some(Entry { name: name.to_owned(), is_dir: false, size: taken[4].parse().ok() }) },
+        _ => { None }
+    }
+}
+
+impl Remote for FtpStream {
+    fn list(&mut self, dir: &str) -> Result<Vec<Entry>, String> {
+        let lines = FtpStream::list(self, Some(dir)).map_err(|e| format!("Failed to list {}: {}", dir, e))?;
+        Ok(lines.iter().filter_map(|line| parse_list_line(line)).collect())
+    }
+
+    fn fetch(&mut self, path: &str, into: &mut File) -> Result<(), String> {
+        let mut contents = self.simple_retr(path).map_err(|e| format!("Failed to download {}: {}", path, e))?;
+        io::copy(&mut contents, into).map(|_| ()).map_err(|e| format!("Failed to write {}: {}", path, e))
+    }
+}
+
+fn connect_ftp(config: &RemoteConfig, credentials: &Credentials) -> Result<FtpStream, String> {
+    let address = (config.host.as_str(), config.port.unwrap_or(21));
+    let mut stream = FtpStream::connect(address).map_err(|e| format!("Failed to connect to {}: {}", config.host, e))?;
+
+    stream.get_ref().set_read_timeout(Some(TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.login(&credentials.username, credentials.password.as_deref().unwrap_or_default())
+        .map_err(|e| format!("Failed to log in to {}: {}", config.host, e))?;
+    stream.transfer_type(Binary).map_err(|e| format!("Failed to set transfer type to binary: {}", e))?;
+
+    Ok(stream)
+}
+
+#[cfg(feature = "sftp")]
+struct SftpRemote {
+    _session: ssh2::Session,    // kept open for as long as the channel
+    sftp: ssh2::Sftp
+}
+
+#[cfg(feature = "sftp")]
+impl Remote for SftpRemote {
+    fn list(&mut self, dir: &str) -> Result<Vec<Entry>, String> {
+        let entries = self.sftp.readdir(Path::new(dir)).map_err(|e| format!("Failed to list {}: {}", dir, e))?;
+
+        Ok(entries.into_iter()
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_str()?.to_owned();
+                Some(Entry { name, is_dir: stat.is_dir(), size: stat.size })
+            })
+            .filter(|entry| entry.is_dir || entry.size.is_some())
+            .collect())
+    }
+
+    fn fetch(&mut self, path: &str, into: &mut File) -> Result<(), String> {
+        let mut file = self.sftp.open(Path::new(path)).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        io::copy(&mut file, into).map(|_| ()).map_err(|e| format!("Failed to download {}: {}", path, e))
+    }
+}
+
+/// Connects to an SFTP server whose host key is in the known hosts file
+#[cfg(feature = "sftp")]
+fn connect_sftp(config: &RemoteConfig, credentials: &Credentials) -> Result<Box<dyn Remote>, String> {
+    let port = config.port.unwrap_or(22);
+    let tcp = std::net::TcpStream::connect((config.host.as_str(), port)).map_err(|e| format!("Failed to connect to {}: {}", config.host, e))?;
+
+    let mut session = ssh2::Session::new().map_err(|e| e.to_string())?;
+    session.set_tcp_stream(tcp);
+    session.set_timeout(TIMEOUT.as_millis() as u32);
+    session.handshake().map_err(|e| format!("SSH handshake with {} failed: {}", config.host, e))?;
+
+    let known_hosts_file = match &credentials.known_hosts {
+        Some(path) => { path.clone() },
+        None => {
+            let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))
+                .ok_or_else(|| "No home directory to find .ssh/known_hosts in, set known_hosts".to_owned())?;
+            Path::new(&home).join(".ssh").join("known_hosts")
+        }
+    };
+
+    let mut known_hosts = session.known_hosts().map_err(|e| e.to_string())?;
+    known_hosts.read_file(&known_hosts_file, ssh2::KnownHostFileKind::OpenSSH)
+        .map_err(|e| format!("Failed to read {}: {}", known_hosts_file.display(), e))?;
+    let (key, _) = session.host_key().ok_or_else(|| format!("{} sent no host key", config.host))?;
+
+    match known_hosts.check_port(&config.host, port, key) {
+        ssh2::CheckResult::Match => {},
+        ssh2::CheckResult::Mismatch => {
+            return Err(format!("The host key of {} doesn't match the one in {}", config.host, known_hosts_file.display()))
+        },
+        ssh2::CheckResult::NotFound => {
+            return Err(format!("{} isn't in {}; add its host key (e.g. with ssh-keyscan) after checking it", config.host, known_hosts_file.display()))
+        },
+        ssh2::CheckResult::Failure => {
+            return Err(format!("Failed to check the host key of {}", config.host))
+        }
+    }
+
+    let username = credentials.username.as_str();
+    match (&credentials.key_file, &credentials.password) {
+        (Some(key_file), passphrase) => { session.userauth_pubkey_file(username, None, key_file, passphrase.as_deref()) },
+        (None, Some(password)) => { session.userauth_password(username, password) },
+        (None, None) => { return Err(format!("No password or key_file given for {}", config.host)) }
+    }.map_err(|e| format!("Failed to log in to {}: {}", config.host, e))?;
+
+    let sftp = session.sftp().map_err(|e| format!("Failed to start SFTP with {}: {}", config.host, e))?;
+    Ok(Box::new(SftpRemote { _session: session, sftp }))
+}
+
+#[cfg(not(feature = "sftp"))]
+fn connect_sftp(config: &RemoteConfig, _: &Credentials) -> Result<Box<dyn Remote>, String> {
+    Err(format!("This build has no SFTP support, needed for {}. Rebuild with `cargo build --release --features sftp`.", config.host))
+}
+
+#[test]
+fn test_parse_list_line() {
+    assert_eq!(
+        parse_list_line("-rw-r--r--   1 usda  staff     1234 Mar  2  2020 lm_xb463 2020-03-02.txt"),
+        Some(Entry { name: "lm_xb463 2020-03-02.txt".to_owned(), is_dir: false, size: Some(1234) })
+    );
+    assert_eq!(
+        parse_list_line("drwxr-xr-x   2 usda  staff     4096 Mar  2 10:00 LM_XB463"),
+        Some(Entry { name: "LM_XB463".to_owned(), is_dir: true, size: None })
+    );
+    assert_eq!(parse_list_line("lrwxrwxrwx   1 usda  staff       9 Mar  2  2020 latest -> LM_XB463"), None);
+
+    assert_eq!(
+        parse_list_line("03-02-20  10:00AM       <DIR>          LM_XB463"),
+        Some(Entry { name: "LM_XB463".to_owned(), is_dir: true, size: None })
+    );
+    assert_eq!(
+        parse_list_line("03-02-20  10:00AM                 1234 2020-03-02.txt"),
+        Some(Entry { name: "2020-03-02.txt".to_owned(), is_dir: false, size: Some(1234) })
+    );
+    assert_eq!(parse_list_line("total 8"), None);
+}
+
+#[test]
+fn test_mirror() {
+    use std::io::Write;
+
+    /// A remote holding files in memory, by path. Those without contents fail to download.
+    struct MemoryRemote(std::collections::BTreeMap<String, Option<&'static str>>);
+
+    impl Remote for MemoryRemote {
+        fn list(&mut self, dir: &str) -> Result<Vec<Entry>, String> {
+            let mut entries: Vec<Entry> = Vec::new();
+            for (path, contents) in &self.0 {
+                let rest = match path.strip_prefix(&format!("{}/", dir)) { Some(r) => { r }, None => { continue } };
+                match rest.split_once('/') {
+                    Some((folder, _)) => {
+                        if !entries.iter().any(|e| e.name == folder) {
+                            entries.push(Entry { name: folder.to_owned(), is_dir: true, size: None });
+                        }
+                    },
+                    None => { entries.push(Entry { name: rest.to_owned(), is_dir: false, size: contents.map(|c| c.len() as u64) }) }
+                }
+            }
+            Ok(entries)
+        }
+
+        fn fetch(&mut self, path: &str, into: &mut File) -> Result<(), String> {
+            let contents = self.0[path].ok_or_else(|| format!("Failed to download {}", path))?;
+            into.write_all(contents.as_bytes()).map_err(|e| e.to_string())
+        }
+    }
+
+    let local = std::env::temp_dir().join(format!("remote_mirror_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&local);
+
+    let mut remote = MemoryRemote(vec![
+        ("/out/LM_XB463/a.txt".to_owned(), Some("Boxed beef\n")),
+        ("/out/LM_XB463/../../escape.txt".to_owned(), Some("no")),
+        ("/out/AL_GR110/b.txt".to_owned(), Some("Grain\n")),
+        ("/out/AL_GR110/broken.txt".to_owned(), None)
+    ].into_iter().collect());
+    let sync = |remote: &mut MemoryRemote| {
+        let mut synced = Synced::default();
+        mirror(remote, "/out", &local, 0, &mut synced);
+        synced
+    };
+
+    // a file that fails is noted, and the others are still downloaded
+    let mut synced = sync(&mut remote);
+    synced.downloaded.sort();
+    assert_eq!(synced.downloaded, vec![local.join("AL_GR110").join("b.txt"), local.join("LM_XB463").join("a.txt")]);
+    assert_eq!(synced.errors, vec!["Failed to download /out/AL_GR110/broken.txt".to_owned()]);
+    assert_eq!(fs::read_to_string(local.join("LM_XB463").join("a.txt")).unwrap(), "Boxed beef\n");
+    assert!(!local.join("AL_GR110").join("broken.txt.partial").exists());
+
+    // unchanged files aren't fetched again, and one that grew is
+    remote.0.remove("/out/AL_GR110/broken.txt");
+    assert_eq!(sync(&mut remote), Synced::default());
+    remote.0.insert("/out/AL_GR110/b.txt".to_owned(), Some("Grain, revised\n"));
+    assert_eq!(sync(&mut remote).downloaded, vec![local.join("AL_GR110").join("b.txt")]);
+
+    fs::remove_dir_all(&local).unwrap();
+}
@@ -0,0 +1,271 @@
+// Shared utilities for fetching HTML pages and the files they link to, as opposed to documented APIs.
+//
+// Every request made through a `Scraper` identifies the crate (User-Agent, and From when a contact address is
+// configured), honours the target site's robots.txt and waits between requests to the same host.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The robots.txt rules that apply to us on one host
+#[derive(Debug, Default, PartialEq)]
+pub struct RobotsRules {
+    allow: Vec<String>,
+    disallow: Vec<String>,
+    crawl_delay: Option<Duration>
+}
+
+impl RobotsRules {
+    /// Parses a robots.txt file, keeping the group addressed to `agent` if there is one, otherwise the `*` group
+    pub fn parse(text: &str, agent: &str) -> RobotsRules {
+        let agent = agent.to_lowercase();
+
+        let mut specific = RobotsRules::default();
+        let mut wildcard = RobotsRules::default();
+        let mut found_specific = false;
+
+        // agents named by the group currently being read; consecutive user-agent lines share one group
+        let mut group_agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let (key, value) = match line.find(':') {
+                Some(i) => { (line[..i].trim().to_lowercase(), line[i+1..].trim()) },
+                None => { continue }
+            };
+
+            if key == "user-agent" {
+                if in_rules {
+                    group_agents.clear();
+                    in_rules = false;
+                }
+                group_agents.push(value.to_lowercase());
+                continue;
+            }
+
+            in_rules = true;
+
+            let targets_us = group_agents.iter().any(|a| a != "*" && agent.contains(a.as_str()));
+            let targets_all = group_agents.iter().any(|a| a == "*");
+
+            let rules = if targets_us {
+                found_specific = true;
+                &mut specific
+            } else if targets_all {
+                &mut wildcard
+            } else {
+                continue
+            };
+
+            match key.as_ref() {
+                "allow" if !value.is_empty() => { rules.allow.push(value.to_owned()) },
+                "disallow" if !value.is_empty() => { rules.disallow.push(value.to_owned()) },
+                "crawl-delay" => {
+                    if let Ok(seconds) = value.parse::<f64>() {
+                        rules.crawl_delay = Some(Duration::from_millis((seconds * 1000.0) as u64));
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        if found_specific { specific } else { wildcard }
+    }
+
+    /// Whether `path` may be fetched; the longest matching rule wins and Allow wins ties
+    pub fn is_allowed(&self, path: &str) -> bool {
+        let longest = |rules: &Vec<String>| rules.iter().filter(|r| rule_matches(r, path)).map(|r| r.len()).max();
+
+        match (longest(&self.allow), longest(&self.disallow)) {
+            (_, None) => { true },
+            (None, Some(_)) => { false },
+            (Some(a), Some(d)) => { a >= d }
+        }
+    }
+}
+
+/// robots.txt path matching: a prefix match, with `*` matching any run of characters and a trailing `$`
+/// anchoring the end of the path
+fn rule_matches(rule: &str, path: &str) -> bool {
+    let (rule, anchored) = match rule.strip_suffix('$') {
+        Some(r) => { (r, true) },
+        None => { (rule, false) }
+    };
+
+    let parts: Vec<&str> = rule.split('*').collect();
+    let mut remaining = match path.strip_prefix(parts[0]) {
+        Some(r) => { r },
+        None => { return false }
+    };
+
+    for (index, part) in parts.iter().enumerate().skip(1) {
+        if anchored && index == parts.len() - 1 {
+            return remaining.ends_with(part);
+        }
+
+        match remaining.find(part) {
+            Some(i) => { remaining = &remaining[i + part.len()..] },
+            None => { return false }
+        }
+    }
+
+    !anchored || remaining.is_empty()
+}
+
+/// Splits a URL into its origin (`https://host`) and path (`/a/b?c`)
+pub fn split_url(url: &str) -> Option<(&str, &str)> {
+    let host_start = url.find("://")? + 3;
+
+    match url[host_start..].find('/') {
+        Some(i) => { Some((&url[..host_start + i], &url[host_start + i..])) },
+        None => { Some((url, "/")) }
+    }
+}
+
+pub struct Scraper {
+    user_agent: String,
+    contact: Option<String>,
+    crawl_delay: Duration,
+    http_connect_timeout: Arc<u64>,
+    http_receive_timeout: Arc<u64>,
+    robots: HashMap<String, RobotsRules>,
+    last_request: HashMap<String, Instant>
+}
+
+impl Scraper {
+    pub fn new(contact: Option<String>, crawl_delay: Duration, http_connect_timeout: Arc<u64>, http_receive_timeout: Arc<u64>) -> Scraper {
+        let user_agent = match contact.as_ref() {
+            Some(c) => { format!("{} (+mailto:{})", crate::usda::USER_AGENT, c) },
+            None => { crate::usda::USER_AGENT.to_owned() }
+        };
+
+        Scraper {
+            user_agent,
+            contact,
+            crawl_delay,
+            http_connect_timeout,
+            http_receive_timeout,
+            robots: HashMap::new(),
+            last_request: HashMap::new()
+        }
+    }
+
+    /// Waits out the crawl delay for `origin`, then performs the request
+    fn request(&mut self, url: &str, origin: &str, delay: Duration) -> ureq::Response {
+        if let Some(last) = self.last_request.get(origin) {
+            let elapsed = last.elapsed();
+            if elapsed < delay {
+                thread::sleep(delay - elapsed);
+            }
+        }
+
+        let mut request = ureq::get(url);
+        request.set("User-Agent", &self.user_agent)
+            .timeout_connect(*self.http_connect_timeout)
+            .timeout_read(*self.http_receive_timeout);
+
+        if let Some(contact) = self.contact.as_ref() {
+            request.set("From", contact);
+        }
+
+        let response = crate::session::send(&mut request);
+        self.last_request.insert(origin.to_owned(), Instant::now());
+
+        response
+    }
+
+    /// Fetches a page as text, provided robots.txt for its host permits it. A host without a robots.txt permits
+    /// everything; one whose robots.txt cannot be retrieved for other reasons permits nothing.
+    pub fn get_string(&mut self, url: &str) -> Result<String, String> {
+        self.get(url)?.into_string().map_err(|e| format!("Failed to read response from {}: {}", url, e))
+    }
+
+    /// Fetches a release file as text, as `get_string` but decoding it by its content type, see usda::content
+    pub fn get_release(&mut self, url: &str) -> Result<String, String> {
+        let response = self.get(url)?;
+        crate::usda::content::read_release(url, response)
+    }
+
+    /// Performs a request permitted by robots.txt, returning a successful response
+    fn get(&mut self, url: &str) -> Result<ureq::Response, String> {
+        let (origin, path) = match split_url(url) {
+            Some(x) => { x },
+            None => { return Err(format!("Not an absolute URL: {}", url)) }
+        };
+
+        if !self.robots.contains_key(origin) {
+            let robots_url = format!("{}/robots.txt", origin);
+            let response = self.request(&robots_url, origin, self.crawl_delay);
+
+            let rules = if response.ok() {
+                RobotsRules::parse(&response.into_string().unwrap_or_default(), &self.user_agent)
+            } else if response.status() == 404 || response.status() == 410 {
+                RobotsRules::default()
+            } else {
+                return Err(format!("Failed to retrieve {}, status {}; not scraping that host", robots_url, response.status()));
+            };
+
+            self.robots.insert(origin.to_owned(), rules);
+        }
+
+        let rules = &self.robots[origin];
+        if !rules.is_allowed(path) {
+            return Err(format!("Fetching {} is disallowed by robots.txt", url));
+        }
+
+        let delay = rules.crawl_delay.map_or(self.crawl_delay, |d| d.max(self.crawl_delay));
+        let response = self.request(url, origin, delay);
+
+        if let Some(error) = response.synthetic_error() {
+            return Err(format!("Failed to retrieve {}. Error: {}", url, error));
+        }
+
+        if !response.ok() {
+            return Err(format!("Failed to retrieve {}, status {}", url, response.status()));
+        }
+
+        Ok(response)
+    }
+}
+
+#[test]
+fn test_robots_rules() {
+    let robots = "
+User-agent: BadBot
+Disallow: /
+
+User-agent: *
+Disallow: /search
+Disallow: /mnreports/*.pdf$
+Allow: /search/help
+Crawl-delay: 2.5
+
+User-agent: data-acquistion
+User-agent: otherbot
+Disallow: /private
+";
+
+    let ours = RobotsRules::parse(robots, "data-acquistion/0.1");
+    assert!(ours.is_allowed("/search"));
+    assert!(!ours.is_allowed("/private/file.txt"));
+    assert_eq!(ours.crawl_delay, None);
+
+    let everyone = RobotsRules::parse(robots, "somebody-else/1.0");
+    assert!(!everyone.is_allowed("/search?q=cattle"));
+    assert!(everyone.is_allowed("/search/help"));
+    assert!(!everyone.is_allowed("/mnreports/lm_xb463.pdf"));
+    assert!(everyone.is_allowed("/mnreports/lm_xb463.txt"));
+    assert!(!everyone.is_allowed("/mnreports/lm_xb463.pdf.pdf"));
+    assert_eq!(everyone.crawl_delay, Some(Duration::from_millis(2500)));
+
+    assert!(RobotsRules::parse("", "anyone").is_allowed("/anything"));
+}
+
+#[test]
+fn test_split_url() {
+    assert_eq!(split_url("https://www.ams.usda.gov/mnreports/lm_xb463.txt"), Some(("https://www.ams.usda.gov", "/mnreports/lm_xb463.txt")));
+    assert_eq!(split_url("https://www.ams.usda.gov"), Some(("https://www.ams.usda.gov", "/")));
+    assert_eq!(split_url("lm_xb463.txt"), None);
+}
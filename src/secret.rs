@@ -0,0 +1,120 @@
+// The secret configuration (config/secret.toml): the credentials kept apart from the rest of the configuration.
+//
+// Each table is typed, so that a missing or misspelt key or table is named when the file is read rather than found
+// by a panic wherever it is first looked up. The credentials of remotes (see config/remotes.toml) are tables of
+// [remotes], e.g. [remotes.partner_archive].
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::digest::SmtpSettings;
+use crate::remote::Credentials;
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PostgresSecret {
+    pub dbname: Option<String>,
+    pub password: Option<String>
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TokenSecret {
+    pub token: String
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KeySecret {
+    pub key: String
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SecretConfig {
+    #[serde(default)]
+    pub postgres: PostgresSecret,
+    pub esmis: Option<TokenSecret>,     // the ESMIS API token, prompted for when not given
+    pub mars: Option<KeySecret>,        // only needed for reports served by version 2 of the datamart API
+    pub smtp: Option<SmtpSettings>,     // see digest.rs
+    pub webhook: Option<TokenSecret>,   // see webhook.rs
+    #[serde(default)]
+    pub remotes: BTreeMap<String, Credentials>
+}
+
+impl SecretConfig {
+    /// The credentials of the table `table` of [remotes], as a remote logs in with them
+    pub fn remote_credentials(&self, table: &str) -> Result<Credentials, String> {
+        self.remotes.get(table).cloned().ok_or_else(|| format!("No [remotes.{}] table of credentials in the secret configuration", table))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText {
+    Number(u16),
+    Text(String)
+}
+
+/// Reads a port given as a number or, as in older secret configurations, as text
+pub fn port<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    match NumberOrText::deserialize(deserializer)? {
+        NumberOrText::Number(port) => { Ok(Some(port)) },
+        NumberOrText::Text(text) => { text.parse().map(Some).map_err(|_| serde::de::Error::custom(format!("invalid port: {}", text))) }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListOrText {
+    List(Vec<String>),
+    Text(String)
+}
+
+/// Reads a list given as an array or as comma-separated text, requiring at least one item
+pub fn list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let items: Vec<String> = match ListOrText::deserialize(deserializer)? {
+        ListOrText::List(items) => { items },
+        ListOrText::Text(text) => { text.split(',').map(str::to_owned).collect() }
+    };
+
+    let items: Vec<String> = items.iter().map(|i| i.trim().to_owned()).filter(|i| !i.is_empty()).collect();
+    if items.is_empty() {
+        return Err(serde::de::Error::custom("expected at least one item"));
+    }
+
+    Ok(items)
+}
+
+#[test]
+fn test_secret_config() {
+    let config: SecretConfig = toml::from_str(r#"
+        [postgres]
+        password = "p"
+
+        [esmis]
+        token = "t"
+
+        [remotes.partner_archive]
+        username = "usda"
+        key_file = "/home/usda/.ssh/id_ed25519"
+    "#).unwrap();
+
+    assert_eq!(config.postgres, PostgresSecret { dbname: None, password: Some("p".to_owned()) });
+    assert_eq!(config.esmis.as_ref().unwrap().token, "t");
+    assert!(config.mars.is_none());
+    assert!(config.remote_credentials("partner_archive").is_ok());
+    assert!(config.remote_credentials("other").unwrap_err().contains("No [remotes.other] table"));
+
+    // keys and tables are checked as the file is read
+    let error = toml::from_str::<SecretConfig>("[esmis]\ntoken = \"t\"\napi_key = \"k\"").unwrap_err().to_string();
+    assert!(error.contains("api_key"), "{}", error);
+    assert!(toml::from_str::<SecretConfig>("[mars]\ntoken = \"k\"").is_err());
+
+    let error = toml::from_str::<SecretConfig>("[postgress]\npassword = \"p\"").unwrap_err().to_string();
+    assert!(error.contains("postgress"), "{}", error);
+
+    let error = toml::from_str::<SecretConfig>("[remotes.partner_archive]\npasword = \"p\"\nusername = \"u\"").unwrap_err().to_string();
+    assert!(error.contains("pasword"), "{}", error);
+}
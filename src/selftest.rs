@@ -0,0 +1,82 @@
+// Checking each source a run depends on with the smallest request it answers (--selftest), so that an expired
+// token, a changed password or a blocked port shows up before a scheduled run rather than halfway through one.
+//
+// Each check is independent of the others and reports what it found, and a source that isn't configured (e.g. MARS
+// without a key) is skipped rather than failed.
+
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass(String),
+    Fail(String),
+    Skip(String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub source: String,
+    pub outcome: Outcome,
+    pub elapsed: Duration
+}
+
+/// Runs the check of `source`, taking an `Err` as a failure and `Ok(None)` as a source that isn't configured
+pub fn check(source: &str, f: impl FnOnce() -> Result<Option<String>, String>) -> Check {
+    let started = Instant::now();
+
+    let outcome = match f() {
+        Ok(Some(detail)) => { Outcome::Pass(detail) },
+        Ok(None) => { Outcome::Skip("not configured".to_owned()) },
+        Err(e) => { Outcome::Fail(e) }
+    };
+
+    Check { source: source.to_owned(), outcome, elapsed: started.elapsed() }
+}
+
+/// Whether none of the checks failed
+pub fn passed(checks: &[Check]) -> bool {
+    !checks.iter().any(|c| matches!(c.outcome, Outcome::Fail(_)))
+}
+
+/// The checks as a table of text, one line per source
+pub fn render(checks: &[Check]) -> String {
+    let width = checks.iter().map(|c| c.source.len()).max().unwrap_or(0).max("source".len());
+
+    let mut table = String::new();
+    let _ = writeln!(table, "{:width$}  {:6}  {:>8}  detail", "source", "result", "time", width = width);
+
+    for check in checks {
+        let (result, detail) = match &check.outcome {
+            Outcome::Pass(d) => { ("pass", d) },
+            Outcome::Fail(d) => { ("FAIL", d) },
+            Outcome::Skip(d) => { ("skip", d) }
+        };
+        let time = format!("{:.1}s", check.elapsed.as_secs_f64());
+
+        let _ = writeln!(table, "{:width$}  {:6}  {:>8}  {}", check.source, result, time, detail, width = width);
+    }
+
+    table
+}
+
+#[test]
+fn test_render() {
+    let checks = vec![
+        check("datamart", || Ok(Some("answered".to_owned()))),
+        check("MARS", || Ok(None)),
+        check("PostgreSQL", || Err("password authentication failed".to_owned()))
+    ];
+
+    assert!(!passed(&checks));
+    assert!(passed(&checks[..2]));
+    assert_eq!(checks[1].outcome, Outcome::Skip("not configured".to_owned()));
+
+    let lines: Vec<String> = render(&checks).lines().map(|l| l.replace(char::is_numeric, "0")).collect();
+    assert_eq!(lines, vec![
+        "source      result      time  detail",
+        "datamart    pass        0.0s  answered",
+        "MARS        skip        0.0s  not configured",
+        "PostgreSQL  FAIL        0.0s  password authentication failed"
+    ]);
+}
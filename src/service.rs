@@ -0,0 +1,199 @@
+// Running daemon mode as a Windows service. --install-service registers the command line it is given, less itself
+// and plus --daemon, to be started by the service control manager with the machine; --uninstall-service removes it.
+//
+// A service starts in the system directory, so the directory --install-service was run from is passed along with
+// --run-as-service and made the working directory again, for relative configuration paths to keep working. A
+// service can't prompt for a password either, so PostgreSQL's has to be in the secret configuration or PGPASSWORD,
+// and its output is best sent to a file with --log-file.
+//
+// Where a periodic --update will do rather than a resident daemon, Task Scheduler can run the command line as it is.
+// Elsewhere, the daemon is left to systemd or the like, and stop_requested is never set.
+
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+pub const SERVICE_NAME: &str = "data-acquisition";
+
+/// The longest the daemon waits between checks for a stop
+pub const STOP_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Whether the service control manager has asked the daemon to stop, which it does between passes
+pub fn stop_requested() -> bool {
+    STOP.load(Ordering::Relaxed)
+}
+
+/// The arguments a service is started with, from those --install-service was given (program name first)
+pub fn service_arguments(arguments: &[OsString], working_directory: &Path) -> Vec<OsString> {
+    let mut service: Vec<OsString> = arguments.iter().skip(1).filter(|a| *a != "--install-service").cloned().collect();
+
+    if !service.iter().any(|a| a == "--daemon") {
+        service.push("--daemon".into());
+    }
+    service.push("--run-as-service".into());
+    service.push(working_directory.as_os_str().to_owned());
+
+    service
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::OsString;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use windows_service::{define_windows_service, service_dispatcher};
+    use windows_service::service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+        ServiceStartType, ServiceState, ServiceStatus, ServiceType
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    use super::{SERVICE_NAME, STOP};
+
+    lazy_static! {
+        /// The daemon, handed from run_as_service to the service's main function
+        static ref DAEMON: Mutex<Option<Box<dyn FnOnce() + Send>>> = Mutex::new(None);
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub fn install(arguments: Vec<OsString>) -> Result<(), String> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE)
+            .map_err(|e| format!("Failed to connect to the service control manager: {}", e))?;
+        let executable_path = std::env::current_exe().map_err(|e| format!("Failed to find this executable: {}", e))?;
+
+        let info = ServiceInfo {
+            name: OsString::from(SERVICE_NAME),
+            display_name: OsString::from("USDA data acquisition"),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path,
+            launch_arguments: arguments,
+            dependencies: vec![],
+            account_name: None,     // LocalSystem
+            account_password: None
+        };
+
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)
+            .map_err(|e| format!("Failed to install the {} service: {}", SERVICE_NAME, e))?;
+        service.set_description("Downloads USDA and NOAA data into PostgreSQL on a schedule")
+            .map_err(|e| format!("Failed to describe the {} service: {}", SERVICE_NAME, e))
+    }
+
+    /// Stops the service if it is running and removes it, which Windows completes once it has stopped
+    pub fn uninstall() -> Result<(), String> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .map_err(|e| format!("Failed to connect to the service control manager: {}", e))?;
+        let service = manager.open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+            .map_err(|e| format!("Failed to open the {} service: {}", SERVICE_NAME, e))?;
+
+        service.delete().map_err(|e| format!("Failed to remove the {} service: {}", SERVICE_NAME, e))?;
+
+        let status = service.query_status().map_err(|e| format!("Failed to query the {} service: {}", SERVICE_NAME, e))?;
+        if status.current_state != ServiceState::Stopped {
+            service.stop().map_err(|e| format!("Failed to stop the {} service: {}", SERVICE_NAME, e))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn run_as_service(working_directory: &Path, daemon: Box<dyn FnOnce() + Send>) -> Result<(), String> {
+        std::env::set_current_dir(working_directory)
+            .map_err(|e| format!("Failed to change to the service's directory {}: {}", working_directory.display(), e))?;
+        *DAEMON.lock().unwrap() = Some(daemon);
+
+        // returns once the service has stopped
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .map_err(|e| format!("Failed to start as a service, --run-as-service is for the service control manager only: {}", e))
+    }
+
+    fn set_state(handle: &ServiceStatusHandle, state: ServiceState, wait_hint: Duration) {
+        let status = ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: if state == ServiceState::Running { ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN } else { ServiceControlAccept::empty() },
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint,
+            process_id: None
+        };
+
+        if let Err(e) = handle.set_service_status(status) {
+            error!("Failed to report the service's state: {}", e);
+        }
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let handler = |control| -> ServiceControlHandlerResult {
+            match control {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    info!("Stop requested, stopping after the current pass.");
+                    STOP.store(true, Ordering::Relaxed);
+                    ServiceControlHandlerResult::NoError
+                },
+                ServiceControl::Interrogate => { ServiceControlHandlerResult::NoError },
+                _ => { ServiceControlHandlerResult::NotImplemented }
+            }
+        };
+
+        let handle = match service_control_handler::register(SERVICE_NAME, handler) {
+            Ok(h) => { h },
+            Err(e) => {
+                error!("Failed to register the service's control handler: {}", e);
+                return;
+            }
+        };
+
+        set_state(&handle, ServiceState::Running, Duration::default());
+
+        if let Some(daemon) = DAEMON.lock().unwrap().take() {
+            daemon();
+        }
+
+        set_state(&handle, ServiceState::Stopped, Duration::default());
+    }
+}
+
+#[cfg(windows)]
+pub use self::windows::{install, run_as_service, uninstall};
+
+#[cfg(not(windows))]
+const UNSUPPORTED: &str = "Windows services are only available on Windows. Elsewhere, run --daemon under systemd or another supervisor.";
+
+#[cfg(not(windows))]
+pub fn install(_arguments: Vec<OsString>) -> Result<(), String> {
+    Err(UNSUPPORTED.to_owned())
+}
+
+#[cfg(not(windows))]
+pub fn uninstall() -> Result<(), String> {
+    Err(UNSUPPORTED.to_owned())
+}
+
+#[cfg(not(windows))]
+pub fn run_as_service(_working_directory: &Path, _daemon: Box<dyn FnOnce() + Send>) -> Result<(), String> {
+    Err(UNSUPPORTED.to_owned())
+}
+
+#[test]
+fn test_service_arguments() {
+    let arguments: Vec<OsString> = ["data-acquisition", "--install-service", "--update-interval", "30", "--log-file", "daemon.log"]
+        .iter().map(OsString::from).collect();
+
+    assert_eq!(
+        service_arguments(&arguments, Path::new("/srv/data-acquisition")),
+        ["--update-interval", "30", "--log-file", "daemon.log", "--daemon", "--run-as-service", "/srv/data-acquisition"]
+            .iter().map(OsString::from).collect::<Vec<OsString>>()
+    );
+
+    let daemon: Vec<OsString> = ["data-acquisition", "--daemon", "--install-service"].iter().map(OsString::from).collect();
+    assert_eq!(service_arguments(&daemon, Path::new("C:\\data")).iter().filter(|a| *a == "--daemon").count(), 1);
+}
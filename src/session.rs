@@ -0,0 +1,395 @@
+// Recording every response a run gets (--record FILE) and running again on those alone (--replay FILE), so that a
+// run that went wrong, say a backfill that failed on a response datamart no longer sends, can be debugged offline
+// and gives the same results each time.
+//
+// A session is a tar file of the exchanges of a run in the order they happened: NNNNNN.json describing the request,
+// an HTTP URL, a NOAA FTP path or a file of a remote archive, and what came back, and NNNNNN.body holding the body
+// as it came. A replay answers each request with the next exchange recorded for it; requests that weren't recorded
+// fail, including those of an update replayed on a later day, which asks for data up to the day it is run.
+// Credentials go in headers, which aren't recorded, so sessions hold none.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref SESSION: Mutex<Option<Session>> = Mutex::new(None);
+}
+
+/// Marks a replayed response whose body wasn't text, and is given in hex, as a ureq response can only be made from
+/// text. `read_body` decodes it.
+const HEX_BODY_HEADER: &str = "X-Session-Body";
+
+/// Response headers not recorded, as the replayed response is made whole again
+const SKIPPED_HEADERS: &[&str] = &["content-length", "transfer-encoding", "connection", "set-cookie"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub kind: String,                   // "http" or "ftp", which remote archives are recorded as too
+    pub key: String,                    // the URL, FTP path or remote archive file requested
+    #[serde(default)]
+    pub status: u16,
+    #[serde(default)]
+    pub status_text: String,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub error: Option<String>           // why the request failed, instead of a response
+}
+
+/// Writes the exchanges of a run to a session file
+pub struct Recorder {
+    builder: tar::Builder<File>,
+    count: usize
+}
+
+/// An exchange of a session file, with where its body is in the file
+struct Stored {
+    exchange: Exchange,
+    offset: u64,
+    size: u64,
+    used: bool
+}
+
+/// Answers requests from a session file
+pub struct Replayer {
+    file: File,
+    exchanges: Vec<Stored>
+}
+
+enum Session {
+    Recording(Recorder),
+    Replaying(Replayer)
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Recorder, String> {
+        let file = File::create(path).map_err(|e| format!("Failed to create session file {}: {}", path.display(), e))?;
+        Ok(Recorder { builder: tar::Builder::new(file), count: 0 })
+    }
+
+    fn append(&mut self, name: &str, size: u64, data: impl Read) -> io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        self.builder.append_data(&mut header, name, data)
+    }
+
+    /// Adds an exchange, with `size` bytes of body read from `body`
+    pub fn add(&mut self, exchange: &Exchange, size: u64, body: impl Read) -> Result<(), String> {
+        let description = serde_json::to_vec(exchange).map_err(|e| e.to_string())?;
+
+        self.append(&format!("{:06}.json", self.count), description.len() as u64, &description[..])
+            .and_then(|_| self.append(&format!("{:06}.body", self.count), size, body))
+            .map_err(|e| format!("Failed to record the response to {} in the session: {}", exchange.key, e))?;
+
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Records the response to a request of `url`, giving back a response the same as it
+    pub fn add_response(&mut self, url: &str, response: ureq::Response) -> ureq::Response {
+        let (exchange, body) = capture(url, response);
+
+        if let Err(e) = self.add(&exchange, body.len() as u64, &body[..]) {
+            error!("{}", e);
+        }
+
+        to_response(&exchange, body)
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.builder.into_inner().and_then(|file| file.sync_all()).map_err(|e| format!("Failed to write the session file: {}", e))
+    }
+}
+
+impl Replayer {
+    pub fn open(path: &Path) -> Result<Replayer, String> {
+        let error = |e: io::Error| format!("Failed to read session file {}: {}", path.display(), e);
+        let file = File::open(path).map_err(error)?;
+
+        let mut exchanges = Vec::new();
+        let mut archive = tar::Archive::new(&file);
+
+        for entry in archive.entries().map_err(error)? {
+            let mut entry = entry.map_err(error)?;
+            let name = entry.path().map_err(error)?.to_string_lossy().into_owned();
+
+            if name.ends_with(".json") {
+                let mut description = String::new();
+                entry.read_to_string(&mut description).map_err(error)?;
+                let exchange = serde_json::from_str(&description).map_err(|e| format!("Invalid exchange {} in session file {}: {}", name, path.display(), e))?;
+                exchanges.push(Stored { exchange, offset: 0, size: 0, used: false });
+            } else if let Some(stored) = exchanges.last_mut() {
+                stored.offset = entry.raw_file_position();
+                stored.size = entry.size();
+            }
+        }
+
+        Ok(Replayer { file: File::open(path).map_err(error)?, exchanges })
+    }
+
+    /// The next exchange recorded for `key`
+    fn take(&mut self, kind: &str, key: &str) -> Option<usize> {
+        let found = self.exchanges.iter().position(|s| !s.used && s.exchange.kind == kind && s.exchange.key == key)?;
+
+        self.exchanges[found].used = true;
+        Some(found)
+    }
+
+    fn body(&mut self, index: usize) -> io::Result<Vec<u8>> {
+        let stored = &self.exchanges[index];
+        let mut body = Vec::with_capacity(stored.size as usize);
+
+        self.file.seek(SeekFrom::Start(stored.offset))?;
+        (&mut self.file).take(stored.size).read_to_end(&mut body)?;
+
+        Ok(body)
+    }
+
+    /// The response recorded to a request of `url`
+    pub fn response(&mut self, url: &str) -> ureq::Response {
+        let index = match self.take("http", url) {
+            Some(index) => { index },
+            None => { return ureq::Error::BadUrl(format!("{} was not recorded in the session replayed", url)).into() }
+        };
+
+        match self.body(index) {
+            Ok(body) => { to_response(&self.exchanges[index].exchange, body) },
+            Err(e) => { ureq::Error::Io(e).into() }
+        }
+    }
+
+    /// The FTP file or listing recorded for `path`, as the bytes it came as
+    pub fn ftp(&mut self, path: &str) -> Result<Vec<u8>, String> {
+        let index = self.take("ftp", path).ok_or_else(|| format!("{} was not recorded in the session replayed", path))?;
+
+        if let Some(error) = &self.exchanges[index].exchange.error {
+            return Err(error.to_owned());
+        }
+
+        self.body(index).map_err(|e| format!("Failed to read {} from the session: {}", path, e))
+    }
+}
+
+/// The exchange of a response to `url`, and its body
+fn capture(url: &str, response: ureq::Response) -> (Exchange, Vec<u8>) {
+    let mut exchange = Exchange {
+        kind: "http".to_owned(),
+        key: url.to_owned(),
+        status: response.status(),
+        status_text: response.status_text().to_owned(),
+        headers: Vec::new(),
+        error: response.synthetic_error().as_ref().map(|e| e.to_string())
+    };
+
+    if exchange.error.is_some() {
+        return (exchange, Vec::new());
+    }
+
+    for name in response.headers_names() {
+        if SKIPPED_HEADERS.contains(&name.to_lowercase().as_str()) {
+            continue;
+        }
+        for value in response.all(&name) {
+            exchange.headers.push((name.to_owned(), value.to_owned()));
+        }
+    }
+
+    let mut body = Vec::new();
+    if let Err(e) = response.into_reader().read_to_end(&mut body) {
+        exchange.error = Some(format!("Failed to read the response: {}", e));
+    }
+
+    (exchange, body)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &[u8]) -> io::Result<Vec<u8>> {
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid hex body"));
+    text.chunks(2).map(|pair| Ok(digit(pair[0])? << 4 | digit(*pair.get(1).unwrap_or(&b'?'))?)).collect()
+}
+
+/// A response made from an exchange and its body. Failed requests fail again, as a connection that failed, so that
+/// they are retried as they were.
+fn to_response(exchange: &Exchange, body: Vec<u8>) -> ureq::Response {
+    if let Some(error) = &exchange.error {
+        return ureq::Error::ConnectionFailed(error.to_owned()).into();
+    }
+
+    let (body, hex) = match String::from_utf8(body) {
+        Ok(text) => { (text, false) },
+        Err(e) => { (to_hex(e.as_bytes()), true) }
+    };
+
+    let mut text = format!("HTTP/1.1 {} {}\r\n", exchange.status, exchange.status_text);
+    for (name, value) in &exchange.headers {
+        text.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if hex {
+        text.push_str(&format!("{}: hex\r\n", HEX_BODY_HEADER));
+    }
+    text.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+
+    text.parse().unwrap_or_else(|e: ureq::Error| e.into())
+}
+
+/// Reads up to `limit` bytes of the body of a response, as sent: responses of a session that weren't text are
+/// decoded from hex
+pub fn read_body(response: ureq::Response, limit: u64) -> io::Result<Vec<u8>> {
+    let hex = response.header(HEX_BODY_HEADER).is_some();
+
+    let mut body = Vec::new();
+    if hex {
+        response.into_reader().take(limit.saturating_mul(2)).read_to_end(&mut body)?;
+        return from_hex(&body);
+    }
+
+    response.into_reader().take(limit).read_to_end(&mut body)?;
+    Ok(body)
+}
+
+/// Records the responses of this run in a session file at `path`
+pub fn record(path: &Path) -> Result<(), String> {
+    *SESSION.lock().unwrap() = Some(Session::Recording(Recorder::create(path)?));
+    Ok(())
+}
+
+/// Answers the requests of this run from the session file at `path`
+pub fn replay(path: &Path) -> Result<(), String> {
+    *SESSION.lock().unwrap() = Some(Session::Replaying(Replayer::open(path)?));
+    Ok(())
+}
+
+/// Completes the session file being recorded, if one is
+pub fn finish() -> Result<(), String> {
+    match SESSION.lock().unwrap().take() {
+        Some(Session::Recording(recorder)) => { recorder.finish() },
+        _ => { Ok(()) }
+    }
+}
+
+/// Whether requests are answered from a session file, and so needn't be spaced out or waited on before retrying
+pub fn is_replaying() -> bool {
+    matches!(SESSION.lock().unwrap().as_ref(), Some(Session::Replaying(_)))
+}
+
+/// Makes a request once, recording its response or answering it from the session replayed
+pub fn send(request: &mut ureq::Request) -> ureq::Response {
+    let url = request.get_url().to_owned();
+
+    if let Some(Session::Replaying(replayer)) = SESSION.lock().unwrap().as_mut() {
+        return replayer.response(&url);
+    }
+
+    // the session isn't locked while the request is made or its body read, so that parallel requests go ahead
+    let response = request.call();
+
+    if !matches!(SESSION.lock().unwrap().as_ref(), Some(Session::Recording(_))) {
+        return response;
+    }
+
+    let (exchange, body) = capture(&url, response);
+
+    if let Some(Session::Recording(recorder)) = SESSION.lock().unwrap().as_mut() {
+        if let Err(e) = recorder.add(&exchange, body.len() as u64, &body[..]) {
+            error!("{}", e);
+        }
+    }
+
+    to_response(&exchange, body)
+}
+
+/// Fetches an FTP file or listing of `path` with `fetch`, recording it or answering from the session replayed
+pub fn ftp(path: &str, fetch: impl FnOnce() -> Result<Vec<u8>, String>) -> Result<Vec<u8>, String> {
+    if let Some(Session::Replaying(replayer)) = SESSION.lock().unwrap().as_mut() {
+        return replayer.ftp(path);
+    }
+
+    let fetched = fetch();
+
+    if let Some(Session::Recording(recorder)) = SESSION.lock().unwrap().as_mut() {
+        let exchange = ftp_exchange(path, fetched.as_ref().err());
+        let body: &[u8] = fetched.as_deref().unwrap_or_default();
+
+        if let Err(e) = recorder.add(&exchange, body.len() as u64, body) {
+            error!("{}", e);
+        }
+    }
+
+    fetched
+}
+
+/// As `ftp`, for a file fetched into `file` rather than into memory. The file is left at its start.
+pub fn ftp_to_file(path: &str, file: &mut File, fetch: impl FnOnce(&mut File) -> Result<(), String>) -> Result<(), String> {
+    if let Some(Session::Replaying(replayer)) = SESSION.lock().unwrap().as_mut() {
+        let body = replayer.ftp(path)?;
+        io::copy(&mut Cursor::new(body), file).map_err(|e| e.to_string())?;
+        file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        return Ok(());
+    }
+
+    let fetched = fetch(file);
+    file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+
+    if let Some(Session::Recording(recorder)) = SESSION.lock().unwrap().as_mut() {
+        let exchange = ftp_exchange(path, fetched.as_ref().err());
+        let size = if fetched.is_ok() { file.metadata().map_err(|e| e.to_string())?.len() } else { 0 };
+
+        if let Err(e) = recorder.add(&exchange, size, (&*file).take(size)) {
+            error!("{}", e);
+        }
+        file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    }
+
+    fetched
+}
+
+fn ftp_exchange(path: &str, error: Option<&String>) -> Exchange {
+    Exchange { kind: "ftp".to_owned(), key: path.to_owned(), status: 0, status_text: String::new(), headers: Vec::new(), error: error.cloned() }
+}
+
+#[test]
+fn test_record_and_replay() {
+    let path = std::env::temp_dir().join(format!("data-acquisition-session-test-{}.tar", std::process::id()));
+    let url = "https://mpr.datamart.ams.usda.gov/services/v1.1/reports/2466/Summary?q=report_date=03/02/2020";
+    let gzipped = vec![0x1f, 0x8b, 0x08, 0x00, 0xff];
+
+    let mut recorder = Recorder::create(&path).unwrap();
+    let response = recorder.add_response(url, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"results\": []}".parse().unwrap());
+    assert_eq!(response.into_string().unwrap(), "{\"results\": []}");
+    recorder.add_response("https://mpr.datamart.ams.usda.gov/services/v1.1/reports/2466", ureq::Error::DnsFailed("no network".to_owned()).into());
+    recorder.add(&ftp_exchange("/pub/data/ghcn/daily/ghcnd_gsn.tar.gz", None), gzipped.len() as u64, &gzipped[..]).unwrap();
+    recorder.finish().unwrap();
+
+    let mut replayer = Replayer::open(&path).unwrap();
+    assert_eq!(replayer.exchanges.len(), 3);
+
+    // a request for another day wasn't recorded, and each response is replayed once
+    let missed = replayer.response("https://mpr.datamart.ams.usda.gov/services/v1.1/reports/2466/Summary?q=report_date=03/03/2020");
+    assert!(matches!(missed.synthetic_error(), Some(ureq::Error::BadUrl(_))));
+    let response = replayer.response(url);
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.header("Content-Type"), Some("application/json"));
+    assert_eq!(read_body(response, 1024).unwrap(), b"{\"results\": []}");
+    assert!(matches!(replayer.response(url).synthetic_error(), Some(ureq::Error::BadUrl(_))));
+
+    // failures fail again, as transient ones
+    let failed = replayer.response("https://mpr.datamart.ams.usda.gov/services/v1.1/reports/2466");
+    assert!(crate::http::is_transient(&failed));
+
+    assert_eq!(replayer.ftp("/pub/data/ghcn/daily/ghcnd_gsn.tar.gz").unwrap(), gzipped);
+    assert!(replayer.ftp("/pub/data/ghcn/daily/ghcnd-stations.txt").is_err());
+
+    // bodies that aren't text are given in hex, and read back as they were
+    let exchange = Exchange { kind: "http".to_owned(), key: url.to_owned(), status: 200, status_text: "OK".to_owned(), headers: vec![], error: None };
+    let binary = to_response(&exchange, gzipped.clone());
+    assert_eq!(read_body(binary, 1024).unwrap(), gzipped);
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,126 @@
+// Decoding downloaded release files into text for the legacy parsers, by what they turn out to be rather than
+// assuming UTF-8 text.
+//
+// The Content-Type header is consulted along with the leading bytes of the body, as servers don't always label
+// files correctly: text is decoded with the charset given (falling back to Windows-1252, which older releases
+// use, when there is none and the body isn't UTF-8), zipped and gzipped releases are unpacked first, and PDFs and
+// other binary files are rejected with an error naming what they are.
+
+use std::io::{Cursor, Read};
+
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252};
+use flate2::read::GzDecoder;
+
+/// Largest release file read, well beyond any text release published
+const MAX_RELEASE_BYTES: u64 = 50 * 1024 * 1024;
+
+/// The text of a release from an HTTP response
+pub fn read_release(url: &str, response: ureq::Response) -> Result<String, String> {
+    let content_type = response.header("Content-Type").map(str::to_owned);
+
+    let bytes = crate::session::read_body(response, MAX_RELEASE_BYTES)
+        .map_err(|e| format!("Failed to read response from {}: {}", url, e))?;
+
+    decode_release(&bytes, content_type.as_deref()).map_err(|e| format!("{}: {}", url, e))
+}
+
+/// The media type and charset of a Content-Type header, lowercased
+fn parse_content_type(content_type: &str) -> (String, Option<String>) {
+    let mut parts = content_type.split(';');
+    let media_type = parts.next().unwrap_or_default().trim().to_lowercase();
+
+    let charset = parts
+        .filter_map(|p| p.trim().split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_lowercase());
+
+    (media_type, charset)
+}
+
+/// The text of a release file, unpacking archives and decoding the charset given by `content_type`
+pub fn decode_release(bytes: &[u8], content_type: Option<&str>) -> Result<String, String> {
+    let (media_type, charset) = content_type.map(parse_content_type).unwrap_or_default();
+
+    if bytes.starts_with(b"%PDF-") || media_type == "application/pdf" {
+        return Err("the release is a PDF, which can't be parsed; only text releases can".to_owned());
+    }
+
+    if bytes.starts_with(b"PK\x03\x04") || media_type == "application/zip" {
+        return decode_release(&unzip_text(bytes)?, None);
+    }
+
+    if bytes.starts_with(&[0x1f, 0x8b]) || media_type == "application/gzip" || media_type == "application/x-gzip" {
+        let mut unpacked = Vec::new();
+        GzDecoder::new(bytes).take(MAX_RELEASE_BYTES).read_to_end(&mut unpacked).map_err(|e| format!("the release isn't valid gzip: {}", e))?;
+        return decode_release(&unpacked, None);
+    }
+
+    let text = match charset {
+        Some(label) => {
+            let encoding = Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown charset {}", label))?;
+            let (text, _, malformed) = encoding.decode(bytes);
+            if malformed {
+                return Err(format!("the release isn't valid {}", encoding.name()));
+            }
+            text.into_owned()
+        },
+        None => {
+            match UTF_8.decode_without_bom_handling_and_without_replacement(bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes)) {
+                Some(text) => { text.into_owned() },
+                None => { WINDOWS_1252.decode(bytes).0.into_owned() }
+            }
+        }
+    };
+
+    // text releases have no control characters beyond line breaks, tabs and the odd form feed
+    if text.chars().any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t' | '\x0c')) {
+        return Err(format!("the release is binary{}, not text", if media_type.is_empty() { String::new() } else { format!(" ({})", media_type) }));
+    }
+
+    Ok(text)
+}
+
+/// The first text file in a zip archive
+fn unzip_text(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(|e| format!("the release isn't a valid zip archive: {}", e))?;
+
+    let name = archive.file_names()
+        .find(|n| n.to_lowercase().ends_with(".txt"))
+        .map(str::to_owned)
+        .ok_or_else(|| "the zip archive has no text file".to_owned())?;
+
+    let mut unpacked = Vec::new();
+    archive.by_name(&name).map_err(|e| e.to_string())?
+        .take(MAX_RELEASE_BYTES).read_to_end(&mut unpacked)
+        .map_err(|e| format!("Failed to unpack {}: {}", name, e))?;
+
+    Ok(unpacked)
+}
+
+#[test]
+fn test_decode_release() {
+    use std::io::Write;
+
+    assert_eq!(decode_release("Dodge City  5.41\n".as_bytes(), Some("text/plain")), Ok("Dodge City  5.41\n".to_owned()));
+    assert_eq!(decode_release(b"\xef\xbb\xbfCORN\n", None), Ok("CORN\n".to_owned()));
+
+    // Windows-1252, labelled and not
+    assert_eq!(decode_release(b"Ca\xf1on City\n", Some("text/plain; charset=\"ISO-8859-1\"")), Ok("Cañon City\n".to_owned()));
+    assert_eq!(decode_release(b"Ca\xf1on City\n", None), Ok("Cañon City\n".to_owned()));
+    assert!(decode_release(b"Ca\xf1on City\n", Some("text/plain; charset=utf-8")).is_err());
+
+    assert!(decode_release(b"%PDF-1.4\n...", None).unwrap_err().contains("PDF"));
+    assert!(decode_release(b"\x00\x01\x02binary", Some("application/octet-stream")).unwrap_err().contains("binary"));
+
+    let mut gzipped = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzipped.write_all(b"SOYBEANS\n").unwrap();
+    assert_eq!(decode_release(&gzipped.finish().unwrap(), None), Ok("SOYBEANS\n".to_owned()));
+
+    let mut zipped = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    zipped.start_file("README.pdf", zip::write::FileOptions::default()).unwrap();
+    zipped.write_all(b"%PDF-1.4").unwrap();
+    zipped.start_file("lm_xb463.TXT", zip::write::FileOptions::default()).unwrap();
+    zipped.write_all(b"Boxed beef\n").unwrap();
+    let zipped = zipped.finish().unwrap().into_inner();
+    assert_eq!(decode_release(&zipped, Some("application/zip")), Ok("Boxed beef\n".to_owned()));
+}
@@ -1,30 +1,309 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use chrono::{NaiveDate, Local, Datelike};
-use regex::Regex;
 use serde::Deserialize;
 
 use super::{USDADataPackage, USDADataPackageSection};
+use super::dates;
+use super::declarative::TextParserSpec;
+use super::marsmodels::MarsFamily;
+use crate::archive;
+use crate::integration::usda::OnConflict;
+use crate::jobs;
+use crate::metrics;
+use super::transform::TransformConfig;
 
 const DATAMART_BASE_URL: &str = "https://mpr.datamart.ams.usda.gov/services/v1.1/reports";
+const DATAMART_V2_BASE_URL: &str = "https://marsapi.ams.usda.gov/services/v1.2/reports";
 
-#[derive(Deserialize, Debug)]
+/// Sections of a report fetched at once. Each is an endpoint of its own, and datamart is slow to answer rather than
+/// slow to send, so a report with many sections comes in about as quickly as its slowest.
+const SECTION_JOBS: usize = 4;
+
+/// Whether responses whose columns differ from the configuration fail to parse, see set_strict_schema
+static STRICT_SCHEMA: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// Where reports are fetched from in place of USDA's servers, by API version, see set_base_url
+    static ref BASE_URLS: Mutex<HashMap<DatamartApiVersion, String>> = Mutex::new(HashMap::new());
+}
+
+/// Reports migrated by USDA to the newer API are served from a different host, require an API key
+/// (HTTP basic auth, key as username) and return typed JSON values rather than strings.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DatamartApiVersion {
+    #[default]
+    #[serde(rename = "1.1")]
+    V1,
+    #[serde(rename = "2")]
+    V2
+}
+
+impl DatamartApiVersion {
+    fn base_url(self) -> String {
+        if let Some(url) = BASE_URLS.lock().unwrap().get(&self) {
+            return url.to_owned();
+        }
+
+        match self {
+            DatamartApiVersion::V1 => DATAMART_BASE_URL,
+            DatamartApiVersion::V2 => DATAMART_V2_BASE_URL
+        }.to_owned()
+    }
+}
+
+/// The [endpoints] table of the datamart configuration: base URLs to fetch reports from in place of USDA's, e.g. a
+/// mirror or a mock server, up to the path of the reports, as in https://mpr.datamart.ams.usda.gov/services/v1.1/reports
+#[derive(Deserialize, Debug, Default, PartialEq)]
+pub struct Endpoints {
+    pub datamart: Option<String>,       // reports of API version 1.1
+    pub datamart_v2: Option<String>     // reports with api_version = "2"
+}
+
+/// Fetches reports of an API version from `url` rather than from USDA for the rest of the run
+pub fn set_base_url(api_version: DatamartApiVersion, url: &str) -> Result<(), String> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(format!("Invalid datamart base URL '{}', expected an http:// or https:// URL", url));
+    }
+
+    BASE_URLS.lock().unwrap().insert(api_version, url.trim_end_matches('/').to_owned());
+    Ok(())
+}
+
+/// Uses the base URLs of the [endpoints] table, those it gives
+pub fn set_endpoints(endpoints: &Endpoints) -> Result<(), String> {
+    if let Some(url) = &endpoints.datamart {
+        set_base_url(DatamartApiVersion::V1, url)?;
+    }
+    if let Some(url) = &endpoints.datamart_v2 {
+        set_base_url(DatamartApiVersion::V2, url)?;
+    }
+
+    Ok(())
+}
+
+/// The span of time a report's rows cover. A weekly report's date is the last day of its week, as in USDA's
+/// "week ending" reports, while a monthly report's date may be any day of its calendar month.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Week,
+    Month
+}
+
+impl Period {
+    /// SQL expressions for the first and last days of the period that `column`, a date, falls in
+    pub fn bounds_sql(self, column: &str) -> (String, String) {
+        match self {
+            Period::Week => { (format!("{} - 6", column), column.to_owned()) },
+            Period::Month => {(
+                format!("date_trunc('month', {}::timestamp)::date", column),
+                format!("(date_trunc('month', {}::timestamp) + interval '1 month - 1 day')::date", column)
+            )}
+        }
+    }
+}
+
+/// How the independent column of a report is read as its report date. Datamart can only be asked for the dates of
+/// reports keyed on `date`; the others are fetched whole and their rows outside the wanted range dropped once parsed.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum IndependentType {
+    /// The first date in the value, e.g. "03/06/2020" or "Week ending Mar 6, 2020"
+    #[default]
+    Date,
+    /// A year and week number, e.g. "2020-W10" or "Week 10, 2020", as the Sunday ending that week
+    Week,
+    /// A month, e.g. "March 2020", as its first day
+    Month,
+    /// A marketing year, e.g. "2019/20", as the first day of the month it starts in
+    MarketingYear { start_month: u32 },
+    /// A date written as a chrono format describes it, e.g. "%Y%m%d"
+    Format { format: String }
+}
+
+impl IndependentType {
+    /// The report date a value of the independent column stands for, None if it isn't of this type
+    pub fn report_date(&self, value: &str) -> Option<NaiveDate> {
+        match self {
+            IndependentType::Date => { dates::find_date(value) },
+            IndependentType::Week => { dates::find_week(value) },
+            IndependentType::Month => { dates::find_month_year(value) },
+            IndependentType::MarketingYear { start_month } => { dates::find_marketing_year(value, *start_month) },
+            IndependentType::Format { format } => { NaiveDate::parse_from_str(value.trim(), format).ok() }
+        }
+    }
+
+    /// Whether datamart can be queried for a range of these
+    pub fn is_date(&self) -> bool {
+        *self == IndependentType::Date
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct DatamartSection {
     pub alias: Option<String>,    // if present, will be used instead of hash key for table name
     pub independent: Vec<String>, // first is always interpreted as a NaiveDate, following are text.
-    pub fields: Vec<String>       // all will be attempted as numeric
+    pub fields: Vec<String>,      // all will be attempted as numeric
+    #[serde(default)]
+    pub types: BTreeMap<String, FieldType>, // fields checked and converted as they are parsed, see FieldType
+    pub ignored: Option<Vec<String>>, // response columns deliberately not stored; listing them reports new ones, see SchemaDrift
+    #[serde(default = "section_required")]
+    pub required: bool            // legacy reports only: whether the report fails without this section
 }
 
-#[derive(Deserialize, Debug)]
+/// The type of a field, declared in a section's `types`. The values of a typed field are checked as a datamart or
+/// MARS response is parsed and put in a standard form; a value that isn't of its type is warned of, counted in the
+/// malformed values metric, and stored as it came. Fields without a type are stored as they come.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldType {
+    Float,
+    Int,
+    Text,
+    Date,
+    Percent     // a number, with or without a trailing %, stored without it
+}
+
+impl FieldType {
+    /// The value in standard form, None if it isn't of this type: numbers without thousands separators, dates as
+    /// YYYY-MM-DD, text as it is
+    pub fn convert(self, value: &str) -> Option<String> {
+        let number = |value: &str| -> Option<String> {
+            let number = value.trim().replace(',', "");
+            number.parse::<f64>().ok().filter(|n| n.is_finite()).map(|_| number)
+        };
+
+        match self {
+            FieldType::Float => { number(value) },
+            FieldType::Int => { value.trim().replace(',', "").parse::<i64>().ok().map(|n| n.to_string()) },
+            FieldType::Text => { Some(value.to_owned()) },
+            FieldType::Date => { dates::find_date(value).map(|d| d.format("%Y-%m-%d").to_string()) },
+            FieldType::Percent => { number(value.trim().trim_end_matches('%')) }
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FieldType::Float => { "float" },
+            FieldType::Int => { "int" },
+            FieldType::Text => { "text" },
+            FieldType::Date => { "date" },
+            FieldType::Percent => { "percent" }
+        }
+    }
+}
+
+/// How the columns of a response differ from those its section is configured with: columns USDA added or renamed
+/// that nothing reads, and configured columns it no longer has. Unknown columns are only looked for in sections
+/// listing the columns they leave out in `ignored`, as responses carry many more columns than are stored.
+#[derive(Debug, Default, PartialEq)]
+pub struct SchemaDrift {
+    pub unknown: BTreeSet<String>,
+    pub missing: BTreeSet<String>
+}
+
+impl SchemaDrift {
+    pub fn is_empty(&self) -> bool {
+        self.unknown.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Compares the columns of the rows of a section with those configured for it and the report's `independent`
+pub fn schema_drift(independent: &str, section: &DatamartSection, rows: &Rows) -> SchemaDrift {
+    let columns: BTreeSet<&String> = rows.iter().flat_map(|row| row.keys()).collect();
+
+    let mut expected: BTreeSet<&String> = section.independent.iter().chain(&section.fields).collect();
+    let independent = independent.to_owned();
+    expected.insert(&independent);
+
+    let missing = expected.iter().filter(|c| !columns.contains(*c)).map(|c| (*c).to_owned()).collect();
+    let unknown = match &section.ignored {
+        Some(ignored) => { columns.iter().filter(|c| !expected.contains(*c) && !ignored.contains(c)).map(|c| (*c).to_owned()).collect() },
+        None => { BTreeSet::new() }
+    };
+
+    SchemaDrift { unknown, missing }
+}
+
+/// Makes responses whose columns differ from the configuration fail to parse, rather than be warned of (--strict-schema)
+pub fn set_strict_schema(strict: bool) {
+    STRICT_SCHEMA.store(strict, Ordering::Relaxed);
+}
+
+fn section_required() -> bool {
+    true
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(dead_code)]
 pub struct DatamartConfig {
     pub name: String,                             // historical "slug name"
     pub description: String,
     pub independent: String,                      // the independent variable, i.e.: date for query
+    #[serde(default)]
+    pub independent_type: IndependentType,        // how the independent variable is read as a date, see IndependentType
+    #[serde(default)]
+    pub api_version: DatamartApiVersion,          // "1.1" unless USDA has migrated the report
+    pub mars_slug: Option<String>,                // the same report in MARS, used when datamart is down
+    #[serde(default)]
+    pub mars_filters: BTreeMap<String, String>,   // MARS query filters, e.g. office_name, narrowing what is fetched from MARS
+    pub mars_family: Option<MarsFamily>,          // read MARS results with a typed model, see marsmodels
+    pub archive_url: Option<String>,              // Market News archive page, used when ESMIS has nothing
+    pub release_time: Option<String>,             // legacy reports only: local HH:MM the report is published, see releases
+    pub connect_timeout: Option<u64>,             // milliseconds, in place of --http-connect-timeout for this report
+    pub read_timeout: Option<u64>,                // milliseconds, in place of --http-receive-timeout for this report
+    pub on_conflict: Option<OnConflict>,          // "keep", "update" or "track_revisions" revised values, whatever the run's setting
+    pub period: Option<Period>,                   // "week" or "month" for reports whose dates stand for a period, see Period
+    pub indexes: Option<Vec<Vec<String>>>,        // secondary indexes of the report's tables, see integration::indexes
+    #[serde(default)]
+    pub transforms: Vec<TransformConfig>,         // applied in order between parsing and insertion
+    pub parser: Option<TextParserSpec>,           // legacy reports only: a parser defined in configuration
+    pub file_date: Option<String>,                // legacy reports only: regex finding the report date in release file names
     pub sections: HashMap<String, DatamartSection> 
 }
 
+impl DatamartConfig {
+    /// The connect and read timeouts of requests for this report: its own if configured, else those given
+    pub fn timeouts(&self, http_connect_timeout: u64, http_receive_timeout: u64) -> (u64, u64) {
+        (self.connect_timeout.unwrap_or(http_connect_timeout), self.read_timeout.unwrap_or(http_receive_timeout))
+    }
+
+    /// The table of one of this report's sections: the report's name and the section's alias, or else its name,
+    /// lowercased, e.g. lm_ct100_summary
+    pub fn table_name(&self, section: &str) -> String {
+        let suffix = match self.sections.get(section).and_then(|s| s.alias.as_ref()) {
+            Some(alias) => { alias },
+            None => { section }
+        };
+
+        format!("{}_{}", self.name, suffix).to_lowercase()
+    }
+
+    /// A copy of this report's configuration restricted to one section, for fetching sections one at a time
+    pub fn only_section(&self, section: &str) -> DatamartConfig {
+        let mut config = self.clone();
+        config.sections.retain(|name, _| name == section);
+        config
+    }
+}
+
+/// The datamart configuration file: report definitions keyed by slug, plus named groups of slugs
+/// declared under `[group]` (e.g. `cattle = ["2466", "2659"]`).
+#[derive(Deserialize, Debug)]
+pub struct DatamartConfigFile {
+    #[serde(default)]
+    pub group: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub endpoints: Endpoints,
+    #[serde(flatten)]
+    pub reports: HashMap<String, DatamartConfig>
+}
+
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct DatamartResponse {
     #[serde(rename(deserialize = "reportSection"))]
     report_section: String,
@@ -35,18 +314,116 @@ pub struct DatamartResponse {
     message: Option<String>
 }
 
+impl DatamartResponse {
+    /// v1.1 suffixes its stat names with a colon (`returnedRows:`), v2 does not.
+    fn stat(&self, name: &str) -> Option<u32> {
+        match self.stats.get(&format!("{}:", name)) {
+            Some(v) => { Some(*v) },
+            None => { self.stats.get(name).copied() }
+        }
+    }
+
+    /// Whether datamart held back rows over its limit
+    fn is_truncated(&self) -> bool {
+        // the +1 is a datamart oddity
+        matches!((self.stat("returnedRows"), self.stat("userAllowedRows")), (Some(returned), Some(allowed)) if returned == allowed + 1)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct DatamartV2Response {
+    #[serde(default)]
+    stats: HashMap<String, u32>,
+    results: Option<Vec<HashMap<String, serde_json::Value>>>,
+    message: Option<String>
+}
+
+/// The newer USDA APIs return typed JSON values; we store everything as text and let insertion decide what is numeric.
+pub fn stringify_results(results: Vec<HashMap<String, serde_json::Value>>) -> Vec<HashMap<String, Option<String>>> {
+    results.into_iter().map(|entry| {
+        entry.into_iter().map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::Null => { None },
+                serde_json::Value::String(s) => { Some(s) },
+                other => { Some(other.to_string()) }
+            };
+            (key, value)
+        }).collect()
+    }).collect()
+}
+
+impl From<DatamartV2Response> for DatamartResponse {
+    fn from(response: DatamartV2Response) -> Self {
+        DatamartResponse {
+            report_section: String::new(),
+            report_sections: Vec::new(),
+            stats: response.stats,
+            results: response.results.map(stringify_results),
+            message: response.message
+        }
+    }
+}
+
+fn fetch_section(target_url: &str, api_version: DatamartApiVersion, api_key: Option<&str>, http_connect_timeout: u64, http_receive_timeout: u64) -> Result<DatamartResponse, String> {
+    let mut request = ureq::get(target_url);
+    request.set("User-Agent", super::USER_AGENT).timeout_connect(http_connect_timeout).timeout_read(http_receive_timeout);
+
+    if api_version == DatamartApiVersion::V2 {
+        match api_key {
+            Some(key) => { request.auth(key, ""); },
+            None => {
+                return Err(format!("Datamart API version 2 requires an API key, none was provided. Target url: {}", target_url));
+            }
+        }
+    }
+
+    let body = crate::http::fetch_text(&mut request, &crate::http::RetryPolicy::DATAMART, true)
+        .map_err(|e| format!("Failed to retrieve data from datamart server with URL {}. Error: {}", target_url, e))?;
+
+    let parsed = match api_version {
+        DatamartApiVersion::V1 => { serde_json::from_str::<DatamartResponse>(&body) },
+        DatamartApiVersion::V2 => { serde_json::from_str::<DatamartV2Response>(&body).map(DatamartResponse::from) }
+    };
+
+    match parsed {
+        Ok(j) => { Ok(j) },
+        Err(_) => { 
+            Err(format!("Response from datamart server is not valid JSON, or the structure has changed significantly. Target url: {}", target_url))
+        }
+    }
+}
+
+/// The URL of a report on datamart itself, which answers with the report's first section and a list of them all
+pub fn report_url(slug_id: &str) -> String {
+    format!("{}/{}", DatamartApiVersion::V1.base_url(), slug_id)
+}
+
+/// The sections datamart lists with a report or section at `url`, and the rows it returned, for describing reports
+/// that aren't configured yet (see discover)
+pub fn fetch_page(url: &str, http_connect_timeout: u64, http_receive_timeout: u64) -> Result<(Vec<String>, Rows), String> {
+    let page = fetch_section(url, DatamartApiVersion::V1, None, http_connect_timeout, http_receive_timeout)?;
+
+    let sections = if page.report_sections.is_empty() {
+        vec![page.report_section].into_iter().filter(|s| !s.is_empty()).collect()
+    } else {
+        page.report_sections
+    };
+
+    Ok((sections, page.results.unwrap_or_default()))
+}
+
 /// Datamart is not very reliable, and we must use very large timeouts to capture data.
 /// This function does a simple query that is expected to return quickly to ensure
 /// that datamart is working and ready for more serious queries, so that we can avoid our
 /// long timeout.
 pub fn check_datamart() -> Result<(), String> {
     const QUICK_DATAMART_TIMEOUT: u64 = 3000;
-    let current_year: i32 = Local::today().year();
+    let current_year: i32 = Local::now().year();
 
     // this is the fastest query I can find
-    let target_url = format!("{0}/2451/?q=report_date=01/01/{1}:12/31/{1}", DATAMART_BASE_URL, current_year);
+    let target_url = format!("{0}/2451/?q=report_date=01/01/{1}:12/31/{1}", DatamartApiVersion::V1.base_url(), current_year);
     
-    let response = ureq::get(&target_url).set("User-Agent", super::USER_AGENT).timeout_connect(QUICK_DATAMART_TIMEOUT).timeout_read(QUICK_DATAMART_TIMEOUT).call();
+    let response = crate::session::send(ureq::get(&target_url).set("User-Agent", super::USER_AGENT).timeout_connect(QUICK_DATAMART_TIMEOUT).timeout_read(QUICK_DATAMART_TIMEOUT));
         
     if let Some(error) = response.synthetic_error() {
         return Err(format!("Failed to retrieve data from datamart server with URL {}. Error: {}", target_url, error));
@@ -62,7 +439,87 @@ pub fn check_datamart() -> Result<(), String> {
 }
 
 
-pub fn process_datamart(slug_id: String, report_date:Option<NaiveDate>, config: &HashMap<String, DatamartConfig>, http_connect_timeout:Arc<u64>, http_receive_timeout:Arc<u64>, minimum_date:Option<NaiveDate>) -> Result<USDADataPackage, String> {
+/// Report dates from and to, inclusive
+pub type DateRange = (NaiveDate, NaiveDate);
+
+/// The rows of a datamart response, by column
+pub type Rows = Vec<HashMap<String, Option<String>>>;
+
+/// The report dates from `from` to `to`, either of which defaults to the whole history of a report, or None for
+/// all of it
+pub fn bounded_range(from: Option<NaiveDate>, to: Option<NaiveDate>) -> Option<DateRange> {
+    match (from, to) {
+        (None, None) => { None },
+        (from, to) => { Some((from.unwrap_or_else(datamart_epoch), to.unwrap_or_else(|| Local::now().naive_local().date()))) }
+    }
+}
+
+/// From a report date to today
+pub fn since(from: NaiveDate) -> DateRange {
+    (from, Local::now().naive_local().date())
+}
+
+/// Where the history of a report is split from when all of it is more than datamart returns at once
+fn datamart_epoch() -> NaiveDate {
+    NaiveDate::from_ymd_opt(1990, 1, 1).unwrap()
+}
+
+/// The URL of a section of a report, limited to `range` if given
+pub fn section_url(base_url: &str, independent: &str, range: Option<DateRange>) -> String {
+    match range {
+        Some((from, to)) if from == to => { format!("{}?q={}={}", base_url, independent, from.format("%m/%d/%Y")) },
+        Some((from, to)) => { format!("{}?q={}={}:{}", base_url, independent, from.format("%m/%d/%Y"), to.format("%m/%d/%Y")) },
+        None => { base_url.to_owned() }
+    }
+}
+
+/// Halves a range of more than one day
+fn split_range((from, to): DateRange) -> Option<(DateRange, DateRange)> {
+    if from >= to {
+        return None;
+    }
+
+    let middle = from + chrono::Duration::days((to - from).num_days() / 2);
+    Some(((from, middle), (middle.succ_opt()?, to)))
+}
+
+/// Fetches the rows of a section in `range` with `fetch`. Datamart cuts responses off at a row limit, so a range
+/// that reaches it is split in two and each half fetched again, down to single days, and the whole history is
+/// fetched as a range from datamart_epoch until today. Gives None if datamart returned no results at all.
+fn fetch_rows(slug_id: &str, range: Option<DateRange>, fetch: &dyn Fn(Option<DateRange>) -> Result<DatamartResponse, String>) -> Result<Option<Rows>, String> {
+    let parsed = fetch(range)?;
+
+    if let Some(message) = parsed.message.as_ref() {
+        info!("slug={} Message from datamart: {}", slug_id, message)
+    };
+
+    if !parsed.is_truncated() {
+        return Ok(parsed.results);
+    }
+
+    let whole = range.unwrap_or_else(|| (datamart_epoch(), Local::now().naive_local().date()));
+    match split_range(whole) {
+        Some((first, second)) => {
+            info!(
+                "slug={} Datamart response for {} to {} reached the row limit, fetching {} to {} and {} to {} apart.",
+                slug_id, whole.0, whole.1, first.0, first.1, second.0, second.1
+            );
+
+            // a part of the range may well have no results
+            let mut rows = fetch_rows(slug_id, Some(first), fetch)?.unwrap_or_default();
+            rows.extend(fetch_rows(slug_id, Some(second), fetch)?.unwrap_or_default());
+            Ok(Some(rows))
+        },
+        None => {
+            warn!("slug={} Datamart response row count for {} is the max limit, there may be additional data available.", slug_id, whole.0);
+            Ok(parsed.results)
+        }
+    }
+}
+
+/// Fetches the report dates of `range`, or all of them if it is None. `api_key` is only consulted for reports
+/// configured with `api_version = "2"`.
+pub fn process_datamart(slug_id: String, range: Option<DateRange>, config: &HashMap<String, DatamartConfig>, http_connect_timeout:Arc<u64>, http_receive_timeout:Arc<u64>, api_key: Option<&str>) -> Result<USDADataPackage, String> {
     if !config.contains_key(&slug_id) {
         return Err(format!("Slug ID {} is not known to our datamart configuration.", slug_id));
     }
@@ -73,141 +530,458 @@ pub fn process_datamart(slug_id: String, report_date:Option<NaiveDate>, config:
     };
 
     let mut result = USDADataPackage::new(report_label.to_owned());
+    result.source = Some("datamart".to_owned());
+    let api_version = config[&slug_id].api_version;
 
-    for section in config[&slug_id].sections.keys() {
-        let section_data = result.sections.entry(section.to_owned()).or_insert_with(Vec::new);
-
-        let target_url = {
-            let base_url = format!("{}/{}", DATAMART_BASE_URL, slug_id);
-            match report_date {
-                Some(d) => {
-                    format!(
-                        "{base_url}/{section}?q={independent}={report_date}", 
-                        base_url=base_url,
-                        section=section,
-                        independent=config[&slug_id].independent,
-                        report_date=d.format("%m/%d/%Y")
-                    )
-                },
-                None => {
-                    match minimum_date {
-                        Some(md) => {
-                            let today = Local::now().naive_local().date();
-
-                            format!(
-                                "{base_url}/{section}?q={independent}={minimum_date}:{today}", 
-                                base_url=base_url,
-                                section=section,
-                                independent=config[&slug_id].independent,
-                                today=today.format("%m/%d/%Y"),
-                                minimum_date=md.format("%m/%d/%Y")
-                            )
-                        },
-                        None => {format!("{base_url}/{section}", base_url=base_url, section=section)}
-                    }
+    let independent = &config[&slug_id].independent;
+    let independent_type = &config[&slug_id].independent_type;
+
+    let (http_connect_timeout, http_receive_timeout) = config[&slug_id].timeouts(*http_connect_timeout, *http_receive_timeout);
+    let sections: Vec<&String> = config[&slug_id].sections.keys().collect();
+
+    let fetch_section_rows = |section: &String| -> Result<Vec<USDADataPackageSection>, String> {
+        let base_url = format!("{}/{}/{}", api_version.base_url(), slug_id, section);
+
+        let fetch = |range: Option<DateRange>| {
+            fetch_section(&section_url(&base_url, independent, range), api_version, api_key, http_connect_timeout, http_receive_timeout)
+        };
+
+        let rows = match independent_type.is_date() {
+            true => { fetch_rows(&slug_id, range, &fetch)? },
+            false => {
+                let response = fetch(None)?;
+                if response.is_truncated() {
+                    warn!("slug={} Datamart response row count is the max limit and can't be split by date, there may be additional data available.", slug_id);
                 }
+                response.results
             }
         };
 
-        let response = ureq::get(&target_url).set("User-Agent", super::USER_AGENT).timeout_connect(*http_connect_timeout).timeout_read(*http_receive_timeout).call();
-        
-        if let Some(error) = response.synthetic_error() {
-            return Err(format!("Failed to retrieve data from datamart server with URL {}. Error: {}", target_url, error));
-        }
+        match rows {
+            Some(results) => {
+                if let Some(root) = archive::payload_archive() {
+                    let date_of = |row: &HashMap<String, Option<String>>| row.get(independent).and_then(|v| v.as_deref()).and_then(|v| independent_type.report_date(v));
+                    if let Err(e) = archive::store_rows(&root, "datamart", &slug_id, section, &results, date_of) {
+                        error!("{}", e);
+                    }
+                }
 
-        let parsed = {
-            let result = response.into_json_deserialize::<DatamartResponse>();
-            match result {
-                Ok(j) => { j },
-                Err(_) => { 
-                    return Err(format!("Response from datamart server is not valid JSON, or the structure has changed significantly. Target url: {}", target_url));
+                let mut parsed = parse_section_results(&slug_id, &config[&slug_id], section, results)
+                    .inspect_err(|_| metrics::count_parse_failure(&config[&slug_id].name))?;
+                if let (false, Some((from, to))) = (independent_type.is_date(), range) {
+                    parsed.retain(|row| row.report_date >= from && row.report_date <= to);
                 }
+                Ok(parsed)
+            },
+            None => {
+                Err("No results found.".to_owned())
             }
-        };
+        }
+    };
 
-        // the +1 is a datamart oddity
-        if parsed.stats["returnedRows:"] == parsed.stats["userAllowedRows:"] + 1 {
-            println!("Warning: datamart response row count is max limit, there may be additional data available.");
+    // requests are retried by http::call, and a section that still fails is left out rather than failing the rest
+    let fetched = jobs::parallel_map(sections, SECTION_JOBS, |section| (section, fetch_section_rows(section)));
+    for (section, parsed) in fetched {
+        result.insert_section(section, parsed);
+    }
+
+    check_sections(&slug_id, &result)?;
+    Ok(result)
+}
+
+/// Fails a package none of whose sections could be fetched, and logs those that couldn't be of one that is kept
+fn check_sections(slug_id: &str, package: &USDADataPackage) -> Result<(), String> {
+    let mut failed: Vec<(&String, &String)> = package.section_errors.iter().collect();
+    failed.sort();
+
+    if package.sections.is_empty() && !failed.is_empty() {
+        let errors: Vec<String> = failed.iter().map(|(section, error)| format!("{}: {}", section, error)).collect();
+        return Err(errors.join("; "));
+    }
+
+    for (section, error) in failed {
+        error!("slug={} section={} Section left out of the report: {}", slug_id, section, error);
+    }
+
+    Ok(())
+}
+
+/// Parses the sections of a datamart response kept by --archive-payloads, as `process_datamart` parses them when
+/// fetched. Sections no longer configured are left out.
+pub fn parse_archived(slug_id: &str, config: &DatamartConfig, sections: BTreeMap<String, Vec<HashMap<String, Option<String>>>>) -> Result<USDADataPackage, String> {
+    let mut result = USDADataPackage::new(config.name.to_owned());
+    result.source = Some("datamart".to_owned());
+
+    for (section, rows) in sections.into_iter().filter(|(section, _)| config.sections.contains_key(section)) {
+        let parsed = parse_section_results(slug_id, config, &section, rows)
+            .inspect_err(|_| metrics::count_parse_failure(&config.name))?;
+        result.sections.entry(section).or_default().extend(parsed);
+    }
+
+    Ok(result)
+}
+
+/// Converts the rows of one section of a datamart-shaped response into package sections, following `config`.
+/// Shared with the MARS fallback, which returns rows in the same shape.
+pub fn parse_section_results(slug_id: &str, config: &DatamartConfig, section: &str, results: Vec<HashMap<String, Option<String>>>) -> Result<Vec<USDADataPackageSection>, String> {
+    let mut section_data = Vec::new();
+
+    let drift = schema_drift(&config.independent, &config.sections[section], &results);
+    if !results.is_empty() && !drift.is_empty() {
+        let message = format!(
+            "slug={} section={} unknown_columns={:?} missing_columns={:?} Response columns differ from the configuration.",
+            slug_id, section, drift.unknown, drift.missing
+        );
+        if STRICT_SCHEMA.load(Ordering::Relaxed) {
+            return Err(message);
         }
+        warn!("{}", message);
+    }
 
-        if let Some(message) = parsed.message {
-            println!("Message from datamart: {}", message)
+    'entries: for entry in results {
+        let lookup = &config.independent;
+        let independent = {
+            match entry.get(lookup).and_then(|v| v.as_ref()) {
+                Some(value) => { value },
+                None => {
+                    // FYI: this actually happens. Values with no assigned date, floating around in the response.
+                    warn!("slug={} Response contains entries with a null independent field, which is irrational. These entries will be skipped.", slug_id);
+                    continue;
+                }
+            }
         };
 
-        match parsed.results {
-            Some(results) => {
-                'entries: for entry in results {
-                    let lookup = &config[&slug_id].independent;
-                    let independent = {
-                        match entry[lookup].as_ref() {
-                            Some(value) => { value },
-                            None => {
-                                // FYI: this actually happens. Values with no assigned date, floating around in the response.
-                                eprintln!("slug={} Response contains entries with a null independent field, which is irrational. These entries will be skipped.", slug_id);
-                                continue;
-                            }
-                        }
-                    };
+        let independent = match config.independent_type.report_date(independent) {
+            Some(d) => { d },
+            None => {
+                return Err(format!("Failed to parse independent column from datamart response as {:?}: {}", config.independent_type, independent))
+            }
+        };
 
-                    lazy_static!{
-                        static ref RE_DATAMART_DATE_CAPTURE: Regex = Regex::new(r"(?P<month>\d+)/(?P<day>\d+)/(?P<year>\d+)").unwrap();
-                    }
+        let mut data = USDADataPackageSection::new(independent);
 
-                    let independent = {
-                        match RE_DATAMART_DATE_CAPTURE.captures(&independent) {
-                            Some(x) => {
-                                NaiveDate::from_ymd(
-                                    x.name("year").unwrap().as_str().parse::<i32>().unwrap(),
-                                    x.name("month").unwrap().as_str().parse::<u32>().unwrap(),
-                                    x.name("day").unwrap().as_str().parse::<u32>().unwrap()
-                                )
-                            },
-                            None => {
-                                return Err(format!("Failed to parse independent column from datamart response: {}", independent))
-                            }
-                        }
-                    };
-
-                    let mut data = USDADataPackageSection::new(independent);
-
-                    for column in &config[&slug_id].sections[section].fields {
-                        let value = { 
-                            match &entry[column] {
-                                Some(s) => { s.to_owned() },
-                                None => { "".to_owned() }
-                            }
-                        };
-                        data.entries.insert(column.to_owned(), value);
-                    }
+        for column in &config.sections[section].fields {
+            let mut value = { 
+                match entry.get(column) {
+                    Some(Some(s)) => { s.to_owned() },
+                    _ => { "".to_owned() }   // a missing column is warned of above
+                }
+            };
 
-                    for column in &config[&slug_id].sections[section].independent {
-                        let value = match entry.get(column) {
-                            Some(v) => {
-                                match v.as_ref() {
-                                    Some(v) => { v },
-                                    None => {
-                                        eprintln!("Failed to get value of independent column `{}` in response for date {}.", column, independent);
-                                        eprintln!("This entry will be skipped. If this happens frequently, your configuration may be wrong to assume this column is an independent.");
-                                        continue 'entries;
-                                    }
-                                }
-                            }
-                            None => {
-                                return Err(format!("Failed to find independent column `{}` in response for date {}. All columns: {:#?}", column, independent, entry.keys()));
-                            }
-                        };
-                        
-                        data.independent.push(value.to_owned());
+            if let Some(field_type) = config.sections[section].types.get(column).filter(|_| !value.trim().is_empty()) {
+                match field_type.convert(&value) {
+                    Some(converted) => { value = converted },
+                    None => {
+                        warn!("slug={} Value `{}` of {} in section {} for {} is not a {}, storing it as it came.", slug_id, value, column, section, independent, field_type.name());
+                        metrics::add(&metrics::MALFORMED_VALUES, &[("report", &config.name)], 1.0);
                     }
-
-                    section_data.push(data);
                 }
-            },
-            None => {
-                return Err("No results found.".to_owned())
             }
+
+            data.entries.insert(column.to_owned(), value);
+        }
+
+        for column in &config.sections[section].independent {
+            let value = match entry.get(column) {
+                Some(v) => {
+                    match v.as_ref() {
+                        Some(v) => { v },
+                        None => {
+                            warn!(
+                                "slug={} Failed to get value of independent column `{}` in response for date {}. This entry will be skipped. \
+                                 If this happens frequently, your configuration may be wrong to assume this column is an independent.",
+                                slug_id, column, independent
+                            );
+                            continue 'entries;
+                        }
+                    }
+                }
+                None => {
+                    return Err(format!("Failed to find independent column `{}` in response for date {}. All columns: {:#?}", column, independent, entry.keys()));
+                }
+            };
+            
+            data.independent.push(value.to_owned());
         }
+
+        section_data.push(data);
     }
 
-    Ok(result)
-}
\ No newline at end of file
+    Ok(section_data)
+}
+
+#[test]
+fn test_datamart_v2_response() {
+    let response: DatamartV2Response = serde_json::from_str(r#"{
+        "stats": {"totalRows": 1, "returnedRows": 1, "userAllowedRows": 1000},
+        "results": [{"report_date": "01/04/2021", "head_count": 1250, "class_description": "STEER", "price": null}]
+    }"#).unwrap();
+    let response = DatamartResponse::from(response);
+
+    assert_eq!(response.stat("returnedRows"), Some(1));
+    let entry = &response.results.unwrap()[0];
+    assert_eq!(entry["head_count"].as_deref(), Some("1250"));
+    assert_eq!(entry["class_description"].as_deref(), Some("STEER"));
+    assert_eq!(entry["price"], None);
+}
+
+#[test]
+fn test_datamart_config_file() {
+    use std::fs;
+
+    let config: DatamartConfigFile = toml::from_str(&fs::read_to_string("config/datamart.toml").unwrap()).unwrap();
+    assert!(config.reports.contains_key("2466"));
+    assert!(!config.reports.contains_key("group"));
+    assert!(!config.reports.contains_key("endpoints"));
+
+    for slug in &config.group["cattle"] {
+        assert!(config.reports.contains_key(slug), "group member {} is not a configured report", slug);
+    }
+}
+
+#[test]
+fn test_parse_section_results() {
+    let config: DatamartConfig = toml::from_str(r#"
+        name = "lm_ct100"
+        description = "test"
+        independent = "report_date"
+        [sections.Summary]
+        independent = ["report_date", "class_description"]
+        fields = ["head_count"]
+    "#).unwrap();
+
+    let mut row: HashMap<String, Option<String>> = HashMap::new();
+    row.insert("report_date".to_owned(), Some("01/04/2021".to_owned()));
+    row.insert("class_description".to_owned(), Some("STEER".to_owned()));
+    row.insert("head_count".to_owned(), Some("1,250".to_owned()));
+
+    let mut undated = row.clone();
+    undated.insert("report_date".to_owned(), None);
+
+    let sections = parse_section_results("2466", &config, "Summary", vec![row, undated]).unwrap();
+    assert_eq!(sections.len(), 1);
+    assert_eq!(sections[0].report_date, NaiveDate::from_ymd_opt(2021, 1, 4).unwrap());
+    assert_eq!(sections[0].independent, vec!["01/04/2021", "STEER"]);
+    assert_eq!(sections[0].entries["head_count"], "1,250");
+}
+
+#[test]
+fn test_independent_types() {
+    let config: DatamartConfig = toml::from_str(r#"
+        name = "gx_gr210"
+        description = "test"
+        independent = "marketing_year"
+        independent_type = { name = "marketing_year", start_month = 9 }
+        [sections.Exports]
+        independent = ["marketing_year", "commodity"]
+        fields = ["bushels"]
+    "#).unwrap();
+
+    let mut row: HashMap<String, Option<String>> = HashMap::new();
+    row.insert("marketing_year".to_owned(), Some("MY 2019/20".to_owned()));
+    row.insert("commodity".to_owned(), Some("Corn".to_owned()));
+    row.insert("bushels".to_owned(), Some("1,000".to_owned()));
+
+    let sections = parse_section_results("3192", &config, "Exports", vec![row.clone()]).unwrap();
+    assert_eq!(sections[0].report_date, NaiveDate::from_ymd_opt(2019, 9, 1).unwrap());
+    assert_eq!(sections[0].independent, vec!["MY 2019/20", "Corn"]);
+
+    row.insert("marketing_year".to_owned(), Some("unknown".to_owned()));
+    assert!(parse_section_results("3192", &config, "Exports", vec![row]).is_err());
+
+    assert_eq!(IndependentType::Week.report_date("Week 10, 2020"), NaiveDate::from_ymd_opt(2020, 3, 8));
+    assert!(!IndependentType::Week.is_date());
+
+    let format = IndependentType::Format { format: "%Y%m%d".to_owned() };
+    assert_eq!(format.report_date(" 20200306 "), NaiveDate::from_ymd_opt(2020, 3, 6));
+    assert_eq!(IndependentType::default().report_date("For week ending 03/06/2020"), NaiveDate::from_ymd_opt(2020, 3, 6));
+}
+
+#[test]
+fn test_schema_drift() {
+    let mut config: DatamartConfig = toml::from_str(r#"
+        name = "lm_ct100"
+        description = "test"
+        independent = "report_date"
+        [sections.Summary]
+        independent = ["report_date", "class_description"]
+        fields = ["head_count", "avg_price"]
+    "#).unwrap();
+
+    let mut row: HashMap<String, Option<String>> = HashMap::new();
+    row.insert("report_date".to_owned(), Some("01/04/2021".to_owned()));
+    row.insert("class_description".to_owned(), Some("STEER".to_owned()));
+    row.insert("head_count".to_owned(), Some("1,250".to_owned()));
+    row.insert("office_name".to_owned(), Some("St. Joseph, MO".to_owned()));
+    row.insert("avg_weight".to_owned(), Some("1,420".to_owned()));
+    let rows = vec![row];
+
+    let drift = schema_drift(&config.independent, &config.sections["Summary"], &rows);
+    assert_eq!(drift, SchemaDrift { unknown: BTreeSet::new(), missing: BTreeSet::from(["avg_price".to_owned()]) });
+
+    config.sections.get_mut("Summary").unwrap().ignored = Some(vec!["office_name".to_owned()]);
+    let drift = schema_drift(&config.independent, &config.sections["Summary"], &rows);
+    assert_eq!(drift.unknown, BTreeSet::from(["avg_weight".to_owned()]));
+
+    // a missing field is warned of and left empty, rather than failing the response
+    let sections = parse_section_results("2466", &config, "Summary", rows).unwrap();
+    assert_eq!(sections[0].entries["avg_price"], "");
+}
+
+#[test]
+fn test_report_timeouts() {
+    let config: DatamartConfig = toml::from_str(r#"
+        name = "lm_hg201"
+        description = "test"
+        independent = "report_date"
+        read_timeout = 190000
+        [sections.Barrows]
+        independent = ["report_date"]
+        fields = ["head_count"]
+    "#).unwrap();
+
+    assert_eq!(config.timeouts(30000, 60000), (30000, 190000));
+}
+
+#[test]
+fn test_table_name() {
+    let config: DatamartConfig = toml::from_str(r#"
+        name = "LM_CT100"
+        description = "test"
+        independent = "report_date"
+        [sections.Summary]
+        independent = ["report_date"]
+        fields = ["head_count"]
+        [sections."Detail Sales"]
+        alias = "detail"
+        independent = ["report_date"]
+        fields = ["head_count"]
+    "#).unwrap();
+
+    assert_eq!(config.table_name("Summary"), "lm_ct100_summary");
+    assert_eq!(config.table_name("Detail Sales"), "lm_ct100_detail");
+}
+
+#[test]
+fn test_check_sections() {
+    let date = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    let mut package = USDADataPackage::new("lm_ct100".to_owned());
+
+    // a report whose sections all failed fails
+    package.insert_section("Summary", Err("status 503".to_owned()));
+    package.insert_section("Detail", Err("timed out".to_owned()));
+    assert_eq!(check_sections("2466", &package), Err("Detail: timed out; Summary: status 503".to_owned()));
+
+    // one with a section that came in is kept, without the others
+    let mut package = USDADataPackage::new("lm_ct100".to_owned());
+    package.insert_section("Summary", Ok(vec![USDADataPackageSection::new(date)]));
+    package.insert_section("Detail", Err("timed out".to_owned()));
+    assert_eq!(check_sections("2466", &package), Ok(()));
+
+    assert_eq!(check_sections("2466", &USDADataPackage::new("lm_ct100".to_owned())), Ok(()));
+}
+
+#[test]
+fn test_endpoints() {
+    let config: DatamartConfigFile = toml::from_str(r#"
+        [endpoints]
+        datamart = "http://localhost:8080/services/v1.1/reports/"
+    "#).unwrap();
+
+    assert_eq!(config.endpoints, Endpoints { datamart: Some("http://localhost:8080/services/v1.1/reports/".to_owned()), datamart_v2: None });
+    assert!(config.reports.is_empty());
+
+    assert!(set_base_url(DatamartApiVersion::V1, "localhost:8080").is_err());
+    assert_eq!(DatamartApiVersion::V1.base_url(), DATAMART_BASE_URL);
+}
+
+#[test]
+fn test_typed_fields() {
+    assert_eq!(FieldType::Float.convert(" 1,250.50"), Some("1250.50".to_owned()));
+    assert_eq!(FieldType::Float.convert("N/A"), None);
+    assert_eq!(FieldType::Int.convert("1,250"), Some("1250".to_owned()));
+    assert_eq!(FieldType::Int.convert("12.5"), None);
+    assert_eq!(FieldType::Percent.convert("45.2%"), Some("45.2".to_owned()));
+    assert_eq!(FieldType::Date.convert("01/04/2021"), Some("2021-01-04".to_owned()));
+    assert_eq!(FieldType::Text.convert("Choice 2-3"), Some("Choice 2-3".to_owned()));
+
+    let config: DatamartConfig = toml::from_str(r#"
+        name = "lm_ct142"
+        description = "test"
+        independent = "report_date"
+        [sections.Detail]
+        independent = ["report_date"]
+        fields = ["head_count", "percentage", "note"]
+        types = { head_count = "int", percentage = "percent" }
+    "#).unwrap();
+
+    let row = |head_count: &str, percentage: &str| -> HashMap<String, Option<String>> {
+        vec![("report_date", "01/04/2021"), ("head_count", head_count), ("percentage", percentage), ("note", "1,000")].into_iter()
+            .map(|(k, v)| (k.to_owned(), Some(v.to_owned()))).collect()
+    };
+
+    // malformed values are kept as they came, as are empty ones and those of untyped fields
+    let sections = parse_section_results("2472", &config, "Detail", vec![row("1,250", "45.2%"), row("12.5", "")]).unwrap();
+    assert_eq!((sections[0].entries["head_count"].as_str(), sections[0].entries["percentage"].as_str()), ("1250", "45.2"));
+    assert_eq!((sections[1].entries["head_count"].as_str(), sections[1].entries["percentage"].as_str()), ("12.5", ""));
+    assert_eq!(sections[0].entries["note"], "1,000");
+}
+
+#[test]
+fn test_fetch_rows() {
+    use std::cell::RefCell;
+
+    // one row a day from the 1st of March 2020, with a limit of 10 rows a response
+    let first = NaiveDate::from_ymd_opt(2020, 3, 1).unwrap();
+    let last = NaiveDate::from_ymd_opt(2020, 4, 14).unwrap();
+    let requests = RefCell::new(Vec::new());
+
+    let fetch = |range: Option<DateRange>| -> Result<DatamartResponse, String> {
+        requests.borrow_mut().push(range);
+        let (from, to) = range.unwrap_or((first, last));
+
+        let mut results: Rows = first.iter_days().take_while(|d| *d <= last)
+            .filter(|d| *d >= from && *d <= to)
+            .map(|d| HashMap::from([("report_date".to_owned(), Some(d.format("%m/%d/%Y").to_string()))]))
+            .collect();
+        let returned = results.len().min(11) as u32;
+        results.truncate(11);
+
+        let stats = HashMap::from([("returnedRows:".to_owned(), returned), ("userAllowedRows:".to_owned(), 10)]);
+        Ok(DatamartResponse { report_section: String::new(), report_sections: Vec::new(), stats, results: Some(results), message: None })
+    };
+
+    let rows = fetch_rows("2466", Some((first, last)), &fetch).unwrap().unwrap();
+    let mut dates: Vec<&str> = rows.iter().map(|r| r["report_date"].as_deref().unwrap()).collect();
+    dates.sort_unstable();
+    dates.dedup();
+    assert_eq!(rows.len(), 45);
+    assert_eq!(dates.len(), 45);
+
+    // the whole history is split from the epoch on
+    requests.borrow_mut().clear();
+    assert_eq!(fetch_rows("2466", None, &fetch).unwrap().unwrap().len(), 45);
+    assert_eq!(requests.borrow()[1].unwrap().0, datamart_epoch());
+
+    // a single day over the limit can't be split, and is kept as it came
+    let day = NaiveDate::from_ymd_opt(2020, 3, 2).unwrap();
+    let crowded = |_: Option<DateRange>| -> Result<DatamartResponse, String> {
+        let stats = HashMap::from([("returnedRows:".to_owned(), 11), ("userAllowedRows:".to_owned(), 10)]);
+        Ok(DatamartResponse { report_section: String::new(), report_sections: Vec::new(), stats, results: Some(vec![HashMap::new(); 11]), message: None })
+    };
+    assert_eq!(fetch_rows("2466", Some((day, day)), &crowded).unwrap().unwrap().len(), 11);
+
+    assert_eq!(section_url("https://example.test/2466/Summary", "report_date", Some((day, day))), "https://example.test/2466/Summary?q=report_date=03/02/2020");
+    assert_eq!(split_range((first, first)), None);
+}
+
+#[test]
+fn test_bounded_range() {
+    let first = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+    let last = NaiveDate::from_ymd_opt(2020, 12, 31).unwrap();
+
+    assert_eq!(bounded_range(None, None), None);
+    assert_eq!(bounded_range(Some(first), Some(last)), Some((first, last)));
+    assert_eq!(bounded_range(None, Some(last)), Some((datamart_epoch(), last)));
+    assert_eq!(bounded_range(Some(first), None), Some(since(first)));
+}
@@ -0,0 +1,172 @@
+use chrono::{NaiveDate, Weekday};
+use regex::Regex;
+
+const MONTH_NAMES: [&str; 12] = [
+    "january", "february", "march", "april", "may", "june",
+    "july", "august", "september", "october", "november", "december"
+];
+
+/// Month number for an English month name in any case, full ("September") or abbreviated ("Sep", "Sept.")
+pub fn month_number(name: &str) -> Option<u32> {
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+
+    if name.len() < 3 {
+        return None;
+    }
+
+    MONTH_NAMES.iter()
+        .position(|m| m.starts_with(&name))
+        .map(|i| i as u32 + 1)
+}
+
+/// Builds a date from captured parts. The month may be a number or a name; two digit years are taken as
+/// 1970-2069, which covers everything USDA has published electronically.
+pub fn from_parts(year: &str, month: &str, day: &str) -> Option<NaiveDate> {
+    let year = match year.len() {
+        2 => {
+            let y = year.parse::<i32>().ok()?;
+            if y < 70 { 2000 + y } else { 1900 + y }
+        },
+        _ => { year.parse::<i32>().ok()? }
+    };
+
+    let month = match month.parse::<u32>() {
+        Ok(m) => { m },
+        Err(_) => { month_number(month)? }
+    };
+
+    NaiveDate::from_ymd_opt(year, month, day.parse::<u32>().ok()?)
+}
+
+/// Finds every date in a line of text, in the order they appear. Recognised formats are those seen across
+/// AMS and NASS reports: "03/06/2020", "3/6/20", "2020-03-06", "Mar 6, 2020", "March 6th, 2020",
+/// "FRI MAR 06 2020" and "6 March 2020".
+pub fn find_dates(text: &str) -> Vec<NaiveDate> {
+    lazy_static! {
+        static ref RE_DATE_FORMATS: Vec<Regex> = vec![
+            Regex::new(r"\b(?P<month>\d{1,2})/(?P<day>\d{1,2})/(?P<year>\d{4}|\d{2})\b").unwrap(),
+            Regex::new(r"\b(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})\b").unwrap(),
+            Regex::new(r"(?i)\b(?P<month>[a-z]{3,9})\.?\s+(?P<day>\d{1,2})(?:st|nd|rd|th)?,?\s+(?P<year>\d{4})\b").unwrap(),
+            Regex::new(r"(?i)\b(?P<day>\d{1,2})\s+(?P<month>[a-z]{3,9})\.?,?\s+(?P<year>\d{4})\b").unwrap(),
+        ];
+    }
+
+    let mut found: Vec<(usize, usize, NaiveDate)> = Vec::new();
+
+    for format in RE_DATE_FORMATS.iter() {
+        for x in format.captures_iter(text) {
+            let whole = x.get(0).unwrap();
+
+            if found.iter().any(|(start, end, _)| whole.start() < *end && *start < whole.end()) {
+                continue;
+            }
+
+            if let Some(d) = from_parts(&x["year"], &x["month"], &x["day"]) {
+                found.push((whole.start(), whole.end(), d));
+            }
+        }
+    }
+
+    found.sort_by_key(|(start, _, _)| *start);
+    found.into_iter().map(|(_, _, d)| d).collect()
+}
+
+/// The first date in a line of text, see `find_dates` for the recognised formats
+pub fn find_date(text: &str) -> Option<NaiveDate> {
+    find_dates(text).into_iter().next()
+}
+
+/// The first "Month YYYY" in a line of text, as the first of that month. Used by monthly reports whose
+/// titles carry no day.
+pub fn find_month_year(text: &str) -> Option<NaiveDate> {
+    lazy_static! {
+        static ref RE_MONTH_YEAR: Regex = Regex::new(r"(?i)\b(?P<month>[a-z]{3,9})\.?,?\s+(?P<year>\d{4})\b").unwrap();
+    }
+
+    RE_MONTH_YEAR.captures_iter(text)
+        .find_map(|x| from_parts(&x["year"], &x["month"], "1"))
+}
+
+/// The first year and ISO week number in a line of text, "2020-W10", "2020 week 10" or "Week 10, 2020", as the
+/// Sunday ending that week
+pub fn find_week(text: &str) -> Option<NaiveDate> {
+    lazy_static! {
+        static ref RE_WEEK: Regex = Regex::new(
+            r"(?i)\b(?:(?P<year>\d{4})\s*-?\s*w(?:ee)?k?\s*(?P<week>\d{1,2})|w(?:ee)?k?\s*(?P<week_first>\d{1,2}),?\s+(?P<year_last>\d{4}))\b"
+        ).unwrap();
+    }
+
+    let x = RE_WEEK.captures(text)?;
+    let year = x.name("year").or_else(|| x.name("year_last"))?.as_str().parse::<i32>().ok()?;
+    let week = x.name("week").or_else(|| x.name("week_first"))?.as_str().parse::<u32>().ok()?;
+
+    NaiveDate::from_isoywd_opt(year, week, Weekday::Sun)
+}
+
+/// The first marketing year in a line of text, "2019/2020", "2019/20", "MY 2019-20" or just "2019", as the first
+/// day of `start_month` in the year it starts
+pub fn find_marketing_year(text: &str, start_month: u32) -> Option<NaiveDate> {
+    lazy_static! {
+        static ref RE_MARKETING_YEAR: Regex = Regex::new(r"\b(?P<year>\d{4})(?:\s*[/-]\s*(?:\d{4}|\d{2}))?\b").unwrap();
+    }
+
+    let year = RE_MARKETING_YEAR.captures(text)?["year"].parse::<i32>().ok()?;
+    NaiveDate::from_ymd_opt(year, start_month, 1)
+}
+
+#[test]
+fn test_month_names() {
+    assert_eq!(month_number("January"), Some(1));
+    assert_eq!(month_number("SEPT."), Some(9));
+    assert_eq!(month_number("dec"), Some(12));
+    assert_eq!(month_number("Ma"), None);
+    assert_eq!(month_number("Certified"), None);
+}
+
+#[test]
+fn test_date_formats() {
+    let expected = NaiveDate::from_ymd_opt(2020, 3, 6).unwrap();
+
+    for text in &[
+        "For Week Ending: 03/06/2020", "printed 3/6/20", "2020-03-06", "Dodge City, KS    Fri Mar 06, 2020",
+        "FRIDAY, MARCH 6TH, 2020", "week of 6 March 2020", "Mar. 6 2020"
+    ] {
+        assert_eq!(find_date(text), Some(expected), "{}", text);
+    }
+
+    assert_eq!(find_date("Total loads 1,234"), None);
+    assert_eq!(find_date("13/45/2020"), None);
+}
+
+#[test]
+fn test_multiple_dates_in_order() {
+    let dates = find_dates("   Dec 23, 2023   :   December 30, 2023  :  01/06/2024");
+
+    assert_eq!(dates, vec![
+        NaiveDate::from_ymd_opt(2023, 12, 23).unwrap(), NaiveDate::from_ymd_opt(2023, 12, 30).unwrap(), NaiveDate::from_ymd_opt(2024, 1, 6).unwrap()
+    ]);
+}
+
+#[test]
+fn test_month_year_titles() {
+    assert_eq!(
+        find_month_year("Pounds Certified - United States: November 2023 and 2022"),
+        Some(NaiveDate::from_ymd_opt(2023, 11, 1).unwrap())
+    );
+    assert_eq!(find_month_year("States: 2023"), None);
+}
+
+#[test]
+fn test_weeks_and_marketing_years() {
+    let week_ending = NaiveDate::from_ymd_opt(2020, 3, 8);
+    for text in &["2020-W10", "2020 week 10", "Week 10, 2020", "wk 10 2020"] {
+        assert_eq!(find_week(text), week_ending, "{}", text);
+    }
+    assert_eq!(find_week("Week 54, 2020"), None);
+
+    let september = NaiveDate::from_ymd_opt(2019, 9, 1);
+    for text in &["2019/2020", "2019/20", "MY 2019-20", "2019"] {
+        assert_eq!(find_marketing_year(text, 9), september, "{}", text);
+    }
+    assert_eq!(find_marketing_year("2019/20", 13), None);
+}
@@ -0,0 +1,218 @@
+// Legacy text reports defined entirely in configuration, for reports regular enough not to need a parser of their own.
+//
+// A report opts in with a `parser` table in legacy.toml (documented at the top of that file) naming the line its
+// date is on and, for each section, the lines the section's rows sit between and a regex capturing them. Groups in
+// the row regex are named after the section's independent columns (other than the date) and fields.
+
+use std::collections::HashMap;
+
+use chrono::NaiveDate;
+use regex::Regex;
+use serde::Deserialize;
+
+use super::{USDADataPackage, USDADataPackageSection};
+use super::dates;
+use super::datamart::{DatamartConfig, DatamartSection};
+use super::textparse;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TextParserSpec {
+    pub date_line: String,                          // regex for the line carrying the report date
+    pub version: Option<u32>,                       // bump when the definition changes what is extracted, defaults to 1
+    pub sections: HashMap<String, TextSectionSpec>  // keyed by the section names in the report's configuration
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TextSectionSpec {
+    pub start: String,              // regex for the line the section's rows follow
+    pub end: Option<String>,        // regex for the line that ends the section; by default the first blank line after a row
+    pub max_lines: Option<usize>,   // the most lines after `start` to look at
+    pub row: String                 // regex capturing a row's independent columns and fields by name
+}
+
+impl TextParserSpec {
+    pub fn version(&self) -> u32 {
+        self.version.unwrap_or(1)
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("Invalid pattern in parser definition '{}': {}", pattern, e))
+}
+
+/// Reads one section's rows. `range` is the compiled start and end patterns and line limit of its definition.
+fn declarative_section(text_array: &[&str], report_date: NaiveDate, section_name: &str, section_config: &DatamartSection, range: (&Regex, Option<&Regex>, Option<usize>), row: &Regex) -> Result<Vec<USDADataPackageSection>, String> {
+    let (start, end, max_lines) = range;
+
+    let start = match textparse::find_line_regex(text_array, start) {
+        Some(line) => { line + 1 },
+        None => {
+            return Err(format!("Failed to locate start of section {}", section_name));
+        }
+    };
+
+    let range_end = match max_lines {
+        Some(n) => { text_array.len().min(start + n) },
+        None => { text_array.len() }
+    };
+
+    let mut section = Vec::new();
+
+    for line in &text_array[start.min(range_end)..range_end] {
+        match end {
+            Some(pattern) => {
+                if pattern.is_match(line) {
+                    break;
+                }
+            },
+            None => {
+                if !section.is_empty() && line.trim().is_empty() {
+                    break;
+                }
+            }
+        }
+
+        let x = match row.captures(line) {
+            Some(x) => { x },
+            None => { continue }
+        };
+
+        let mut data = USDADataPackageSection::new(report_date);
+        data.independent.push(report_date.format("%Y-%m-%d").to_string());
+
+        for column in section_config.independent.iter().skip(1) {
+            data.independent.push(x.name(column).map(|m| m.as_str().trim()).unwrap_or_default().to_owned());
+        }
+
+        for field in &section_config.fields {
+            if let Some(value) = x.name(field) {
+                data.entries.insert(field.to_owned(), value.as_str().trim().to_owned());
+            }
+        }
+
+        section.push(data);
+    }
+
+    if section.is_empty() {
+        return Err(format!("No rows found in section {}", section_name));
+    }
+
+    Ok(section)
+}
+
+/// The report date of a report, from the line the definition says carries it
+pub fn find_report_date(spec: &TextParserSpec, text_array: &[&str]) -> Result<NaiveDate, String> {
+    let location = match textparse::find_line_regex(text_array, &compile(&spec.date_line)?) {
+        Some(line) => { line },
+        None => {
+            return Err(format!("Failed to locate date line matching '{}'", spec.date_line));
+        }
+    };
+
+    // monthly reports only name the month
+    match dates::find_date(text_array[location]).or_else(|| dates::find_month_year(text_array[location])) {
+        Some(d) => { Ok(d) },
+        None => {
+            Err(format!("Failed to parse date line for report: {}", text_array[location].trim()))
+        }
+    }
+}
+
+/// Parses a report with the definition in its configuration
+pub fn declarative_parse(identifier: &str, config: &DatamartConfig, text: &str) -> Result<USDADataPackage, String> {
+    let spec = match config.parser.as_ref() {
+        Some(s) => { s },
+        None => {
+            return Err(format!("No parser is defined for {}", identifier));
+        }
+    };
+
+    let text_array = textparse::lines(text);
+    let report_date = find_report_date(spec, &text_array)?;
+
+    let mut structure = USDADataPackage::new(identifier.to_owned());
+    structure.parser_version = Some(spec.version());
+
+    for (section_name, section_spec) in &spec.sections {
+        let section_config = match config.sections.get(section_name) {
+            Some(s) => { s },
+            None => {
+                return Err(format!("Parser definition names a section missing from the configuration: {}", section_name));
+            }
+        };
+
+        let row = compile(&section_spec.row)?;
+        let group_names: Vec<&str> = row.capture_names().flatten().collect();
+
+        for column in section_config.independent.iter().skip(1) {
+            if !group_names.contains(&column.as_str()) {
+                return Err(format!("Row pattern for section {} has no group for independent column {}", section_name, column));
+            }
+        }
+
+        let start = compile(&section_spec.start)?;
+        let end = match section_spec.end.as_ref() {
+            Some(pattern) => { Some(compile(pattern)?) },
+            None => { None }
+        };
+
+        let result = declarative_section(&text_array, report_date, section_name, section_config, (&start, end.as_ref(), section_spec.max_lines), &row);
+        structure.insert_section(section_name, result);
+    }
+
+    Ok(structure)
+}
+
+#[test]
+fn test_declarative_parse() {
+    let mut config: DatamartConfig = toml::from_str(r#"
+        name = "al_gr110"
+        description = "Alabama Daily Grain Bids"
+        independent = "report_date"
+        [sections.corn]
+        independent = ["report_date", "location"]
+        fields = ["bid", "change"]
+        [sections.soybeans]
+        independent = ["report_date", "location"]
+        fields = ["bid"]
+        [parser]
+        date_line = "^Montgomery, AL"
+        version = 2
+        [parser.sections.corn]
+        start = "^CORN"
+        row = '^\s*(?P<location>[A-Za-z ]+?)\s{2,}(?P<bid>\d+\.\d+)(?:\s+(?P<change>[+-]\d+))?'
+        [parser.sections.soybeans]
+        start = "^SOYBEANS"
+        end = "^Source"
+        row = '^\s*(?P<location>[A-Za-z ]+?)\s{2,}(?P<bid>\d+\.\d+)'
+    "#).unwrap();
+
+    let text = "Montgomery, AL    Thu Mar 05, 2020    USDA Market News
+
+CORN
+  North Alabama      3.85   +2
+  South Alabama      3.91
+
+SOYBEANS
+  North Alabama      8.62
+
+  South Alabama      8.70
+Source: USDA
+  Not a row          1.00
+";
+
+    let structure = declarative_parse("AL_GR110", &config, text).unwrap();
+    assert_eq!(structure.parser_version, Some(2));
+
+    let corn = &structure.sections["corn"];
+    assert_eq!(corn.len(), 2);
+    assert_eq!(corn[0].independent, vec!["2020-03-05", "North Alabama"]);
+    assert_eq!(corn[0].entries["change"], "+2");
+    assert!(!corn[1].entries.contains_key("change"));
+
+    // an explicit end reads past blank lines
+    assert_eq!(structure.sections["soybeans"].len(), 2);
+
+    config.parser.as_mut().unwrap().sections.get_mut("soybeans").unwrap().row = r"(?P<bid>\d+\.\d+)".to_owned();
+    assert!(declarative_parse("AL_GR110", &config, text).is_err());
+}
@@ -0,0 +1,291 @@
+// Describing datamart reports from what datamart serves: as a configuration entry for those that aren't configured
+// yet (--discover-datamart), or as the columns of each section, for writing or checking one (--inspect).
+//
+// Datamart lists a report's sections with the report itself, and a page of each section's recent rows shows its
+// columns. Datamart sends every value as text, so columns are typed by what their values look like: dates and text
+// are taken as independents, what a row is about, and numbers as fields. That is right more often than not, but the
+// configuration written from it deserves a look before it is used.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+use chrono::Local;
+
+use super::dates;
+use super::datamart::{self, Rows};
+
+/// Recent days of a section sampled, when its date column is known
+const SAMPLE_DAYS: i64 = 90;
+
+/// Values of each column kept as samples
+const SAMPLES: usize = 3;
+
+/// Columns datamart repeats on every row to describe the report rather than the row
+const METADATA_COLUMNS: &[&str] = &[
+    "slug_id", "slug_name", "report_title", "published_date", "final_ind", "narrative", "office_name", "office_code",
+    "office_city", "office_state", "market_location_name", "market_location_city", "market_location_state",
+    "market_type", "market_type_category", "report_date_end_range"
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnKind {
+    Date,
+    Number,
+    Text,
+    Empty   // no values in the sample
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub kind: ColumnKind,
+    pub samples: Vec<String>    // distinct values, in the order they were found
+}
+
+fn is_date(value: &str) -> bool {
+    value.len() <= 10 && dates::find_date(value).is_some()
+}
+
+fn is_number(value: &str) -> bool {
+    value.replace(',', "").parse::<f64>().is_ok()
+}
+
+/// The columns of a section's rows, by name, typed by their values
+pub fn summarize_columns(rows: &Rows) -> Vec<Column> {
+    let names: BTreeSet<&String> = rows.iter().flat_map(|row| row.keys()).collect();
+
+    names.into_iter().map(|name| {
+        let values: Vec<&str> = rows.iter()
+            .filter_map(|row| row.get(name).and_then(|v| v.as_deref()))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect();
+
+        let kind = if values.is_empty() {
+            ColumnKind::Empty
+        } else if values.iter().all(|v| is_date(v)) {
+            ColumnKind::Date
+        } else if values.iter().all(|v| is_number(v)) {
+            ColumnKind::Number
+        } else {
+            ColumnKind::Text
+        };
+
+        let mut samples: Vec<String> = Vec::new();
+        for value in values {
+            if samples.len() == SAMPLES {
+                break;
+            }
+            if !samples.iter().any(|s| s == value) {
+                samples.push(value.to_owned());
+            }
+        }
+
+        Column { name: name.to_owned(), kind, samples }
+    }).collect()
+}
+
+/// The column a section is dated by: report_date or report_date_end if they are dates, or else its first date column
+pub fn date_column(columns: &[Column]) -> Option<&str> {
+    let dated = |name: &str| columns.iter().any(|c| c.name == name && c.kind == ColumnKind::Date);
+
+    ["report_date", "report_date_end"].iter().copied().find(|name| dated(name))
+        .or_else(|| columns.iter().find(|c| c.kind == ColumnKind::Date).map(|c| c.name.as_str()))
+}
+
+/// The sections of a report with the rows sampled from each: those of its last SAMPLE_DAYS days where its date
+/// column is known and there are any, or otherwise whatever datamart gives for the section
+pub fn sample_report(slug_id: &str, http_connect_timeout: u64, http_receive_timeout: u64) -> Result<BTreeMap<String, Rows>, String> {
+    let report_url = datamart::report_url(slug_id);
+    let (sections, first_rows) = datamart::fetch_page(&report_url, http_connect_timeout, http_receive_timeout)?;
+
+    if sections.is_empty() {
+        return Err(format!("Datamart lists no sections for report {}", slug_id));
+    }
+
+    let first_columns = summarize_columns(&first_rows);
+    let date_column = date_column(&first_columns);
+    let today = Local::now().naive_local().date();
+    let recent = (today - chrono::Duration::days(SAMPLE_DAYS), today);
+
+    let mut sampled = BTreeMap::new();
+    for section in sections {
+        let section_url = format!("{}/{}", report_url, section.replace(' ', "%20"));
+
+        // a section dated by another column than the first answers the narrower request with nothing, or an error
+        let recent_rows = date_column
+            .and_then(|column| datamart::fetch_page(&datamart::section_url(&section_url, column, Some(recent)), http_connect_timeout, http_receive_timeout).ok())
+            .map(|(_, rows)| rows)
+            .filter(|rows| !rows.is_empty());
+
+        let rows = match recent_rows {
+            Some(rows) => { rows },
+            None => { datamart::fetch_page(&section_url, http_connect_timeout, http_receive_timeout)?.1 }
+        };
+
+        sampled.insert(section, rows);
+    }
+
+    Ok(sampled)
+}
+
+impl ColumnKind {
+    fn name(self) -> &'static str {
+        match self {
+            ColumnKind::Date => { "date" },
+            ColumnKind::Number => { "number" },
+            ColumnKind::Text => { "text" },
+            ColumnKind::Empty => { "empty" }
+        }
+    }
+}
+
+/// The sections of a report as lines of text: each section's columns with their type and sample values
+pub fn describe_sections(sections: &BTreeMap<String, Rows>) -> String {
+    let mut description = String::new();
+
+    for (section, rows) in sections {
+        let columns = summarize_columns(rows);
+        let width = columns.iter().map(|c| c.name.len()).max().unwrap_or(0);
+
+        let _ = writeln!(description, "{} ({} rows sampled)", section, rows.len());
+        for column in &columns {
+            let _ = writeln!(description, "  {:width$}  {:6}  {}", column.name, column.kind.name(), column.samples.join(" | "), width = width);
+        }
+    }
+
+    description
+}
+
+/// Quotes a string for TOML
+fn quote(text: &str) -> String {
+    toml::Value::String(text.to_owned()).to_string()
+}
+
+/// A TOML key, quoted unless it is bare
+fn key(text: &str) -> String {
+    if !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        text.to_owned()
+    } else {
+        quote(text)
+    }
+}
+
+fn quote_list(items: &[&str]) -> String {
+    format!("[{}]", items.iter().map(|i| quote(i)).collect::<Vec<String>>().join(", "))
+}
+
+/// A datamart.toml entry for a report, from the rows sampled from each of its sections. The report's name and
+/// description are taken from the slug_name and report_title datamart gives its rows, where it does.
+pub fn skeleton_config(slug_id: &str, sections: &BTreeMap<String, Rows>) -> String {
+    let summaries: BTreeMap<&String, Vec<Column>> = sections.iter().map(|(name, rows)| (name, summarize_columns(rows))).collect();
+
+    let first_value = |column: &str| -> Option<String> {
+        summaries.values().flatten().find(|c| c.name == column).and_then(|c| c.samples.first().cloned())
+    };
+    let name = first_value("slug_name").map(|n| n.to_lowercase()).unwrap_or_else(|| format!("report_{}", slug_id));
+    let description = first_value("report_title").unwrap_or_default();
+    let independent = summaries.values().find_map(|columns| date_column(columns).map(str::to_owned)).unwrap_or_else(|| "report_date".to_owned());
+
+    let mut config = String::new();
+    let _ = writeln!(config, "[{}]", key(slug_id));
+    let _ = writeln!(config, "name = {}", quote(&name));
+    let _ = writeln!(config, "description = {}", quote(&description));
+    let _ = writeln!(config, "independent = {}", quote(&independent));
+    let _ = writeln!(config, "    [{}.sections]", key(slug_id));
+
+    for (section, columns) in &summaries {
+        let date = date_column(columns).unwrap_or(&independent);
+        let mut independents = vec![date];
+        let mut fields = Vec::new();
+
+        for column in columns {
+            if column.name == date || METADATA_COLUMNS.contains(&column.name.as_str()) {
+                continue;
+            }
+
+            match column.kind {
+                ColumnKind::Number => { fields.push(column.name.as_str()) },
+                ColumnKind::Date | ColumnKind::Text => { independents.push(column.name.as_str()) },
+                ColumnKind::Empty => {}
+            }
+        }
+
+        let _ = writeln!(config, "        [{}.sections.{}]", key(slug_id), key(section));
+        let _ = writeln!(config, "        independent = {}", quote_list(&independents));
+        let _ = writeln!(config, "        fields = {}", quote_list(&fields));
+    }
+
+    config
+}
+
+#[cfg(test)]
+fn test_rows(rows: &[&[(&str, Option<&str>)]]) -> Rows {
+    rows.iter().map(|row| row.iter().map(|(k, v)| (k.to_string(), v.map(str::to_owned))).collect()).collect()
+}
+
+#[test]
+fn test_summarize_columns() {
+    let rows = test_rows(&[
+        &[("report_date", Some("03/02/2020")), ("class_description", Some("STEER")), ("head_count", Some("1,250")), ("comment", None)],
+        &[("report_date", Some("03/02/2020")), ("class_description", Some("HEIFER")), ("head_count", Some("830")), ("comment", Some(""))],
+        &[("report_date", Some("03/03/2020")), ("class_description", Some("STEER")), ("head_count", Some("n/a"))]
+    ]);
+
+    let columns = summarize_columns(&rows);
+    let kinds: Vec<(&str, ColumnKind)> = columns.iter().map(|c| (c.name.as_str(), c.kind)).collect();
+    assert_eq!(kinds, vec![
+        ("class_description", ColumnKind::Text),
+        ("comment", ColumnKind::Empty),
+        ("head_count", ColumnKind::Text),
+        ("report_date", ColumnKind::Date)
+    ]);
+    assert_eq!(columns[0].samples, vec!["STEER", "HEIFER"]);
+    assert_eq!(date_column(&columns), Some("report_date"));
+}
+
+#[test]
+fn test_describe_sections() {
+    let mut sections = BTreeMap::new();
+    sections.insert("Detail".to_owned(), test_rows(&[
+        &[("report_date", Some("03/02/2020")), ("class_description", Some("STEER")), ("head_count", Some("1,250"))],
+        &[("report_date", Some("03/02/2020")), ("class_description", Some("HEIFER")), ("head_count", Some("830"))]
+    ]));
+
+    assert_eq!(describe_sections(&sections), "\
+Detail (2 rows sampled)
+  class_description  text    STEER | HEIFER
+  head_count         number  1,250 | 830
+  report_date        date    03/02/2020
+");
+}
+
+#[test]
+fn test_skeleton_config() {
+    let mut sections = BTreeMap::new();
+    sections.insert("Summary".to_owned(), test_rows(&[
+        &[("report_date", Some("03/02/2020")), ("slug_name", Some("LM_CT100")), ("report_title", Some("5 Area Daily Weighted Average")), ("previous_day_head_count", Some("1,250"))]
+    ]));
+    sections.insert("Current Detail".to_owned(), test_rows(&[
+        &[("report_date", Some("03/02/2020")), ("class_description", Some("STEER")), ("weighted_avg_price", Some("118.50")), ("head_count", Some("830"))]
+    ]));
+
+    let config = skeleton_config("2466", &sections);
+    assert_eq!(config, "\
+[2466]
+name = \"lm_ct100\"
+description = \"5 Area Daily Weighted Average\"
+independent = \"report_date\"
+    [2466.sections]
+        [2466.sections.\"Current Detail\"]
+        independent = [\"report_date\", \"class_description\"]
+        fields = [\"head_count\", \"weighted_avg_price\"]
+        [2466.sections.Summary]
+        independent = [\"report_date\"]
+        fields = [\"previous_day_head_count\"]
+");
+
+    // it reads back as a report's configuration
+    let parsed: BTreeMap<String, datamart::DatamartConfig> = toml::from_str(&config).unwrap();
+    assert_eq!(parsed["2466"].sections["Current Detail"].fields, vec!["head_count", "weighted_avg_price"]);
+}
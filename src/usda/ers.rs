@@ -0,0 +1,158 @@
+// https://www.ers.usda.gov/data-products/feed-grains-database/ (FeedGrains.csv)
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use chrono::NaiveDate;
+
+use super::{USDADataPackage, USDADataPackageSection};
+use super::dates;
+use super::datamart::{DatamartConfig, DatamartSection, DatamartApiVersion};
+
+const ERS_INDEPENDENT: [&str; 3] = ["report_date", "geography", "timeperiod"];
+const ERS_FREQUENCIES: [&str; 3] = ["annual", "quarterly", "monthly"];
+
+/// Monthly rows are dated on the first of their month, everything else on January 1 of its year; the
+/// original time period description is kept as an independent column either way.
+fn ers_report_date(year: i32, frequency: &str, timeperiod: &str) -> Option<NaiveDate> {
+    let month = if frequency == "monthly" {
+        dates::month_number(timeperiod)?
+    } else {
+        1
+    };
+
+    NaiveDate::from_ymd_opt(year, month, 1)
+}
+
+/// Parses an ERS yearbook database export (the long format CSV shared by the Feed Grains and Oil Crops
+/// databases) into annual, quarterly and monthly sections. Variable names are formed from the commodity,
+/// attribute and unit, e.g. "Corn - Prices received by farmers (Dollars per bushel)".
+pub fn yearbook_parse<R: Read>(reader: R) -> Result<USDADataPackage, String> {
+    let mut reader = csv::Reader::from_reader(reader);
+
+    let header = match reader.headers() {
+        Ok(h) => { h.clone() },
+        Err(e) => { return Err(format!("Failed to read ERS file header: {}", e)) }
+    };
+
+    let position = |name: &str| header.iter().position(|h| h.trim() == name).ok_or(format!("ERS file has no {} column", name));
+    let geography_column = position("SC_GeographyIndented_Desc")?;
+    let commodity_column = position("SC_Commodity_Desc")?;
+    let attribute_column = position("SC_Attribute_Desc")?;
+    let unit_column = position("SC_Unit_Desc")?;
+    let year_column = position("Year_ID")?;
+    let frequency_column = position("SC_Frequency_Desc")?;
+    let timeperiod_column = position("Timeperiod_Desc")?;
+    let amount_column = position("Amount")?;
+
+    let mut grouped: HashMap<(String, NaiveDate, String, String), USDADataPackageSection> = HashMap::new();
+
+    for (number, record) in reader.records().enumerate() {
+        let record = match record {
+            Ok(r) => { r },
+            Err(e) => { return Err(format!("Failed to read ERS file record {}: {}", number + 1, e)) }
+        };
+
+        let field = |column: usize| record.get(column).unwrap_or("").trim();
+
+        let frequency = field(frequency_column).to_lowercase();
+        let timeperiod = field(timeperiod_column);
+
+        // each frequency is a section of its own, and only these have tables
+        if !ERS_FREQUENCIES.contains(&frequency.as_str()) {
+            return Err(format!("Unknown frequency on ERS file record {}: '{}', expected one of {:?}", number + 1, field(frequency_column), ERS_FREQUENCIES));
+        }
+
+        let report_date = match field(year_column).parse::<i32>().ok().and_then(|y| ers_report_date(y, &frequency, timeperiod)) {
+            Some(d) => { d },
+            None => { return Err(format!("Invalid period on ERS file record {}: '{}' '{}'", number + 1, field(year_column), timeperiod)) }
+        };
+
+        let variable = format!("{} - {} ({})", field(commodity_column), field(attribute_column), field(unit_column));
+        let key = (frequency, report_date, field(geography_column).to_owned(), timeperiod.to_owned());
+
+        let data = grouped.entry(key).or_insert_with_key(|(_, report_date, geography, timeperiod)| {
+            let mut data = USDADataPackageSection::new(*report_date);
+            data.independent.push(report_date.format("%Y-%m-%d").to_string());
+            data.independent.push(geography.to_owned());
+            data.independent.push(timeperiod.to_owned());
+            data
+        });
+
+        data.entries.insert(variable, field(amount_column).to_owned());
+    }
+
+    let mut structure = USDADataPackage::new("ers_yearbook".to_owned());
+    structure.source = Some("ers".to_owned());
+
+    for ((frequency, _, _, _), data) in grouped {
+        structure.sections.entry(frequency).or_default().push(data);
+    }
+
+    Ok(structure)
+}
+
+/// The table layout of ERS yearbook data, in the shape of a datamart configuration
+pub fn yearbook_structure() -> DatamartConfig {
+    let mut sections: HashMap<String, DatamartSection> = HashMap::new();
+
+    for section in &ERS_FREQUENCIES {
+        sections.insert(section.to_string(), DatamartSection {
+            alias: None,
+            independent: ERS_INDEPENDENT.iter().map(|c| c.to_string()).collect(),
+            fields: Vec::new(),
+            types: Default::default(),
+            ignored: None,
+            required: true
+        });
+    }
+
+    DatamartConfig {
+        name: "ers_yearbook".to_owned(),
+        description: "USDA ERS Feed Grains and Oil Crops yearbook series".to_owned(),
+        independent: "report_date".to_owned(),
+        api_version: DatamartApiVersion::V1,
+        mars_slug: None,
+        mars_filters: Default::default(),
+        independent_type: Default::default(),
+        mars_family: None,
+        on_conflict: None,
+        period: None,
+        file_date: None,
+        indexes: None,
+        archive_url: None,
+        release_time: None,
+        connect_timeout: None,
+        read_timeout: None,
+        transforms: Vec::new(),
+        parser: None,
+        sections
+    }
+}
+
+#[test]
+fn test_yearbook_parse() {
+    use std::io::Cursor;
+
+    let test_string = r#"SC_Group_ID,SC_Group_Desc,SC_GroupCommod_ID,SC_GroupCommod_Desc,SC_Geography_ID,SortOrder,SC_GeographyIndented_Desc,SC_Commodity_ID,SC_Commodity_Desc,SC_Attribute_ID,SC_Attribute_Desc,SC_Unit_ID,SC_Unit_Desc,Year_ID,SC_Frequency_ID,SC_Frequency_Desc,Timeperiod_ID,Timeperiod_Desc,Amount
+2,Prices,9,Corn,1,0.8,United States,9,Corn,18,Prices received by farmers,2,Dollars per bushel,1975,1,Monthly,9,Sep,2.76
+2,Prices,9,Corn,1,0.8,United States,9,Corn,18,Prices received by farmers,2,Dollars per bushel,1975,3,Annual,69,Commodity Market Year,2.54
+1,Supply and use,9,Corn,1,0.8,United States,9,Corn,1,Planted acreage,4,"Million acres",1975,3,Annual,69,Commodity Market Year,78.719
+1,Supply and use,9,Corn,1,0.8,United States,9,Corn,3,Beginning stocks,3,Million bushels,1975,2,Quarterly,43,Q1 Sep-Nov,361
+"#;
+
+    let structure = yearbook_parse(Cursor::new(test_string)).unwrap();
+    assert_eq!(structure.sections.len(), 3);
+
+    let monthly = &structure.sections["monthly"];
+    assert_eq!(monthly[0].report_date, NaiveDate::from_ymd_opt(1975, 9, 1).unwrap());
+    assert_eq!(monthly[0].entries["Corn - Prices received by farmers (Dollars per bushel)"], "2.76");
+
+    let annual = &structure.sections["annual"];
+    assert_eq!(annual.len(), 1);
+    assert_eq!(annual[0].independent, vec!["1975-01-01", "United States", "Commodity Market Year"]);
+    assert_eq!(annual[0].entries["Corn - Planted acreage (Million acres)"], "78.719");
+
+    let weekly = test_string.replace("Quarterly,43", "Weekly,43");
+    assert_eq!(yearbook_parse(Cursor::new(weekly)).unwrap_err(), "Unknown frequency on ERS file record 4: 'Weekly', expected one of [\"annual\", \"quarterly\", \"monthly\"]");
+}
@@ -1,11 +1,16 @@
 // https://usda.library.cornell.edu/apidoc/index.html#/release/findReleaseByIdentifier
+// https://usda.library.cornell.edu/apidoc/index.html#/release/findReleaseByDate
 
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use chrono::NaiveDate;
 
 use serde::Deserialize; 
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct ESMISRelease {
     pub id: String,
     pub files: Vec<String>,
@@ -21,9 +26,49 @@ pub struct ESMISRelease {
     pub temporal_coverage: Option<String>
 }
 
+impl ESMISRelease {
+    /// The day the release was published, from the start of `release_datetime`
+    pub fn release_date(&self) -> Result<NaiveDate, String> {
+        self.release_datetime.get(..10)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+            .ok_or_else(|| format!("Invalid ESMIS release date: {}", self.release_datetime))
+    }
+}
+
 const API_ROOT: &str = "https://usda.library.cornell.edu/api/v1";
 
-pub fn fetch_releases_by_identifier(token:&str, identifier:String, start_date: Option<NaiveDate>, end_date: Option<NaiveDate>, http_connect_timeout:Arc<u64>, http_receive_timeout:Arc<u64>) -> Result<Option<Vec<String>>, String> {
+/// Reports beyond which an update looks up releases by date in one pass, rather than one report at a time
+pub const BATCH_THRESHOLD: usize = 6;
+
+/// Days behind that a report may be to have its releases looked up in the batched pass; those further behind would
+/// have it page through too much of ESMIS, and are looked up on their own
+pub const BATCH_MAX_DAYS: i64 = 31;
+
+/// Pause between the page requests of a batched lookup, to go easy on the API
+const PAGE_DELAY: Duration = Duration::from_millis(500);
+
+/// Pages after which a batched lookup stops, should the API ignore the page parameter
+const MAX_PAGES: u32 = 100;
+
+/// Looks up releases, through the response cache when `cached`, which lookups for the latest release are not
+fn find_releases(token: &str, target_url: &str, cached: bool, http_connect_timeout: Arc<u64>, http_receive_timeout: Arc<u64>) -> Result<Vec<ESMISRelease>, String> {
+    let mut request = ureq::get(target_url);
+    request.set("User-Agent", super::USER_AGENT)
+        .set("Authorization", &format!("Bearer {}", token))
+        .timeout_connect(*http_connect_timeout).timeout_read(*http_receive_timeout);
+    let body = crate::http::fetch_text(&mut request, &crate::http::RetryPolicy::ESMIS, cached)
+        .map_err(|e| format!("Failed to retrieve data from datamart server with URL {}. Error: {}", target_url, e))?;
+
+    match serde_json::from_str::<Vec<ESMISRelease>>(&body) {
+        Ok(j) => { Ok(j) },
+        Err(_) => {
+            Err(format!("Response from datamart server is not valid JSON, or the structure has changed significantly. Target url: {}", target_url))
+        }
+    }
+}
+
+/// The releases of a report published between two dates, or all of them, with their metadata
+pub fn fetch_release_records(token: &str, identifier: &str, start_date: Option<NaiveDate>, end_date: Option<NaiveDate>, http_connect_timeout: Arc<u64>, http_receive_timeout: Arc<u64>) -> Result<Vec<ESMISRelease>, String> {
     let target_url = {
         let base = format!("{}/release/findByIdentifier/{}", API_ROOT, identifier);
 
@@ -37,30 +82,105 @@ pub fn fetch_releases_by_identifier(token:&str, identifier:String, start_date: O
         }
     };
 
-    let response = ureq::get(&target_url)
-        .set("User-Agent", super::USER_AGENT)
-        .set("Authorization", &format!("Bearer {}", token))
-        .timeout_connect(*http_connect_timeout).timeout_read(*http_receive_timeout).call();
+    find_releases(token, &target_url, true, http_connect_timeout, http_receive_timeout)
+}
+
+/// The releases of several reports, each published between its own start date and `end_date`, by identifier. Every
+/// release ESMIS lists for those days is paged through in one pass, so that the requests made don't grow with the
+/// number of reports. Every identifier asked for is in the result, with no releases if none were found.
+pub fn fetch_release_records_batch(token: &str, reports: &[(&str, NaiveDate)], end_date: NaiveDate, http_connect_timeout: Arc<u64>, http_receive_timeout: Arc<u64>) -> Result<HashMap<String, Vec<ESMISRelease>>, String> {
+    let start_date = match reports.iter().map(|(_, start)| *start).min() {
+        Some(d) => { d },
+        None => { return Ok(HashMap::new()) }
+    };
+
+    let mut found: Vec<ESMISRelease> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+
+    for page in 1..=MAX_PAGES {
+        if page > 1 {
+            thread::sleep(PAGE_DELAY);
+        }
 
-    if let Some(error) = response.synthetic_error() {
-        return Err(format!("Failed to retrieve data from datamart server with URL {}. Error: {}", target_url, error));
+        let target_url = format!(
+            "{}/release/findByDate?start_date={}&end_date={}&page={}",
+            API_ROOT, start_date.format("%Y-%m-%d"), end_date.format("%Y-%m-%d"), page
+        );
+
+        let releases = find_releases(token, &target_url, true, http_connect_timeout.clone(), http_receive_timeout.clone())?;
+        let before = seen.len();
+
+        for release in releases {
+            if seen.insert(release.id.clone()) {
+                found.push(release);
+            }
+        }
+
+        // an empty page, or one repeating the last, is the end of the listing
+        if seen.len() == before {
+            break;
+        }
     }
 
-    let parsed = {
-        let result = response.into_json_deserialize::<Vec<ESMISRelease>>();
-        match result {
-            Ok(j) => { j },
-            Err(_) => { 
-                return Err(format!("Response from datamart server is not valid JSON, or the structure has changed significantly. Target url: {}", target_url));
+    Ok(group_by_identifier(found, reports))
+}
+
+/// Sorts releases out by the reports asked for, keeping those published from each report's start date
+fn group_by_identifier(releases: Vec<ESMISRelease>, reports: &[(&str, NaiveDate)]) -> HashMap<String, Vec<ESMISRelease>> {
+    let mut grouped: HashMap<String, Vec<ESMISRelease>> = reports.iter().map(|(identifier, _)| ((*identifier).to_owned(), Vec::new())).collect();
+
+    for release in releases {
+        let release_date = match release.release_date() {
+            Ok(d) => { d },
+            Err(_) => { continue }
+        };
+
+        let report = reports.iter().find(|(identifier, _)| release.identifier.iter().any(|i| i.eq_ignore_ascii_case(identifier)));
+        if let Some((identifier, start)) = report {
+            if release_date >= *start {
+                grouped.get_mut(*identifier).unwrap().push(release);
             }
         }
+    }
+
+    grouped
+}
+
+/// The most recent release of a report, which ESMIS lists as soon as it is published, possibly before its files
+pub fn fetch_latest_release(token: &str, identifier: &str, http_connect_timeout: Arc<u64>, http_receive_timeout: Arc<u64>) -> Result<Option<ESMISRelease>, String> {
+    let target_url = format!("{}/release/findByIdentifier/{}?latest=true", API_ROOT, identifier);
+    let releases = find_releases(token, &target_url, false, http_connect_timeout, http_receive_timeout)?;
+
+    Ok(releases.into_iter().next())
+}
+
+#[test]
+fn test_group_by_identifier() {
+    let release = |id: &str, identifier: &str, date: &str| -> ESMISRelease {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "files": [format!("https://downloads.usda.library.cornell.edu/{}.txt", id)],
+            "title": ["A report"],
+            "release_datetime": date,
+            "identifier": [identifier],
+            "agency_acronym": ["AMS"]
+        })).unwrap()
     };
 
-    let mut result: Vec<String> = Vec::new();
+    let releases = vec![
+        release("a1", "LM_XB463", "2020-03-05T15:00:00Z"),
+        release("a2", "LM_XB463", "2020-03-06T15:00:00Z"),
+        release("b1", "broihatc", "2020-03-04T15:00:00Z"),
+        release("c1", "DC_GR110", "2020-03-06T15:00:00Z")  // not asked for
+    ];
 
-    for release in parsed {
-        result.push(release.files.first().unwrap().to_owned());
-    }
+    let start = |day| NaiveDate::from_ymd_opt(2020, 3, day).unwrap();
+    let reports = [("LM_XB463", start(6)), ("BroiHatc", start(1)), ("PoulSlau", start(1))];
+    let grouped = group_by_identifier(releases, &reports);
 
-    Ok(Some(result))
-}
\ No newline at end of file
+    let ids = |identifier: &str| -> Vec<&str> { grouped[identifier].iter().map(|r| r.id.as_str()).collect() };
+    assert_eq!(ids("LM_XB463"), vec!["a2"]);
+    assert_eq!(ids("BroiHatc"), vec!["b1"]);
+    assert!(ids("PoulSlau").is_empty());
+    assert!(!grouped.contains_key("DC_GR110"));
+}
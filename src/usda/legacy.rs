@@ -1,89 +1,35 @@
 use super::{USDADataPackage, USDADataPackageSection}; // used to emulate datamart structure for easy integration
+use super::dates;
+use super::datamart::DatamartConfig;
+use super::declarative;
+use crate::metrics;
+use super::textparse::{self, find_line_contains, find_line_regex, find_line_starts_with, find_line_starts_with_any};
 
 use chrono::NaiveDate;
 use regex::Regex;
 
-/// Finds the zero-indexed line number that matches a regex pattern.
-/// If your regex is trivial, consider using the faster `find_line_contains`
-fn find_line_regex(text_array: &[&str], pattern:&Regex) -> Option<usize> {
-    for (number, line) in text_array.iter().enumerate() {
-        if pattern.is_match(line) {
-            return Some(number)
-        }
-    }
-
-    None
-}
-
-/// Finds the zero-indexed line number that contains a string.
-/// For more advanced finding, consider using the slower `find_line_regex`
-fn find_line_contains(text_array: &[&str], pattern:&str) -> Option<usize> {
-    for (number, line) in text_array.iter().enumerate() {
-        if line.contains(pattern) {
-            return Some(number)
-        }
-    }
-
-    None
-}
-
-/// Finds the zero-indexed line number that starts with a string.
-/// For more advanced finding, consider using the slower `find_line_regex`
-/// For basic finding that isn't anchored to the start of a line, consider `find_line_contains`
-fn find_line_starts_with(text_array: &[&str], pattern:&str) -> Option<usize> {
-    for (number, line) in text_array.iter().enumerate() {
-        if line.starts_with(pattern) {
-            return Some(number)
-        }
-    }
-
-    None
-}
-
-pub fn lmxb463_text_parse(text: String) -> Result<USDADataPackage, String> {
-    let text_array: Vec<&str> = text.split_terminator('\n').collect();
-
-    let location: usize = {
-        match find_line_starts_with(&text_array, "For Week Ending:") {
-            Some(line) => { line },
-            None => {
-                return Err("Failed to find date line".to_owned());
-            }
+/// One row section of label/value pairs from the block following `location`
+fn lmxb463_labelled_section(text_array: &[&str], report_date: NaiveDate, location: Option<usize>, pattern: &Regex, description: &str) -> Result<Vec<USDADataPackageSection>, String> {
+    let location = match location {
+        Some(line) => { line },
+        None => {
+            return Err(format!("Failed to locate {} section", description));
         }
     };
 
-    let report_date = {
-        lazy_static! {
-            static ref RE_DATE_PARSE: Regex = Regex::new(r"(?P<month>\d+)/(?P<day>\d+)/(?P<year>\d{4})").unwrap();
-        }
+    let mut section = USDADataPackageSection::new(report_date);
+    section.independent.push(report_date.format("%Y-%m-%d").to_string());
+    section.entries.extend(textparse::labelled_values(&textparse::block_until(text_array, location, None), pattern)?);
 
-        match RE_DATE_PARSE.captures(text_array[location]) {
-            Some(x) => {
-                NaiveDate::from_ymd(
-                    x.name("year").unwrap().as_str().parse::<i32>().unwrap(),
-                    x.name("month").unwrap().as_str().parse::<u32>().unwrap(),
-                    x.name("day").unwrap().as_str().parse::<u32>().unwrap()
-                )
-            },
-            None => {
-                return Err("Failed to parse date line for report, aborting.".to_owned());
-            }
-        }
-    };
+    Ok(vec![section])
+}
 
-    let location = {
-        match find_line_starts_with(&text_array, "TOTAL LOADS OF PRODUCT REPORTED") {
-            Some(line) => {
-                line
-            },
-            None => {
-                match find_line_starts_with(&text_array, "TOTAL LOADS") {
-                    Some(line) => {line},
-                    None => {
-                        return Err("Failed to find total load count location.".to_owned());
-                    }
-                }
-            }
+/// The load total and primal cutout values
+fn lmxb463_summary(text_array: &[&str], report_date: NaiveDate) -> Result<Vec<USDADataPackageSection>, String> {
+    let location = match find_line_starts_with_any(text_array, &["TOTAL LOADS OF PRODUCT REPORTED", "TOTAL LOADS"]) {
+        Some(line) => { line },
+        None => {
+            return Err("Failed to find total load count location.".to_owned());
         }
     };
 
@@ -102,7 +48,6 @@ pub fn lmxb463_text_parse(text: String) -> Result<USDADataPackage, String> {
         }
     };
 
-    let mut structure = USDADataPackage::new("LM_XB463".to_owned());
     let mut summary_section = USDADataPackageSection::new(report_date);
     summary_section.independent.push(report_date.format("%Y-%m-%d").to_string());
     
@@ -110,7 +55,7 @@ pub fn lmxb463_text_parse(text: String) -> Result<USDADataPackage, String> {
     
     // primal cutout values
     let location = {
-        match find_line_starts_with(&text_array, "Weekly Cutout Value") {
+        match find_line_starts_with(text_array, "Weekly Cutout Value") {
             Some(line) => {line},
             None => {
                 return Err("Failed to locate cutout value line".to_owned());
@@ -122,7 +67,7 @@ pub fn lmxb463_text_parse(text: String) -> Result<USDADataPackage, String> {
         static ref RE_PRIMAL_VALUE: Regex = Regex::new(r"(?i)(?P<label>(([A-Z])\s?)+)\s+(?P<comprehensive>\d+\.\d{2})\s+(?P<prime>\d+\.\d{2})\s+(?P<branded>\d+\.\d{2})\s+(?P<choice>\d+\.\d{2})\s+(?P<select>\d+\.\d{2})\s+(?P<ungraded>\d+\.\d{2})").unwrap();
     }
     
-    for line in &text_array[location..=location+8] {
+    for line in textparse::block_until(text_array, location, None) {
         match RE_PRIMAL_VALUE.captures(line) {
             Some(x) => {
                 for column in &["comprehensive", "prime", "branded", "choice", "select", "ungraded"] {
@@ -138,167 +83,50 @@ pub fn lmxb463_text_parse(text: String) -> Result<USDADataPackage, String> {
         }
     }
 
-    let section = structure.sections.entry("summary".to_owned()).or_insert_with(Vec::new);
-    section.push(summary_section);
+    Ok(vec![summary_section])
+}
 
-    // quality breakdown   
-    let mut quality_section = USDADataPackageSection::new(report_date);
-    quality_section.independent.push(report_date.format("%Y-%m-%d").to_string());
+pub fn lmxb463_text_parse(text: String) -> Result<USDADataPackage, String> {
+    let text_array = textparse::lines(&text);
 
-    let location = {
-        match find_line_starts_with(&text_array, "Quality breakdown:") {
-            Some(line) => { line },
-            None => {
-                // different report version
-                match find_line_starts_with(&text_array, "TOTAL LOADS") {
-                    Some(line) => { line },
-                    None => {
-                        return Err("Failed to locate quality section location".to_owned());
-                    }
-                }
-            }
-        }
-    } + 1;
+    let report_date = textparse::date_on_line(&text_array, "For Week Ending:")?;
 
     lazy_static! {
         static ref RE_QUALITY_VALUE: Regex = Regex::new(r"(?i)(?P<label>[A-Z]+)\**\s+(?P<value>([0-9,]+))").unwrap();
-    }
-
-    for line in &text_array[location..=location+4] {
-        let quality = RE_QUALITY_VALUE.captures(line).unwrap();
-        quality_section.entries.insert(quality.name("label").unwrap().as_str().to_owned(), quality.name("value").unwrap().as_str().to_owned());
-    }
-
-    let section = structure.sections.entry("quality".to_owned()).or_insert_with(Vec::new);
-    section.push(quality_section);
-
-    // sales type
-    let mut sales_section = USDADataPackageSection::new(report_date);
-    sales_section.independent.push(report_date.format("%Y-%m-%d").to_string());
-
-    let location = {
-        lazy_static! {
-            static ref RE_LOCATION_SALES: Regex = Regex::new(r"(?i)^((Sales type breakdown:)|(TYPE OF SALES))").unwrap();
-        }
-
-        match find_line_regex(&text_array, &RE_LOCATION_SALES) {
-            Some(line) => { line },
-            None => {
-                return Err("Failed to locate sales section".to_owned());
-            }
-        }
-    } + 1;
-
-    lazy_static! {
+        static ref RE_LOCATION_SALES: Regex = Regex::new(r"(?i)^((Sales type breakdown:)|(TYPE OF SALES))").unwrap();
         static ref RE_SALES_VALUE: Regex = Regex::new(r"(?i)(?P<label>(([A-Z0-9/\-]+)\s{0,2})+)\s+(?P<value>([0-9,]+))").unwrap();
+        static ref RE_LOCATION_DESTINATION: Regex = Regex::new(r"(?i)^Destination breakdown:").unwrap();
+        static ref RE_DESTINATION_VALUE: Regex = Regex::new(r"(?i)(?P<label>(([A-Z]+)\s?)+)\s+(?P<value>([0-9,]+))").unwrap();
+        static ref RE_LOCATION_DELIVERY: Regex = Regex::new(r"(?i)^Delivery period breakdown:").unwrap();
+        static ref RE_DELIVERY_VALUE: Regex = Regex::new(r"(?i)(?P<label>(([A-Z0-9-]+)\s?)+)\s+(?P<value>([0-9,]+))").unwrap();
     }
 
-    for line in &text_array[location..=location+3] {
-        let sales = RE_SALES_VALUE.captures(line).unwrap();
-        sales_section.entries.insert(sales.name("label").unwrap().as_str().trim().to_owned(), sales.name("value").unwrap().as_str().to_owned());
-    }
-
-    let section = structure.sections.entry("sales_type".to_owned()).or_insert_with(Vec::new);
-    section.push(sales_section);
-
-    // destination
-    let location = {
-        lazy_static! {
-            static ref RE_LOCATION_DESTINATION: Regex = Regex::new(r"(?i)^Destination breakdown:").unwrap();
-        }
-        find_line_regex(&text_array, &RE_LOCATION_DESTINATION)
-    };
-
-    if let Some(line) = location {
-        let line = line + 1;
-
-        let mut destination_section = USDADataPackageSection::new(report_date);
-        destination_section.independent.push(report_date.format("%Y-%m-%d").to_string());
-
-        lazy_static! {
-            static ref RE_DESTINATION_VALUE: Regex = Regex::new(r"(?i)(?P<label>(([A-Z]+)\s?)+)\s+(?P<value>([0-9,]+))").unwrap();
-        }
-
-        for line in &text_array[line..=line+2] {
-            let result = RE_DESTINATION_VALUE.captures(line).unwrap();
-            destination_section.entries.insert(result.name("label").unwrap().as_str().trim().to_owned(), result.name("value").unwrap().as_str().to_owned());
-        }
-        
-        let section = structure.sections.entry("destination".to_owned()).or_insert_with(Vec::new);
-        section.push(destination_section);
-    }
-
-    // delivery period
-    let location = {
-        lazy_static! {
-            static ref RE_LOCATION_DELIVERY: Regex = Regex::new(r"(?i)^Delivery period breakdown:").unwrap();
-        }
-        find_line_regex(&text_array, &RE_LOCATION_DELIVERY)
-    };
+    let mut structure = USDADataPackage::new("LM_XB463".to_owned());
 
-    if let Some(line) = location {
-        let line = line + 1;
+    structure.insert_section("summary", lmxb463_summary(&text_array, report_date));
 
-        lazy_static! {
-            static ref RE_DELIVERY_VALUE: Regex = Regex::new(r"(?i)(?P<label>(([A-Z0-9-]+)\s?)+)\s+(?P<value>([0-9,]+))").unwrap();
-        }
+    // older report versions list quality directly under the load total
+    let location = find_line_starts_with_any(&text_array, &["Quality breakdown:", "TOTAL LOADS"]);
+    structure.insert_section("quality", lmxb463_labelled_section(&text_array, report_date, location, &RE_QUALITY_VALUE, "quality"));
 
-        let mut delivery_section = USDADataPackageSection::new(report_date);
-        delivery_section.independent.push(report_date.format("%Y-%m-%d").to_string());
+    let location = find_line_regex(&text_array, &RE_LOCATION_SALES);
+    structure.insert_section("sales_type", lmxb463_labelled_section(&text_array, report_date, location, &RE_SALES_VALUE, "sales type"));
 
-        for line in &text_array[line..=line+3] {
-            let result = RE_DELIVERY_VALUE.captures(line).unwrap();
-            delivery_section.entries.insert(result.name("label").unwrap().as_str().trim().to_owned(), result.name("value").unwrap().as_str().to_owned());
-        }
+    let location = find_line_regex(&text_array, &RE_LOCATION_DESTINATION);
+    structure.insert_section("destination", lmxb463_labelled_section(&text_array, report_date, location, &RE_DESTINATION_VALUE, "destination"));
 
-        let section = structure.sections.entry("delivery".to_owned()).or_insert_with(Vec::new);
-        section.push(delivery_section);
-    }
+    let location = find_line_regex(&text_array, &RE_LOCATION_DELIVERY);
+    structure.insert_section("delivery", lmxb463_labelled_section(&text_array, report_date, location, &RE_DELIVERY_VALUE, "delivery period"));
 
     Ok(structure)
 }
 
 pub fn dcgr110_text_parse(text: String) -> Result<USDADataPackage, String> {
-    let text_array: Vec<&str> = text.split_terminator('\n').collect();
+    let text_array = textparse::lines(&text);
 
     let mut structure = USDADataPackage::new(String::from("DC_GR110"));  
 
-    let location: usize = {
-        match find_line_starts_with(&text_array, "Dodge City, KS") {
-            Some(line) => {line},
-            None => {
-                return Err("Failed to locate report date line".to_owned());
-            }
-        }
-    };
-
-    let report_date = {
-        lazy_static! {
-            static ref RE_DATE_PARSE: Regex = Regex::new(r"(?i)(?P<month>[a-z]+)\s+(?P<day>\d+),\s+(?P<year>\d{4})").unwrap();
-        }
-
-        match RE_DATE_PARSE.captures(text_array[location]) {
-            Some(x) => {
-                let month_name = x.name("month").unwrap().as_str().to_lowercase();
-                let month = match month_name.as_ref() {
-                    "jan" => {1},  "feb" => {2},  "mar" => {3},
-                    "apr" => {4},  "may" => {5},  "jun" => {6},
-                    "jul" => {7},  "aug" => {8},  "sep" => {9},
-                    "oct" => {10}, "nov" => {11}, "dec" => {12},
-                    _ => return Err(format!("Invalid month name captured: {}",  month_name))
-                };
-
-                NaiveDate::from_ymd(
-                    x.name("year").unwrap().as_str().parse::<i32>().unwrap(),
-                    month,
-                    x.name("day").unwrap().as_str().parse::<u32>().unwrap()
-                )
-            },
-            None => {
-                return Err("Failed to parse date line for report, aborting.".to_owned());
-            }
-        }
-    };
+    let report_date = textparse::date_on_line(&text_array, "Dodge City, KS")?;
 
     let mut location: usize = {
         match find_line_contains(&text_array, "HRW WHEAT ORD US NO 1") {
@@ -314,7 +142,7 @@ pub fn dcgr110_text_parse(text: String) -> Result<USDADataPackage, String> {
     }
 
     let mut section_order = vec!["soybeans", "sorghum", "corn", "wheat",];
-    let mut section = structure.sections.entry(section_order.pop().unwrap().to_string()).or_insert_with(Vec::new);
+    let mut section = structure.sections.entry(section_order.pop().unwrap().to_string()).or_default();
 
     loop {
         let result = RE_PRICE_LINE.captures(text_array[location]);
@@ -344,7 +172,7 @@ pub fn dcgr110_text_parse(text: String) -> Result<USDADataPackage, String> {
                 if section_order.is_empty() {
                     break;
                 } else {
-                    section = structure.sections.entry(section_order.pop().unwrap().to_string()).or_insert_with(Vec::new);
+                    section = structure.sections.entry(section_order.pop().unwrap().to_string()).or_default();
                     location += 2;
                 }
             }
@@ -352,10 +180,393 @@ pub fn dcgr110_text_parse(text: String) -> Result<USDADataPackage, String> {
         
         location += 1;
 
-        if location == text_array.len() && !section_order.is_empty() {
-            return Err(format!("Failed to parse report, hit end of report early. Missed sections: {:?}", section_order))
+        if location >= text_array.len() {
+            for missed in section_order.drain(..) {
+                structure.section_errors.insert(missed.to_owned(), "Hit end of report before reaching section".to_owned());
+            }
+            break;
+        }
+    }
+
+    Ok(structure)
+}
+
+/// One state table of the Broiler Hatchery report, keeping only its most recent week
+fn broihatc_table(text_array: &[&str], title: &str, section_name: &str) -> Result<Vec<USDADataPackageSection>, String> {
+    let table = textparse::table_block(text_array, title)?;
+
+    let report_date = match table.header.iter().flat_map(|line| dates::find_dates(line)).last() {
+        Some(d) => { d },
+        None => {
+            return Err(format!("Failed to find week ending dates for table: {}", title));
+        }
+    };
+
+    let mut section = Vec::new();
+
+    for (label, cells) in table.rows {
+        if label.starts_with("Percent") {
+            continue;
+        }
+
+        let value = match cells.last() {
+            Some(v) => { v.to_owned() },
+            None => { continue }
+        };
+
+        let mut data = USDADataPackageSection::new(report_date);
+        data.independent.push(report_date.format("%Y-%m-%d").to_string());
+        data.independent.push(label);
+        data.entries.insert(section_name.to_string(), value);
+        section.push(data);
+    }
+
+    Ok(section)
+}
+
+/// Weekly NASS Broiler Hatchery. Only the report's own week (the rightmost column) is kept from each table,
+/// earlier weeks having been recorded by earlier releases.
+pub fn broihatc_text_parse(text: String) -> Result<USDADataPackage, String> {
+    let text_array = textparse::lines(&text);
+
+    let mut structure = USDADataPackage::new("BroiHatc".to_owned());
+
+    for (title, section_name) in &[("Broiler-Type Eggs Set - States", "eggs_set"), ("Broiler-Type Chicks Placed - States", "chicks_placed")] {
+        structure.insert_section(section_name, broihatc_table(&text_array, title, section_name));
+    }
+
+    Ok(structure)
+}
+
+/// Monthly NASS Poultry Slaughter, the federally inspected summary table by class
+pub fn poulslau_text_parse(text: String) -> Result<USDADataPackage, String> {
+    let text_array = textparse::lines(&text);
+
+    let title = "Poultry Slaughtered Under Federal Inspection and Pounds Certified - United States";
+    let report_date = match find_line_starts_with(&text_array, title).and_then(|line| dates::find_month_year(text_array[line])) {
+        Some(d) => { d },
+        None => {
+            return Err("Failed to parse date from table title, aborting.".to_owned());
+        }
+    };
+    let report_year = report_date.format("%Y").to_string();
+
+    let table = textparse::table_block(&text_array, title)?;
+
+    lazy_static! {
+        static ref RE_YEAR: Regex = Regex::new(r"\b(?:19|20)\d{2}\b").unwrap();
+    }
+
+    // each measure has previous year, current year and percent columns; the header tells us which year comes first
+    let offset = table.header.iter()
+        .map(|line| RE_YEAR.find_iter(line).map(|m| m.as_str()).collect::<Vec<&str>>())
+        .find(|years| years.len() >= 2)
+        .and_then(|years| years.iter().take(2).position(|y| *y == report_year));
+
+    let offset = match offset {
+        Some(o) => { o },
+        None => {
+            return Err("Failed to find year columns in slaughter table header".to_owned());
+        }
+    };
+
+    let mut section = Vec::new();
+
+    for (label, cells) in table.rows {
+        if cells.len() < 9 {
+            continue;
+        }
+
+        let mut data = USDADataPackageSection::new(report_date);
+        data.independent.push(report_date.format("%Y-%m-%d").to_string());
+        data.independent.push(label.to_lowercase());
+
+        for (index, field) in ["number_slaughtered", "live_weight", "ready_to_cook_weight"].iter().enumerate() {
+            data.entries.insert(field.to_string(), cells[index * 3 + offset].to_owned());
         }
+
+        section.push(data);
     }
 
+    if section.is_empty() {
+        return Err("No rows found in federally inspected slaughter table".to_owned());
+    }
+
+    let mut structure = USDADataPackage::new("PoulSlau".to_owned());
+    structure.sections.insert("federally_inspected".to_owned(), section);
+
     Ok(structure)
-}
\ No newline at end of file
+}
+
+/// The current version of each legacy parser. Bump a parser's version whenever a change alters what it extracts
+/// from a document, so that `--reparse` can find the rows written by the previous version.
+pub fn parser_version(identifier: &str) -> Option<u32> {
+    match identifier.to_uppercase().as_ref() {
+        "LM_XB463" => { Some(1) },
+        "DC_GR110" => { Some(1) },
+        "BROIHATC" => { Some(1) },
+        "POULSLAU" => { Some(1) },
+        _ => { None }
+    }
+}
+
+/// The current version of a report's parser, whether built in or defined in its configuration
+pub fn report_parser_version(identifier: &str, config: &DatamartConfig) -> Option<u32> {
+    match config.parser.as_ref() {
+        Some(spec) => { Some(spec.version()) },
+        None => { parser_version(identifier) }
+    }
+}
+
+/// Report dates from and to, inclusive, either end open, for taking part of an archive (--from and --to)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DateFilter {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>
+}
+
+impl DateFilter {
+    pub fn is_unbounded(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.from.is_none_or(|from| date >= from) && self.to.is_none_or(|to| date <= to)
+    }
+}
+
+/// The report date in the name of a release file, read with the `file_date` pattern of its configuration, a regex
+/// with groups named year, month and day. None if there is no pattern or the name doesn't match it.
+pub fn file_name_date(config: &DatamartConfig, file_name: &str) -> Result<Option<NaiveDate>, String> {
+    let pattern = match config.file_date.as_ref() {
+        Some(p) => { Regex::new(p).map_err(|e| format!("Invalid file_date pattern of {}: {}", config.name, e))? },
+        None => { return Ok(None) }
+    };
+
+    Ok(pattern.captures(file_name).and_then(|c| {
+        dates::from_parts(c.name("year")?.as_str(), c.name("month")?.as_str(), c.name("day")?.as_str())
+    }))
+}
+
+/// The report date of a release found without parsing the rest of it, which reports with a parser defined in
+/// their configuration allow
+pub fn release_date(config: &DatamartConfig, text: &str) -> Option<NaiveDate> {
+    declarative::find_report_date(config.parser.as_ref()?, &textparse::lines(text)).ok()
+}
+
+/// Parses report text with the parser defined in its configuration, or failing that the built in parser for its
+/// identifier. Sections that couldn't be read fail the report if the configuration marks them required (the
+/// default), and are otherwise left out with a warning.
+pub fn parse_report(identifier: &str, config: &DatamartConfig, text: String) -> Result<USDADataPackage, String> {
+    parse_sections(identifier, config, text).inspect_err(|_| metrics::count_parse_failure(&config.name))
+}
+
+fn parse_sections(identifier: &str, config: &DatamartConfig, text: String) -> Result<USDADataPackage, String> {
+    let package = match config.parser {
+        Some(_) => { declarative::declarative_parse(identifier, config, &text) },
+        None => { text_parse(identifier, text) }
+    }?;
+
+    let mut failed: Vec<(&String, &String)> = package.section_errors.iter().collect();
+    failed.sort();
+
+    for (section, error) in failed {
+        if config.sections.get(section).is_none_or(|s| s.required) {
+            return Err(format!("Failed to parse required section {}: {}", section, error));
+        }
+
+        warn!("{} optional section {} skipped: {}", identifier, section, error);
+    }
+
+    if package.sections.is_empty() {
+        return Err(format!("No sections could be parsed from {} report", identifier));
+    }
+
+    Ok(package)
+}
+
+/// Dispatches report text to the parser for its identifier
+pub fn text_parse(identifier: &str, text: String) -> Result<USDADataPackage, String> {
+    let mut package = match identifier.to_uppercase().as_ref() {
+        "LM_XB463" => { lmxb463_text_parse(text) },
+        "DC_GR110" => { dcgr110_text_parse(text) },
+        "BROIHATC" => { broihatc_text_parse(text) },
+        "POULSLAU" => { poulslau_text_parse(text) },
+        _ => { Err(format!("Unknown report type encountered: {}", identifier)) }
+    }?;
+
+    package.parser_version = parser_version(identifier);
+    Ok(package)
+}
+
+#[test]
+fn test_broihatc_text_parse() {
+    let test_string = "Broiler Hatchery
+ISSN: 1520-7528
+Released January 3, 2024, by the National Agricultural Statistics Service (NASS).
+
+Broiler-Type Eggs Set - States and United States: 2023
+-------------------------------------------------------------------------------------
+                 |                          Week ending
+      State      |-------------------------------------------------------------------
+                 | Dec 9, 2023  | Dec 16, 2023 | Dec 23, 2023 | Dec 30, 2023
+-------------------------------------------------------------------------------------
+                 |                         (1,000 eggs)
+                 |
+Alabama .........|     29,418   |     29,656   |     29,533   |     29,101
+Arkansas ........|     24,117   |     24,302   |     24,215   |     23,998
+United States ...|    243,560   |    244,001   |    243,112   |    241,250
+                 |
+Percent of year  |
+  ago ...........|        101   |        100   |        101   |        101
+-------------------------------------------------------------------------------------
+
+Broiler-Type Chicks Placed - States and United States: 2023
+-------------------------------------------------------------------------------------
+                 |                          Week ending
+      State      |-------------------------------------------------------------------
+                 | Dec 9, 2023  | Dec 16, 2023 | Dec 23, 2023 | Dec 30, 2023
+-------------------------------------------------------------------------------------
+                 |                         (1,000 chicks)
+                 |
+Alabama .........|     20,001   |     20,110   |     20,050   |     19,870
+United States ...|    175,420   |    175,880   |    175,010   |    174,220
+-------------------------------------------------------------------------------------
+";
+
+    let structure = text_parse("BroiHatc", test_string.to_owned()).unwrap();
+    assert_eq!(structure.parser_version, parser_version("BROIHATC"));
+
+    let eggs = &structure.sections["eggs_set"];
+    assert_eq!(eggs.len(), 3);
+    assert_eq!(eggs[0].report_date, NaiveDate::from_ymd_opt(2023, 12, 30).unwrap());
+    assert_eq!(eggs[2].independent, vec!["2023-12-30", "United States"]);
+    assert_eq!(eggs[2].entries["eggs_set"], "241,250");
+    assert!(eggs.iter().all(|row| !row.independent[1].starts_with("Percent")));
+
+    let chicks = &structure.sections["chicks_placed"];
+    assert_eq!(chicks.len(), 2);
+    assert_eq!(chicks[0].entries["chicks_placed"], "19,870");
+}
+
+#[test]
+fn test_poulslau_text_parse() {
+    let test_string = "Poultry Slaughtered Under Federal Inspection and Pounds Certified - United States: November 2023 and 2022
+------------------------------------------------------------------------------------------------------------------
+                  |     Number slaughtered      |         Live weight          |     Ready-to-cook weight
+      Class       |-----------------------------------------------------------------------------------------------
+                  |   2022   |   2023   |Percent|    2022    |    2023    |Percent|    2022    |    2023    |Percent
+------------------------------------------------------------------------------------------------------------------
+                  |    (1,000 head)     |(pct)  |      (1,000 pounds)     |(pct)  |      (1,000 pounds)     |(pct)
+                  |
+Chickens ..........|  758,216 |  762,431 |   101 |  4,846,330 |  4,912,874 |   101 |  3,640,154 |  3,693,320 |   101
+  Young chickens ..|  747,002 |  751,640 |   101 |  4,784,226 |  4,851,020 |   101 |  3,606,119 |  3,659,101 |   101
+Turkeys ...........|   18,342 |   17,001 |    93 |    596,902 |    555,441 |    93 |    475,920 |    441,812 |    93
+------------------------------------------------------------------------------------------------------------------
+";
+
+    let structure = text_parse("PoulSlau", test_string.to_owned()).unwrap();
+    let section = &structure.sections["federally_inspected"];
+
+    assert_eq!(section.len(), 3);
+    assert_eq!(section[0].report_date, NaiveDate::from_ymd_opt(2023, 11, 1).unwrap());
+    assert_eq!(section[1].independent, vec!["2023-11-01", "young chickens"]);
+    assert_eq!(section[2].entries["number_slaughtered"], "17,001");
+    assert_eq!(section[2].entries["ready_to_cook_weight"], "441,812");
+}
+
+#[test]
+fn test_lmxb463_text_parse() {
+    let test_string = "LM_XB463
+Des Moines, IA    Mon Mar 09, 2020    USDA Market News
+
+National Weekly Comprehensive Boxed Beef Cutout
+For Week Ending: 03/06/2020
+
+TOTAL LOADS OF PRODUCT REPORTED:     5,432
+
+Weekly Cutout Value    Comprehensive   Prime  Branded   Choice   Select  Ungraded
+Primal Rib                    401.22  520.10   430.55   410.00   380.25    350.10
+* Prime cutout includes branded product
+Primal Chuck                  190.01  220.30   200.40   195.20   185.30    170.00
+
+Quality breakdown:
+Prime          310
+Branded*     1,020
+Choice       2,800
+* Branded product is also included in its grade
+Select         900
+Ungraded       402
+
+Sales type breakdown:
+Negotiated   1,200
+Formula      3,900
+Forward        332
+
+Destination breakdown:
+Domestic     5,000
+Export         432
+";
+
+    let structure = text_parse("LM_XB463", test_string.to_owned()).unwrap();
+
+    let summary = &structure.sections["summary"][0];
+    assert_eq!(summary.report_date, NaiveDate::from_ymd_opt(2020, 3, 6).unwrap());
+    assert_eq!(summary.entries["total_loads"], "5,432");
+    assert_eq!(summary.entries["primal_chuck__ungraded"], "170.00");
+
+    // footnotes within a block neither end it nor become values
+    let quality = &structure.sections["quality"][0];
+    assert_eq!(quality.entries.len(), 5);
+    assert_eq!(quality.entries["Ungraded"], "402");
+
+    assert_eq!(structure.sections["sales_type"][0].entries.len(), 3);
+    assert_eq!(structure.sections["destination"][0].entries["Export"], "432");
+    assert!(!structure.sections.contains_key("delivery"));
+    assert!(structure.section_errors.contains_key("delivery"));
+
+    // the missing section only fails the report when required
+    let mut config: DatamartConfig = toml::from_str(r#"
+        name = "lm_xb463"
+        description = "Comprehensive beef cutout"
+        independent = "report_date"
+        [sections.summary]
+        independent = ["report_date"]
+        fields = []
+        [sections.delivery]
+        independent = ["report_date"]
+        fields = []
+        required = false
+    "#).unwrap();
+
+    assert!(parse_report("LM_XB463", &config, test_string.to_owned()).is_ok());
+
+    config.sections.get_mut("delivery").unwrap().required = true;
+    assert!(parse_report("LM_XB463", &config, test_string.to_owned()).is_err());
+}
+
+#[test]
+fn test_release_dates() {
+    let mut config: DatamartConfig = toml::from_str(r#"
+        name = "al_gr110"
+        description = "test"
+        independent = "report_date"
+        file_date = '(?P<month>\d{2})(?P<day>\d{2})(?P<year>\d{4})\.txt$'
+        [parser]
+        date_line = "^Montgomery, AL"
+        [parser.sections]
+        [sections]
+    "#).unwrap();
+
+    assert_eq!(file_name_date(&config, "al_gr110_03022020.txt"), Ok(NaiveDate::from_ymd_opt(2020, 3, 2)));
+    assert_eq!(file_name_date(&config, "al_gr110_latest.txt"), Ok(None));
+    assert_eq!(release_date(&config, "USDA Market News\nMontgomery, AL    Mon Mar 02, 2020\nCORN"), NaiveDate::from_ymd_opt(2020, 3, 2));
+
+    config.file_date = Some("(?P<year>".to_owned());
+    assert!(file_name_date(&config, "al_gr110_03022020.txt").is_err());
+
+    let season = DateFilter { from: NaiveDate::from_ymd_opt(2019, 9, 1), to: None };
+    assert!(season.contains(NaiveDate::from_ymd_opt(2020, 3, 2).unwrap()));
+    assert!(!season.contains(NaiveDate::from_ymd_opt(2019, 8, 31).unwrap()));
+    assert!(DateFilter::default().is_unbounded());
+}
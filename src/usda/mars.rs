@@ -1,17 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use chrono::{NaiveDate, Local};
 use serde::Deserialize;
 
-use super::datamart::DatamartResponse;
 use super::{USDADataPackage, USDADataPackageSection};
+use super::datamart::{DateRange, DatamartConfig, parse_section_results, stringify_results};
+use super::marsmodels::parse_typed_section;
+use crate::{archive, metrics};
 
 const MARS_BASE_URL: &str = "https://marsapi.ams.usda.gov/services/v1/reports";
 
+/// Characters escaped in the values of MARS query filters, which are joined with `;` inside the `q` parameter
+const FILTER_ESCAPED: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'/').remove(b':');
+
+// defaults for quick metadata queries; report fetches take their timeouts from the caller
 const CONNECT_TIMEOUT: u64 = 5000;
 const RECEIVE_TIMEOUT: u64 = 5000;
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct ReportMetadata {
     slug_id: String,
     report_title: String,
@@ -25,11 +31,11 @@ pub struct ReportMetadata {
 
 #[derive(Deserialize, Debug)]
 pub struct ReportResult {
-    results: Vec<HashMap<String, Option<String>>>
+    results: Vec<HashMap<String, serde_json::Value>>
 }
 
 pub fn list_reports(api_key: &str) -> Result<Vec<ReportMetadata>, String> {
-    let response = ureq::get(MARS_BASE_URL).set("User-Agent", super::USER_AGENT).auth(api_key, &"".to_owned()).timeout_connect(CONNECT_TIMEOUT).timeout_read(RECEIVE_TIMEOUT).call();
+    let response = crate::session::send(ureq::get(MARS_BASE_URL).set("User-Agent", super::USER_AGENT).auth(api_key, "").timeout_connect(CONNECT_TIMEOUT).timeout_read(RECEIVE_TIMEOUT));
 
     if let Some(error) = response.synthetic_error() {
         return Err(format!("Failed to retrieve data from MARS server with URL {}. Error: {}", MARS_BASE_URL, error));
@@ -46,38 +52,121 @@ pub fn list_reports(api_key: &str) -> Result<Vec<ReportMetadata>, String> {
     }
 }
 
-pub fn get_report(api_key: &str, report: &str, minimum_begin_date: Option<NaiveDate>) -> Result<(), String> {
-    let target = match minimum_begin_date {
-        Some(d) => {
-            let today = Local::now().naive_local().date();
-            format!(
-                "{}/{}?report_begin_date={}:{}", MARS_BASE_URL, report,
-                d.format("%Y-%m-%d"),
-                today.format("%Y-%m-%d")
-            )
-        },
+/// The query string of a report request: a range of begin dates, and filters narrowing the rows returned (e.g.
+/// `office_name`), which MARS takes as `field=value` pairs in its `q` parameter
+fn report_query(begin_dates: Option<DateRange>, filters: &BTreeMap<String, String>) -> String {
+    let mut parameters: Vec<String> = Vec::new();
+
+    if let Some((from, to)) = begin_dates {
+        parameters.push(format!("report_begin_date={}:{}", from.format("%Y-%m-%d"), to.format("%Y-%m-%d")));
+    }
+
+    if !filters.is_empty() {
+        let pairs: Vec<String> = filters.iter()
+            .map(|(field, value)| format!("{}={}", field, percent_encoding::utf8_percent_encode(value, FILTER_ESCAPED)))
+            .collect();
+        parameters.push(format!("q={}", pairs.join(";")));
+    }
+
+    if parameters.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", parameters.join("&"))
+    }
+}
+
+/// Fetches a report, or one section of it, returning its rows as MARS sends them. `filters` are MARS query filters,
+/// so that only the offices or markets wanted are sent.
+fn get_results(api_key: &str, report: &str, section: Option<&str>, filters: &BTreeMap<String, String>, begin_dates: Option<DateRange>, http_connect_timeout: u64, http_receive_timeout: u64) -> Result<Vec<HashMap<String, serde_json::Value>>, String> {
+    let base = match section {
+        Some(s) => {format!("{}/{}/{}", MARS_BASE_URL, report, s)},
         None => {format!("{}/{}", MARS_BASE_URL, report)}
     };
 
-    let response = ureq::get(&target).set("User-Agent", super::USER_AGENT).auth(api_key, &"".to_owned()).timeout_connect(CONNECT_TIMEOUT).timeout_read(RECEIVE_TIMEOUT).call();
+    let target = format!("{}{}", base, report_query(begin_dates, filters));
 
-    if let Some(error) = response.synthetic_error() {
-        return Err(format!("Failed to retrieve data from MARS server with URL {}. Error: {}", target, error));
-    }
+    let mut request = ureq::get(&target);
+    request.set("User-Agent", super::USER_AGENT).auth(api_key, "").timeout_connect(http_connect_timeout).timeout_read(http_receive_timeout);
+    crate::http::call(&mut request, &crate::http::RetryPolicy::MARS, |response| {
+        if let Some(error) = response.synthetic_error() {
+            return Err(format!("Failed to retrieve data from MARS server with URL {}. Error: {}", target, error));
+        }
 
-    let result = response.into_json_deserialize::<ReportResult>();
-    match result {
-        Ok(r) => { println!("{:?}", r.results[0]) },
-        Err(_) => { 
-            return Err(format!("Response from MARS server is not valid JSON, or the structure has changed significantly. Target url: {}", target))
+        let result = response.into_json_deserialize::<ReportResult>();
+        match result {
+            Ok(r) => { Ok(r.results) },
+            Err(_) => {
+                Err(format!("Response from MARS server is not valid JSON, or the structure has changed significantly. Target url: {}", target))
+            }
         }
+    })
+}
+
+/// Fetches a report, or one section of it, returning its rows with every value rendered as text
+pub fn get_report(api_key: &str, report: &str, section: Option<&str>, filters: &BTreeMap<String, String>, begin_dates: Option<DateRange>, http_connect_timeout: u64, http_receive_timeout: u64) -> Result<Vec<HashMap<String, Option<String>>>, String> {
+    get_results(api_key, report, section, filters, begin_dates, http_connect_timeout, http_receive_timeout).map(stringify_results)
+}
+
+/// Fetches a datamart-configured report from its MARS equivalent (`mars_slug`), section by section, limited to the
+/// report dates of `range` if given. Used when datamart itself is unavailable; rows are tagged with "mars" as their
+/// source.
+pub fn process_datamart_equivalent(slug_id: &str, config: &HashMap<String, DatamartConfig>, api_key: &str, range: Option<DateRange>, http_connect_timeout: u64, http_receive_timeout: u64) -> Result<USDADataPackage, String> {
+    let current_config = match config.get(slug_id) {
+        Some(c) => { c },
+        None => { return Err(format!("Slug ID {} is not known to our datamart configuration.", slug_id)) }
+    };
+
+    let mars_slug = match current_config.mars_slug.as_ref() {
+        Some(s) => { s },
+        None => { return Err(format!("Slug ID {} has no MARS equivalent configured.", slug_id)) }
     };
 
-    Ok(())
+    let (http_connect_timeout, http_receive_timeout) = current_config.timeouts(http_connect_timeout, http_receive_timeout);
+
+    let mut result = USDADataPackage::new(current_config.name.to_owned());
+    result.source = Some("mars".to_owned());
+
+    for section in current_config.sections.keys() {
+        let rows = get_results(api_key, mars_slug, Some(section), &current_config.mars_filters, range, http_connect_timeout, http_receive_timeout)?;
+
+        if let Some(root) = archive::payload_archive() {
+            let date_of = |row: &HashMap<String, serde_json::Value>| row.get(&current_config.independent).and_then(|v| v.as_str()).and_then(|v| current_config.independent_type.report_date(v));
+            if let Err(e) = archive::store_rows(&root, "mars", slug_id, section, &rows, date_of) {
+                error!("{}", e);
+            }
+        }
+
+        let section_data = parse_section(slug_id, current_config, section, rows)?;
+        result.sections.entry(section.to_owned()).or_default().extend(section_data);
+    }
+
+    Ok(result)
 }
 
+/// Parses the rows of one section of a MARS response, with the typed models of the report's family if it has one
+fn parse_section(slug_id: &str, config: &DatamartConfig, section: &str, rows: Vec<HashMap<String, serde_json::Value>>) -> Result<Vec<USDADataPackageSection>, String> {
+    match config.mars_family {
+        Some(family) => { parse_typed_section(family, config, section, rows) },
+        None => { parse_section_results(slug_id, config, section, stringify_results(rows)) }
+    }.inspect_err(|_| metrics::count_parse_failure(&config.name))
+}
+
+/// Parses the sections of a MARS response kept by --archive-payloads, as `process_datamart_equivalent` parses them
+/// when fetched. Sections no longer configured are left out.
+pub fn parse_archived(slug_id: &str, config: &DatamartConfig, sections: BTreeMap<String, Vec<HashMap<String, serde_json::Value>>>) -> Result<USDADataPackage, String> {
+    let mut result = USDADataPackage::new(config.name.to_owned());
+    result.source = Some("mars".to_owned());
+
+    for (section, rows) in sections.into_iter().filter(|(section, _)| config.sections.contains_key(section)) {
+        let section_data = parse_section(slug_id, config, &section, rows)?;
+        result.sections.entry(section).or_default().extend(section_data);
+    }
+
+    Ok(result)
+}
 
 #[test]
+#[ignore = "requires network access and a MARS key in config/secret.toml"]
 fn test_list_reports() {
     use std::fs;
     use std::collections::HashMap;
@@ -98,6 +187,7 @@ fn test_list_reports() {
 }
 
 #[test]
+#[ignore = "requires network access and a MARS key in config/secret.toml"]
 fn test_get_report() {
     use std::fs;
     use std::collections::HashMap;
@@ -114,5 +204,20 @@ fn test_get_report() {
         }
     };
 
-    println!("{:?}", get_report(&secret_config["mars"]["key"], "1095", None).unwrap());
-}
\ No newline at end of file
+    println!("{:?}", get_report(&secret_config["mars"]["key"], "1095", None, &BTreeMap::new(), None, CONNECT_TIMEOUT, RECEIVE_TIMEOUT).unwrap()[0]);
+}
+
+#[test]
+fn test_report_query() {
+    use chrono::NaiveDate;
+
+    let mut filters = BTreeMap::new();
+    assert_eq!(report_query(None, &filters), "");
+
+    filters.insert("office_name".to_owned(), "Des Moines, IA".to_owned());
+    filters.insert("market_type".to_owned(), "Auction Livestock".to_owned());
+    assert_eq!(report_query(None, &filters), "?q=market_type=Auction%20Livestock;office_name=Des%20Moines%2C%20IA");
+
+    let begin = (NaiveDate::from_ymd_opt(2020, 3, 2).unwrap(), NaiveDate::from_ymd_opt(2020, 12, 31).unwrap());
+    assert_eq!(report_query(Some(begin), &filters), "?report_begin_date=2020-03-02:2020-12-31&q=market_type=Auction%20Livestock;office_name=Des%20Moines%2C%20IA");
+}
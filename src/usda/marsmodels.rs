@@ -0,0 +1,332 @@
+// Typed models of the common MARS result families (auctions, direct trade and boxed cuts), so that the values of
+// these reports are read as numbers in known units rather than taken as whatever text MARS sends. A report fetched
+// from MARS (see mars::process_datamart_equivalent) is read this way when its configuration sets `mars_family`.
+//
+// MARS sends some numbers as text with thousands separators ("1,234"), which are read as numbers all the same, and
+// quotes some auction prices per pound, which are converted to dollars per hundredweight like the rest.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Deserializer};
+use serde::de::{DeserializeOwned, Error};
+
+use super::USDADataPackageSection;
+use super::datamart::DatamartConfig;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarsFamily {
+    Auction,
+    DirectTrade,
+    BoxedCuts
+}
+
+/// A MARS number, which may arrive as a JSON number or as text, blank when there is none
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None | Some(serde_json::Value::Null) => { Ok(None) },
+        Some(serde_json::Value::Number(n)) => { Ok(n.as_f64()) },
+        Some(serde_json::Value::String(s)) => {
+            let trimmed = s.trim().replace(",", "");
+            if trimmed.is_empty() {
+                return Ok(None);
+            }
+            trimmed.parse::<f64>().map(Some).map_err(|_| D::Error::custom(format!("Not a number: {}", s)))
+        },
+        Some(other) => { Err(D::Error::custom(format!("Not a number: {}", other))) }
+    }
+}
+
+fn is_per_pound(price_unit: &str) -> bool {
+    let unit = price_unit.trim().to_lowercase();
+    unit == "per lb" || unit == "per pound"
+}
+
+/// Prices quoted per pound as dollars per hundredweight; those per cwt, head or unit are left as they are. The
+/// `price_unit` of a result is given as the unit after conversion.
+fn per_cwt(price: Option<f64>, price_unit: Option<&str>) -> Option<f64> {
+    match price_unit {
+        Some(unit) if is_per_pound(unit) => { price.map(|p| p * 100.0) },
+        _ => { price }
+    }
+}
+
+/// Fields shared by every family
+#[derive(Deserialize, Debug)]
+struct Common {
+    report_date: String,
+    report_begin_date: Option<String>,
+    report_end_date: Option<String>,
+    office_name: Option<String>,
+    market_type: Option<String>,
+    commodity: Option<String>,
+    class: Option<String>,
+    price_unit: Option<String>
+}
+
+impl Common {
+    fn text(&self, field: &str) -> Option<&str> {
+        match field {
+            "report_date" => { Some(&self.report_date) },
+            "report_begin_date" => { self.report_begin_date.as_deref() },
+            "report_end_date" => { self.report_end_date.as_deref() },
+            "office_name" => { self.office_name.as_deref() },
+            "market_type" => { self.market_type.as_deref() },
+            "commodity" => { self.commodity.as_deref() },
+            "class" => { self.class.as_deref() },
+            "price_unit" => { self.price_unit.as_deref().map(|u| if is_per_pound(u) { "Per Cwt" } else { u }) },
+            _ => { None }
+        }
+    }
+}
+
+/// A result of a MARS report, its fields looked up by their MARS names
+trait MarsRecord: DeserializeOwned {
+    /// A text field, e.g. a location or cut name
+    fn text(&self, field: &str) -> Option<&str>;
+
+    /// A number field, in the family's units
+    fn number(&self, field: &str) -> Option<f64>;
+}
+
+/// Livestock auctions, e.g. feeder cattle auction summaries
+#[derive(Deserialize, Debug)]
+struct AuctionResult {
+    #[serde(flatten)]
+    common: Common,
+    market_location_name: Option<String>,
+    market_location_state: Option<String>,
+    frame: Option<String>,
+    muscle_grade: Option<String>,
+    quality_grade_name: Option<String>,
+    lot_desc: Option<String>,
+    #[serde(default, deserialize_with = "number")]
+    receipts: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    head_count: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    avg_weight_min: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    avg_weight_max: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    avg_weight: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    avg_price_min: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    avg_price_max: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    avg_price: Option<f64>
+}
+
+impl MarsRecord for AuctionResult {
+    fn text(&self, field: &str) -> Option<&str> {
+        match field {
+            "market_location_name" => { self.market_location_name.as_deref() },
+            "market_location_state" => { self.market_location_state.as_deref() },
+            "frame" => { self.frame.as_deref() },
+            "muscle_grade" => { self.muscle_grade.as_deref() },
+            "quality_grade_name" => { self.quality_grade_name.as_deref() },
+            "lot_desc" => { self.lot_desc.as_deref() },
+            _ => { self.common.text(field) }
+        }
+    }
+
+    fn number(&self, field: &str) -> Option<f64> {
+        let price_unit = self.common.price_unit.as_deref();
+
+        match field {
+            "receipts" => { self.receipts },
+            "head_count" => { self.head_count },
+            "avg_weight_min" => { self.avg_weight_min },
+            "avg_weight_max" => { self.avg_weight_max },
+            "avg_weight" => { self.avg_weight },
+            "avg_price_min" => { per_cwt(self.avg_price_min, price_unit) },
+            "avg_price_max" => { per_cwt(self.avg_price_max, price_unit) },
+            "avg_price" => { per_cwt(self.avg_price, price_unit) },
+            _ => { None }
+        }
+    }
+}
+
+/// Negotiated and formula purchases reported directly by packers, e.g. the livestock mandatory price reports
+#[derive(Deserialize, Debug)]
+struct DirectTradeResult {
+    #[serde(flatten)]
+    common: Common,
+    purchase_type: Option<String>,
+    selling_basis_description: Option<String>,
+    grade_description: Option<String>,
+    #[serde(default, deserialize_with = "number")]
+    head_count: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    weight_range_low: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    weight_range_high: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    weighted_avg_weight: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    price_range_low: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    price_range_high: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    weighted_avg_price: Option<f64>
+}
+
+impl MarsRecord for DirectTradeResult {
+    fn text(&self, field: &str) -> Option<&str> {
+        match field {
+            "purchase_type" => { self.purchase_type.as_deref() },
+            "selling_basis_description" => { self.selling_basis_description.as_deref() },
+            "grade_description" => { self.grade_description.as_deref() },
+            _ => { self.common.text(field) }
+        }
+    }
+
+    fn number(&self, field: &str) -> Option<f64> {
+        let price_unit = self.common.price_unit.as_deref();
+
+        match field {
+            "head_count" => { self.head_count },
+            "weight_range_low" => { self.weight_range_low },
+            "weight_range_high" => { self.weight_range_high },
+            "weighted_avg_weight" => { self.weighted_avg_weight },
+            "price_range_low" => { per_cwt(self.price_range_low, price_unit) },
+            "price_range_high" => { per_cwt(self.price_range_high, price_unit) },
+            "weighted_avg_price" => { per_cwt(self.weighted_avg_price, price_unit) },
+            _ => { None }
+        }
+    }
+}
+
+/// Boxed beef, pork and lamb cuts, priced FOB plant
+#[derive(Deserialize, Debug)]
+struct BoxedCutsResult {
+    #[serde(flatten)]
+    common: Common,
+    item_description: Option<String>,
+    grade: Option<String>,
+    #[serde(default, deserialize_with = "number")]
+    number_trades: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    total_pounds: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    price_range_low: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    price_range_high: Option<f64>,
+    #[serde(default, deserialize_with = "number")]
+    weighted_average: Option<f64>
+}
+
+impl MarsRecord for BoxedCutsResult {
+    fn text(&self, field: &str) -> Option<&str> {
+        match field {
+            "item_description" => { self.item_description.as_deref() },
+            "grade" => { self.grade.as_deref() },
+            _ => { self.common.text(field) }
+        }
+    }
+
+    fn number(&self, field: &str) -> Option<f64> {
+        let price_unit = self.common.price_unit.as_deref();
+
+        match field {
+            "number_trades" => { self.number_trades },
+            "total_pounds" => { self.total_pounds },
+            "price_range_low" => { per_cwt(self.price_range_low, price_unit) },
+            "price_range_high" => { per_cwt(self.price_range_high, price_unit) },
+            "weighted_average" => { per_cwt(self.weighted_average, price_unit) },
+            _ => { None }
+        }
+    }
+}
+
+/// Reads a section of a MARS report as results of `family`, into rows of the section's configured independent
+/// columns and fields, as `datamart::parse_section_results` does with untyped results
+pub fn parse_typed_section(family: MarsFamily, config: &DatamartConfig, section: &str, results: Vec<HashMap<String, serde_json::Value>>) -> Result<Vec<USDADataPackageSection>, String> {
+    match family {
+        MarsFamily::Auction => { convert::<AuctionResult>(config, section, results) },
+        MarsFamily::DirectTrade => { convert::<DirectTradeResult>(config, section, results) },
+        MarsFamily::BoxedCuts => { convert::<BoxedCutsResult>(config, section, results) }
+    }
+}
+
+fn convert<R: MarsRecord>(config: &DatamartConfig, section: &str, results: Vec<HashMap<String, serde_json::Value>>) -> Result<Vec<USDADataPackageSection>, String> {
+    let section_config = &config.sections[section];
+    let mut section_data = Vec::new();
+
+    'entries: for entry in results {
+        let record: R = serde_json::from_value(serde_json::Value::Object(entry.into_iter().collect()))
+            .map_err(|e| format!("MARS result of {} doesn't have the expected structure: {}", config.name, e))?;
+
+        let date_text = match record.text(&config.independent) {
+            Some(d) => { d },
+            None => {
+                warn!("{}: MARS result without a {}, skipped.", config.name, config.independent);
+                continue;
+            }
+        };
+
+        let report_date = config.independent_type.report_date(date_text)
+            .ok_or_else(|| format!("Failed to parse independent column from MARS response as {:?}: {}", config.independent_type, date_text))?;
+
+        let mut data = USDADataPackageSection::new(report_date);
+
+        for column in &section_config.independent {
+            match record.text(column) {
+                Some(value) => { data.independent.push(value.to_owned()) },
+                None => {
+                    warn!("Failed to get value of independent column `{}` in MARS response for date {}, the entry will be skipped.", column, report_date);
+                    continue 'entries;
+                }
+            }
+        }
+
+        for column in &section_config.fields {
+            let value = match record.number(column) {
+                Some(n) => { n.to_string() },
+                None => { record.text(column).unwrap_or_default().to_owned() }
+            };
+            data.entries.insert(column.to_owned(), value);
+        }
+
+        section_data.push(data);
+    }
+
+    Ok(section_data)
+}
+
+#[test]
+fn test_parse_typed_section() {
+    use crate::usda::datamart::DatamartSection;
+
+    let mut config = crate::integration::usda::test_structure("feeder_auction");
+    config.sections.insert("bids".to_owned(), DatamartSection {
+        alias: None,
+        independent: vec!["report_date".to_owned(), "market_location_name".to_owned()],
+        fields: vec!["head_count".to_owned(), "avg_price".to_owned(), "price_unit".to_owned(), "frame".to_owned()],
+        types: Default::default(),
+        ignored: None,
+        required: true
+    });
+
+    let results: Vec<HashMap<String, serde_json::Value>> = serde_json::from_value(serde_json::json!([
+        {
+            "report_date": "03/02/2020", "market_location_name": "Dodge City", "frame": "Medium and Large",
+            "head_count": "1,204", "avg_price": 1.425, "price_unit": "Per Lb"
+        },
+        { "report_date": "03/02/2020", "head_count": 12 }
+    ])).unwrap();
+
+    let rows = parse_typed_section(MarsFamily::Auction, &config, "bids", results).unwrap();
+    assert_eq!(rows.len(), 1); // the second has no location
+    assert_eq!(rows[0].independent, vec!["03/02/2020", "Dodge City"]);
+    assert_eq!(rows[0].entries["head_count"], "1204");
+    assert_eq!(rows[0].entries["avg_price"], "142.5");
+    assert_eq!(rows[0].entries["frame"], "Medium and Large");
+    assert_eq!(rows[0].entries["price_unit"], "Per Cwt");
+
+    let invalid: Vec<HashMap<String, serde_json::Value>> = serde_json::from_value(serde_json::json!([
+        { "report_date": "03/02/2020", "market_location_name": "Dodge City", "head_count": "many" }
+    ])).unwrap();
+    assert!(parse_typed_section(MarsFamily::Auction, &config, "bids", invalid).is_err());
+}
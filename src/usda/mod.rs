@@ -1,9 +1,20 @@
 use std::collections::HashMap;
 
+pub mod content;
 pub mod datamart;
+pub mod dates;
+pub mod declarative;
+pub mod discover;
+pub mod ers;
 pub mod esmis;
 pub mod legacy;
 pub mod mars;
+pub mod marsmodels;
+pub mod nass;
+pub mod portal;
+pub mod sources;
+pub mod textparse;
+pub mod transform;
 
 use chrono::NaiveDate;
 
@@ -13,7 +24,8 @@ pub const USER_AGENT: &str = "data-acquistion/0.1";
 pub struct USDADataPackageSection {
     pub report_date: NaiveDate,
     pub independent: Vec<String>,
-    pub entries: HashMap<String, String>
+    pub entries: HashMap<String, String>,
+    pub provenance: HashMap<String, String> // variable name -> "transform@version", for values computed rather than reported
 }
 
 
@@ -22,7 +34,8 @@ impl USDADataPackageSection {
         USDADataPackageSection {
             report_date,
             independent: Vec::new(),
-            entries: HashMap::new()
+            entries: HashMap::new(),
+            provenance: HashMap::new()
         }
     }
 }
@@ -34,6 +47,9 @@ pub struct USDADataPackage {
         String, // section name
         Vec<USDADataPackageSection>
     >,
+    pub source: Option<String>, // the system that supplied the data, e.g. "datamart" or "mars"
+    pub parser_version: Option<u32>, // set by legacy text parsers, see legacy::parser_version
+    pub section_errors: HashMap<String, String>, // sections that failed to be read or fetched and why, see legacy::parse_report and datamart::process_datamart
 }
 
 impl USDADataPackage {
@@ -41,6 +57,32 @@ impl USDADataPackage {
         USDADataPackage {
             name,
             sections: HashMap::new(),
+            source: None,
+            parser_version: None,
+            section_errors: HashMap::new(),
         }
     }
+
+    /// The number of database rows the package will be inserted as, one per entry
+    pub fn row_count(&self) -> usize {
+        self.sections.values().flatten().map(|s| s.entries.len()).sum()
+    }
+
+    /// Adds a parsed section, or records why it couldn't be parsed so the rest of the report can still be used
+    pub fn insert_section(&mut self, name: &str, result: Result<Vec<USDADataPackageSection>, String>) {
+        match result {
+            Ok(section) => { self.sections.insert(name.to_owned(), section); },
+            Err(e) => { self.section_errors.insert(name.to_owned(), e); }
+        }
+    }
+
+    /// Adds the rows of another package of the same report, as when several releases are fetched as one
+    pub fn extend(&mut self, other: USDADataPackage) {
+        for (name, sections) in other.sections {
+            self.sections.entry(name).or_default().extend(sections);
+        }
+        self.section_errors.extend(other.section_errors);
+        self.source = self.source.take().or(other.source);
+        self.parser_version = self.parser_version.or(other.parser_version);
+    }
 }
@@ -0,0 +1,154 @@
+// https://www.nass.usda.gov/datasets/ (qs.census<year>.txt.gz)
+
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use chrono::NaiveDate;
+
+use super::{USDADataPackage, USDADataPackageSection};
+use super::datamart::{DatamartConfig, DatamartSection, DatamartApiVersion};
+
+const CENSUS_INDEPENDENT: [&str; 4] = ["report_date", "state_fips_code", "county_code", "domaincat_desc"];
+
+/// Maps a census statistic category to the section (and table) it is stored in, if we keep it at all.
+fn census_section(statisticcat_desc: &str) -> Option<&'static str> {
+    match statisticcat_desc {
+        "OPERATIONS" => { Some("operations") },
+        "SALES" => { Some("sales") },
+        s if s.starts_with("AREA") => { Some("acreage") }, // AREA, AREA HARVESTED, AREA OPERATED, ...
+        _ => { None }
+    }
+}
+
+/// Parses a Census of Agriculture bulk file (tab separated, with a header row) into county-level operations,
+/// acreage and sales sections. Each row's SHORT_DESC becomes the variable name. Withheld values such as "(D)"
+/// are kept as text with no numeric value.
+pub fn census_parse<R: BufRead>(reader: R) -> Result<USDADataPackage, String> {
+    let mut lines = reader.lines();
+
+    let header: Vec<String> = match lines.next() {
+        Some(Ok(h)) => { h.split('\t').map(|c| c.trim().trim_matches('"').to_uppercase()).collect() },
+        Some(Err(e)) => { return Err(format!("Failed to read census file header: {}", e)) },
+        None => { return Err("Census file is empty".to_owned()) }
+    };
+
+    let position = |name: &str| header.iter().position(|h| h == name).ok_or(format!("Census file has no {} column", name));
+    let agg_level_column = position("AGG_LEVEL_DESC")?;
+    let statisticcat_column = position("STATISTICCAT_DESC")?;
+    let short_desc_column = position("SHORT_DESC")?;
+    let domaincat_column = position("DOMAINCAT_DESC")?;
+    let state_column = position("STATE_FIPS_CODE")?;
+    let county_column = position("COUNTY_CODE")?;
+    let year_column = position("YEAR")?;
+    let value_column = position("VALUE")?;
+
+    // rows sharing a county, domain category and year are gathered into one package section
+    let mut grouped: HashMap<(&'static str, NaiveDate, String, String, String), USDADataPackageSection> = HashMap::new();
+
+    for (number, line) in lines.enumerate() {
+        let line = match line {
+            Ok(l) => { l },
+            Err(e) => { return Err(format!("Failed to read census file line {}: {}", number + 2, e)) }
+        };
+
+        let fields: Vec<&str> = line.split('\t').map(|c| c.trim().trim_matches('"')).collect();
+        let field = |column: usize| fields.get(column).copied().unwrap_or("");
+
+        if field(agg_level_column) != "COUNTY" {
+            continue;
+        }
+
+        let section = match census_section(field(statisticcat_column)) {
+            Some(s) => { s },
+            None => { continue }
+        };
+
+        let report_date = match field(year_column).parse::<i32>().ok().and_then(|y| NaiveDate::from_ymd_opt(y, 1, 1)) {
+            Some(d) => { d },
+            None => { return Err(format!("Invalid year on census file line {}: '{}'", number + 2, field(year_column))) }
+        };
+
+        let key = (section, report_date, field(state_column).to_owned(), field(county_column).to_owned(), field(domaincat_column).to_owned());
+
+        let data = grouped.entry(key).or_insert_with_key(|(_, report_date, state, county, domaincat)| {
+            let mut data = USDADataPackageSection::new(*report_date);
+            data.independent.push(report_date.format("%Y-%m-%d").to_string());
+            data.independent.push(state.to_owned());
+            data.independent.push(county.to_owned());
+            data.independent.push(domaincat.to_owned());
+            data
+        });
+
+        data.entries.insert(field(short_desc_column).to_owned(), field(value_column).to_owned());
+    }
+
+    let mut structure = USDADataPackage::new("census".to_owned());
+    structure.source = Some("nass".to_owned());
+
+    for ((section, _, _, _, _), data) in grouped {
+        structure.sections.entry(section.to_owned()).or_default().push(data);
+    }
+
+    Ok(structure)
+}
+
+/// The table layout of census data, in the shape of a datamart configuration
+pub fn census_structure() -> DatamartConfig {
+    let mut sections: HashMap<String, DatamartSection> = HashMap::new();
+
+    for section in &["operations", "acreage", "sales"] {
+        sections.insert(section.to_string(), DatamartSection {
+            alias: None,
+            independent: CENSUS_INDEPENDENT.iter().map(|c| c.to_string()).collect(),
+            fields: Vec::new(),
+            types: Default::default(),
+            ignored: None,
+            required: true
+        });
+    }
+
+    DatamartConfig {
+        name: "census".to_owned(),
+        description: "USDA NASS Census of Agriculture, county level".to_owned(),
+        independent: "report_date".to_owned(),
+        api_version: DatamartApiVersion::V1,
+        mars_slug: None,
+        mars_filters: Default::default(),
+        independent_type: Default::default(),
+        mars_family: None,
+        on_conflict: None,
+        period: None,
+        file_date: None,
+        indexes: None,
+        archive_url: None,
+        release_time: None,
+        connect_timeout: None,
+        read_timeout: None,
+        transforms: Vec::new(),
+        parser: None,
+        sections
+    }
+}
+
+#[test]
+fn test_census_parse() {
+    use std::io::Cursor;
+
+    let test_string = "SOURCE_DESC\tSECTOR_DESC\tSTATISTICCAT_DESC\tSHORT_DESC\tDOMAIN_DESC\tDOMAINCAT_DESC\tAGG_LEVEL_DESC\tSTATE_FIPS_CODE\tCOUNTY_CODE\tYEAR\tVALUE\tCV_%
+CENSUS\tANIMALS & PRODUCTS\tOPERATIONS\tCATTLE, COWS, BEEF - OPERATIONS WITH INVENTORY\tTOTAL\tNOT SPECIFIED\tCOUNTY\t20\t057\t2017\t142\t15.2
+CENSUS\tANIMALS & PRODUCTS\tSALES\tCATTLE, INCL CALVES - SALES, MEASURED IN $\tTOTAL\tNOT SPECIFIED\tCOUNTY\t20\t057\t2017\t(D)\t(D)
+CENSUS\tCROPS\tAREA HARVESTED\tCORN, GRAIN - ACRES HARVESTED\tTOTAL\tNOT SPECIFIED\tCOUNTY\t20\t057\t2017\t38,219\t4.1
+CENSUS\tCROPS\tAREA HARVESTED\tCORN, GRAIN - ACRES HARVESTED\tTOTAL\tNOT SPECIFIED\tSTATE\t20\t\t2017\t5,186,002\t1.0
+CENSUS\tCROPS\tYIELD\tCORN, GRAIN - YIELD, MEASURED IN BU / ACRE\tTOTAL\tNOT SPECIFIED\tCOUNTY\t20\t057\t2017\t160.3\t
+";
+
+    let structure = census_parse(Cursor::new(test_string)).unwrap();
+    assert_eq!(structure.sections.len(), 3);
+
+    let acreage = &structure.sections["acreage"];
+    assert_eq!(acreage.len(), 1);
+    assert_eq!(acreage[0].independent, vec!["2017-01-01", "20", "057", "NOT SPECIFIED"]);
+    assert_eq!(acreage[0].entries["CORN, GRAIN - ACRES HARVESTED"], "38,219");
+
+    assert_eq!(structure.sections["sales"][0].entries["CATTLE, INCL CALVES - SALES, MEASURED IN $"], "(D)");
+}
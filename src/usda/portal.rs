@@ -0,0 +1,89 @@
+// AMS Market News report archive pages, a last resort for legacy reports missing from ESMIS
+//
+// An archive page is an HTML listing of links to the plain text releases of one report, configured per report
+// with `archive_url` in the legacy configuration.
+
+use chrono::NaiveDate;
+use regex::Regex;
+
+use super::dates;
+use crate::scrape::Scraper;
+
+/// Resolves a link found on `page_url` to an absolute URL
+fn resolve_link(page_url: &str, href: &str) -> String {
+    if href.starts_with("http://") || href.starts_with("https://") {
+        href.to_owned()
+    } else if let Some(path) = href.strip_prefix("//") {
+        format!("https://{}", path)
+    } else if href.starts_with('/') {
+        // scheme://host + absolute path
+        let host_end = page_url.find("://").map(|i| i + 3).and_then(|i| page_url[i..].find('/').map(|j| i + j)).unwrap_or(page_url.len());
+        format!("{}{}", &page_url[..host_end], href)
+    } else {
+        let directory_end = page_url.rfind('/').map(|i| i + 1).unwrap_or(page_url.len());
+        format!("{}{}", &page_url[..directory_end], href)
+    }
+}
+
+/// Finds a release date embedded in a link, as YYYYMMDD or YYYY-MM-DD
+fn link_date(link: &str) -> Option<NaiveDate> {
+    lazy_static! {
+        static ref RE_LINK_DATE: Regex = Regex::new(r"(?P<year>(?:19|20)\d{2})-?(?P<month>[01]\d)-?(?P<day>[0-3]\d)").unwrap();
+    }
+
+    let x = RE_LINK_DATE.captures_iter(link).last()?;
+
+    dates::from_parts(&x["year"], &x["month"], &x["day"])
+}
+
+/// Extracts the text release links from an archive page. Links with a date in them are limited to those on or
+/// after `start_date`; undated links are always returned, it being up to the caller to skip known data.
+pub fn parse_archive_links(page_url: &str, html: &str, start_date: Option<NaiveDate>) -> Vec<String> {
+    lazy_static! {
+        static ref RE_TEXT_LINK: Regex = Regex::new(r#"(?i)href\s*=\s*["']([^"']+\.txt)["']"#).unwrap();
+    }
+
+    let mut links: Vec<String> = Vec::new();
+
+    for x in RE_TEXT_LINK.captures_iter(html) {
+        let link = resolve_link(page_url, &x[1].replace("&amp;", "&"));
+
+        if let (Some(start), Some(date)) = (start_date, link_date(&link)) {
+            if date < start {
+                continue;
+            }
+        }
+
+        if !links.contains(&link) {
+            links.push(link);
+        }
+    }
+
+    links
+}
+
+pub fn fetch_archive_links(scraper: &mut Scraper, archive_url: &str, start_date: Option<NaiveDate>) -> Result<Vec<String>, String> {
+    let html = scraper.get_string(archive_url)?;
+
+    Ok(parse_archive_links(archive_url, &html, start_date))
+}
+
+#[test]
+fn test_parse_archive_links() {
+    let html = r#"<html><body><ul>
+        <li><a href="/mnreports/lm_xb463_20200103.txt">January 3</a></li>
+        <li><a href='lm_xb463_2020-01-10.txt'>January 10</a></li>
+        <li><a href="https://www.ams.usda.gov/mnreports/lm_xb463_20191227.txt">December 27</a></li>
+        <li><a href="/mnreports/lm_xb463.txt">Latest</a></li>
+        <li><a href="/mnreports/lm_xb463_20200103.txt">duplicate</a></li>
+        <li><a href="/mnreports/lm_xb463_20200103.pdf">PDF</a></li>
+    </ul></body></html>"#;
+
+    let links = parse_archive_links("https://www.ams.usda.gov/market-news/archive/index.html", html, NaiveDate::from_ymd_opt(2020, 1, 1));
+
+    assert_eq!(links, vec![
+        "https://www.ams.usda.gov/mnreports/lm_xb463_20200103.txt",
+        "https://www.ams.usda.gov/market-news/archive/lm_xb463_2020-01-10.txt",
+        "https://www.ams.usda.gov/mnreports/lm_xb463.txt",
+    ]);
+}
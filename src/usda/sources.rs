@@ -0,0 +1,144 @@
+// Which datamart slug, MARS slug and ESMIS identifier stand for the same report (--sources), as the three ID systems
+// are easily confused: LM_CT100 is report 2466 to datamart and MARS, while ESMIS knows broiler hatchery as BroiHatc.
+//
+// The mapping is built from what is known here (KNOWN_SOURCES), then the datamart and legacy configurations (a
+// datamart report's slug and mars_slug, a legacy report's ESMIS identifier), then config/sources.toml, each adding
+// to and correcting what came before. Reports are keyed by their name, e.g. lm_ct100.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+use super::datamart::{DatamartApiVersion, DatamartConfig};
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ReportSources {
+    pub description: Option<String>,
+    pub datamart: Option<String>,   // datamart slug ID
+    pub mars: Option<String>,       // MARS slug ID
+    pub esmis: Option<String>       // ESMIS identifier
+}
+
+/// A report's IDs known without configuration
+struct KnownReport {
+    name: &'static str,
+    datamart: Option<&'static str>,
+    mars: Option<&'static str>,
+    esmis: Option<&'static str>
+}
+
+/// Reports whose IDs are known without configuration. Livestock mandatory reports kept their slugs when USDA moved
+/// them to MARS, as with `api_version = "2"` in datamart.toml.
+const KNOWN_SOURCES: &[KnownReport] = &[
+    KnownReport { name: "lm_ct100", datamart: Some("2466"), mars: Some("2466"), esmis: None },
+    KnownReport { name: "lm_ct109", datamart: Some("2659"), mars: Some("2659"), esmis: None },
+    KnownReport { name: "lm_ct142", datamart: Some("2472"), mars: Some("2472"), esmis: None },
+    KnownReport { name: "lm_ct151", datamart: Some("2478"), mars: Some("2478"), esmis: None },
+    KnownReport { name: "lm_ct152", datamart: Some("2479"), mars: Some("2479"), esmis: None },
+    KnownReport { name: "lm_ct153", datamart: Some("2480"), mars: Some("2480"), esmis: None },
+    KnownReport { name: "lm_ct154", datamart: Some("2481"), mars: Some("2481"), esmis: None },
+    KnownReport { name: "broihatc", datamart: None, mars: None, esmis: Some("BroiHatc") },
+    KnownReport { name: "poulslau", datamart: None, mars: None, esmis: Some("PoulSlau") }
+];
+
+/// Sets the IDs `update` has, leaving the others as they were
+fn merge(sources: &mut BTreeMap<String, ReportSources>, name: &str, update: ReportSources) {
+    let entry = sources.entry(name.to_lowercase()).or_default();
+
+    if update.description.is_some() { entry.description = update.description; }
+    if update.datamart.is_some() { entry.datamart = update.datamart; }
+    if update.mars.is_some() { entry.mars = update.mars; }
+    if update.esmis.is_some() { entry.esmis = update.esmis; }
+}
+
+/// The IDs of every report known, by name
+pub fn report_sources(datamart_config: &HashMap<String, DatamartConfig>, legacy_config: &HashMap<String, DatamartConfig>, extra: &BTreeMap<String, ReportSources>) -> BTreeMap<String, ReportSources> {
+    let mut sources = BTreeMap::new();
+
+    for known in KNOWN_SOURCES {
+        let ids = ReportSources { description: None, datamart: known.datamart.map(str::to_owned), mars: known.mars.map(str::to_owned), esmis: known.esmis.map(str::to_owned) };
+        merge(&mut sources, known.name, ids);
+    }
+
+    for (slug, config) in datamart_config {
+        // reports on version 2 of the API are fetched from MARS by their datamart slug
+        let mars = config.mars_slug.clone().or_else(|| (config.api_version == DatamartApiVersion::V2).then(|| slug.to_owned()));
+        merge(&mut sources, &config.name, ReportSources { description: Some(config.description.to_owned()), datamart: Some(slug.to_owned()), mars, esmis: None });
+    }
+
+    for (identifier, config) in legacy_config {
+        merge(&mut sources, &config.name, ReportSources { description: Some(config.description.to_owned()), esmis: Some(identifier.to_owned()), ..Default::default() });
+    }
+
+    for (name, report) in extra {
+        merge(&mut sources, name, report.clone());
+    }
+
+    sources
+}
+
+/// The reports `id` names: by report name, or any of their IDs, without regard to case
+pub fn find_report<'a>(sources: &'a BTreeMap<String, ReportSources>, id: &str) -> Vec<(&'a String, &'a ReportSources)> {
+    let matches = |candidate: &Option<String>| candidate.as_ref().is_some_and(|c| c.eq_ignore_ascii_case(id));
+
+    sources.iter()
+        .filter(|(name, report)| name.eq_ignore_ascii_case(id) || matches(&report.datamart) || matches(&report.mars) || matches(&report.esmis))
+        .collect()
+}
+
+/// A report's IDs as lines of text, "-" standing for those it has none of
+pub fn describe(name: &str, report: &ReportSources) -> String {
+    let id = |id: &Option<String>| id.clone().unwrap_or_else(|| "-".to_owned());
+
+    format!(
+        "{}{}\n  datamart slug:     {}\n  MARS slug:         {}\n  ESMIS identifier:  {}\n",
+        name,
+        report.description.as_ref().map(|d| format!(" ({})", d)).unwrap_or_default(),
+        id(&report.datamart), id(&report.mars), id(&report.esmis)
+    )
+}
+
+#[test]
+fn test_report_sources() {
+    let datamart_config: HashMap<String, DatamartConfig> = toml::from_str(r#"
+        [2466]
+        name = "lm_ct100"
+        description = "5 Area Daily Weighted Average Direct Slaughter Cattle - Negotiated"
+        independent = "report_date"
+        [2466.sections]
+
+        [3208]
+        name = "lm_hg201"
+        description = "National Daily Direct Hog"
+        independent = "report_date"
+        api_version = "2"
+        [3208.sections]
+    "#).unwrap();
+    let legacy_config: HashMap<String, DatamartConfig> = toml::from_str(r#"
+        [BroiHatc]
+        name = "broihatc"
+        description = "Broiler Hatchery"
+        independent = "report_date"
+        [BroiHatc.sections]
+    "#).unwrap();
+    let extra: BTreeMap<String, ReportSources> = toml::from_str("[LM_HG201]\nesmis = \"LMHG201\"").unwrap();
+
+    let sources = report_sources(&datamart_config, &legacy_config, &extra);
+
+    assert_eq!(sources["lm_ct100"].mars.as_deref(), Some("2466"));
+    assert_eq!(sources["lm_hg201"], ReportSources {
+        description: Some("National Daily Direct Hog".to_owned()),
+        datamart: Some("3208".to_owned()),
+        mars: Some("3208".to_owned()),
+        esmis: Some("LMHG201".to_owned())
+    });
+    assert_eq!(sources["broihatc"].description.as_deref(), Some("Broiler Hatchery"));
+
+    let found = find_report(&sources, "broihatc");
+    assert_eq!(found.len(), 1);
+    assert_eq!(find_report(&sources, "2466")[0].0, "lm_ct100");
+    assert_eq!(find_report(&sources, "LM_CT100")[0].0, "lm_ct100");
+    assert!(find_report(&sources, "9999").is_empty());
+
+    assert!(describe("broihatc", found[0].1).contains("datamart slug:     -\n"));
+}
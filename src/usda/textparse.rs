@@ -0,0 +1,252 @@
+// Building blocks for parsing plain text reports: locating anchor lines, capturing dates and labelled values,
+// and pulling rows out of dot-leader tables. See legacy.rs for parsers built from them.
+
+use chrono::NaiveDate;
+use regex::Regex;
+
+use super::dates;
+
+/// Splits report text into lines, the form all of the finders here take
+pub fn lines(text: &str) -> Vec<&str> {
+    text.split_terminator('\n').map(|line| line.trim_end_matches('\r')).collect()
+}
+
+/// Finds the zero-indexed line number that matches a regex pattern.
+/// If your regex is trivial, consider using the faster `find_line_contains`
+pub fn find_line_regex(text_array: &[&str], pattern:&Regex) -> Option<usize> {
+    for (number, line) in text_array.iter().enumerate() {
+        if pattern.is_match(line) {
+            return Some(number)
+        }
+    }
+
+    None
+}
+
+/// Finds the zero-indexed line number that contains a string.
+/// For more advanced finding, consider using the slower `find_line_regex`
+pub fn find_line_contains(text_array: &[&str], pattern:&str) -> Option<usize> {
+    for (number, line) in text_array.iter().enumerate() {
+        if line.contains(pattern) {
+            return Some(number)
+        }
+    }
+
+    None
+}
+
+/// Finds the zero-indexed line number that starts with a string.
+/// For more advanced finding, consider using the slower `find_line_regex`
+/// For basic finding that isn't anchored to the start of a line, consider `find_line_contains`
+pub fn find_line_starts_with(text_array: &[&str], pattern:&str) -> Option<usize> {
+    for (number, line) in text_array.iter().enumerate() {
+        if line.starts_with(pattern) {
+            return Some(number)
+        }
+    }
+
+    None
+}
+
+/// Finds the first line starting with any of the patterns, trying them in order. For reports whose wording
+/// changed between versions.
+pub fn find_line_starts_with_any(text_array: &[&str], patterns: &[&str]) -> Option<usize> {
+    patterns.iter().find_map(|pattern| find_line_starts_with(text_array, pattern))
+}
+
+/// The date on the first line starting with `anchor`, in any format `dates::find_dates` recognises
+pub fn date_on_line(text_array: &[&str], anchor: &str) -> Result<NaiveDate, String> {
+    let location = match find_line_starts_with(text_array, anchor) {
+        Some(line) => { line },
+        None => {
+            return Err(format!("Failed to locate date line: {}", anchor));
+        }
+    };
+
+    match dates::find_date(text_array[location]) {
+        Some(d) => { Ok(d) },
+        None => { Err(format!("Failed to parse date line for report: {}", text_array[location].trim())) }
+    }
+}
+
+/// Captures a label and value from a line with a pattern that has `label` and `value` named groups. The label
+/// is trimmed.
+pub fn labelled_value(line: &str, pattern: &Regex) -> Option<(String, String)> {
+    let x = pattern.captures(line)?;
+
+    Some((x.name("label")?.as_str().trim().to_owned(), x.name("value")?.as_str().to_owned()))
+}
+
+/// Captures a label and value from each line of a block that has one, failing only if none do
+pub fn labelled_values(block: &[&str], pattern: &Regex) -> Result<Vec<(String, String)>, String> {
+    let values: Vec<(String, String)> = block.iter().filter_map(|line| labelled_value(line, pattern)).collect();
+
+    if values.is_empty() {
+        return Err(format!("Failed to capture any values from block beginning: {}", block.first().map(|l| l.trim()).unwrap_or_default()));
+    }
+
+    Ok(values)
+}
+
+/// Footnotes USDA inserts within blocks, e.g. "* Includes product sold on a formula basis" or "Note: ..."
+fn is_footnote(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with('*') || line.starts_with("Note:") || line.starts_with("Source:")
+}
+
+/// The lines of the block following `location`, however many there are. The block ends at the first line matching
+/// `terminator` when one is given; otherwise at the first blank line or unindented heading (text ending in a colon)
+/// after the block has begun. Leading blank lines and footnotes are skipped.
+pub fn block_until<'a>(text_array: &[&'a str], location: usize, terminator: Option<&Regex>) -> Vec<&'a str> {
+    lazy_static! {
+        static ref RE_HEADING: Regex = Regex::new(r"^\S.*:\s*$").unwrap();
+    }
+
+    let mut block = Vec::new();
+
+    for line in text_array.iter().skip(location + 1) {
+        match terminator {
+            Some(pattern) => {
+                if pattern.is_match(line) {
+                    break;
+                }
+            },
+            None => {
+                if !block.is_empty() && (line.trim().is_empty() || RE_HEADING.is_match(line)) {
+                    break;
+                }
+            }
+        }
+
+        if line.trim().is_empty() || is_footnote(line) {
+            continue;
+        }
+
+        block.push(*line);
+    }
+
+    block
+}
+
+/// Splits a NASS text table row such as `Alabama .........|     29,418 |  29,656` into its label and value
+/// cells. Both the older `:` and the newer `|` column separators are accepted. Rows without dot leaders
+/// (titles, headers, footnotes) yield `None`.
+pub fn table_row(line: &str) -> Option<(String, Vec<String>)> {
+    lazy_static! {
+        static ref RE_TABLE_ROW: Regex = Regex::new(r"^(?P<label>\s*[A-Za-z][A-Za-z0-9 ,'/()-]*?)\s*\.{2,}\s*[:|](?P<rest>.*)$").unwrap();
+        static ref RE_CELL_SPLIT: Regex = Regex::new(r"[\s:|]+").unwrap();
+    }
+
+    let captures = RE_TABLE_ROW.captures(line)?;
+    let cells = RE_CELL_SPLIT.split(captures.name("rest").unwrap().as_str())
+        .filter(|c| !c.is_empty())
+        .map(|c| c.to_owned())
+        .collect();
+
+    Some((captures.name("label").unwrap().as_str().trim().to_owned(), cells))
+}
+
+/// A dot-leader table: the lines between its title and first row, and its rows
+pub struct TableBlock<'a> {
+    pub header: Vec<&'a str>,
+    pub rows: Vec<(String, Vec<String>)>,
+}
+
+/// Extracts the table whose title line starts with `title`. The table ends at the first rule or unindented text
+/// after its rows have begun.
+pub fn table_block<'a>(text_array: &[&'a str], title: &str) -> Result<TableBlock<'a>, String> {
+    let location = match find_line_starts_with(text_array, title) {
+        Some(line) => { line },
+        None => {
+            return Err(format!("Failed to locate table: {}", title));
+        }
+    };
+
+    let mut block = TableBlock { header: Vec::new(), rows: Vec::new() };
+
+    for line in &text_array[location+1..] {
+        match table_row(line) {
+            Some(row) => { block.rows.push(row) },
+            None => {
+                if !block.rows.is_empty() && (line.starts_with("---") || line.starts_with(|c: char| c.is_ascii_alphabetic())) {
+                    break;
+                }
+
+                if block.rows.is_empty() {
+                    block.header.push(line);
+                }
+            }
+        }
+    }
+
+    if block.rows.is_empty() {
+        return Err(format!("No rows found in table: {}", title));
+    }
+
+    Ok(block)
+}
+
+#[test]
+fn test_finding_lines() {
+    let text_array = lines("Title\r\nFor Week Ending: 03/06/2020\nTOTAL LOADS 12\n");
+
+    assert_eq!(find_line_starts_with(&text_array, "TOTAL"), Some(2));
+    assert_eq!(find_line_contains(&text_array, "Ending"), Some(1));
+    assert_eq!(find_line_regex(&text_array, &Regex::new(r"\d+$").unwrap()), Some(1));
+    assert_eq!(find_line_starts_with_any(&text_array, &["TOTAL LOADS OF PRODUCT", "TOTAL LOADS"]), Some(2));
+    assert_eq!(date_on_line(&text_array, "For Week Ending").unwrap(), NaiveDate::from_ymd_opt(2020, 3, 6).unwrap());
+    assert!(date_on_line(&text_array, "Title").is_err());
+}
+
+#[test]
+fn test_capturing_labelled_values() {
+    let pattern = Regex::new(r"(?i)(?P<label>(([A-Z]+)\s?)+)\s+(?P<value>[0-9,]+)").unwrap();
+    let text_array = lines("Destination breakdown:\nDomestic   1,024\nOops\nExport     12\n");
+
+    assert_eq!(labelled_value(text_array[1], &pattern), Some(("Domestic".to_owned(), "1,024".to_owned())));
+    assert_eq!(labelled_values(&text_array[1..], &pattern).unwrap().len(), 2);
+    assert!(labelled_values(&text_array[2..3], &pattern).is_err());
+}
+
+#[test]
+fn test_finding_block_boundaries() {
+    let text_array = lines("Quality breakdown:
+
+Prime        12
+* Prime includes branded product
+Choice      700
+Select      150
+
+Sales type breakdown:
+Formula     900
+Sales type breakdown continued:
+");
+
+    assert_eq!(block_until(&text_array, 0, None), vec!["Prime        12", "Choice      700", "Select      150"]);
+    assert_eq!(block_until(&text_array, 7, None), vec!["Formula     900"]);
+
+    let terminator = Regex::new("^Sales").unwrap();
+    assert_eq!(block_until(&text_array, 0, Some(&terminator)).len(), 3);
+}
+
+#[test]
+fn test_extracting_table_blocks() {
+    let text_array = lines("Eggs Set - States
+--------------------------------
+      State      | Dec 23, 2023
+--------------------------------
+Alabama .........|     29,418
+  United States ...:    243,560
+Percent of year  |
+  ago ...........|        101
+");
+
+    let block = table_block(&text_array, "Eggs Set").unwrap();
+    assert_eq!(block.header.len(), 3);
+    assert_eq!(block.rows, vec![
+        ("Alabama".to_owned(), vec!["29,418".to_owned()]),
+        ("United States".to_owned(), vec!["243,560".to_owned()])
+    ]);
+
+    assert!(table_block(&text_array, "Chicks Placed").is_err());
+}
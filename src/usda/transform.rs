@@ -0,0 +1,351 @@
+// Transformations applied to a package between parsing and insertion, configured per report:
+//
+//     transforms = [
+//         { name = "trim" },
+//         { name = "rename_variables", mapping = { "Avg Price" = "avg_price" } },
+//         { name = "require_variables", variables = ["avg_price"] },
+//         { name = "round", precision = { avg_price = 2, dress_pct = 1 }, mode = "half_even" },
+//     ]
+//
+// Transforms run in the order given, each receiving the previous one's output.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::USDADataPackage;
+
+pub trait PackageTransform {
+    fn name(&self) -> &'static str;
+    /// Bumped whenever the transform's output changes, so rows written by older versions can be told apart
+    fn version(&self) -> u32;
+    fn apply(&self, package: USDADataPackage) -> Result<USDADataPackage, String>;
+
+    /// The provenance recorded against values this transform computes
+    fn provenance(&self) -> String {
+        format!("{}@{}", self.name(), self.version())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "name", rename_all = "snake_case")]
+pub enum TransformConfig {
+    /// Trims whitespace from values and drops those left empty
+    Trim,
+    /// Renames variables, leaving those not in the mapping alone
+    RenameVariables { mapping: HashMap<String, String> },
+    /// Drops variables that are not wanted
+    DropVariables { variables: Vec<String> },
+    /// Rejects the package if any row lacks one of these variables
+    RequireVariables { variables: Vec<String> },
+    /// Multiplies numeric values, in place or into a new variable named with `suffix`
+    Scale { variables: Vec<String>, factor: f64, suffix: Option<String> },
+    /// Rounds numeric values to a number of decimals per variable
+    Round { precision: HashMap<String, usize>, #[serde(default)] mode: RoundingMode }
+}
+
+/// How `round` settles the digits it drops
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingMode {
+    /// Halves away from zero, as in most published figures
+    #[default]
+    HalfUp,
+    /// Halves to the even digit, so that rounding doesn't bias sums
+    HalfEven,
+    /// Drops the digits, towards zero
+    Down
+}
+
+impl TransformConfig {
+    pub fn build(&self) -> Box<dyn PackageTransform> {
+        match self {
+            TransformConfig::Trim => { Box::new(Trim) },
+            TransformConfig::RenameVariables { mapping } => { Box::new(RenameVariables { mapping: mapping.clone() }) },
+            TransformConfig::DropVariables { variables } => { Box::new(DropVariables { variables: variables.clone() }) },
+            TransformConfig::RequireVariables { variables } => { Box::new(RequireVariables { variables: variables.clone() }) },
+            TransformConfig::Scale { variables, factor, suffix } => {
+                Box::new(Scale { variables: variables.clone(), factor: *factor, suffix: suffix.clone() })
+            },
+            TransformConfig::Round { precision, mode } => { Box::new(Round { precision: precision.clone(), mode: *mode }) }
+        }
+    }
+}
+
+/// Runs `package` through each transform in turn
+pub fn apply_transforms(mut package: USDADataPackage, transforms: &[Box<dyn PackageTransform>]) -> Result<USDADataPackage, String> {
+    for transform in transforms {
+        package = transform.apply(package).map_err(|e| format!("Transform {} failed: {}", transform.name(), e))?;
+    }
+
+    Ok(package)
+}
+
+/// Runs `package` through the transforms in its report configuration
+pub fn transform_package(package: USDADataPackage, transforms: &[TransformConfig]) -> Result<USDADataPackage, String> {
+    let built: Vec<Box<dyn PackageTransform>> = transforms.iter().map(TransformConfig::build).collect();
+    apply_transforms(package, &built)
+}
+
+struct Trim;
+
+impl PackageTransform for Trim {
+    fn name(&self) -> &'static str { "trim" }
+    fn version(&self) -> u32 { 1 }
+
+    fn apply(&self, mut package: USDADataPackage) -> Result<USDADataPackage, String> {
+        for row in package.sections.values_mut().flatten() {
+            row.entries = row.entries.drain()
+                .map(|(key, value)| (key, value.trim().to_owned()))
+                .filter(|(_, value)| !value.is_empty())
+                .collect();
+        }
+
+        Ok(package)
+    }
+}
+
+struct RenameVariables {
+    mapping: HashMap<String, String>
+}
+
+impl PackageTransform for RenameVariables {
+    fn name(&self) -> &'static str { "rename_variables" }
+    fn version(&self) -> u32 { 1 }
+
+    fn apply(&self, mut package: USDADataPackage) -> Result<USDADataPackage, String> {
+        for row in package.sections.values_mut().flatten() {
+            row.entries = row.entries.drain()
+                .map(|(key, value)| (self.mapping.get(&key).cloned().unwrap_or(key), value))
+                .collect();
+            row.provenance = row.provenance.drain()
+                .map(|(key, value)| (self.mapping.get(&key).cloned().unwrap_or(key), value))
+                .collect();
+        }
+
+        Ok(package)
+    }
+}
+
+struct DropVariables {
+    variables: Vec<String>
+}
+
+impl PackageTransform for DropVariables {
+    fn name(&self) -> &'static str { "drop_variables" }
+    fn version(&self) -> u32 { 1 }
+
+    fn apply(&self, mut package: USDADataPackage) -> Result<USDADataPackage, String> {
+        for row in package.sections.values_mut().flatten() {
+            row.entries.retain(|key, _| !self.variables.contains(key));
+            row.provenance.retain(|key, _| !self.variables.contains(key));
+        }
+
+        Ok(package)
+    }
+}
+
+struct RequireVariables {
+    variables: Vec<String>
+}
+
+impl PackageTransform for RequireVariables {
+    fn name(&self) -> &'static str { "require_variables" }
+    fn version(&self) -> u32 { 1 }
+
+    fn apply(&self, package: USDADataPackage) -> Result<USDADataPackage, String> {
+        for (section, rows) in &package.sections {
+            for row in rows {
+                if let Some(missing) = self.variables.iter().find(|v| !row.entries.contains_key(*v)) {
+                    return Err(format!("{} is missing from a {} row dated {}", missing, section, row.report_date));
+                }
+            }
+        }
+
+        Ok(package)
+    }
+}
+
+struct Scale {
+    variables: Vec<String>,
+    factor: f64,
+    suffix: Option<String>
+}
+
+impl PackageTransform for Scale {
+    fn name(&self) -> &'static str { "scale" }
+    fn version(&self) -> u32 { 1 }
+
+    fn apply(&self, mut package: USDADataPackage) -> Result<USDADataPackage, String> {
+        for row in package.sections.values_mut().flatten() {
+            for variable in &self.variables {
+                let scaled = match row.entries.get(variable).and_then(|v| v.replace(',', "").parse::<f64>().ok()) {
+                    Some(v) => { (v * self.factor).to_string() },
+                    None => { continue } // missing or not numeric
+                };
+
+                let target = match self.suffix.as_ref() {
+                    Some(suffix) => { format!("{}{}", variable, suffix) },
+                    None => { variable.to_owned() }
+                };
+
+                row.entries.insert(target.to_owned(), scaled);
+                row.provenance.insert(target, self.provenance());
+            }
+        }
+
+        Ok(package)
+    }
+}
+
+struct Round {
+    precision: HashMap<String, usize>,
+    mode: RoundingMode
+}
+
+impl PackageTransform for Round {
+    fn name(&self) -> &'static str { "round" }
+    fn version(&self) -> u32 { 1 }
+
+    fn apply(&self, mut package: USDADataPackage) -> Result<USDADataPackage, String> {
+        for row in package.sections.values_mut().flatten() {
+            for (variable, decimals) in &self.precision {
+                if let Some(value) = row.entries.get_mut(variable) {
+                    // values that aren't numbers (e.g. "N/A") are left as they are
+                    if let Some(rounded) = round_decimal(value, *decimals, self.mode) {
+                        *value = rounded;
+                    }
+                }
+            }
+        }
+
+        Ok(package)
+    }
+}
+
+/// Rounds a decimal number given as text to `decimals` places, working on its digits rather than a float so that
+/// the result is exactly what is printed (e.g. 2.675 rounds half up to 2.68, which as a float it would not)
+pub fn round_decimal(text: &str, decimals: usize, mode: RoundingMode) -> Option<String> {
+    let text = text.trim().replace(',', "");
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => { (true, rest) },
+        None => { (false, text.strip_prefix('+').unwrap_or(&text)) }
+    };
+
+    let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if integer.is_empty() && fraction.is_empty() || !integer.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    // the digits kept, as numbers, and those dropped
+    let mut digits: Vec<u8> = integer.bytes().chain(fraction.bytes().chain(std::iter::repeat(b'0')).take(decimals)).map(|b| b - b'0').collect();
+    let dropped = fraction.get(decimals..).unwrap_or_default().as_bytes();
+
+    let round_up = match (mode, dropped.split_first()) {
+        (_, None) | (RoundingMode::Down, _) => { false },
+        (RoundingMode::HalfUp, Some((first, _))) => { *first >= b'5' },
+        (RoundingMode::HalfEven, Some((first, rest))) => {
+            *first > b'5' || (*first == b'5' && (rest.iter().any(|d| *d != b'0') || digits.last().map(|d| d % 2 == 1).unwrap_or(false)))
+        }
+    };
+
+    if round_up {
+        let mut carry = true;
+        for digit in digits.iter_mut().rev() {
+            *digit += 1;
+            carry = *digit == 10;
+            if !carry {
+                break;
+            }
+            *digit = 0;
+        }
+        if carry {
+            digits.insert(0, 1);
+        }
+    }
+
+    let split = digits.len() - decimals;
+    let integer: String = digits[..split].iter().map(|d| (b'0' + d) as char).collect();
+    let integer = match integer.trim_start_matches('0') { "" => { "0" }, trimmed => { trimmed } };
+    let fraction: String = digits[split..].iter().map(|d| (b'0' + d) as char).collect();
+
+    let sign = if negative && digits.iter().any(|d| *d != 0) { "-" } else { "" };
+    match decimals {
+        0 => { Some(format!("{}{}", sign, integer)) },
+        _ => { Some(format!("{}{}.{}", sign, integer, fraction)) }
+    }
+}
+
+#[cfg(test)]
+fn test_transform_package() -> USDADataPackage {
+    use chrono::NaiveDate;
+    use super::USDADataPackageSection;
+
+    let mut row = USDADataPackageSection::new(NaiveDate::from_ymd_opt(2020, 3, 2).unwrap());
+    row.independent.push("2020-03-02".to_owned());
+    row.entries.insert("Avg Price".to_owned(), " 1,210.50 ".to_owned());
+    row.entries.insert("Head Count".to_owned(), "  ".to_owned());
+    row.entries.insert("comment".to_owned(), "steady".to_owned());
+
+    let mut package = USDADataPackage::new("test".to_owned());
+    package.sections.insert("summary".to_owned(), vec![row]);
+    package
+}
+
+#[test]
+fn test_transform_chain() {
+    let transforms: Vec<TransformConfig> = toml::from_str::<HashMap<String, Vec<TransformConfig>>>(r#"
+        transforms = [
+            { name = "trim" },
+            { name = "rename_variables", mapping = { "Avg Price" = "avg_price" } },
+            { name = "drop_variables", variables = ["comment"] },
+            { name = "scale", variables = ["avg_price"], factor = 0.01, suffix = "_per_lb" },
+        ]
+    "#).unwrap().remove("transforms").unwrap();
+
+    let package = transform_package(test_transform_package(), &transforms).unwrap();
+    let entries = &package.sections["summary"][0].entries;
+
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries["avg_price"], "1,210.50");
+    assert!((entries["avg_price_per_lb"].parse::<f64>().unwrap() - 12.105).abs() < 1e-9);
+
+    let provenance = &package.sections["summary"][0].provenance;
+    assert_eq!(provenance.len(), 1);
+    assert_eq!(provenance["avg_price_per_lb"], "scale@1");
+}
+
+#[test]
+fn test_round_decimal() {
+    assert_eq!(round_decimal("223.99998", 2, RoundingMode::HalfUp), Some("224.00".to_owned()));
+    assert_eq!(round_decimal(" 1,210.555 ", 2, RoundingMode::HalfUp), Some("1210.56".to_owned()));
+    assert_eq!(round_decimal("2.675", 2, RoundingMode::HalfUp), Some("2.68".to_owned()));
+    assert_eq!(round_decimal("2.665", 2, RoundingMode::HalfEven), Some("2.66".to_owned()));
+    assert_eq!(round_decimal("2.6651", 2, RoundingMode::HalfEven), Some("2.67".to_owned()));
+    assert_eq!(round_decimal("2.679", 2, RoundingMode::Down), Some("2.67".to_owned()));
+    assert_eq!(round_decimal("-0.004", 2, RoundingMode::HalfUp), Some("0.00".to_owned()));
+    assert_eq!(round_decimal("-9.96", 1, RoundingMode::HalfUp), Some("-10.0".to_owned()));
+    assert_eq!(round_decimal("62.5", 0, RoundingMode::HalfEven), Some("62".to_owned()));
+    assert_eq!(round_decimal(".5", 0, RoundingMode::HalfUp), Some("1".to_owned()));
+    assert_eq!(round_decimal("64", 1, RoundingMode::HalfUp), Some("64.0".to_owned()));
+    assert_eq!(round_decimal("N/A", 2, RoundingMode::HalfUp), None);
+    assert_eq!(round_decimal("-", 2, RoundingMode::HalfUp), None);
+
+    let round: Vec<TransformConfig> = toml::from_str::<HashMap<String, Vec<TransformConfig>>>(r#"
+        transforms = [{ name = "round", precision = { "Avg Price" = 1, comment = 2 } }]
+    "#).unwrap().remove("transforms").unwrap();
+    let package = transform_package(test_transform_package(), &round).unwrap();
+    assert_eq!(package.sections["summary"][0].entries["Avg Price"], "1210.5");
+    assert_eq!(package.sections["summary"][0].entries["comment"], "steady");
+}
+
+#[test]
+fn test_require_variables() {
+    let require = vec![TransformConfig::RequireVariables { variables: vec!["Avg Price".to_owned()] }];
+    assert!(transform_package(test_transform_package(), &require).is_ok());
+
+    let require = vec![TransformConfig::Trim, TransformConfig::RequireVariables { variables: vec!["Head Count".to_owned()] }];
+    let error = transform_package(test_transform_package(), &require).unwrap_err();
+    assert!(error.contains("require_variables"));
+    assert!(error.contains("Head Count"));
+}
@@ -0,0 +1,168 @@
+// Watching a directory for text releases dropped into it, as by an email or SFTP feed, so that they are ingested as
+// they arrive rather than by a later --backfill-text. Files are laid out as for --backfill-text, in a folder named
+// for their report (e.g. incoming/LM_XB463/2020-03-02.txt).
+//
+// A file is handed over once it has gone unchanged for a moment, so that one still being written, or copied in
+// over a slow link, isn't read half-finished.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+/// How long a file must go unchanged before it is ingested
+pub const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Whether a file is a text release, by its extension in any case
+pub fn is_text_file(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()).map(|e| e.eq_ignore_ascii_case("txt")).unwrap_or(false)
+}
+
+/// The report identifier of a text file, as for --backfill-text: the name of the folder it is in, uppercased.
+/// Folder names that aren't valid Unicode can't name a report.
+pub fn folder_identifier(path: &Path) -> Result<String, String> {
+    let folder = path.parent().and_then(Path::file_name).ok_or_else(|| format!("{} isn't in a report folder", path.display()))?;
+
+    folder.to_str()
+        .map(str::to_uppercase)
+        .ok_or_else(|| format!("Report folder name {} isn't valid Unicode", folder.to_string_lossy()))
+}
+
+/// The report identifier of a text file under `root`, see `folder_identifier`. Files directly in `root` and files
+/// other than text have none.
+pub fn report_identifier(root: &Path, path: &Path) -> Option<String> {
+    let folder = path.parent()?;
+
+    if !is_text_file(path) || folder == root || !folder.starts_with(root) {
+        return None;
+    }
+
+    folder_identifier(path).ok()
+}
+
+/// Files changed recently, by when they last changed
+#[derive(Default)]
+struct Pending {
+    changed: HashMap<PathBuf, Instant>
+}
+
+impl Pending {
+    fn touch(&mut self, path: PathBuf, at: Instant) {
+        self.changed.insert(path, at);
+    }
+
+    /// Takes the files unchanged for `settle` as of `now`, in order
+    fn settled(&mut self, now: Instant, settle: Duration) -> Vec<PathBuf> {
+        let mut settled: Vec<PathBuf> = self.changed.iter()
+            .filter(|(_, at)| now.duration_since(**at) >= settle)
+            .map(|(path, _)| path.clone())
+            .collect();
+        settled.sort();
+
+        for path in &settled {
+            self.changed.remove(path);
+        }
+
+        settled
+    }
+
+    /// How long until the next file settles, if any is pending
+    fn next_wait(&self, now: Instant, settle: Duration) -> Option<Duration> {
+        self.changed.values().map(|at| (*at + settle).saturating_duration_since(now)).min()
+    }
+}
+
+/// Watches `root` and the folders under it until the watch fails, calling `ingest` with the path and report
+/// identifier of each text file created or written once it has settled
+pub fn watch(root: &Path, mut ingest: impl FnMut(&Path, &str)) -> Result<(), String> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let _ = sender.send(event);
+    }).map_err(|e| format!("Failed to watch {}: {}", root.display(), e))?;
+
+    watcher.watch(root, RecursiveMode::Recursive).map_err(|e| format!("Failed to watch {}: {}", root.display(), e))?;
+    info!("Watching {} for text releases.", root.display());
+
+    let mut pending = Pending::default();
+    loop {
+        let received = match pending.next_wait(Instant::now(), SETTLE_TIME) {
+            Some(wait) => { receiver.recv_timeout(wait) },
+            None => { receiver.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected) }
+        };
+
+        match received {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    for path in event.paths {
+                        if report_identifier(root, &path).is_some() {
+                            pending.touch(path, Instant::now());
+                        }
+                    }
+                }
+            },
+            Ok(Err(e)) => {
+                error!("Error watching {}: {}", root.display(), e);
+            },
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(format!("Stopped watching {}", root.display()));
+            }
+        }
+
+        for path in pending.settled(Instant::now(), SETTLE_TIME) {
+            // a file renamed or removed again before it settled is passed over
+            if path.is_file() {
+                if let Some(identifier) = report_identifier(root, &path) {
+                    ingest(&path, &identifier);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_report_identifier() {
+    let root = Path::new("/srv/incoming");
+
+    assert_eq!(report_identifier(root, Path::new("/srv/incoming/lm_xb463/2020-03-02.TXT")), Some("LM_XB463".to_owned()));
+    assert_eq!(report_identifier(root, Path::new("/srv/incoming/BroiHatc/a.txt")), Some("BROIHATC".to_owned()));
+    assert_eq!(report_identifier(root, Path::new("/srv/incoming/a.txt")), None);
+    assert_eq!(report_identifier(root, Path::new("/srv/incoming/LM_XB463/a.pdf")), None);
+    assert_eq!(report_identifier(root, Path::new("/srv/incoming/LM_XB463/.a.txt.partial")), None);
+}
+
+#[test]
+fn test_folder_identifier() {
+    assert_eq!(folder_identifier(&Path::new("text").join("BroiHatc").join("2020-03-02.TXT")), Ok("BROIHATC".to_owned()));
+    assert!(folder_identifier(Path::new("a.txt")).is_err());
+
+    #[cfg(unix)]
+    {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"text/LM_\xff/a.txt"));
+        assert!(folder_identifier(path).is_err());
+        assert!(is_text_file(path));
+    }
+}
+
+#[test]
+fn test_pending_settles() {
+    let mut pending = Pending::default();
+    let start = Instant::now();
+    let settle = Duration::from_secs(2);
+
+    pending.touch(PathBuf::from("b.txt"), start);
+    pending.touch(PathBuf::from("a.txt"), start + Duration::from_secs(1));
+    assert_eq!(pending.next_wait(start, settle), Some(settle));
+
+    // a write to a file puts it back
+    pending.touch(PathBuf::from("b.txt"), start + Duration::from_secs(1));
+    assert!(pending.settled(start + Duration::from_secs(2), settle).is_empty());
+
+    assert_eq!(pending.settled(start + Duration::from_secs(3), settle), vec![PathBuf::from("a.txt"), PathBuf::from("b.txt")]);
+    assert_eq!(pending.next_wait(start, settle), None);
+}
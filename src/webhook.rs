@@ -0,0 +1,152 @@
+// On-demand fetches for daemon mode, so upstream systems can trigger a pull as soon as USDA publishes.
+//
+// `POST /fetch/{slug}` queues an update of one report, and `POST /fetch/{slug}?date=YYYY-MM-DD` a fetch of one
+// report date. Requests must carry `Authorization: Bearer <token>`, the token being set under [webhook] in the
+// secret configuration. Requests are answered as soon as they are queued; the daemon runs them between updates.
+
+use std::collections::HashSet;
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+
+use chrono::NaiveDate;
+use tiny_http::{Method, Response, Server};
+
+#[derive(Debug, PartialEq)]
+pub struct FetchRequest {
+    pub slug: String,
+    pub date: Option<NaiveDate>
+}
+
+/// Reads a fetch request from a request path, or gives the HTTP status and message to refuse it with
+pub fn parse_fetch_path(path: &str) -> Result<FetchRequest, (u16, String)> {
+    let (path, query) = match path.find('?') {
+        Some(i) => { (&path[..i], Some(&path[i + 1..])) },
+        None => { (path, None) }
+    };
+
+    let slug = match path.strip_prefix("/fetch/") {
+        Some(s) if !s.is_empty() && !s.contains('/') => { s },
+        _ => { return Err((404, "Not found".to_owned())) }
+    };
+
+    let slug = percent_encoding::percent_decode_str(slug).decode_utf8()
+        .map_err(|_| (400, "Invalid report name".to_owned()))?
+        .into_owned();
+
+    let mut date = None;
+    for pair in query.unwrap_or_default().split('&').filter(|p| !p.is_empty()) {
+        match pair.split_once('=') {
+            Some(("date", value)) => {
+                date = Some(NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| (400, format!("Invalid date, expected YYYY-MM-DD: {}", value)))?);
+            },
+            _ => { return Err((400, format!("Unknown parameter: {}", pair))) }
+        }
+    }
+
+    Ok(FetchRequest { slug, date })
+}
+
+/// Checks an Authorization header against the configured token, without returning early on the first difference
+pub fn authorized(header: Option<&str>, token: &str) -> bool {
+    let presented = match header.and_then(|h| h.strip_prefix("Bearer ")) {
+        Some(p) => { p.trim().as_bytes() },
+        None => { return false }
+    };
+
+    if token.is_empty() || presented.len() != token.len() {
+        return false;
+    }
+
+    presented.iter().zip(token.as_bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Listens on `address` on a thread of its own, passing accepted requests for known reports to `sender`.
+/// Report names are compared without regard to case and passed on as configured.
+pub fn serve(address: &str, token: String, known: HashSet<String>, sender: Sender<FetchRequest>) -> Result<JoinHandle<()>, String> {
+    let server = Server::http(address).map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+
+    Ok(std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let header = request.headers().iter()
+                .find(|h| h.field.equiv("Authorization"))
+                .map(|h| h.value.as_str().to_owned());
+
+            let (status, message) = if *request.method() != Method::Post {
+                (405, "Only POST is supported".to_owned())
+            } else if !authorized(header.as_deref(), &token) {
+                (401, "Unauthorized".to_owned())
+            } else {
+                match parse_fetch_path(request.url()) {
+                    Ok(fetch) => {
+                        match known.iter().find(|k| k.eq_ignore_ascii_case(&fetch.slug)) {
+                            Some(slug) => {
+                                let message = format!("Queued fetch of {}", slug);
+                                match sender.send(FetchRequest { slug: slug.to_owned(), date: fetch.date }) {
+                                    Ok(_) => { (202, message) },
+                                    Err(_) => { (503, "Not accepting requests".to_owned()) }
+                                }
+                            },
+                            None => { (404, format!("Unknown report: {}", fetch.slug)) }
+                        }
+                    },
+                    Err(refusal) => { refusal }
+                }
+            };
+
+            if let Err(e) = request.respond(Response::from_string(message).with_status_code(status)) {
+                error!("Failed to answer fetch request: {}", e);
+            }
+        }
+    }))
+}
+
+#[test]
+fn test_parse_fetch_path() {
+    assert_eq!(parse_fetch_path("/fetch/2466"), Ok(FetchRequest { slug: "2466".to_owned(), date: None }));
+    assert_eq!(
+        parse_fetch_path("/fetch/LM_XB463?date=2020-03-06"),
+        Ok(FetchRequest { slug: "LM_XB463".to_owned(), date: NaiveDate::from_ymd_opt(2020, 3, 6) })
+    );
+
+    assert_eq!(parse_fetch_path("/fetch/").unwrap_err().0, 404);
+    assert_eq!(parse_fetch_path("/status").unwrap_err().0, 404);
+    assert_eq!(parse_fetch_path("/fetch/2466?date=03/06/2020").unwrap_err().0, 400);
+    assert_eq!(parse_fetch_path("/fetch/2466?since=2020-03-06").unwrap_err().0, 400);
+}
+
+#[test]
+fn test_authorized() {
+    assert!(authorized(Some("Bearer s3cret"), "s3cret"));
+    assert!(!authorized(Some("Bearer s3cre"), "s3cret"));
+    assert!(!authorized(Some("s3cret"), "s3cret"));
+    assert!(!authorized(None, "s3cret"));
+    assert!(!authorized(Some("Bearer "), ""));
+}
+
+#[test]
+fn test_serve() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::mpsc::channel;
+
+    let (sender, receiver) = channel();
+    let known: HashSet<String> = vec!["LM_XB463".to_owned()].into_iter().collect();
+
+    // find a free port, then hand it to the server
+    let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    serve(&address, "s3cret".to_owned(), known, sender).unwrap();
+
+    let post = |path: &str, token: &str| -> String {
+        let mut stream = TcpStream::connect(&address).unwrap();
+        write!(stream, "POST {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", path, token).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    assert!(post("/fetch/lm_xb463?date=2020-03-06", "wrong").starts_with("HTTP/1.1 401"));
+    assert!(post("/fetch/DC_GR110", "s3cret").starts_with("HTTP/1.1 404"));
+    assert!(post("/fetch/lm_xb463?date=2020-03-06", "s3cret").starts_with("HTTP/1.1 202"));
+
+    assert_eq!(receiver.recv().unwrap(), FetchRequest { slug: "LM_XB463".to_owned(), date: NaiveDate::from_ymd_opt(2020, 3, 6) });
+}